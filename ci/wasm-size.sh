@@ -0,0 +1,21 @@
+#!/bin/sh
+# Build the dprint Wasm plugin with the minimal feature set and fail if it
+# grows past the budget (in bytes, WASM_SIZE_BUDGET overrides it).
+#
+#   rustup target add wasm32-unknown-unknown
+#   ci/wasm-size.sh
+set -eu
+
+budget="${WASM_SIZE_BUDGET:-655360}"
+wasm=target/wasm32-unknown-unknown/release/pqm_formatter.wasm
+
+cd "$(dirname "$0")/.."
+cargo rustc --release --lib --crate-type cdylib --target wasm32-unknown-unknown \
+    --no-default-features --features dprint
+size=$(wc -c < "$wasm" | tr -d ' ')
+
+echo "$wasm: $size bytes (budget $budget)"
+if [ "$size" -gt "$budget" ]; then
+    echo "error: the minimal Wasm build is larger than its budget" >&2
+    exit 1
+fi
@@ -0,0 +1,113 @@
+//! Side-by-side gallery of the formatting presets
+//!
+//! Formats every query in `tests/corpus` with the default, compact, and
+//! expanded presets and writes the results, next to the original, to
+//! `examples/gallery.html`. The page is committed, so a layout change shows
+//! up in review as a change to it.
+//!
+//! ```text
+//! cargo run --example gallery             # regenerate examples/gallery.html
+//! cargo run --example gallery -- --check  # fail if it is out of date
+//! ```
+
+use pqm_formatter::{format, Config};
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+const CORPUS_DIR: &str = "tests/corpus";
+const GALLERY_FILE: &str = "examples/gallery.html";
+
+fn presets() -> Vec<(&'static str, Config)> {
+    vec![
+        ("default", Config::default()),
+        ("compact", Config::compact()),
+        ("expanded", Config::expanded()),
+    ]
+}
+
+fn queries() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(CORPUS_DIR)
+        .unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", CORPUS_DIR, e);
+            process::exit(1);
+        })
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "pq"))
+        .collect();
+    files.sort();
+    files
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn gallery() -> String {
+    let presets = presets();
+    let mut html = String::from(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <title>pqmfmt gallery</title>\n\
+         <style>\n\
+         body { font-family: sans-serif; }\n\
+         table { border-collapse: collapse; }\n\
+         th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }\n\
+         pre { margin: 0; font-size: 12px; }\n\
+         </style>\n\
+         </head>\n\
+         <body>\n\
+         <!-- Generated by `cargo run --example gallery`; do not edit -->\n\
+         <h1>pqmfmt gallery</h1>\n",
+    );
+    for path in queries() {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let code = fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", path.display(), e);
+            process::exit(1);
+        });
+        
+        html.push_str(&format!("<h2>{}</h2>\n<table>\n<tr><th>original</th>", escape_html(&name)));
+        for (preset, _) in &presets {
+            html.push_str(&format!("<th>{}</th>", preset));
+        }
+        html.push_str("</tr>\n<tr>");
+        html.push_str(&format!("<td><pre>{}</pre></td>", escape_html(&code)));
+        for (preset, config) in &presets {
+            let formatted = format(&code, config.clone()).unwrap_or_else(|e| {
+                eprintln!("Error formatting {} with {}: {:?}", name, preset, e);
+                process::exit(1);
+            });
+            html.push_str(&format!("<td><pre>{}</pre></td>", escape_html(&formatted)));
+        }
+        html.push_str("</tr>\n</table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn main() {
+    let check = std::env::args().skip(1).any(|arg| arg == "--check");
+    let html = gallery();
+    
+    if check {
+        let current = fs::read_to_string(GALLERY_FILE).unwrap_or_default();
+        if current != html {
+            eprintln!(
+                "{} is out of date: run `cargo run --example gallery` and review the layout changes",
+                GALLERY_FILE
+            );
+            process::exit(1);
+        }
+        eprintln!("{} is up to date", GALLERY_FILE);
+        return;
+    }
+    
+    if let Err(e) = fs::write(GALLERY_FILE, &html) {
+        eprintln!("Error writing {}: {}", GALLERY_FILE, e);
+        process::exit(1);
+    }
+    eprintln!("Wrote {}", GALLERY_FILE);
+}
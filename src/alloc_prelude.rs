@@ -0,0 +1,7 @@
+//! The parts of the std prelude that come from `alloc`, imported by the
+//! library modules so that they also build without the `std` feature
+
+pub(crate) use alloc::boxed::Box;
+pub(crate) use alloc::string::{String, ToString};
+pub(crate) use alloc::vec::Vec;
+pub(crate) use alloc::{format, vec};
@@ -0,0 +1,515 @@
+//! Query inventory analysis
+//!
+//! Summarizes what a query does without evaluating it: its steps, the data
+//! sources it connects to, and the names it expects from elsewhere in the
+//! workbook or report.
+
+use core::fmt;
+
+use crate::alloc_prelude::*;
+use crate::ast::{Document, Expr, ExprKind, LetExpr, Trivia};
+use crate::float;
+use crate::json;
+use crate::lexer::Lexer;
+use crate::parser::{ParseError, Parser};
+
+/// Library functions that open a connection to a data source
+pub const CONNECTOR_FUNCTIONS: &[&str] = &[
+    "AnalysisServices.Database",
+    "AzureStorage.Blobs",
+    "Csv.Document",
+    "Excel.Workbook",
+    "File.Contents",
+    "Folder.Contents",
+    "Folder.Files",
+    "MySQL.Database",
+    "OData.Feed",
+    "Odbc.DataSource",
+    "Odbc.Query",
+    "OleDb.DataSource",
+    "Oracle.Database",
+    "PostgreSQL.Database",
+    "SharePoint.Contents",
+    "SharePoint.Files",
+    "SharePoint.Tables",
+    "Snowflake.Databases",
+    "Sql.Database",
+    "Sql.Databases",
+    "Web.Contents",
+    "Web.Page",
+];
+
+/// Whether `name` is one of [`CONNECTOR_FUNCTIONS`]
+pub fn is_connector_function(name: &str) -> bool {
+    CONNECTOR_FUNCTIONS.contains(&name)
+}
+
+/// What argument `index` of connector `function` holds, e.g. `"server"`
+/// for the first argument of `Sql.Database`
+pub fn connector_argument_role(function: &str, index: usize) -> Option<&'static str> {
+    let role = match (function, index) {
+        ("Sql.Database" | "Sql.Databases" | "Oracle.Database" | "PostgreSQL.Database"
+        | "MySQL.Database" | "AnalysisServices.Database" | "Snowflake.Databases", 0) => "server",
+        ("Sql.Database" | "PostgreSQL.Database" | "MySQL.Database"
+        | "AnalysisServices.Database", 1) => "database",
+        ("Snowflake.Databases", 1) => "warehouse",
+        ("Web.Contents" | "Web.Page" | "OData.Feed" | "SharePoint.Contents" | "SharePoint.Files"
+        | "SharePoint.Tables" | "AzureStorage.Blobs", 0) => "url",
+        ("File.Contents" | "Folder.Contents" | "Folder.Files", 0) => "path",
+        ("Odbc.DataSource" | "Odbc.Query" | "OleDb.DataSource", 0) => "connection",
+        ("Odbc.Query", 1) => "query",
+        _ => return None,
+    };
+    Some(role)
+}
+
+/// A call to a connector function
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataSource {
+    /// Connector function name, e.g. `Sql.Database`
+    pub function: String,
+    /// Text literal arguments, in order
+    pub literals: Vec<String>,
+    /// Line of the call
+    pub line: usize,
+}
+
+/// Summary of a single query
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuerySummary {
+    /// Names of the top-level `let` bindings
+    pub steps: Vec<String>,
+    /// Connector calls, in source order
+    pub data_sources: Vec<DataSource>,
+    /// Free names used as connector arguments (usually query parameters)
+    pub parameters: Vec<String>,
+    /// Other free names (usually other queries)
+    pub query_references: Vec<String>,
+}
+
+impl QuerySummary {
+    /// Summarize a parsed document
+    pub fn from_document(document: &Document) -> Self {
+        let mut summary = QuerySummary::default();
+        
+        if let Some(l) = steps_let(&document.expression) {
+            summary.steps = l.bindings.iter().map(|b| b.name.name.clone()).collect();
+        }
+        
+        let mut connector_args = Vec::new();
+        document.expression.walk(&mut |expr| {
+            if let ExprKind::FunctionCall(call) = &expr.kind {
+                if let ExprKind::Identifier(name) = &call.function.kind {
+                    if is_connector_function(name) {
+                        summary.data_sources.push(DataSource {
+                            function: name.clone(),
+                            literals: call
+                                .arguments
+                                .iter()
+                                .filter_map(|arg| match &arg.kind {
+                                    ExprKind::Text(s) => Some(s.clone()),
+                                    _ => None,
+                                })
+                                .collect(),
+                            line: expr.span.line,
+                        });
+                        for arg in &call.arguments {
+                            arg.walk(&mut |e| {
+                                if let Some(name) = reference_name(e) {
+                                    connector_args.push(name.to_string());
+                                }
+                            });
+                        }
+                    }
+                }
+            }
+        });
+        
+        let mut free = Vec::new();
+        collect_free_names(&document.expression, &mut Vec::new(), &mut free);
+        for name in free {
+            if connector_args.contains(&name) {
+                summary.parameters.push(name);
+            } else {
+                summary.query_references.push(name);
+            }
+        }
+        
+        summary
+    }
+}
+
+impl fmt::Display for QuerySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.steps.is_empty() {
+            writeln!(f, "Steps: 0")?;
+        } else {
+            let names: Vec<String> = self.steps.iter().map(|s| display_name(s)).collect();
+            writeln!(f, "Steps: {} ({})", self.steps.len(), names.join(", "))?;
+        }
+        
+        if self.data_sources.is_empty() {
+            writeln!(f, "Data sources: none")?;
+        } else {
+            writeln!(f, "Data sources:")?;
+            for source in &self.data_sources {
+                let literals: Vec<String> =
+                    source.literals.iter().map(|s| format!("\"{}\"", s)).collect();
+                writeln!(
+                    f,
+                    "    {}({})  line {}",
+                    source.function,
+                    literals.join(", "),
+                    source.line
+                )?;
+            }
+        }
+        
+        writeln!(f, "Parameters: {}", name_list(&self.parameters))?;
+        write!(f, "Query references: {}", name_list(&self.query_references))
+    }
+}
+
+/// Measurements an analyst noted after a step, in a line comment such as
+/// `// ~2.5s`, `// rows: 12000`, or `// ~120ms, rows: 3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepAnnotation {
+    /// Duration after `~`, in milliseconds
+    pub milliseconds: Option<u64>,
+    pub rows: Option<u64>,
+    /// Unit the duration was written in: `ms`, `s`, `m`, or `h`
+    unit: &'static str,
+}
+
+impl StepAnnotation {
+    /// Recognize the text of a line comment (without `//`) as an annotation.
+    /// Every comma- or semicolon-separated part must be a duration or a row
+    /// count.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use pqm_formatter::analysis::StepAnnotation;
+    ///
+    /// let annotation = StepAnnotation::parse(" ~ 2.5 sec;ROWS:12000").unwrap();
+    /// assert_eq!(annotation.milliseconds, Some(2500));
+    /// assert_eq!(annotation.rows, Some(12000));
+    /// assert_eq!(annotation.to_string(), "~2.5s, rows: 12000");
+    /// assert!(StepAnnotation::parse("slow, fix later").is_none());
+    /// ```
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut annotation = StepAnnotation {
+            milliseconds: None,
+            rows: None,
+            unit: "s",
+        };
+        for part in text.split([',', ';']).map(str::trim) {
+            if let Some(duration) = part.strip_prefix('~') {
+                let duration = duration.trim_start();
+                let split = duration
+                    .find(|c: char| !c.is_ascii_digit() && c != '.')
+                    .unwrap_or(duration.len());
+                let value: f64 = duration[..split].parse().ok()?;
+                let (unit, factor) = match duration[split..].trim_start() {
+                    "ms" => ("ms", 1.0),
+                    "s" | "sec" => ("s", 1_000.0),
+                    "m" | "min" => ("m", 60_000.0),
+                    "h" => ("h", 3_600_000.0),
+                    _ => return None,
+                };
+                if annotation.milliseconds.is_some() {
+                    return None;
+                }
+                annotation.milliseconds = Some(float::round(value * factor) as u64);
+                annotation.unit = unit;
+            } else {
+                let prefix = part.get(..4).filter(|p| p.eq_ignore_ascii_case("rows"))?;
+                let count = part[prefix.len()..].trim_start();
+                let count = count.strip_prefix([':', '=']).unwrap_or(count).trim_start();
+                if annotation.rows.is_some() || !count.starts_with(|c: char| c.is_ascii_digit()) {
+                    return None;
+                }
+                annotation.rows = Some(count.replace('_', "").parse().ok()?);
+            }
+        }
+        Some(annotation)
+    }
+}
+
+impl fmt::Display for StepAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(milliseconds) = self.milliseconds {
+            let factor = match self.unit {
+                "ms" => 1.0,
+                "m" => 60_000.0,
+                "h" => 3_600_000.0,
+                _ => 1_000.0,
+            };
+            parts.push(format!("~{}{}", milliseconds as f64 / factor, self.unit));
+        }
+        if let Some(rows) = self.rows {
+            parts.push(format!("rows: {}", rows));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// A step with the comments written around it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepInfo {
+    pub name: String,
+    /// Comment text without `//` or `/* */`, one comment per line;
+    /// the annotation is not included
+    pub comment: Option<String>,
+    /// Measurements noted in a line comment after the step
+    pub annotation: Option<StepAnnotation>,
+    pub line: usize,
+}
+
+impl StepInfo {
+    /// JSON object `{"file": ..., "name": ..., "comment": ..., "line": ...}`;
+    /// `file` is omitted when `None` and `comment` is `null` when absent.
+    /// An annotation adds `"duration_ms"` and `"rows"` (`null` if not noted).
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        let mut members = Vec::new();
+        if let Some(file) = file {
+            members.push(format!("\"file\": {}", json::quote(file)));
+        }
+        members.push(format!("\"name\": {}", json::quote(&self.name)));
+        members.push(format!(
+            "\"comment\": {}",
+            self.comment.as_deref().map_or_else(|| "null".to_string(), json::quote)
+        ));
+        if let Some(annotation) = &self.annotation {
+            let number = |n: Option<u64>| n.map_or_else(|| "null".to_string(), |n| n.to_string());
+            members.push(format!("\"duration_ms\": {}", number(annotation.milliseconds)));
+            members.push(format!("\"rows\": {}", number(annotation.rows)));
+        }
+        members.push(format!("\"line\": {}", self.line));
+        format!("{{{}}}", members.join(", "))
+    }
+    
+    /// CSV row `file,name,comment,line` (RFC 4180 quoting)
+    pub fn to_csv_row(&self, file: &str) -> String {
+        format!(
+            "{},{},{},{}",
+            csv_field(file),
+            csv_field(&self.name),
+            csv_field(self.comment.as_deref().unwrap_or("")),
+            self.line
+        )
+    }
+}
+
+/// Steps of a query with their comments
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{analysis, parse_document, Config};
+///
+/// let code = "let\n    // Raw rows\n    Source = 1\nin\n    Source";
+/// let (document, _) = parse_document(code, &Config::default()).unwrap();
+/// let steps = analysis::step_infos(&document);
+/// assert_eq!(steps[0].comment.as_deref(), Some("Raw rows"));
+/// assert_eq!(steps[0].line, 3);
+/// ```
+pub fn step_infos(document: &Document) -> Vec<StepInfo> {
+    let Some(steps) = steps_let(&document.expression) else {
+        return Vec::new();
+    };
+    steps
+        .bindings
+        .iter()
+        .map(|binding| {
+            let annotation = step_annotation(&binding.trailing_trivia);
+            let comments: Vec<&str> = binding
+                .leading_trivia
+                .iter()
+                .chain(&binding.trailing_trivia)
+                .filter(|t| annotation.is_none() || !is_annotation(t))
+                .filter_map(|t| match t {
+                    Trivia::LineComment(text) | Trivia::BlockComment(text) => Some(text.trim()),
+                    _ => None,
+                })
+                .filter(|text| !text.is_empty())
+                .collect();
+            StepInfo {
+                name: binding.name.name.clone(),
+                comment: (!comments.is_empty()).then(|| comments.join("\n")),
+                annotation,
+                line: binding.span.line,
+            }
+        })
+        .collect()
+}
+
+/// The annotation among the comments after a step: the first line comment
+/// that is one
+pub fn step_annotation(trailing: &[Trivia]) -> Option<StepAnnotation> {
+    trailing.iter().find_map(|t| match t {
+        Trivia::LineComment(text) => StepAnnotation::parse(text),
+        _ => None,
+    })
+}
+
+fn is_annotation(trivia: &Trivia) -> bool {
+    matches!(trivia, Trivia::LineComment(text) if StepAnnotation::parse(text).is_some())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The `let` whose bindings are a query's steps: the top-level expression,
+/// or the body of a query that is a function
+pub fn steps_let(expr: &Expr) -> Option<&LetExpr> {
+    match &expr.kind {
+        ExprKind::Let(l) => Some(l),
+        ExprKind::Parenthesized(inner) => steps_let(inner),
+        ExprKind::Function(f) => steps_let(&f.body),
+        _ => None,
+    }
+}
+
+/// Parse `code` and summarize it
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::analysis::describe;
+///
+/// let summary = describe(r#"let Source = Sql.Database(Server, "Sales") in Source"#).unwrap();
+/// assert_eq!(summary.steps, ["Source"]);
+/// assert_eq!(summary.data_sources[0].function, "Sql.Database");
+/// assert_eq!(summary.parameters, ["Server"]);
+/// ```
+pub fn describe(code: &str) -> Result<QuerySummary, Vec<ParseError>> {
+    let tokens = Lexer::new(code).tokenize();
+    let document = Parser::new(tokens).parse()?;
+    Ok(QuerySummary::from_document(&document))
+}
+
+/// Name referenced by an identifier expression, without an `@` prefix
+fn reference_name(expr: &Expr) -> Option<&str> {
+    match &expr.kind {
+        ExprKind::Identifier(name) | ExprKind::QuotedIdentifier(name) => Some(name),
+        ExprKind::InclusiveIdentifier(ident) => Some(&ident.name),
+        _ => None,
+    }
+}
+
+/// Collect names that are referenced but not bound anywhere in scope.
+/// Dotted names are library members and are skipped.
+pub(crate) fn collect_free_names(expr: &Expr, scope: &mut Vec<String>, out: &mut Vec<String>) {
+    let bound = match &expr.kind {
+        ExprKind::Let(l) => l.bindings.iter().map(|b| b.name.name.clone()).collect(),
+        ExprKind::Record(r) => r.fields.iter().map(|f| f.name.name.clone()).collect(),
+        ExprKind::Function(f) => f.parameters.iter().map(|p| p.name.name.clone()).collect(),
+        _ => Vec::new(),
+    };
+    
+    if let Some(name) = reference_name(expr) {
+        if !name.contains('.') && !scope.iter().any(|s| s == name) && !out.iter().any(|s| s == name) {
+            out.push(name.to_string());
+        }
+        return;
+    }
+    
+    let depth = scope.len();
+    scope.extend(bound);
+    for child in expr.children() {
+        collect_free_names(child, scope, out);
+    }
+    scope.truncate(depth);
+}
+
+pub(crate) fn display_name(name: &str) -> String {
+    let plain = name.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+    if plain {
+        name.to_string()
+    } else {
+        format!("#\"{}\"", name)
+    }
+}
+
+fn name_list(names: &[String]) -> String {
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.iter().map(|s| display_name(s)).collect::<Vec<_>>().join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_describe_query() {
+        let code = r#"let
+    Source = Sql.Database(ServerName, "Sales"),
+    Orders = Source{[Schema = "dbo", Item = "Orders"]}[Data],
+    Joined = Table.NestedJoin(Orders, {"CustomerID"}, #"Customer List", {"ID"}, "C", JoinKind.LeftOuter),
+    Rates = Csv.Document(Web.Contents("https://example.com/rates.csv")),
+    #"Added Rate" = Table.AddColumn(Joined, "Rate", each [Amount] * Multiplier)
+in
+    #"Added Rate""#;
+        let summary = describe(code).unwrap();
+        assert_eq!(summary.steps, ["Source", "Orders", "Joined", "Rates", "Added Rate"]);
+        let functions: Vec<&str> = summary.data_sources.iter().map(|d| d.function.as_str()).collect();
+        assert_eq!(functions, ["Sql.Database", "Csv.Document", "Web.Contents"]);
+        assert_eq!(summary.data_sources[0].literals, ["Sales"]);
+        assert_eq!(summary.data_sources[2].line, 5);
+        assert_eq!(summary.parameters, ["ServerName"]);
+        assert_eq!(summary.query_references, ["Customer List", "Multiplier"]);
+    }
+    
+    #[test]
+    fn test_function_query_scopes() {
+        let summary = describe("(path as text) => let Source = File.Contents(path), x = [a = 1, b = a] in Source").unwrap();
+        assert_eq!(summary.steps, ["Source", "x"]);
+        assert!(summary.parameters.is_empty());
+        assert!(summary.query_references.is_empty());
+    }
+    
+    #[test]
+    fn test_step_infos() {
+        let code = "let\n    // Load\n    /* raw, \"csv\" */\n    Source = 1,\n    #\"Filtered Rows\" = Source\nin\n    #\"Filtered Rows\"";
+        let (document, _) = crate::parse_document(code, &crate::Config::default()).unwrap();
+        let steps = step_infos(&document);
+        assert_eq!(steps.len(), 2);
+        assert_eq!(
+            steps[0].to_json(Some("q.pq")),
+            r#"{"file": "q.pq", "name": "Source", "comment": "Load\nraw, \"csv\"", "line": 4}"#
+        );
+        assert_eq!(steps[1].to_json(None), r#"{"name": "Filtered Rows", "comment": null, "line": 5}"#);
+        assert_eq!(steps[0].to_csv_row("q.pq"), "q.pq,Source,\"Load\nraw, \"\"csv\"\"\",4");
+        
+        let code = "let\n    Source = 1, // ~120ms, rows: 3\n    Kept = Source // trimmed; rows: 2\nin\n    Kept";
+        let (document, _) = crate::parse_document(code, &crate::Config::default()).unwrap();
+        let steps = step_infos(&document);
+        assert_eq!(steps[0].comment, None);
+        assert_eq!(
+            steps[0].to_json(None),
+            r#"{"name": "Source", "comment": null, "duration_ms": 120, "rows": 3, "line": 2}"#
+        );
+        assert_eq!(steps[1].annotation, None);
+        assert_eq!(steps[1].comment.as_deref(), Some("trimmed; rows: 2"));
+    }
+    
+    #[test]
+    fn test_display() {
+        let summary = describe("let #\"My Step\" = Other in #\"My Step\"").unwrap();
+        assert_eq!(
+            summary.to_string(),
+            "Steps: 1 (#\"My Step\")\nData sources: none\nParameters: none\nQuery references: Other"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 //! Abstract Syntax Tree definitions for Power Query M language
 
+use crate::alloc_prelude::*;
+pub use crate::token::NumberLiteral;
 use crate::token::Span;
 
 /// Root document node
@@ -7,6 +9,133 @@ use crate::token::Span;
 pub struct Document {
     pub expression: Expr,
     pub span: Span,
+    /// Comments on lines of their own after the expression
+    pub trailing_trivia: Vec<Trivia>,
+    /// Original text, filled in by [`crate::parse_document`]; empty for
+    /// documents parsed from bare tokens
+    pub source: String,
+}
+
+impl Document {
+    /// Original text of `expr`, a node of this document.
+    ///
+    /// ```rust
+    /// use pqm_formatter::{ast::ExprKind, parse_document, Config};
+    ///
+    /// let (document, _) = parse_document("let Total = 1 +  2 in Total", &Config::default()).unwrap();
+    /// let ExprKind::Let(l) = &document.expression.kind else { unreachable!() };
+    /// assert_eq!(document.source_of(&l.bindings[0].value), "1 +  2");
+    /// ```
+    pub fn source_of(&self, expr: &Expr) -> &str {
+        expr.span.slice(&self.source)
+    }
+    
+    /// Every node of the tree in source order, parents first, with its span
+    /// and its depth below the document expression (which is at depth 0).
+    /// Let bindings, section members, record fields, and function
+    /// parameters are nodes of their own between an expression and its
+    /// children.
+    ///
+    /// ```rust
+    /// use pqm_formatter::ast::{ExprKind, NodeRef};
+    /// use pqm_formatter::{parse_document, Config};
+    ///
+    /// let (document, _) = parse_document("let Total = [a = 1][a] in Total", &Config::default()).unwrap();
+    /// let fields = document
+    ///     .nodes()
+    ///     .filter(|(node, _, _)| matches!(node, NodeRef::RecordField(_)))
+    ///     .count();
+    /// assert_eq!(fields, 1);
+    ///
+    /// // The innermost node at an offset: `1`
+    /// let (node, _, depth) = document
+    ///     .nodes()
+    ///     .filter(|(_, span, _)| span.start <= 17 && 17 < span.end)
+    ///     .last()
+    ///     .unwrap();
+    /// assert!(matches!(node, NodeRef::Expr(e) if matches!(e.kind, ExprKind::Number(_))));
+    /// assert_eq!(depth, 5);
+    /// ```
+    pub fn nodes(&self) -> Nodes<'_> {
+        Nodes {
+            stack: vec![(NodeRef::Expr(&self.expression), 0)],
+        }
+    }
+}
+
+/// A node of the tree, as yielded by [`Document::nodes`]
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum NodeRef<'a> {
+    Expr(&'a Expr),
+    Binding(&'a Binding),
+    SectionMember(&'a SectionMember),
+    RecordField(&'a RecordField),
+    Parameter(&'a Parameter),
+}
+
+impl<'a> NodeRef<'a> {
+    pub fn span(&self) -> Span {
+        match self {
+            NodeRef::Expr(e) => e.span,
+            NodeRef::Binding(b) => b.span,
+            NodeRef::SectionMember(m) => m.span,
+            NodeRef::RecordField(f) => f.span,
+            NodeRef::Parameter(p) => p.span,
+        }
+    }
+    
+    /// Child nodes, in source order
+    pub(crate) fn children(&self) -> Vec<NodeRef<'a>> {
+        match *self {
+            NodeRef::Expr(expr) => match &expr.kind {
+                ExprKind::Let(l) => l
+                    .bindings
+                    .iter()
+                    .map(NodeRef::Binding)
+                    .chain([NodeRef::Expr(&l.body)])
+                    .collect(),
+                ExprKind::Section(s) => s
+                    .attributes
+                    .iter()
+                    .map(NodeRef::Expr)
+                    .chain(s.members.iter().map(NodeRef::SectionMember))
+                    .collect(),
+                ExprKind::Record(r) => r.fields.iter().map(NodeRef::RecordField).collect(),
+                ExprKind::Function(f) => f
+                    .parameters
+                    .iter()
+                    .map(NodeRef::Parameter)
+                    .chain([NodeRef::Expr(&f.body)])
+                    .collect(),
+                _ => expr.children().into_iter().map(NodeRef::Expr).collect(),
+            },
+            NodeRef::Binding(b) => vec![NodeRef::Expr(&b.value)],
+            NodeRef::SectionMember(m) => m
+                .attributes
+                .iter()
+                .chain([&m.value])
+                .map(NodeRef::Expr)
+                .collect(),
+            NodeRef::RecordField(f) => vec![NodeRef::Expr(&f.value)],
+            NodeRef::Parameter(_) => Vec::new(),
+        }
+    }
+}
+
+/// Preorder iterator over the nodes of a document, from [`Document::nodes`]
+pub struct Nodes<'a> {
+    stack: Vec<(NodeRef<'a>, usize)>,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = (NodeRef<'a>, Span, usize);
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, depth) = self.stack.pop()?;
+        self.stack.extend(node.children().into_iter().rev().map(|child| (child, depth + 1)));
+        Some((node, node.span(), depth))
+    }
 }
 
 /// Expression node
@@ -18,6 +147,10 @@ pub struct Expr {
     pub leading_trivia: Vec<Trivia>,
     /// Trailing trivia (comments, whitespace after this expression)
     pub trailing_trivia: Vec<Trivia>,
+    /// Print the original source text of this node instead of formatting it
+    /// (a "format island"). Takes effect when the formatter was created
+    /// with [`Formatter::with_source`](crate::Formatter::with_source).
+    pub verbatim: bool,
 }
 
 impl Expr {
@@ -27,6 +160,7 @@ impl Expr {
             span,
             leading_trivia: Vec::new(),
             trailing_trivia: Vec::new(),
+            verbatim: false,
         }
     }
     
@@ -39,20 +173,170 @@ impl Expr {
         self.trailing_trivia = trivia;
         self
     }
+    
+    /// Direct child expressions, in source order
+    pub fn children(&self) -> Vec<&Expr> {
+        match &self.kind {
+            ExprKind::Null
+            | ExprKind::Logical(_)
+            | ExprKind::Number(_)
+            | ExprKind::Text(_)
+            | ExprKind::Identifier(_)
+            | ExprKind::QuotedIdentifier(_)
+            | ExprKind::InclusiveIdentifier(_)
+            | ExprKind::Type(_)
+            | ExprKind::Underscore
+            | ExprKind::HashSections
+            | ExprKind::HashShared
+            | ExprKind::HashUnknown(_)
+            | ExprKind::Verbatim(_) => Vec::new(),
+            ExprKind::Let(l) => {
+                let mut children: Vec<&Expr> = l.bindings.iter().map(|b| &b.value).collect();
+                children.push(&l.body);
+                children
+            }
+            ExprKind::Section(s) => s
+                .attributes
+                .iter()
+                .chain(s.members.iter().flat_map(|m| m.attributes.iter().chain([&m.value])))
+                .collect(),
+            ExprKind::If(i) => vec![&i.condition, &i.then_branch, &i.else_branch],
+            ExprKind::Try(t) => core::iter::once(&t.expr)
+                .chain(t.otherwise.as_ref())
+                .chain(t.catch.as_ref())
+                .collect(),
+            ExprKind::Error(e) | ExprKind::Each(e) | ExprKind::Parenthesized(e) => vec![e],
+            ExprKind::Function(f) => vec![&f.body],
+            ExprKind::FunctionCall(c) => core::iter::once(&c.function).chain(&c.arguments).collect(),
+            ExprKind::Record(r) => r.fields.iter().map(|f| &f.value).collect(),
+            ExprKind::List(l) => l.items.iter().collect(),
+            ExprKind::FieldAccess(f) => vec![&f.expr],
+            ExprKind::SectionAccess(a) => vec![&a.section],
+            ExprKind::FieldProjection(f) => vec![&f.expr],
+            ExprKind::ItemAccess(i) => vec![&i.expr, &i.index],
+            ExprKind::Binary(b) => vec![&b.left, &b.right],
+            ExprKind::Unary(u) => vec![&u.operand],
+            ExprKind::Metadata(m) => vec![&m.expr, &m.metadata],
+            ExprKind::HashTable(t) => vec![&t.columns, &t.rows],
+            ExprKind::HashBinary(b) => vec![&b.value],
+            ExprKind::HashDate(d) => vec![&d.year, &d.month, &d.day],
+            ExprKind::HashTime(t) => vec![&t.hour, &t.minute, &t.second],
+            ExprKind::HashDatetime(d) => {
+                vec![&d.year, &d.month, &d.day, &d.hour, &d.minute, &d.second]
+            }
+            ExprKind::HashDatetimezone(d) => vec![
+                &d.year,
+                &d.month,
+                &d.day,
+                &d.hour,
+                &d.minute,
+                &d.second,
+                &d.offset_hours,
+                &d.offset_minutes,
+            ],
+            ExprKind::HashDuration(d) => vec![&d.days, &d.hours, &d.minutes, &d.seconds],
+        }
+    }
+    
+    /// Direct child expressions, in source order, for rewriting
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match &mut self.kind {
+            ExprKind::Null
+            | ExprKind::Logical(_)
+            | ExprKind::Number(_)
+            | ExprKind::Text(_)
+            | ExprKind::Identifier(_)
+            | ExprKind::QuotedIdentifier(_)
+            | ExprKind::InclusiveIdentifier(_)
+            | ExprKind::Type(_)
+            | ExprKind::Underscore
+            | ExprKind::HashSections
+            | ExprKind::HashShared
+            | ExprKind::HashUnknown(_)
+            | ExprKind::Verbatim(_) => Vec::new(),
+            ExprKind::Let(l) => {
+                let mut children: Vec<&mut Expr> = l.bindings.iter_mut().map(|b| &mut b.value).collect();
+                children.push(&mut l.body);
+                children
+            }
+            ExprKind::Section(s) => s
+                .attributes
+                .iter_mut()
+                .chain(s.members.iter_mut().flat_map(|m| m.attributes.iter_mut().chain([&mut m.value])))
+                .collect(),
+            ExprKind::If(i) => vec![&mut i.condition, &mut i.then_branch, &mut i.else_branch],
+            ExprKind::Try(t) => core::iter::once(&mut t.expr)
+                .chain(t.otherwise.as_mut())
+                .chain(t.catch.as_mut())
+                .collect(),
+            ExprKind::Error(e) | ExprKind::Each(e) | ExprKind::Parenthesized(e) => vec![e],
+            ExprKind::Function(f) => vec![&mut f.body],
+            ExprKind::FunctionCall(c) => {
+                core::iter::once(&mut c.function).chain(&mut c.arguments).collect()
+            }
+            ExprKind::Record(r) => r.fields.iter_mut().map(|f| &mut f.value).collect(),
+            ExprKind::List(l) => l.items.iter_mut().collect(),
+            ExprKind::FieldAccess(f) => vec![&mut f.expr],
+            ExprKind::SectionAccess(a) => vec![&mut a.section],
+            ExprKind::FieldProjection(f) => vec![&mut f.expr],
+            ExprKind::ItemAccess(i) => vec![&mut i.expr, &mut i.index],
+            ExprKind::Binary(b) => vec![&mut b.left, &mut b.right],
+            ExprKind::Unary(u) => vec![&mut u.operand],
+            ExprKind::Metadata(m) => vec![&mut m.expr, &mut m.metadata],
+            ExprKind::HashTable(t) => vec![&mut t.columns, &mut t.rows],
+            ExprKind::HashBinary(b) => vec![&mut b.value],
+            ExprKind::HashDate(d) => vec![&mut d.year, &mut d.month, &mut d.day],
+            ExprKind::HashTime(t) => vec![&mut t.hour, &mut t.minute, &mut t.second],
+            ExprKind::HashDatetime(d) => vec![
+                &mut d.year,
+                &mut d.month,
+                &mut d.day,
+                &mut d.hour,
+                &mut d.minute,
+                &mut d.second,
+            ],
+            ExprKind::HashDatetimezone(d) => vec![
+                &mut d.year,
+                &mut d.month,
+                &mut d.day,
+                &mut d.hour,
+                &mut d.minute,
+                &mut d.second,
+                &mut d.offset_hours,
+                &mut d.offset_minutes,
+            ],
+            ExprKind::HashDuration(d) => {
+                vec![&mut d.days, &mut d.hours, &mut d.minutes, &mut d.seconds]
+            }
+        }
+    }
+    
+    /// Visit this expression and all of its descendants, parents first
+    pub fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Expr)) {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
+        }
+    }
 }
 
-/// Expression kinds
+/// Expression kinds. New kinds are added as the grammar grows, so matches
+/// outside this crate need a wildcard arm.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum ExprKind {
     // Literals
     Null,
     Logical(bool),
-    Number(f64),
+    Number(NumberLiteral),
     Text(String),
     
     // Identifiers
     Identifier(String),
     QuotedIdentifier(String),
+    /// `@name`, a reference that includes the binding being defined, as in
+    /// a recursive function; the identifier is the name without the `@`
+    InclusiveIdentifier(Identifier),
     
     // Let expression
     Let(LetExpr),
@@ -87,6 +371,9 @@ pub enum ExprKind {
     // Field projection: record[[field1], [field2], ...]
     FieldProjection(Box<FieldProjectionExpr>),
     
+    // Section member access: Section1!Member
+    SectionAccess(Box<SectionAccessExpr>),
+    
     // Item access: list{index}
     ItemAccess(Box<ItemAccessExpr>),
     
@@ -110,11 +397,27 @@ pub enum ExprKind {
     
     // Built-in constructors
     HashTable(Box<HashTableExpr>),
+    HashBinary(Box<HashBinaryExpr>),
     HashDate(Box<HashDateExpr>),
     HashTime(Box<HashTimeExpr>),
     HashDatetime(Box<HashDatetimeExpr>),
     HashDatetimezone(Box<HashDatetimezoneExpr>),
     HashDuration(Box<HashDurationExpr>),
+    
+    // Environment records: #sections and #shared
+    HashSections,
+    HashShared,
+    
+    // Unrecognized #keyword, kept verbatim
+    HashUnknown(String),
+    
+    // Source region the parser could not understand (lenient mode),
+    // emitted exactly as written
+    Verbatim(Span),
+    
+    // Section document: section Name; shared A = ...; B = ...;
+    // Only ever the expression of a Document
+    Section(Box<Section>),
 }
 
 /// Let expression: let bindings in body
@@ -124,10 +427,41 @@ pub struct LetExpr {
     pub body: Box<Expr>,
 }
 
+/// Section document: `section Name;` followed by its members
+#[derive(Debug, Clone)]
+pub struct Section {
+    /// Literal attribute record before `section`
+    pub attributes: Option<Expr>,
+    pub name: Identifier,
+    /// Comments after `section Name;` on its line
+    pub trailing_trivia: Vec<Trivia>,
+    pub members: Vec<SectionMember>,
+}
+
+/// Member of a section document: `shared Name = value;`
+#[derive(Debug, Clone)]
+pub struct SectionMember {
+    /// Literal attribute record before the member
+    /// (`[DataSource.Kind = "..."]`)
+    pub attributes: Option<Expr>,
+    /// Whether the member is `shared`, visible outside the section
+    pub shared: bool,
+    pub name: Identifier,
+    pub value: Expr,
+    /// From the attributes or name to the `;`
+    pub span: Span,
+    /// Comments on lines of their own before the member
+    pub leading_trivia: Vec<Trivia>,
+    /// Comments before the `;` and on its line after it
+    pub trailing_trivia: Vec<Trivia>,
+}
+
 /// Variable binding in let expression
 #[derive(Debug, Clone)]
 pub struct Binding {
     pub name: Identifier,
+    /// Written `@name`
+    pub inclusive: bool,
     pub value: Expr,
     pub span: Span,
     pub leading_trivia: Vec<Trivia>,
@@ -156,11 +490,13 @@ pub struct IfExpr {
     pub else_branch: Expr,
 }
 
-/// Try expression: try expr otherwise fallback
+/// Try expression: try expr otherwise fallback, or try expr catch (e) => handler
 #[derive(Debug, Clone)]
 pub struct TryExpr {
     pub expr: Expr,
     pub otherwise: Option<Expr>,
+    /// `catch` handler; always an `ExprKind::Function` with at most one parameter
+    pub catch: Option<Expr>,
 }
 
 /// Function expression: (params) => body
@@ -178,6 +514,8 @@ pub struct Parameter {
     pub type_annotation: Option<TypeAnnotation>,
     pub optional: bool,
     pub span: Span,
+    pub leading_trivia: Vec<Trivia>,
+    pub trailing_trivia: Vec<Trivia>,
 }
 
 /// Type annotation
@@ -189,8 +527,10 @@ pub struct TypeAnnotation {
 
 /// Type kinds
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum TypeKind {
     Any,
+    AnyNonNull,
     None,
     Null,
     Logical,
@@ -203,12 +543,23 @@ pub enum TypeKind {
     Text,
     Binary,
     Type,
+    Action,
     List(Option<Box<TypeAnnotation>>),
+    /// `record` without fields: any record, unlike the closed `[]`
+    AnyRecord,
     Record(Vec<FieldType>),
+    /// `table` without a row type
+    AnyTable,
     Table(Vec<FieldType>),
-    Function(Vec<TypeAnnotation>, Box<TypeAnnotation>),
+    /// `function` without a signature
+    AnyFunction,
+    Function(Vec<Parameter>, Box<TypeAnnotation>),
     Custom(String),
     Nullable(Box<TypeAnnotation>),
+    /// Parenthesized type: `(nullable number)`
+    Parenthesized(Box<TypeAnnotation>),
+    /// Parenthesized expression in type position: `(type text meta [...])`
+    Expr(Box<Expr>),
 }
 
 /// Field type in record/table types
@@ -241,6 +592,10 @@ pub struct RecordField {
     pub span: Span,
     pub leading_trivia: Vec<Trivia>,
     pub trailing_trivia: Vec<Trivia>,
+    /// The field could not be parsed and is printed as written: `span`
+    /// covers it, `value` is an `ExprKind::Verbatim` of the same span, and
+    /// `name` is empty
+    pub verbatim: bool,
 }
 
 /// List expression: {item1, item2, item3}
@@ -265,6 +620,14 @@ pub struct FieldProjectionExpr {
     pub optional: bool,
 }
 
+/// Section access expression: Section1!Member. `section` is usually an
+/// identifier, but `#sections[Section1]!Member` is accepted as well.
+#[derive(Debug, Clone)]
+pub struct SectionAccessExpr {
+    pub section: Expr,
+    pub member: Identifier,
+}
+
 /// Item access expression: expr{index} or expr{index}?
 #[derive(Debug, Clone)]
 pub struct ItemAccessExpr {
@@ -388,6 +751,9 @@ impl UnaryOp {
 #[derive(Debug, Clone)]
 pub struct TypeExpr {
     pub type_annotation: TypeAnnotation,
+    /// Whether `type` is written. Always true for a type expression on its
+    /// own; the type operand of `as`/`is` usually leaves it out (`x as text`).
+    pub keyword: bool,
 }
 
 /// Metadata expression: expr meta record
@@ -404,6 +770,13 @@ pub struct HashTableExpr {
     pub rows: Expr,
 }
 
+/// #binary constructor: `#binary("AQID")` from base64 text or
+/// `#binary({1, 2, 3})` from a list of bytes
+#[derive(Debug, Clone)]
+pub struct HashBinaryExpr {
+    pub value: Expr,
+}
+
 /// #date constructor
 #[derive(Debug, Clone)]
 pub struct HashDateExpr {
@@ -0,0 +1,43 @@
+//! Subcommands and clipboard mode of the `pqmfmt` binary
+//!
+//! A subcommand is only recognized as the first argument. Everything after
+//! `--` is a file to format, so `pqmfmt -- diff` formats a file named `diff`.
+
+pub mod clipboard;
+mod inspect;
+mod install;
+mod sections;
+#[cfg(feature = "self-update")]
+mod update;
+
+/// A subcommand, run with the arguments that follow its name
+type Run = fn(&[String]);
+
+/// Subcommands by name
+const SUBCOMMANDS: &[(&str, Run)] = &[
+    ("install-hook", install::install_hook),
+    ("install-service", install::install_service),
+    ("install-shell-ext", install::install_shell_ext),
+    #[cfg(feature = "self-update")]
+    ("self-update", update::self_update),
+    ("describe", inspect::describe),
+    ("diff", inspect::diff),
+    ("docstub", inspect::docstub),
+    ("bundle", sections::bundle),
+    ("split", sections::split),
+];
+
+/// Run the subcommand `args` (without the program name) start with.
+/// Returns false if they do not start with one, for formatting.
+pub fn run_subcommand(args: &[String]) -> bool {
+    let Some((name, rest)) = args.split_first() else {
+        return false;
+    };
+    match SUBCOMMANDS.iter().find(|(subcommand, _)| subcommand == name) {
+        Some((_, run)) => {
+            run(rest);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,260 @@
+//! Clipboard mode: format the clipboard in place, with the native
+//! clipboard commands of each platform
+
+use crate::{format_content, Options};
+use pqm_formatter::detect::{looks_like_m, Confidence};
+use pqm_formatter::{split_copied_queries, Config};
+use std::process::{self, Command};
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use std::io::Write;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use std::process::Stdio;
+
+/// Get clipboard content using native commands
+fn get_clipboard() -> Result<String, String> {
+    #[cfg(target_os = "windows")]
+    {
+        // Use Windows Forms Clipboard API and save to temp file with UTF-8
+        let temp_path = std::env::temp_dir().join("pqmfmt_clipboard_in.txt");
+        let temp_path_str = temp_path.to_string_lossy().replace('\\', "\\\\");
+        
+        let ps_script = format!(r#"
+Add-Type -AssemblyName System.Windows.Forms
+$text = [System.Windows.Forms.Clipboard]::GetText()
+[System.IO.File]::WriteAllText('{}', $text, [System.Text.Encoding]::UTF8)
+"#, temp_path_str);
+
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-STA", "-Command", &ps_script])
+            .output()
+            .map_err(|e| format!("Failed to execute PowerShell: {}", e))?;
+        
+        if !output.status.success() {
+            return Err(format!("PowerShell error: {}", String::from_utf8_lossy(&output.stderr)));
+        }
+        
+        // Read the UTF-8 file
+        let content = std::fs::read_to_string(&temp_path)
+            .map_err(|e| format!("Failed to read clipboard content: {}", e))?;
+        
+        // Clean up temp file
+        let _ = std::fs::remove_file(&temp_path);
+        
+        // Remove BOM if present
+        let content = content.strip_prefix('\u{FEFF}').unwrap_or(&content);
+        Ok(content.to_string())
+    }
+    
+    #[cfg(target_os = "macos")]
+    {
+        let output = Command::new("pbpaste")
+            .output()
+            .map_err(|e| format!("Failed to execute pbpaste: {}", e))?;
+        
+        if output.status.success() {
+            String::from_utf8(output.stdout)
+                .map_err(|e| format!("Invalid UTF-8 in clipboard: {}", e))
+        } else {
+            Err(format!("pbpaste error: {}", String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+    
+    #[cfg(target_os = "linux")]
+    {
+        // Try xclip first, then xsel
+        let output = Command::new("xclip")
+            .args(["-selection", "clipboard", "-o"])
+            .output()
+            .or_else(|_| {
+                Command::new("xsel")
+                    .args(["--clipboard", "--output"])
+                    .output()
+            })
+            .map_err(|e| format!("Failed to execute xclip/xsel: {}", e))?;
+        
+        if output.status.success() {
+            String::from_utf8(output.stdout)
+                .map_err(|e| format!("Invalid UTF-8 in clipboard: {}", e))
+        } else {
+            Err(format!("Clipboard error: {}", String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+    
+    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+    {
+        Err("Clipboard not supported on this platform".to_string())
+    }
+}
+
+/// Set clipboard content using native commands
+fn set_clipboard(content: &str) -> Result<(), String> {
+    #[cfg(target_os = "windows")]
+    {
+        // Write content to a temp file with UTF-8 encoding
+        let temp_path = std::env::temp_dir().join("pqmfmt_clipboard_out.txt");
+        
+        std::fs::write(&temp_path, content.as_bytes())
+            .map_err(|e| format!("Failed to write temp file: {}", e))?;
+        
+        let temp_path_str = temp_path.to_string_lossy().replace('\\', "\\\\");
+        
+        // Use Windows Forms Clipboard API for proper Unicode support
+        let ps_script = format!(r#"
+Add-Type -AssemblyName System.Windows.Forms
+$text = [System.IO.File]::ReadAllText('{}', [System.Text.Encoding]::UTF8)
+[System.Windows.Forms.Clipboard]::SetText($text)
+"#, temp_path_str);
+
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-STA", "-Command", &ps_script])
+            .output()
+            .map_err(|e| format!("Failed to execute PowerShell: {}", e))?;
+        
+        // Clean up temp file
+        let _ = std::fs::remove_file(&temp_path);
+        
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!("PowerShell Set-Clipboard failed: {}", 
+                String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+    
+    #[cfg(target_os = "macos")]
+    {
+        let mut child = Command::new("pbcopy")
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to execute pbcopy: {}", e))?;
+        
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(content.as_bytes())
+                .map_err(|e| format!("Failed to write to pbcopy: {}", e))?;
+        }
+        
+        let status = child.wait()
+            .map_err(|e| format!("Failed to wait for pbcopy: {}", e))?;
+        
+        if status.success() {
+            Ok(())
+        } else {
+            Err("pbcopy failed".to_string())
+        }
+    }
+    
+    #[cfg(target_os = "linux")]
+    {
+        // Try xclip first, then xsel
+        let result = Command::new("xclip")
+            .args(["-selection", "clipboard"])
+            .stdin(Stdio::piped())
+            .spawn();
+        
+        let mut child = match result {
+            Ok(child) => child,
+            Err(_) => {
+                Command::new("xsel")
+                    .args(["--clipboard", "--input"])
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("Failed to execute xclip/xsel: {}", e))?
+            }
+        };
+        
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(content.as_bytes())
+                .map_err(|e| format!("Failed to write to clipboard: {}", e))?;
+        }
+        
+        let status = child.wait()
+            .map_err(|e| format!("Failed to wait for clipboard command: {}", e))?;
+        
+        if status.success() {
+            Ok(())
+        } else {
+            Err("Clipboard command failed".to_string())
+        }
+    }
+    
+    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+    {
+        Err("Clipboard not supported on this platform".to_string())
+    }
+}
+
+/// Process clipboard: read, format, and write back
+pub fn process_clipboard(config: Config, opts: &Options) {
+    let content = match get_clipboard() {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Error reading clipboard: {}", e);
+            process::exit(1);
+        }
+    };
+    
+    // Several queries copied at once from the Power Query editor
+    if let Some(queries) = split_copied_queries(&content) {
+        let result = format_copied_queries(&queries, config, opts);
+        write_clipboard_result(&content, result);
+        return;
+    }
+    
+    // Check if content looks like Power Query M code
+    let trimmed = content.trim();
+    if !opts.force && looks_like_m(trimmed) == Confidence::None {
+        eprintln!("Clipboard does not contain Power Query M code (use --force to format it anyway)");
+        if !trimmed.is_empty() {
+            eprintln!("Clipboard content preview: {}...", 
+                &trimmed.chars().take(50).collect::<String>());
+        }
+        process::exit(1);
+    }
+    
+    write_clipboard_result(&content, format_content(&content, config, opts));
+}
+
+/// Format each query of a multi-query copy, keeping the `// Name` headers
+fn format_copied_queries(
+    queries: &[(&str, &str)],
+    config: Config,
+    opts: &Options,
+) -> Result<String, String> {
+    let mut formatted = Vec::new();
+    for (name, code) in queries {
+        match format_content(code, config.clone(), opts) {
+            Ok(query) => formatted.push(format!("// {}\n{}", name, query)),
+            Err(e) => return Err(format!("{}:\n{}", name, e)),
+        }
+    }
+    Ok(formatted.join("\n"))
+}
+
+/// Put formatted code on the clipboard, or the error and the original code
+fn write_clipboard_result(content: &str, result: Result<String, String>) {
+    match result {
+        Ok(formatted) => {
+            if let Err(e) = set_clipboard(&formatted) {
+                eprintln!("Error writing to clipboard: {}", e);
+                process::exit(1);
+            }
+            eprintln!("Formatted code copied to clipboard.");
+        }
+        Err(error_msg) => {
+            // On error, put error message + original code in clipboard
+            let error_output = format!(
+                "// Format Error:\n// {}\n\n{}",
+                error_msg.replace('\n', "\n// "),
+                content
+            );
+            if let Err(e) = set_clipboard(&error_output) {
+                eprintln!("Error writing to clipboard: {}", e);
+                process::exit(1);
+            }
+            eprintln!("Format error. Error message and original code copied to clipboard.");
+            eprintln!("{}", error_msg);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! `pqmfmt describe`, `diff`, and `docstub`: reports about queries
+
+use pqm_formatter::{analysis, diff, refactor};
+use std::fs;
+use std::process;
+
+/// `pqmfmt describe FILE...`
+pub fn describe(files: &[String]) {
+    if files.is_empty() {
+        eprintln!("Error: describe requires at least one file");
+        process::exit(1);
+    }
+    
+    let mut has_errors = false;
+    for (i, file_path) in files.iter().enumerate() {
+        let content = match fs::read_to_string(file_path) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", file_path, e);
+                has_errors = true;
+                continue;
+            }
+        };
+        
+        match analysis::describe(&content) {
+            Ok(summary) => {
+                if files.len() > 1 {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("{}:", file_path);
+                }
+                println!("{}", summary);
+            }
+            Err(errors) => {
+                eprintln!("Error in {}:", file_path);
+                for e in &errors {
+                    eprintln!("{}", e);
+                }
+                has_errors = true;
+            }
+        }
+    }
+    
+    if has_errors {
+        process::exit(1);
+    }
+}
+
+/// `pqmfmt diff OLD NEW`
+pub fn diff(args: &[String]) {
+    let [old_path, new_path] = args else {
+        eprintln!("Error: diff requires two files");
+        process::exit(1);
+    };
+    
+    let read = |file_path: &String| {
+        let content = fs::read_to_string(file_path).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", file_path, e);
+            process::exit(1);
+        });
+        diff::parse(&content).unwrap_or_else(|errors| {
+            eprintln!("Error in {}:", file_path);
+            for e in &errors {
+                eprintln!("{}", e);
+            }
+            process::exit(1);
+        })
+    };
+    let old = read(old_path);
+    let new = read(new_path);
+    println!("{}", diff::QueryDiff::between(&old, &new));
+}
+
+/// `pqmfmt docstub FILE`
+pub fn docstub(args: &[String]) {
+    let [file_path] = args else {
+        eprintln!("Error: docstub requires exactly one file");
+        process::exit(1);
+    };
+    
+    let content = match fs::read_to_string(file_path) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file_path, e);
+            process::exit(1);
+        }
+    };
+    
+    // Functions without a named binding are named after the file
+    let name = std::path::Path::new(file_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Function");
+    match refactor::doc_stub(&content, name) {
+        Ok(stub) => print!("{}", stub),
+        Err(e) => {
+            eprintln!("Error in {}: {}", file_path, e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! `pqmfmt install-hook`, `install-service`, and `install-shell-ext`: hook
+//! pqmfmt into git, the macOS Services menu, and the Windows Explorer
+
+use std::env;
+use std::fs;
+use std::process::{self, Command};
+
+/// First line after the shebang of hooks written by `install-hook`
+const HOOK_MARKER: &str = "# Generated by `pqmfmt install-hook`";
+
+/// First line after the shebang of the script run by the Quick Action
+/// written by `install-service`
+const SERVICE_MARKER: &str = "# Generated by `pqmfmt install-service`";
+
+/// Name of the Quick Action in the Services menu
+const SERVICE_NAME: &str = "Format Power Query M";
+
+/// Label of the Explorer context menu verb and the Send To entry written by
+/// `install-shell-ext`
+const SHELL_EXT_LABEL: &str = "Format with pqmfmt";
+
+/// Second line of the Send To script written by `install-shell-ext`
+const SHELL_EXT_MARKER: &str = "@rem Generated by `pqmfmt install-shell-ext`";
+
+/// Script installed as `.git/hooks/pre-commit`
+fn pre_commit_script() -> String {
+    format!(
+        r#"#!/bin/sh
+{marker}
+#
+# Checks the staged contents of .pq/.m files, not the working tree copies,
+# so partially staged files are judged by what will actually be committed.
+# To fix a failure without committing unstaged edits:
+#
+#     git stash push --keep-index        # set unstaged changes aside
+#     pqmfmt -w FILE && git add FILE
+#     git stash pop
+#
+# Set PQMFMT to use a pqmfmt that is not on PATH. Bypass with --no-verify.
+
+PQMFMT="${{PQMFMT:-pqmfmt}}"
+
+files=$(git diff --cached --name-only --diff-filter=ACMR -- '*.pq' '*.m')
+[ -z "$files" ] && exit 0
+
+status=0
+IFS='
+'
+for file in $files; do
+    if ! git show ":$file" | "$PQMFMT" --check --stdin >/dev/null 2>&1; then
+        echo "pqmfmt: $file is not formatted (run: pqmfmt -w \"$file\")" >&2
+        status=1
+    fi
+done
+exit $status
+"#,
+        marker = HOOK_MARKER
+    )
+}
+
+/// Snippet for `.pre-commit-config.yaml` (pre-commit framework)
+const PRE_COMMIT_CONFIG: &str = r#"repos:
+  - repo: local
+    hooks:
+      - id: pqmfmt
+        name: pqmfmt
+        entry: pqmfmt --check
+        language: system
+        files: \.(pq|m)$
+"#;
+
+/// `pqmfmt install-hook [--force] [--pre-commit-config]`
+pub fn install_hook(args: &[String]) {
+    let mut force = false;
+    for arg in args {
+        match arg.as_str() {
+            "--force" => force = true,
+            "--pre-commit-config" => {
+                print!("{}", PRE_COMMIT_CONFIG);
+                return;
+            }
+            _ => {
+                eprintln!("Unknown option for install-hook: {}", arg);
+                process::exit(1);
+            }
+        }
+    }
+    
+    // Honors worktrees and core.hooksPath
+    let output = match Command::new("git").args(["rev-parse", "--git-path", "hooks"]).output() {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            eprintln!("Error: not inside a git repository");
+            process::exit(1);
+        }
+    };
+    let hooks_dir = std::path::PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let hook_path = hooks_dir.join("pre-commit");
+    
+    if let Ok(existing) = fs::read_to_string(&hook_path) {
+        if !existing.contains(HOOK_MARKER) && !force {
+            eprintln!(
+                "Error: {} already exists and was not written by pqmfmt (use --force to replace it)",
+                hook_path.display()
+            );
+            process::exit(1);
+        }
+    }
+    
+    if let Err(e) = fs::create_dir_all(&hooks_dir).and_then(|_| fs::write(&hook_path, pre_commit_script())) {
+        eprintln!("Error writing {}: {}", hook_path.display(), e);
+        process::exit(1);
+    }
+    
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(&hook_path, fs::Permissions::from_mode(0o755)) {
+            eprintln!("Error making {} executable: {}", hook_path.display(), e);
+            process::exit(1);
+        }
+    }
+    
+    eprintln!("Installed pre-commit hook: {}", hook_path.display());
+}
+
+/// `pqmfmt install-service [--uninstall]`
+///
+/// Writes `~/Library/Services/Format Power Query M.workflow`, a Quick Action
+/// that passes the selected text to `pqmfmt --stdin --best-effort` and
+/// replaces it with the output, so text that cannot be formatted stays as it is.
+pub fn install_service(args: &[String]) {
+    let mut uninstall = false;
+    for arg in args {
+        match arg.as_str() {
+            "--uninstall" => uninstall = true,
+            _ => {
+                eprintln!("Unknown option for install-service: {}", arg);
+                process::exit(1);
+            }
+        }
+    }
+    if !cfg!(target_os = "macos") {
+        eprintln!("Error: install-service is only available on macOS");
+        process::exit(1);
+    }
+    let home = match env::var_os("HOME") {
+        Some(home) => std::path::PathBuf::from(home),
+        None => {
+            eprintln!("Error: HOME is not set");
+            process::exit(1);
+        }
+    };
+    let workflow = home.join("Library/Services").join(format!("{}.workflow", SERVICE_NAME));
+    let contents = workflow.join("Contents");
+    let script_path = contents.join("pqmfmt-service.sh");
+    
+    if workflow.exists() {
+        let ours = fs::read_to_string(&script_path).is_ok_and(|s| s.contains(SERVICE_MARKER));
+        if !ours {
+            eprintln!("Error: {} already exists and was not written by pqmfmt", workflow.display());
+            process::exit(1);
+        }
+    }
+    if uninstall {
+        if !workflow.exists() {
+            eprintln!("{} is not installed", SERVICE_NAME);
+            return;
+        }
+        if let Err(e) = fs::remove_dir_all(&workflow) {
+            eprintln!("Error removing {}: {}", workflow.display(), e);
+            process::exit(1);
+        }
+        eprintln!("Removed Quick Action: {}", workflow.display());
+        return;
+    }
+    
+    // Services run with a minimal PATH, so the script names this executable
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Error locating the pqmfmt executable: {}", e);
+            process::exit(1);
+        }
+    };
+    let files = [
+        (contents.join("Info.plist"), service_info_plist()),
+        (contents.join("document.wflow"), service_workflow(&script_path)),
+        (script_path.clone(), service_script(&exe)),
+    ];
+    for (path, content) in &files {
+        if let Err(e) = fs::create_dir_all(&contents).and_then(|_| fs::write(path, content)) {
+            eprintln!("Error writing {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+    
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)) {
+            eprintln!("Error making {} executable: {}", script_path.display(), e);
+            process::exit(1);
+        }
+    }
+    
+    eprintln!("Installed Quick Action: {}", workflow.display());
+    eprintln!("Select M code in any app and choose Services > {}.", SERVICE_NAME);
+}
+
+/// Script run by the Quick Action, with the selected text on stdin
+fn service_script(exe: &std::path::Path) -> String {
+    format!(
+        "#!/bin/sh\n{marker}\nexec {exe} --stdin --best-effort\n",
+        marker = SERVICE_MARKER,
+        exe = shell_quote(&exe.to_string_lossy())
+    )
+}
+
+/// `Contents/Info.plist` of the Quick Action: its Services menu entry
+fn service_info_plist() -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>NSServices</key>
+	<array>
+		<dict>
+			<key>NSMenuItem</key>
+			<dict>
+				<key>default</key>
+				<string>{name}</string>
+			</dict>
+			<key>NSMessage</key>
+			<string>runWorkflowAsService</string>
+			<key>NSSendTypes</key>
+			<array>
+				<string>public.utf8-plain-text</string>
+			</array>
+			<key>NSReturnTypes</key>
+			<array>
+				<string>public.utf8-plain-text</string>
+			</array>
+		</dict>
+	</array>
+</dict>
+</plist>
+"#,
+        name = SERVICE_NAME
+    )
+}
+
+/// `Contents/document.wflow` of the Quick Action: one "Run Shell Script"
+/// action receiving text on stdin, whose output replaces the selected text
+fn service_workflow(script_path: &std::path::Path) -> String {
+    let command = format!("exec {}", shell_quote(&script_path.to_string_lossy()));
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>AMApplicationBuild</key>
+	<string>523</string>
+	<key>AMApplicationVersion</key>
+	<string>2.10</string>
+	<key>AMDocumentVersion</key>
+	<string>2</string>
+	<key>actions</key>
+	<array>
+		<dict>
+			<key>action</key>
+			<dict>
+				<key>AMAccepts</key>
+				<dict>
+					<key>Container</key>
+					<string>List</string>
+					<key>Optional</key>
+					<true/>
+					<key>Types</key>
+					<array>
+						<string>com.apple.cocoa.string</string>
+					</array>
+				</dict>
+				<key>AMActionVersion</key>
+				<string>2.0.3</string>
+				<key>AMProvides</key>
+				<dict>
+					<key>Container</key>
+					<string>List</string>
+					<key>Types</key>
+					<array>
+						<string>com.apple.cocoa.string</string>
+					</array>
+				</dict>
+				<key>ActionBundlePath</key>
+				<string>/System/Library/Automator/Run Shell Script.action</string>
+				<key>ActionName</key>
+				<string>Run Shell Script</string>
+				<key>ActionParameters</key>
+				<dict>
+					<key>COMMAND_STRING</key>
+					<string>{command}</string>
+					<key>CheckedForUserDefaultShell</key>
+					<true/>
+					<key>inputMethod</key>
+					<integer>0</integer>
+					<key>shell</key>
+					<string>/bin/sh</string>
+					<key>source</key>
+					<string></string>
+				</dict>
+				<key>BundleIdentifier</key>
+				<string>com.apple.RunShellScript</string>
+				<key>CFBundleVersion</key>
+				<string>2.0.3</string>
+				<key>Class Name</key>
+				<string>RunShellScriptAction</string>
+			</dict>
+			<key>isViewVisible</key>
+			<integer>1</integer>
+		</dict>
+	</array>
+	<key>connectors</key>
+	<dict/>
+	<key>workflowMetaData</key>
+	<dict>
+		<key>serviceInputTypeIdentifier</key>
+		<string>com.apple.Automator.text</string>
+		<key>serviceOutputTypeIdentifier</key>
+		<string>com.apple.Automator.text</string>
+		<key>serviceProcessesInput</key>
+		<integer>0</integer>
+		<key>workflowTypeIdentifier</key>
+		<string>com.apple.Automator.servicesMenu</string>
+	</dict>
+</dict>
+</plist>
+"#,
+        command = xml_escape(&command)
+    )
+}
+
+/// `pqmfmt install-shell-ext [--uninstall]`
+///
+/// Registers a "Format with pqmfmt" verb for `.pq` and `.m` files under
+/// `HKCU\Software\Classes\SystemFileAssociations` (so whatever program
+/// opens them is not changed) and writes a Send To script, both running
+/// `pqmfmt -w` on the chosen files and keeping the window open on errors.
+pub fn install_shell_ext(args: &[String]) {
+    let mut uninstall = false;
+    for arg in args {
+        match arg.as_str() {
+            "--uninstall" => uninstall = true,
+            _ => {
+                eprintln!("Unknown option for install-shell-ext: {}", arg);
+                process::exit(1);
+            }
+        }
+    }
+    if !cfg!(windows) {
+        eprintln!("Error: install-shell-ext is only available on Windows");
+        process::exit(1);
+    }
+    let send_to = match env::var_os("APPDATA") {
+        Some(appdata) => std::path::PathBuf::from(appdata)
+            .join(r"Microsoft\Windows\SendTo")
+            .join(format!("{}.cmd", SHELL_EXT_LABEL)),
+        None => {
+            eprintln!("Error: APPDATA is not set");
+            process::exit(1);
+        }
+    };
+    if let Ok(existing) = fs::read_to_string(&send_to) {
+        if !existing.contains(SHELL_EXT_MARKER) {
+            eprintln!("Error: {} already exists and was not written by pqmfmt", send_to.display());
+            process::exit(1);
+        }
+    }
+    
+    if uninstall {
+        for extension in ["pq", "m"] {
+            // Fails if the key is not there, which is fine
+            let _ = Command::new("reg")
+                .args(["delete", &shell_ext_key(extension), "/f"])
+                .stdout(process::Stdio::null())
+                .stderr(process::Stdio::null())
+                .status();
+        }
+        if send_to.exists() {
+            if let Err(e) = fs::remove_file(&send_to) {
+                eprintln!("Error removing {}: {}", send_to.display(), e);
+                process::exit(1);
+            }
+        }
+        eprintln!("Removed \"{}\" from the context menu and Send To", SHELL_EXT_LABEL);
+        return;
+    }
+    
+    let exe = match env::current_exe() {
+        Ok(exe) => exe.to_string_lossy().into_owned(),
+        Err(e) => {
+            eprintln!("Error locating the pqmfmt executable: {}", e);
+            process::exit(1);
+        }
+    };
+    let command = format!(r#"cmd.exe /c ""{}" -w "%1" || pause""#, exe);
+    for extension in ["pq", "m"] {
+        let key = shell_ext_key(extension);
+        let command_key = format!(r"{}\command", key);
+        for (key, value) in [
+            (&key, &["/ve", "/d", SHELL_EXT_LABEL][..]),
+            (&key, &["/v", "Icon", "/d", &exe][..]),
+            (&command_key, &["/ve", "/d", &command][..]),
+        ] {
+            let status = Command::new("reg")
+                .arg("add")
+                .arg(key)
+                .args(value)
+                .arg("/f")
+                .stdout(process::Stdio::null())
+                .status();
+            if !status.is_ok_and(|s| s.success()) {
+                eprintln!("Error writing registry key {}", key);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = fs::write(&send_to, send_to_script(&exe)) {
+        eprintln!("Error writing {}: {}", send_to.display(), e);
+        process::exit(1);
+    }
+    eprintln!("Added \"{}\" to the context menu of .pq/.m files and to Send To", SHELL_EXT_LABEL);
+}
+
+/// Registry key of the context menu verb for files with `extension`
+fn shell_ext_key(extension: &str) -> String {
+    format!(r"HKCU\Software\Classes\SystemFileAssociations\.{}\shell\pqmfmt", extension)
+}
+
+/// Send To script: formats every file it is given
+fn send_to_script(exe: &str) -> String {
+    format!(
+        "@echo off\r\n{marker}\r\n\"{exe}\" -w %* || pause\r\n",
+        marker = SHELL_EXT_MARKER,
+        exe = exe
+    )
+}
+
+/// `s` as a single-quoted shell word
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -0,0 +1,160 @@
+//! `pqmfmt bundle` and `split`: between query files and section documents
+
+use pqm_formatter::{refactor, Config};
+use std::fs;
+use std::process;
+
+/// `pqmfmt bundle DIR [-o FILE] [--name SECTION]`
+pub fn bundle(args: &[String]) {
+    let mut dir = None;
+    let mut output = None;
+    let mut section = "Section1".to_string();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-o" | "--output" | "--name" if i + 1 >= args.len() => {
+                eprintln!("Error: {} requires a value", args[i]);
+                process::exit(1);
+            }
+            "-o" | "--output" => {
+                i += 1;
+                output = Some(args[i].clone());
+            }
+            "--name" => {
+                i += 1;
+                section = args[i].clone();
+            }
+            arg if arg.starts_with('-') => {
+                eprintln!("Unknown option for bundle: {}", arg);
+                process::exit(1);
+            }
+            arg if dir.is_none() => dir = Some(arg.to_string()),
+            _ => {
+                eprintln!("Error: bundle takes a single directory");
+                process::exit(1);
+            }
+        }
+        i += 1;
+    }
+    let Some(dir) = dir else {
+        eprintln!("Error: bundle requires a directory");
+        process::exit(1);
+    };
+    
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", dir, e);
+            process::exit(1);
+        }
+    };
+    let mut paths: Vec<std::path::PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.is_file() && matches!(path.extension().and_then(|e| e.to_str()), Some("pq" | "m"))
+        })
+        .collect();
+    paths.sort();
+    
+    // Members are named after their files
+    let mut queries = Vec::new();
+    for path in &paths {
+        let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+        match fs::read_to_string(path) {
+            Ok(code) => queries.push((name, code)),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    let members: Vec<(&str, &str)> = queries.iter().map(|(n, c)| (n.as_str(), c.as_str())).collect();
+    
+    let bundled = match refactor::bundle(&section, &members, &Config::default()) {
+        Ok(bundled) => bundled,
+        Err(e) => {
+            eprintln!("Error in {}", e);
+            process::exit(1);
+        }
+    };
+    match output {
+        Some(path) => {
+            if let Err(e) = fs::write(&path, bundled) {
+                eprintln!("Error writing to {}: {}", path, e);
+                process::exit(1);
+            }
+            eprintln!("Bundled {} queries into {}", members.len(), path);
+        }
+        None => print!("{}", bundled),
+    }
+}
+
+/// `pqmfmt split FILE [-d DIR]`
+pub fn split(args: &[String]) {
+    let mut file = None;
+    let mut dir = ".".to_string();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-d" | "--dir" if i + 1 >= args.len() => {
+                eprintln!("Error: {} requires a directory", args[i]);
+                process::exit(1);
+            }
+            "-d" | "--dir" => {
+                i += 1;
+                dir = args[i].clone();
+            }
+            arg if arg.starts_with('-') => {
+                eprintln!("Unknown option for split: {}", arg);
+                process::exit(1);
+            }
+            arg if file.is_none() => file = Some(arg.to_string()),
+            _ => {
+                eprintln!("Error: split takes a single file");
+                process::exit(1);
+            }
+        }
+        i += 1;
+    }
+    let Some(file) = file else {
+        eprintln!("Error: split requires a section document");
+        process::exit(1);
+    };
+    
+    let content = match fs::read_to_string(&file) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            process::exit(1);
+        }
+    };
+    let members = match refactor::split_section(&content, &Config::default()) {
+        Ok(members) => members,
+        Err(e) => {
+            eprintln!("Error in {}: {}", file, e);
+            process::exit(1);
+        }
+    };
+    
+    if let Err(e) = fs::create_dir_all(&dir) {
+        eprintln!("Error creating {}: {}", dir, e);
+        process::exit(1);
+    }
+    for member in &members {
+        // Keep names readable, but never let them leave the directory
+        let file_name: String = member
+            .name
+            .chars()
+            .map(|c| if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+            .collect();
+        let path = std::path::Path::new(&dir).join(format!("{}.pq", file_name));
+        if let Err(e) = fs::write(&path, &member.code) {
+            eprintln!("Error writing to {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        if let Some(attributes) = &member.attributes {
+            eprintln!("Warning: attributes of {} were not kept: {}", member.name, attributes);
+        }
+        eprintln!("Wrote {}", path.display());
+    }
+}
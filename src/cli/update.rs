@@ -0,0 +1,221 @@
+//! `pqmfmt self-update`
+//!
+//! Releases carry one executable per platform, named by [`asset_name`], and
+//! a `SHA256SUMS` file listing their SHA-256 hashes. Downloads use `curl`
+//! (included in macOS, Windows 10 and later, and most Linux distributions),
+//! so no TLS stack is built into pqmfmt.
+
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command};
+
+/// Releases of pqmfmt; `latest` redirects to the page of the latest one
+const RELEASES_URL: &str = "https://github.com/fukuyori/pqm-formatter/releases";
+
+/// Name of the release asset listing `<sha256>  <asset name>` per line
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+/// `pqmfmt self-update [--check]`
+pub fn self_update(args: &[String]) {
+    let current = crate::VERSION;
+    let mut check_only = false;
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check_only = true,
+            _ => {
+                eprintln!("Unknown option for self-update: {}", arg);
+                process::exit(1);
+            }
+        }
+    }
+    
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Error locating the pqmfmt executable: {}", e);
+            process::exit(1);
+        }
+    };
+    // Left behind by an earlier update on Windows, where a running
+    // executable can be renamed but not deleted
+    let previous = exe.with_extension("old");
+    let _ = fs::remove_file(&previous);
+    
+    let tag = match latest_tag() {
+        Ok(tag) => tag,
+        Err(e) => {
+            eprintln!("Error checking for updates: {}", e);
+            process::exit(1);
+        }
+    };
+    let version = tag.trim_start_matches('v');
+    if !is_newer(version, current) {
+        eprintln!("pqmfmt {} is up to date", current);
+        return;
+    }
+    if check_only {
+        eprintln!("pqmfmt {} is available (installed: {})", version, current);
+        return;
+    }
+    
+    let name = asset_name(env::consts::OS, env::consts::ARCH);
+    let verified = download(&asset_url(&tag, CHECKSUMS_ASSET)).and_then(|sums| {
+        let expected = expected_checksum(&String::from_utf8_lossy(&sums), &name)
+            .ok_or_else(|| format!("{} does not list {}", CHECKSUMS_ASSET, name))?;
+        let binary = download(&asset_url(&tag, &name))?;
+        if sha256_hex(&binary) != expected {
+            return Err(format!("checksum of {} does not match {}", name, CHECKSUMS_ASSET));
+        }
+        Ok(binary)
+    });
+    let binary = match verified {
+        Ok(binary) => binary,
+        Err(e) => {
+            eprintln!("Error downloading pqmfmt {}: {}", version, e);
+            process::exit(1);
+        }
+    };
+    
+    if let Err(e) = replace_executable(&exe, &binary) {
+        eprintln!("Error replacing {}: {}", exe.display(), e);
+        process::exit(1);
+    }
+    eprintln!("Updated pqmfmt {} -> {}", current, version);
+}
+
+/// Tag of the latest release, e.g. `v0.6.0`, from where its page redirects
+fn latest_tag() -> Result<String, String> {
+    let output = curl(&format!("{}/latest", RELEASES_URL), &["--head", "--write-out", "\n%{url_effective}"])?;
+    let output = String::from_utf8_lossy(&output);
+    let url = output.lines().last().unwrap_or_default();
+    tag_from_url(url).ok_or_else(|| "no release found".to_string())
+}
+
+/// Tag in the URL of a release page (`.../releases/tag/v0.6.0`)
+fn tag_from_url(url: &str) -> Option<String> {
+    let (_, tag) = url.trim().rsplit_once("/releases/tag/")?;
+    (!tag.is_empty() && !tag.contains('/')).then(|| tag.to_string())
+}
+
+/// Download URL of the asset `name` of the release tagged `tag`
+fn asset_url(tag: &str, name: &str) -> String {
+    format!("{}/download/{}/{}", RELEASES_URL, tag, name)
+}
+
+/// Name of the release asset holding the executable for a platform, from
+/// `std::env::consts::OS` and `ARCH`: `pqmfmt-macos-aarch64`,
+/// `pqmfmt-windows-x86_64.exe`, ...
+fn asset_name(os: &str, arch: &str) -> String {
+    let extension = if os == "windows" { ".exe" } else { "" };
+    format!("pqmfmt-{}-{}{}", os, arch, extension)
+}
+
+/// Whether version `candidate` is later than `current` (both `x.y.z`;
+/// a pre-release suffix such as `-beta.1` sorts before the release)
+fn is_newer(candidate: &str, current: &str) -> bool {
+    fn parse(version: &str) -> (Vec<u64>, bool) {
+        let version = version.trim_start_matches('v');
+        let (numbers, pre) = match version.split_once('-') {
+            Some((numbers, _)) => (numbers, true),
+            None => (version, false),
+        };
+        (numbers.split('.').map(|n| n.parse().unwrap_or(0)).collect(), pre)
+    }
+    let (candidate, candidate_pre) = parse(candidate);
+    let (current, current_pre) = parse(current);
+    match candidate.cmp(&current) {
+        std::cmp::Ordering::Equal => current_pre && !candidate_pre,
+        ordering => ordering.is_gt(),
+    }
+}
+
+/// Hash listed for `name` in a `SHA256SUMS` file (`sha256sum` output)
+fn expected_checksum(sums: &str, name: &str) -> Option<String> {
+    sums.lines().find_map(|line| {
+        let (hash, file) = line.split_once(char::is_whitespace)?;
+        // `sha256sum -b` marks binary files with `*`
+        let file = file.trim_start().trim_start_matches('*');
+        (file == name).then(|| hash.to_ascii_lowercase())
+    })
+}
+
+/// SHA-256 of `data` as lowercase hex
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Body of `url`, following redirects
+fn download(url: &str) -> Result<Vec<u8>, String> {
+    curl(url, &[])
+}
+
+/// Output of `curl` for `url` with the options `extra`, following redirects
+fn curl(url: &str, extra: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--header", "User-Agent: pqmfmt"])
+        .args(extra)
+        .arg(url)
+        .output()
+        .map_err(|e| format!("could not run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(output.stdout)
+}
+
+/// Put `binary` in place of the executable at `exe`. The new file is
+/// written next to it first, so a failed write leaves `exe` untouched.
+fn replace_executable(exe: &Path, binary: &[u8]) -> io::Result<()> {
+    let new = exe.with_extension("new");
+    fs::write(&new, binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new, fs::Permissions::from_mode(0o755))?;
+    }
+    // Windows does not allow replacing a running executable, only renaming it
+    if cfg!(windows) {
+        fs::rename(exe, exe.with_extension("old"))?;
+    }
+    fs::rename(&new, exe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_release_urls() {
+        let page = "https://github.com/fukuyori/pqm-formatter/releases/tag/v0.6.0";
+        assert_eq!(tag_from_url(page).as_deref(), Some("v0.6.0"));
+        // No release yet
+        assert_eq!(tag_from_url("https://github.com/fukuyori/pqm-formatter/releases"), None);
+        assert_eq!(
+            asset_url("v0.6.0", &asset_name("windows", "x86_64")),
+            "https://github.com/fukuyori/pqm-formatter/releases/download/v0.6.0/pqmfmt-windows-x86_64.exe"
+        );
+    }
+    
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.6.0", "0.5.0"));
+        assert!(is_newer("v0.10.0", "0.9.3"));
+        assert!(is_newer("1.0.0", "1.0.0-beta.2"));
+        assert!(!is_newer("0.5.0", "0.5.0"));
+        assert!(!is_newer("0.5.0-rc.1", "0.5.0"));
+        assert!(!is_newer("0.4.9", "0.5.0"));
+    }
+    
+    #[test]
+    fn test_checksums() {
+        let sums = "0a1b  pqmfmt-linux-x86_64\nFFEE *pqmfmt-windows-x86_64.exe\n";
+        assert_eq!(expected_checksum(sums, "pqmfmt-linux-x86_64").as_deref(), Some("0a1b"));
+        assert_eq!(expected_checksum(sums, "pqmfmt-windows-x86_64.exe").as_deref(), Some("ffee"));
+        assert_eq!(expected_checksum(sums, "pqmfmt-macos-aarch64"), None);
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -0,0 +1,101 @@
+//! Host compatibility checks
+//!
+//! Power Query ships in several hosts whose mashup engines do not move in
+//! lockstep. This pass reports syntax and library functions that the
+//! selected [`Dialect`] does not support, so incompatible code is caught
+//! before it is deployed.
+
+use crate::alloc_prelude::*;
+use crate::ast::{BinaryOp, Document, ExprKind};
+use crate::config::Dialect;
+use crate::diagnostic::Diagnostic;
+use crate::token::Span;
+
+/// Functions only available in Power BI
+const POWER_BI_ONLY_FUNCTIONS: &[&str] = &[
+    "PowerBI.Dataflows",
+    "Python.Execute",
+    "R.Execute",
+    "Web.BrowserContents",
+];
+
+/// Report constructs in `document` that `dialect` does not support.
+///
+/// Every diagnostic uses the code `unsupported-in-dialect`.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::config::Dialect;
+/// use pqm_formatter::{compat, Lexer, Parser};
+///
+/// let document = Parser::new(Lexer::new("a ?? b").tokenize()).parse().unwrap();
+/// assert!(compat::check(&document, Dialect::PowerBI).is_empty());
+/// assert_eq!(compat::check(&document, Dialect::Legacy).len(), 1);
+/// ```
+pub fn check(document: &Document, dialect: Dialect) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let host = match dialect {
+        Dialect::PowerBI => return diagnostics,
+        Dialect::Excel => "Excel",
+        Dialect::Legacy => "older Power Query engines",
+    };
+    
+    document.expression.walk(&mut |expr| match &expr.kind {
+        ExprKind::Try(t) if t.catch.is_some() && dialect == Dialect::Legacy => {
+            diagnostics.push(unsupported("`try ... catch`", host, expr.span));
+        }
+        ExprKind::Binary(b) if b.operator == BinaryOp::Coalesce && dialect == Dialect::Legacy => {
+            diagnostics.push(unsupported("The `??` operator", host, expr.span));
+        }
+        ExprKind::FunctionCall(call) => {
+            if let ExprKind::Identifier(name) = &call.function.kind {
+                if POWER_BI_ONLY_FUNCTIONS.contains(&name.as_str()) {
+                    diagnostics.push(unsupported(name, host, call.function.span));
+                }
+            }
+        }
+        _ => {}
+    });
+    diagnostics
+}
+
+fn unsupported(what: &str, host: &str, span: Span) -> Diagnostic {
+    Diagnostic::warning(
+        "unsupported-in-dialect",
+        format!("{} is not supported in {}", what, host),
+        span,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    
+    fn messages(code: &str, dialect: Dialect) -> Vec<String> {
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        check(&document, dialect).into_iter().map(|d| d.message).collect()
+    }
+    
+    #[test]
+    fn test_legacy_syntax() {
+        let code = "let a = try x catch (e) => 0, b = a ?? 1 in b";
+        assert!(messages(code, Dialect::PowerBI).is_empty());
+        assert!(messages(code, Dialect::Excel).is_empty());
+        assert_eq!(
+            messages(code, Dialect::Legacy),
+            [
+                "`try ... catch` is not supported in older Power Query engines",
+                "The `??` operator is not supported in older Power Query engines",
+            ]
+        );
+    }
+    
+    #[test]
+    fn test_power_bi_only_functions() {
+        let code = "R.Execute(\"output <- dataset\", [dataset = Source])";
+        assert!(messages(code, Dialect::PowerBI).is_empty());
+        assert_eq!(messages(code, Dialect::Excel), ["R.Execute is not supported in Excel"]);
+    }
+}
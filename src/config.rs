@@ -1,7 +1,187 @@
 //! Configuration for the Power Query M formatter
 
+use crate::alloc_prelude::*;
+use core::str::FromStr;
+
+pub mod directive;
+#[cfg(all(feature = "lints", feature = "std"))]
+pub mod file;
+
+/// How section member access is written in formatted output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(rename_all = "lowercase"))]
+pub enum SectionAccessStyle {
+    /// Keep whichever form the source uses
+    #[default]
+    Preserve,
+    /// `Section1!Member`
+    Bang,
+    /// `#sections[Section1][Member]`
+    Bracket,
+}
+
+/// Whether functions of one row are written with `each` or as lambdas
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(rename_all = "lowercase"))]
+pub enum EachStyle {
+    /// Keep whichever form the source uses
+    #[default]
+    Preserve,
+    /// `each [Amount] > 0` for `(row) => row[Amount] > 0`
+    Each,
+    /// `(row) => row[Amount] > 0` for `each [Amount] > 0`
+    Lambda,
+}
+
+/// How function call arguments are laid out when a call is wrapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(rename_all = "lowercase"))]
+pub enum CallWrapStyle {
+    /// One argument per line, indented one level, `)` on its own line
+    #[default]
+    Block,
+    /// Arguments aligned under the first one, `)` after the last:
+    ///
+    /// ```text
+    /// Table.SelectRows(Source,
+    ///                  each [Value] > 1)
+    /// ```
+    Hanging,
+}
+
+/// How the items of a wrapped list are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(rename_all = "kebab-case"))]
+pub enum ListFillStyle {
+    /// One item per line
+    #[default]
+    OnePerLine,
+    /// As many items per line as fit in `max_line_length`, for lists of
+    /// simple items without comments:
+    ///
+    /// ```text
+    /// {
+    ///     1, 2, 3, 4, 5, 6, 7, 8,
+    ///     9, 10, 11, 12
+    /// }
+    /// ```
+    Fill,
+}
+
+/// Which comment syntax formatted output uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(rename_all = "lowercase"))]
+pub enum CommentStyle {
+    /// Keep each comment as written
+    #[default]
+    Preserve,
+    /// `// comment`, converting block comments that are alone at the end
+    /// of their line and span one line
+    Line,
+    /// `/* comment */`, converting line comments
+    Block,
+}
+
+/// Host application the code is meant to run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(rename_all = "lowercase"))]
+pub enum Dialect {
+    /// Power BI Desktop and the Power BI service
+    #[default]
+    PowerBI,
+    /// Power Query in current Excel (Microsoft 365)
+    Excel,
+    /// Older mashup engines, such as the Excel 2010/2013 add-in and Excel 2016
+    Legacy,
+}
+
+impl Dialect {
+    /// Name used on the command line and in configuration files
+    pub fn name(self) -> &'static str {
+        match self {
+            Dialect::PowerBI => "powerbi",
+            Dialect::Excel => "excel",
+            Dialect::Legacy => "legacy",
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "powerbi" | "PowerBI" => Ok(Dialect::PowerBI),
+            "excel" | "Excel" => Ok(Dialect::Excel),
+            "legacy" | "Legacy" => Ok(Dialect::Legacy),
+            _ => Err(format!(
+                "Unknown dialect '{}' (expected powerbi, excel, or legacy)",
+                s
+            )),
+        }
+    }
+}
+
+/// Version of the layout rules.
+///
+/// Layout changes ship in a new edition so that code formatted with an
+/// older one stays byte-identical until its owners opt in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum StyleEdition {
+    /// The original layout
+    #[default]
+    #[cfg_attr(feature = "schema", schemars(rename = "2024"))]
+    Edition2024,
+    /// No trailing space after `=` when a binding or field value starts on
+    /// the next line, and a long `and`/`or` condition after `each` written
+    /// one clause per line
+    #[cfg_attr(feature = "schema", schemars(rename = "2025"))]
+    Edition2025,
+}
+
+impl StyleEdition {
+    /// Every edition, oldest first
+    pub const ALL: [StyleEdition; 2] = [StyleEdition::Edition2024, StyleEdition::Edition2025];
+    
+    /// The newest edition
+    pub const LATEST: StyleEdition = StyleEdition::Edition2025;
+    
+    /// Name used on the command line and in configuration files
+    pub fn name(self) -> &'static str {
+        match self {
+            StyleEdition::Edition2024 => "2024",
+            StyleEdition::Edition2025 => "2025",
+        }
+    }
+}
+
+impl FromStr for StyleEdition {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        StyleEdition::ALL
+            .into_iter()
+            .find(|edition| edition.name() == s)
+            .ok_or_else(|| format!("Unknown style edition '{}' (expected 2024 or 2025)", s))
+    }
+}
+
 /// Formatter configuration
+///
+/// Options are added from release to release, so outside this crate a
+/// `Config` is built from [`Config::default`], [`Config::compact`], or
+/// [`Config::expanded`] and then adjusted:
+///
+/// ```rust
+/// use pqm_formatter::Config;
+///
+/// let mut config = Config::compact();
+/// config.max_line_length = 100;
+/// ```
 #[derive(Debug, Clone)]
+#[non_exhaustive]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema), schemars(default))]
 pub struct Config {
     /// Number of spaces per indentation level
     pub indent_size: usize,
@@ -12,8 +192,11 @@ pub struct Config {
     /// Maximum line length before wrapping
     pub max_line_length: usize,
     
-    /// Add trailing commas in lists and records
-    pub trailing_comma: bool,
+    /// Add a trailing comma after the last field of multi-line records
+    pub trailing_comma_records: bool,
+    
+    /// Add a trailing comma after the last item of multi-line lists
+    pub trailing_comma_lists: bool,
     
     /// Add space inside brackets: [ A = 1 ] vs [A = 1]
     pub space_in_brackets: bool,
@@ -27,9 +210,22 @@ pub struct Config {
     /// Align equals signs in let bindings and records
     pub align_equals: bool,
     
+    /// Layout of wrapped function call arguments (`Hanging` needs the
+    /// `wrapping` feature)
+    pub call_wrap_style: CallWrapStyle,
+    
+    /// Only the outermost N nested calls may wrap; calls nested deeper
+    /// (without let/if/try/functions or comments) stay on one line even if
+    /// long. `None` lets every level wrap.
+    pub max_call_nesting_inline: Option<usize>,
+    
     /// Threshold for multiline expansion (number of elements)
     pub multiline_threshold: usize,
     
+    /// Longest function call argument (estimated width, string literals
+    /// excluded) that still allows the call to stay on one line
+    pub max_inline_arg_length: usize,
+    
     /// Always expand let bindings to multiple lines
     pub always_expand_let: bool,
     
@@ -39,42 +235,142 @@ pub struct Config {
     /// Always expand lists to multiple lines
     pub always_expand_lists: bool,
     
+    /// Layout of the items of wrapped lists (`Fill` needs the `wrapping`
+    /// feature)
+    pub list_fill_style: ListFillStyle,
+    
+    /// Keep each row of a wrapped list of equally long lists of simple
+    /// items (e.g. `#table` rows) on one line, with the columns aligned
+    /// (needs the `alignment` feature)
+    pub align_nested_lists: bool,
+    
+    /// Comment syntax to write; `Line` and `Block` also leave exactly one
+    /// space after `//`
+    pub comment_style: CommentStyle,
+    
+    /// Write number literals in one style (`0.5`, `1e06`, `0xFF`) instead
+    /// of as written (`.5`, `1E+06`, `0Xff`)
+    pub normalize_numbers: bool,
+    
+    /// Write the numbers in `#date`, `#time`, `#datetime`, `#datetimezone`
+    /// and `#duration` from their values (`#date(2024, 1, 1)` for
+    /// `#date(2024, 01, 01.0)`), since generated code pads and decorates
+    /// them in many ways
+    pub normalize_constructor_numbers: bool,
+    
+    /// Write text literals with only `""`, `#(cr)`, `#(lf)` and `#(tab)`
+    /// escaped, decoding other escapes (`#(0041)` becomes `A`), instead of
+    /// as written
+    pub normalize_text_escapes: bool,
+    
+    /// Write identifiers, quoted or not, in Unicode normalization form C,
+    /// so that a name typed with decomposed accents (as macOS input often
+    /// produces) matches the same name typed with composed ones
+    pub normalize_identifiers_nfc: bool,
+    
+    /// Right-align step annotation comments (`// ~2.5s`, `// rows: 12000`)
+    /// so they end at this column; `None` leaves one space before them
+    /// (needs the `alignment` feature)
+    pub annotation_column: Option<usize>,
+    
     /// Preserve blank lines between bindings
     pub preserve_blank_lines: bool,
     
     /// Maximum consecutive blank lines to preserve
     pub max_blank_lines: usize,
+    
+    /// Normalize section member access to `!` or `#sections[...]` form
+    pub section_access_style: SectionAccessStyle,
+    
+    /// Write the shared members of section documents in alphabetical
+    /// order, with their attributes and comments. Other members, and members
+    /// after a `// pqmfmt: keep-order` comment, keep their places.
+    pub sort_section_members: bool,
+    
+    /// Rewrite functions of one parameter that only read fields of it
+    /// between `(row) => row[A]` and `each [A]` (see
+    /// [`transform::prefer_each`](crate::transform::prefer_each)).
+    /// Applies to the whole document; needs the `transforms` feature.
+    pub prefer_each: EachStyle,
+    
+    /// Keep regions the parser cannot understand as written instead of failing
+    pub lenient: bool,
+    
+    /// Reject constructs the Power Query engine rejects even though they
+    /// can be parsed, such as keywords as unquoted field names
+    pub strict: bool,
+    
+    /// Host the code targets; constructs it does not support are reported
+    /// (as errors in strict mode)
+    pub dialect: Dialect,
+    
+    /// Version of the layout rules to apply
+    pub style_edition: StyleEdition,
 }
 
 impl Default for Config {
     fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    /// Create a new config with default values. Like the presets, this is a
+    /// `const fn`, for configurations fixed at compile time:
+    ///
+    /// ```rust
+    /// use pqm_formatter::Config;
+    ///
+    /// const CONFIG: Config = {
+    ///     let mut config = Config::compact();
+    ///     config.indent_size = 2;
+    ///     config
+    /// };
+    /// assert_eq!(CONFIG.max_line_length, 200);
+    /// ```
+    pub const fn new() -> Self {
         Self {
             indent_size: 4,
             use_tabs: false,
             max_line_length: 120,
-            trailing_comma: false,
+            trailing_comma_records: false,
+            trailing_comma_lists: false,
             space_in_brackets: false,
             space_in_braces: false,
             space_in_parens: false,
             align_equals: false,
+            call_wrap_style: CallWrapStyle::Block,
+            max_call_nesting_inline: None,
             multiline_threshold: 1,  // 2要素以上で展開 (> 1)
+            max_inline_arg_length: 30,
             always_expand_let: true,
             always_expand_records: false,
             always_expand_lists: false,
+            list_fill_style: ListFillStyle::OnePerLine,
+            align_nested_lists: false,
+            comment_style: CommentStyle::Preserve,
+            normalize_numbers: false,
+            normalize_constructor_numbers: false,
+            normalize_text_escapes: false,
+            normalize_identifiers_nfc: false,
+            annotation_column: None,
             preserve_blank_lines: true,
             max_blank_lines: 2,
+            section_access_style: SectionAccessStyle::Preserve,
+            sort_section_members: false,
+            prefer_each: EachStyle::Preserve,
+            lenient: false,
+            strict: false,
+            dialect: Dialect::PowerBI,
+            style_edition: StyleEdition::Edition2024,
         }
     }
-}
-
-impl Config {
-    /// Create a new config with default values
-    pub fn new() -> Self {
-        Self::default()
-    }
     
     /// Create a compact config (minimal whitespace, single line when possible)
-    pub fn compact() -> Self {
+    ///
+    /// Comments are kept: block comments stay inline, and a line comment
+    /// ends its line, so a construct containing one is never single-lined.
+    pub const fn compact() -> Self {
         Self {
             indent_size: 4,  // Same as default
             max_line_length: 200,  // Allow longer lines
@@ -82,21 +378,178 @@ impl Config {
             always_expand_let: false,
             always_expand_records: false,
             always_expand_lists: false,
-            ..Self::default()
+            ..Self::new()
         }
     }
     
     /// Create an expanded config (maximum readability)
-    pub fn expanded() -> Self {
+    pub const fn expanded() -> Self {
         Self {
             always_expand_let: true,
             always_expand_records: true,
             always_expand_lists: true,
             multiline_threshold: 1,
-            ..Self::default()
+            ..Self::new()
         }
     }
     
+    /// Names accepted by [`set`](Self::set), in field order
+    /// (`trailing_comma` sets both records and lists)
+    pub const OPTIONS: [&'static str; 34] = [
+        "indent_size",
+        "use_tabs",
+        "max_line_length",
+        "trailing_comma",
+        "trailing_comma_records",
+        "trailing_comma_lists",
+        "space_in_brackets",
+        "space_in_braces",
+        "space_in_parens",
+        "align_equals",
+        "call_wrap_style",
+        "max_call_nesting_inline",
+        "multiline_threshold",
+        "max_inline_arg_length",
+        "always_expand_let",
+        "always_expand_records",
+        "always_expand_lists",
+        "list_fill_style",
+        "align_nested_lists",
+        "comment_style",
+        "normalize_numbers",
+        "normalize_constructor_numbers",
+        "normalize_text_escapes",
+        "normalize_identifiers_nfc",
+        "annotation_column",
+        "preserve_blank_lines",
+        "max_blank_lines",
+        "section_access_style",
+        "sort_section_members",
+        "prefer_each",
+        "lenient",
+        "strict",
+        "dialect",
+        "style_edition",
+    ];
+    
+    /// Whether `key` names an option
+    pub fn is_option(key: &str) -> bool {
+        Self::OPTIONS.contains(&key)
+    }
+    
+    /// Set the option named `key` (the field name, e.g. `max_line_length`)
+    /// from its text form, as used by [`directive`] comments.
+    ///
+    /// ```rust
+    /// use pqm_formatter::Config;
+    ///
+    /// let mut config = Config::default();
+    /// config.set("trailing_comma_lists", "true").unwrap();
+    /// assert!(config.trailing_comma_lists);
+    /// assert!(config.set("indent_size", "wide").is_err());
+    /// ```
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn flag(value: &str) -> Result<bool, String> {
+            value.parse().map_err(|_| format!("Expected true or false, found '{}'", value))
+        }
+        fn number(value: &str) -> Result<usize, String> {
+            value.parse().map_err(|_| format!("Expected a non-negative integer, found '{}'", value))
+        }
+        
+        match key {
+            "indent_size" => self.indent_size = number(value)?,
+            "use_tabs" => self.use_tabs = flag(value)?,
+            "max_line_length" => self.max_line_length = number(value)?,
+            "trailing_comma" => {
+                self.trailing_comma_records = flag(value)?;
+                self.trailing_comma_lists = self.trailing_comma_records;
+            }
+            "trailing_comma_records" => self.trailing_comma_records = flag(value)?,
+            "trailing_comma_lists" => self.trailing_comma_lists = flag(value)?,
+            "space_in_brackets" => self.space_in_brackets = flag(value)?,
+            "space_in_braces" => self.space_in_braces = flag(value)?,
+            "space_in_parens" => self.space_in_parens = flag(value)?,
+            "align_equals" => self.align_equals = flag(value)?,
+            "call_wrap_style" => {
+                self.call_wrap_style = match value {
+                    "block" => CallWrapStyle::Block,
+                    "hanging" => CallWrapStyle::Hanging,
+                    _ => return Err(format!("Expected block or hanging, found '{}'", value)),
+                }
+            }
+            "max_call_nesting_inline" => {
+                self.max_call_nesting_inline = match value {
+                    "none" => None,
+                    value => Some(number(value)?),
+                }
+            }
+            "multiline_threshold" => self.multiline_threshold = number(value)?,
+            "max_inline_arg_length" => self.max_inline_arg_length = number(value)?,
+            "always_expand_let" => self.always_expand_let = flag(value)?,
+            "always_expand_records" => self.always_expand_records = flag(value)?,
+            "always_expand_lists" => self.always_expand_lists = flag(value)?,
+            "list_fill_style" => {
+                self.list_fill_style = match value {
+                    "one-per-line" => ListFillStyle::OnePerLine,
+                    "fill" => ListFillStyle::Fill,
+                    _ => return Err(format!("Expected one-per-line or fill, found '{}'", value)),
+                }
+            }
+            "align_nested_lists" => self.align_nested_lists = flag(value)?,
+            "comment_style" => {
+                self.comment_style = match value {
+                    "preserve" => CommentStyle::Preserve,
+                    "line" => CommentStyle::Line,
+                    "block" => CommentStyle::Block,
+                    _ => return Err(format!("Expected preserve, line, or block, found '{}'", value)),
+                }
+            }
+            "normalize_numbers" => self.normalize_numbers = flag(value)?,
+            "normalize_constructor_numbers" => self.normalize_constructor_numbers = flag(value)?,
+            "normalize_text_escapes" => self.normalize_text_escapes = flag(value)?,
+            "normalize_identifiers_nfc" => self.normalize_identifiers_nfc = flag(value)?,
+            "annotation_column" => {
+                self.annotation_column = match value {
+                    "none" => None,
+                    value => Some(number(value)?),
+                }
+            }
+            "preserve_blank_lines" => self.preserve_blank_lines = flag(value)?,
+            "max_blank_lines" => self.max_blank_lines = number(value)?,
+            "section_access_style" => {
+                self.section_access_style = match value {
+                    "preserve" => SectionAccessStyle::Preserve,
+                    "bang" => SectionAccessStyle::Bang,
+                    "bracket" => SectionAccessStyle::Bracket,
+                    _ => return Err(format!("Expected preserve, bang, or bracket, found '{}'", value)),
+                }
+            }
+            "sort_section_members" => self.sort_section_members = flag(value)?,
+            "prefer_each" => {
+                self.prefer_each = match value {
+                    "preserve" => EachStyle::Preserve,
+                    "each" => EachStyle::Each,
+                    "lambda" => EachStyle::Lambda,
+                    _ => return Err(format!("Expected preserve, each, or lambda, found '{}'", value)),
+                }
+            }
+            "lenient" => self.lenient = flag(value)?,
+            "strict" => self.strict = flag(value)?,
+            "dialect" => self.dialect = value.parse()?,
+            "style_edition" => self.style_edition = value.parse()?,
+            _ => return Err(format!("Unknown option '{}'", key)),
+        }
+        Ok(())
+    }
+    
+    /// JSON Schema describing every option, for editor completion and
+    /// validation of configuration files
+    #[cfg(feature = "schema")]
+    pub fn json_schema() -> String {
+        let schema = schemars::schema_for!(Config);
+        serde_json::to_string_pretty(&schema).expect("a schema always serializes")
+    }
+    
     /// Get the indentation string
     pub fn indent_str(&self) -> String {
         if self.use_tabs {
@@ -136,4 +589,33 @@ mod tests {
         };
         assert_eq!(tab_config.indent_str(), "\t");
     }
+    
+    #[test]
+    fn test_options_are_settable() {
+        let mut config = Config::default();
+        for key in Config::OPTIONS {
+            let error = config.set(key, "?").unwrap_err();
+            assert!(!error.starts_with("Unknown option"), "{}", key);
+        }
+        assert!(!Config::is_option("colour"));
+    }
+    
+    #[test]
+    fn test_trailing_comma_sets_records_and_lists() {
+        let mut config = Config::default();
+        config.set("trailing_comma", "true").unwrap();
+        assert!(config.trailing_comma_records && config.trailing_comma_lists);
+        // Calls never get one, which M does not accept
+        assert!(!Config::is_option("trailing_comma_calls"));
+    }
+    
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_json_schema_uses_config_file_values() {
+        let schema = Config::json_schema();
+        assert!(schema.contains("\"max_line_length\""));
+        assert!(schema.contains("\"hanging\""));
+        assert!(schema.contains("\"powerbi\""));
+        assert!(schema.contains("\"2025\""));
+    }
 }
@@ -0,0 +1,145 @@
+//! Per-file configuration overrides
+//!
+//! A comment on the first line of a file can adjust the configuration that
+//! file is formatted with:
+//!
+//! ```text
+//! // pqmfmt: max_line_length=100 trailing_comma=true
+//! ```
+//!
+//! Settings are `key=value` pairs separated by whitespace, using the names
+//! accepted by [`Config::set`]. Settings that cannot be applied are
+//! reported as warnings and otherwise ignored.
+//!
+//! The same comment anywhere else is scoped: it applies to the expression,
+//! binding, or record field that follows it, on top of the settings in
+//! effect there, so a single wide `#table` can get a longer line limit:
+//!
+//! ```text
+//! let
+//!     // pqmfmt: max_line_length=200
+//!     Rates = #table({"Code", "Rate"}, {{"A", 0.1}, {"B", 0.2}}),
+//!     Result = ...
+//! ```
+//!
+//! A scoped directive can also hold [`KEEP_ORDER`], which pins the section
+//! member after it in place when `sort_section_members` is set.
+
+use super::Config;
+use crate::alloc_prelude::*;
+use crate::diagnostic::Diagnostic;
+use crate::token::Span;
+
+/// Prefix of the comment that carries the settings
+const PREFIX: &str = "pqmfmt:";
+
+/// Word of a scoped directive keeping the section member after it in place
+pub const KEEP_ORDER: &str = "keep-order";
+
+/// Text of the settings in the first-line directive of `code`, with its
+/// byte offset, if there is one
+pub fn find(code: &str) -> Option<(usize, &str)> {
+    let bom = if code.starts_with('\u{FEFF}') { '\u{FEFF}'.len_utf8() } else { 0 };
+    let first_line = code[bom..].lines().next()?;
+    let comment = first_line.trim_start().strip_prefix("//")?;
+    let settings = comment.trim_start().strip_prefix(PREFIX)?;
+    Some((bom + first_line.len() - settings.len(), settings))
+}
+
+/// Settings text of a scoped directive, from the text of a line comment
+/// after `//`
+pub fn scoped(comment: &str) -> Option<&str> {
+    comment.trim_start().strip_prefix(PREFIX)
+}
+
+/// Whether the line comment text `comment` (after `//`) is a scoped
+/// directive holding [`KEEP_ORDER`]
+pub fn keeps_order(comment: &str) -> bool {
+    scoped(comment).is_some_and(|settings| settings.split_whitespace().any(|word| word == KEEP_ORDER))
+}
+
+/// Apply the settings text of a directive to `config`, returning the byte
+/// offset within `settings` and the problem for each setting that cannot be
+/// applied
+pub fn apply_settings(settings: &str, config: &mut Config) -> Vec<(usize, String)> {
+    let mut problems = Vec::new();
+    let mut column_offset = 0;
+    for setting in settings.split_whitespace() {
+        // Offset of this setting within the settings text
+        let at = column_offset + settings[column_offset..].find(setting).unwrap_or(0);
+        column_offset = at + setting.len();
+        
+        let result = match setting.split_once('=') {
+            Some((key, value)) => config.set(key, value),
+            None if setting == KEEP_ORDER => Ok(()),
+            None => Err(format!("Expected key=value, found '{}'", setting)),
+        };
+        if let Err(message) = result {
+            problems.push((at, message));
+        }
+    }
+    problems
+}
+
+/// Apply the first-line directive of `code`, if any, to `config`.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::config::directive;
+/// use pqm_formatter::Config;
+///
+/// let mut config = Config::default();
+/// let warnings = directive::apply("// pqmfmt: indent_size=2 colour=red\nlet a = 1 in a", &mut config);
+/// assert_eq!(config.indent_size, 2);
+/// assert_eq!(warnings[0].code, "invalid-directive");
+/// ```
+pub fn apply(code: &str, config: &mut Config) -> Vec<Diagnostic> {
+    let Some((offset, settings)) = find(code) else {
+        return Vec::new();
+    };
+    
+    apply_settings(settings, config)
+        .into_iter()
+        .map(|(at, message)| {
+            let start = offset + at;
+            let length = settings[at..].split_whitespace().next().map_or(0, str::len);
+            let span = Span::new(start, start + length, 1, code[..start].chars().count() + 1);
+            Diagnostic::warning("invalid-directive", message, span)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_find() {
+        assert_eq!(find("// pqmfmt: a=1\nlet"), Some((10, " a=1")));
+        assert_eq!(find("\u{FEFF}//pqmfmt:a=1"), Some((12, "a=1")));
+        assert_eq!(find("let\n// pqmfmt: a=1"), None);
+        assert_eq!(find("// formatted with pqmfmt: a=1"), None);
+    }
+    
+    #[test]
+    fn test_apply() {
+        let mut config = Config::default();
+        let warnings = apply(
+            "// pqmfmt: max_line_length=100 trailing_comma=true call_wrap_style=diagonal tabs\n1",
+            &mut config,
+        );
+        assert_eq!(config.max_line_length, 100);
+        assert!(config.trailing_comma_records);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].span.column, 52);
+        assert_eq!(warnings[1].message, "Expected key=value, found 'tabs'");
+    }
+    
+    #[test]
+    fn test_keep_order() {
+        assert!(keeps_order(" pqmfmt: max_line_length=200 keep-order"));
+        assert!(!keeps_order(" keep-order"));
+        assert!(apply_settings(" keep-order", &mut Config::default()).is_empty());
+    }
+}
@@ -0,0 +1,509 @@
+//! Configuration files
+//!
+//! Settings can be kept next to the queries they apply to, in TOML or JSON:
+//!
+//! ```toml
+//! # pqmfmt.toml
+//! max_line_length = 100
+//! call_wrap_style = "hanging"
+//! ```
+//!
+//! ```json
+//! { "max_line_length": 100, "call_wrap_style": "hanging" }
+//! ```
+//!
+//! Keys are the names accepted by [`Config::set`]. Files are looked up from
+//! a directory towards the root; the nearest directory holding any of
+//! [`FILE_NAMES`] wins, and within it the first name in that list is used.
+//!
+//! A file can build on another with `extends`, naming either a file
+//! (relative to the extending one) or a preset:
+//!
+//! ```toml
+//! extends = "../pqmfmt.toml"    # or "preset:compact"
+//! trailing_comma = true
+//! ```
+//!
+//! A `[policy]` table (a `"policy"` object in JSON) sets up the
+//! [`Policy`] lint, added to those of the files extended:
+//!
+//! ```toml
+//! [policy]
+//! banned_functions = ["Web.Contents"]
+//! required_wrappers = { "Sql.Database" = "Value.NativeQuery" }
+//! ```
+
+use super::Config;
+use crate::json::{self, JsonValue};
+use crate::lint::policy::Policy;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Recognized file names, in order of precedence within one directory
+pub const FILE_NAMES: [&str; 4] = ["pqmfmt.toml", ".pqmfmt.toml", "pqmfmt.json", ".pqmfmtrc.json"];
+
+/// A configuration file found by [`discover`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    /// The file that applies
+    pub path: PathBuf,
+    /// Other configuration files in the same directory, which are ignored
+    pub shadowed: Vec<PathBuf>,
+}
+
+/// Find the configuration file for code in `dir`: the first of
+/// [`FILE_NAMES`] in `dir` or the closest ancestor holding any of them.
+pub fn discover(dir: &Path) -> Option<Discovered> {
+    for dir in dir.ancestors() {
+        let mut found = FILE_NAMES.iter().map(|name| dir.join(name)).filter(|path| path.is_file());
+        if let Some(path) = found.next() {
+            return Some(Discovered {
+                path,
+                shadowed: found.collect(),
+            });
+        }
+    }
+    None
+}
+
+/// One `key = value` setting read from a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub key: String,
+    /// Value in the text form [`Config::set`] accepts
+    pub value: String,
+    /// Line of the setting in its file (1-based; 0 if unknown)
+    pub line: usize,
+}
+
+/// What a configuration file builds on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extends {
+    /// `preset:default`, `preset:compact`, or `preset:expanded`
+    Preset(String),
+    /// Another configuration file, already loaded
+    File(Box<ConfigFile>),
+}
+
+/// Settings of a configuration file, in file order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// Base the settings are applied on top of
+    pub extends: Option<Extends>,
+    pub settings: Vec<Setting>,
+    /// Rules of the `[policy]` table of this file
+    pub policy: Policy,
+}
+
+impl ConfigFile {
+    /// Read and parse a configuration file, and the files it extends;
+    /// `.json` files are read as JSON, anything else as TOML
+    pub fn load(path: &Path) -> Result<Self, String> {
+        Self::load_extending(path, &mut Vec::new())
+    }
+    
+    /// [`load`](Self::load), with `chain` holding the files that extend
+    /// this one so that cycles are caught
+    fn load_extending(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Self, String> {
+        let error = |e: String| format!("{}: {}", path.display(), e);
+        let text = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let (mut settings, policy) = if path.extension().is_some_and(|ext| ext == "json") {
+            parse_json(&text)
+        } else {
+            parse_toml(&text)
+        }
+        .map_err(error)?;
+        
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if chain.contains(&canonical) {
+            return Err(error("extends itself".to_string()));
+        }
+        
+        let extends = match settings.iter().position(|s| s.key == "extends") {
+            Some(index) => {
+                let setting = settings.remove(index);
+                if let Some(name) = setting.value.strip_prefix("preset:") {
+                    if preset(name).is_none() {
+                        return Err(error(format!(
+                            "unknown preset '{}' (expected default, compact, or expanded)",
+                            name
+                        )));
+                    }
+                    Some(Extends::Preset(name.to_string()))
+                } else {
+                    let base = path.parent().unwrap_or(Path::new("")).join(&setting.value);
+                    chain.push(canonical);
+                    let parent = Self::load_extending(&base, chain);
+                    chain.pop();
+                    Some(Extends::File(Box::new(parent?)))
+                }
+            }
+            None => None,
+        };
+        
+        Ok(Self {
+            path: path.to_path_buf(),
+            extends,
+            settings,
+            policy,
+        })
+    }
+    
+    /// The policy of this file, added to those of the files it extends
+    pub fn policy(&self) -> Policy {
+        let mut policy = match &self.extends {
+            Some(Extends::File(parent)) => parent.policy(),
+            _ => Policy::default(),
+        };
+        policy.extend(&self.policy);
+        policy
+    }
+    
+    /// Apply the settings to `config`, after those of the files this one
+    /// extends (a preset replaces `config`), stopping at the first invalid one
+    pub fn apply(&self, config: &mut Config) -> Result<(), String> {
+        match &self.extends {
+            Some(Extends::Preset(name)) => *config = preset(name).unwrap_or_default(),
+            Some(Extends::File(parent)) => parent.apply(config)?,
+            None => {}
+        }
+        for setting in &self.settings {
+            config.set(&setting.key, &setting.value).map_err(|e| {
+                format!("{}:{}: {}: {}", self.path.display(), setting.line, setting.key, e)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Configuration of a preset named in `extends = "preset:NAME"`
+fn preset(name: &str) -> Option<Config> {
+    match name {
+        "default" => Some(Config::default()),
+        "compact" => Some(Config::compact()),
+        "expanded" => Some(Config::expanded()),
+        _ => None,
+    }
+}
+
+/// Settings and policy of a JSON object
+fn parse_json(text: &str) -> Result<(Vec<Setting>, Policy), String> {
+    let value = json::parse(text)?;
+    if !matches!(value, JsonValue::Object(_)) {
+        return Err("Expected a JSON object".to_string());
+    }
+    let mut policy = Policy::default();
+    let settings = value
+        .members()
+        .iter()
+        .filter(|(key, _)| key != "policy")
+        .map(|(key, value)| {
+            let value = match value {
+                JsonValue::Null => "none".to_string(),
+                JsonValue::Bool(b) => b.to_string(),
+                JsonValue::Number(n) if *n >= 0.0 && n.fract() == 0.0 => (*n as usize).to_string(),
+                JsonValue::String(s) => s.clone(),
+                _ => return Err(format!("{}: expected a boolean, integer, string, or null", key)),
+            };
+            Ok(Setting { key: key.clone(), value, line: 0 })
+        })
+        .collect::<Result<_, _>>()?;
+    if let Some(rules) = value.get("policy") {
+        let JsonValue::Object(rules) = rules else {
+            return Err("policy: expected an object".to_string());
+        };
+        for (key, value) in rules {
+            let error = || format!("policy.{}: expected {}", key, policy_value(key).unwrap_or("a known key"));
+            match (key.as_str(), value) {
+                ("banned_functions", JsonValue::Array(items)) => {
+                    for item in items {
+                        let JsonValue::String(function) = item else {
+                            return Err(error());
+                        };
+                        policy.ban(function.as_str());
+                    }
+                }
+                ("required_wrappers", JsonValue::Object(pairs)) => {
+                    for (function, wrapper) in pairs {
+                        let JsonValue::String(wrapper) = wrapper else {
+                            return Err(error());
+                        };
+                        policy.require_wrapper(function.as_str(), wrapper.as_str());
+                    }
+                }
+                _ => return Err(error()),
+            }
+        }
+    }
+    Ok((settings, policy))
+}
+
+/// What the value of the policy setting `key` must be, or `None` if there
+/// is no such setting
+fn policy_value(key: &str) -> Option<&'static str> {
+    match key {
+        "banned_functions" => Some("a list of function names"),
+        "required_wrappers" => Some("a table mapping function names to wrapper names"),
+        _ => None,
+    }
+}
+
+/// Settings and policy of a TOML document: `key = value` lines with
+/// booleans, integers, and basic strings, and `#` comments, then an
+/// optional `[policy]` table, whose values are lists of strings (which may
+/// span lines) and inline tables of strings
+fn parse_toml(text: &str) -> Result<(Vec<Setting>, Policy), String> {
+    let mut settings = Vec::new();
+    let mut policy = Policy::default();
+    let mut in_policy = false;
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let Some((name, rest)) = header.split_once(']') else {
+                return Err(format!("line {}: expected ]", line_number));
+            };
+            check_comment(rest, line_number)?;
+            if name.trim() != "policy" {
+                return Err(format!("line {}: unknown table [{}] (expected [policy])", line_number, name.trim()));
+            }
+            in_policy = true;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected key = value", line_number));
+        };
+        if in_policy {
+            let key = key.trim();
+            let mut value = value.to_string();
+            // A list or table continues to its closing bracket
+            let mut tokens = toml_tokens(&value).map_err(|e| format!("line {}: {}", line_number, e))?;
+            while !balanced(&tokens) {
+                let Some((_, next)) = lines.next() else {
+                    return Err(format!("line {}: {}: unterminated list", line_number, key));
+                };
+                value.push('\n');
+                value.push_str(next);
+                tokens = toml_tokens(&value).map_err(|e| format!("line {}: {}", line_number, e))?;
+            }
+            set_policy(&mut policy, key, &tokens).map_err(|e| format!("line {}: policy.{}: {}", line_number, key, e))?;
+            continue;
+        }
+        let value = value.trim();
+        let value = if let Some(rest) = value.strip_prefix('"') {
+            let Some(end) = rest.find('"') else {
+                return Err(format!("line {}: unterminated string", line_number));
+            };
+            check_comment(&rest[end + 1..], line_number)?;
+            rest[..end].to_string()
+        } else {
+            value.split_once('#').map_or(value, |(v, _)| v).trim().to_string()
+        };
+        settings.push(Setting {
+            key: key.trim().to_string(),
+            value,
+            line: line_number,
+        });
+    }
+    Ok((settings, policy))
+}
+
+/// A token of a TOML policy value
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TomlToken {
+    /// A basic string or a bare key
+    Text(String),
+    Punct(char),
+}
+
+/// Tokens of `text`, without whitespace and comments
+fn toml_tokens(text: &str) -> Result<Vec<TomlToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => return Err("unterminated string".to_string()),
+                        Some(c) => string.push(c),
+                    }
+                }
+                tokens.push(TomlToken::Text(string));
+            }
+            '[' | ']' | '{' | '}' | ',' | '=' => tokens.push(TomlToken::Punct(c)),
+            c if c.is_whitespace() => {}
+            c if c.is_alphanumeric() || matches!(c, '_' | '-' | '.') => {
+                let mut bare = c.to_string();
+                while let Some(c) = chars.next_if(|&c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+                    bare.push(c);
+                }
+                tokens.push(TomlToken::Text(bare));
+            }
+            c => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Whether every bracket and brace of `tokens` is closed
+fn balanced(tokens: &[TomlToken]) -> bool {
+    let depth = tokens.iter().fold(0isize, |depth, token| match token {
+        TomlToken::Punct('[' | '{') => depth + 1,
+        TomlToken::Punct(']' | '}') => depth - 1,
+        _ => depth,
+    });
+    depth <= 0
+}
+
+/// Set the policy setting `key` from the tokens of its value
+fn set_policy(policy: &mut Policy, key: &str, tokens: &[TomlToken]) -> Result<(), String> {
+    let expected = || format!("expected {}", policy_value(key).unwrap_or("a known key"));
+    let (open, close) = match key {
+        "banned_functions" => ('[', ']'),
+        "required_wrappers" => ('{', '}'),
+        _ => return Err(expected()),
+    };
+    let [TomlToken::Punct(first), items @ .., TomlToken::Punct(last)] = tokens else {
+        return Err(expected());
+    };
+    if (*first, *last) != (open, close) {
+        return Err(expected());
+    }
+    for item in items.split(|token| *token == TomlToken::Punct(',')) {
+        match item {
+            [] => {}
+            [TomlToken::Text(function)] if open == '[' => policy.ban(function.as_str()),
+            [TomlToken::Text(function), TomlToken::Punct('='), TomlToken::Text(wrapper)] if open == '{' => {
+                policy.require_wrapper(function.as_str(), wrapper.as_str())
+            }
+            _ => return Err(expected()),
+        }
+    }
+    Ok(())
+}
+
+/// Only a comment may follow a quoted value
+fn check_comment(rest: &str, line_number: usize) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("line {}: unexpected text after string", line_number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_parse_toml() {
+        let (settings, policy) = parse_toml("# style\nmax_line_length = 100 # narrow\n\ncall_wrap_style = \"hanging\"\n").unwrap();
+        assert_eq!(
+            settings,
+            [
+                Setting { key: "max_line_length".into(), value: "100".into(), line: 2 },
+                Setting { key: "call_wrap_style".into(), value: "hanging".into(), line: 4 },
+            ]
+        );
+        assert!(policy.is_empty());
+        assert!(parse_toml("use_tabs").is_err());
+        assert!(parse_toml("dialect = \"excel").is_err());
+    }
+    
+    #[test]
+    fn test_parse_json() {
+        let (settings, _) = parse_json(r#"{"use_tabs": true, "max_call_nesting_inline": null, "indent_size": 2}"#).unwrap();
+        let values: Vec<&str> = settings.iter().map(|s| s.value.as_str()).collect();
+        assert_eq!(values, ["true", "none", "2"]);
+        assert!(parse_json("[]").is_err());
+        assert!(parse_json(r#"{"indent_size": [2]}"#).is_err());
+    }
+    
+    #[test]
+    fn test_policy() {
+        let toml = "indent_size = 2\n\n[policy]\nbanned_functions = [\n    \"Web.Contents\", # no web sources\n    \"Odbc.Query\",\n]\nrequired_wrappers = { \"Sql.Database\" = \"Value.NativeQuery\" }\n";
+        let (settings, policy) = parse_toml(toml).unwrap();
+        assert_eq!(settings.len(), 1);
+        assert_eq!(policy.banned_functions, ["Web.Contents", "Odbc.Query"]);
+        assert_eq!(policy.required_wrappers, [("Sql.Database".to_string(), "Value.NativeQuery".to_string())]);
+        
+        let json = r#"{"policy": {"banned_functions": ["Web.Contents", "Odbc.Query"], "required_wrappers": {"Sql.Database": "Value.NativeQuery"}}}"#;
+        assert_eq!(parse_json(json).unwrap().1, policy);
+        
+        assert!(parse_toml("[style]\nindent_size = 2").unwrap_err().contains("unknown table [style]"));
+        assert!(parse_toml("[policy]\nbanned_functions = [\"Web.Contents\"").unwrap_err().contains("unterminated list"));
+        assert!(parse_toml("[policy]\nbanned_functions = \"Web.Contents\"").unwrap_err().contains("expected a list"));
+        assert!(parse_toml("[policy]\nallowed_functions = []").is_err());
+        assert!(parse_json(r#"{"policy": {"required_wrappers": ["Sql.Database"]}}"#).is_err());
+    }
+    
+    #[test]
+    fn test_discover_precedence() {
+        let root = std::env::temp_dir().join(format!("pqmfmt-config-{}", std::process::id()));
+        let nested = root.join("reports").join("sales");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join("pqmfmt.json"), "{}").unwrap();
+        fs::write(root.join(".pqmfmtrc.json"), "{}").unwrap();
+        
+        let found = discover(&nested).unwrap();
+        assert_eq!(found.path, root.join("pqmfmt.json"));
+        assert_eq!(found.shadowed, [root.join(".pqmfmtrc.json")]);
+        
+        fs::write(root.join("reports").join(".pqmfmt.toml"), "indent_size = 2\n").unwrap();
+        let found = discover(&nested).unwrap();
+        assert_eq!(found.path, root.join("reports").join(".pqmfmt.toml"));
+        assert!(found.shadowed.is_empty());
+        
+        let mut config = Config::default();
+        ConfigFile::load(&found.path).unwrap().apply(&mut config).unwrap();
+        assert_eq!(config.indent_size, 2);
+        
+        fs::remove_dir_all(&root).unwrap();
+    }
+    
+    #[test]
+    fn test_extends() {
+        let root = std::env::temp_dir().join(format!("pqmfmt-extends-{}", std::process::id()));
+        let project = root.join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(
+            root.join("pqmfmt.toml"),
+            "extends = \"preset:compact\"\nindent_size = 2\nmax_line_length = 80\n[policy]\nbanned_functions = [\"Web.Contents\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join("pqmfmt.json"),
+            r#"{"max_line_length": 100, "extends": "../pqmfmt.toml", "policy": {"banned_functions": ["Odbc.Query"]}}"#,
+        )
+        .unwrap();
+        
+        let mut config = Config::expanded();
+        let file = ConfigFile::load(&project.join("pqmfmt.json")).unwrap();
+        file.apply(&mut config).unwrap();
+        assert_eq!(file.policy().banned_functions, ["Web.Contents", "Odbc.Query"]);
+        assert_eq!(config.indent_size, 2);
+        assert_eq!(config.max_line_length, 100);
+        assert!(!config.always_expand_lists);
+        
+        fs::write(root.join("pqmfmt.toml"), "extends = \"project/pqmfmt.json\"\n").unwrap();
+        let error = ConfigFile::load(&project.join("pqmfmt.json")).unwrap_err();
+        assert!(error.contains("extends itself"), "{}", error);
+        
+        fs::write(root.join("pqmfmt.toml"), "extends = \"preset:tiny\"\n").unwrap();
+        assert!(ConfigFile::load(&root.join("pqmfmt.toml")).unwrap_err().contains("unknown preset"));
+        
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
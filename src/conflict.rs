@@ -0,0 +1,279 @@
+//! Formatting files with merge conflicts
+//!
+//! A file with `<<<<<<<`/`=======`/`>>>>>>>` conflict markers does not
+//! parse, but each side of it does: taking our side of every conflict gives
+//! one complete query, taking theirs gives another. [`split`] separates
+//! the two versions, each is formatted on its own, and [`join`] puts them
+//! back together with markers around the lines where the formatted versions
+//! differ. Resolving the conflict later leaves formatted code either way.
+
+use crate::alloc_prelude::*;
+use core::fmt;
+
+/// Marker at the start of our side of a conflict
+const OURS_MARKER: &str = "<<<<<<<";
+/// Marker at the start of the base section of a diff3-style conflict
+const BASE_MARKER: &str = "|||||||";
+/// Marker between the two sides of a conflict
+const SEPARATOR: &str = "=======";
+/// Marker at the end of their side of a conflict
+const THEIRS_MARKER: &str = ">>>>>>>";
+
+/// Both versions of a conflicted file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sides {
+    /// The file with our side of every conflict
+    pub ours: String,
+    /// The file with their side of every conflict
+    pub theirs: String,
+    /// First line of the first conflict, e.g. `<<<<<<< HEAD`
+    pub ours_marker: String,
+    /// Last line of the first conflict, e.g. `>>>>>>> feature`
+    pub theirs_marker: String,
+}
+
+/// Malformed conflict markers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl core::error::Error for ConflictError {}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Outside,
+    Ours,
+    Theirs,
+}
+
+/// Separate the two versions of a file with conflict markers. Returns
+/// `Ok(None)` if there are none; a file has conflicts only if a line
+/// starts with `<<<<<<<`.
+///
+/// Conflicts with a base section (`merge.conflictStyle = diff3`) are
+/// rejected, as the base cannot be placed back after formatting.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::conflict::split;
+///
+/// let code = "let\n<<<<<<< HEAD\n    a = 1\n=======\n    a = 2\n>>>>>>> main\nin\n    a\n";
+/// let sides = split(code).unwrap().unwrap();
+/// assert_eq!(sides.ours, "let\n    a = 1\nin\n    a\n");
+/// assert_eq!(sides.theirs, "let\n    a = 2\nin\n    a\n");
+/// assert_eq!(sides.theirs_marker, ">>>>>>> main");
+/// assert!(split("let a = 1 in a").unwrap().is_none());
+/// ```
+pub fn split(code: &str) -> Result<Option<Sides>, ConflictError> {
+    // Most files have none; do not copy them
+    if !code.lines().any(|line| is_marker(line, OURS_MARKER)) {
+        return Ok(None);
+    }
+    let mut state = State::Outside;
+    let mut ours = String::new();
+    let mut theirs = String::new();
+    let mut markers: Option<(String, String)> = None;
+    let mut ours_marker = String::new();
+    let mut start = 0;
+    
+    for (i, line) in code.split_inclusive('\n').enumerate() {
+        let text = line.trim_end_matches(['\n', '\r']);
+        let error = |message: &str| ConflictError {
+            line: i + 1,
+            message: message.to_string(),
+        };
+        match state {
+            State::Outside if is_marker(text, OURS_MARKER) => {
+                state = State::Ours;
+                ours_marker = text.to_string();
+                start = i + 1;
+            }
+            State::Ours if is_marker(text, BASE_MARKER) => {
+                return Err(error(
+                    "Conflicts with a base section (diff3 style) cannot be formatted",
+                ));
+            }
+            State::Ours if is_marker(text, SEPARATOR) => state = State::Theirs,
+            State::Theirs if is_marker(text, THEIRS_MARKER) => {
+                state = State::Outside;
+                markers.get_or_insert_with(|| (ours_marker.clone(), text.to_string()));
+            }
+            State::Ours | State::Theirs if is_marker(text, OURS_MARKER) => {
+                return Err(error("Conflict inside a conflict"));
+            }
+            State::Outside => {
+                ours.push_str(line);
+                theirs.push_str(line);
+            }
+            State::Ours => ours.push_str(line),
+            State::Theirs => theirs.push_str(line),
+        }
+    }
+    
+    if state != State::Outside {
+        return Err(ConflictError {
+            line: start,
+            message: "Conflict is not closed".to_string(),
+        });
+    }
+    Ok(markers.map(|(ours_marker, theirs_marker)| Sides {
+        ours,
+        theirs,
+        ours_marker,
+        theirs_marker,
+    }))
+}
+
+/// Whether `line` is the conflict marker `marker`, alone or followed by a
+/// label
+fn is_marker(line: &str, marker: &str) -> bool {
+    line.strip_prefix(marker)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t']))
+}
+
+/// Merge two formatted versions into one text, with conflict markers around
+/// the lines where they differ
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::conflict::join;
+///
+/// let text = join("a\nb\nc\n", "a\nB\nc\n", "<<<<<<< HEAD", ">>>>>>> main");
+/// assert_eq!(text, "a\n<<<<<<< HEAD\nb\n=======\nB\n>>>>>>> main\nc\n");
+/// ```
+pub fn join(ours: &str, theirs: &str, ours_marker: &str, theirs_marker: &str) -> String {
+    let ours: Vec<&str> = ours.lines().collect();
+    let theirs: Vec<&str> = theirs.lines().collect();
+    let prefix = ours.iter().zip(&theirs).take_while(|(a, b)| a == b).count();
+    let suffix = ours[prefix..]
+        .iter()
+        .rev()
+        .zip(theirs[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    
+    let mut text = String::new();
+    for line in &ours[..prefix] {
+        text.push_str(line);
+        text.push('\n');
+    }
+    let ours_middle = &ours[prefix..ours.len() - suffix];
+    let theirs_middle = &theirs[prefix..theirs.len() - suffix];
+    let mut pending: (Vec<&str>, Vec<&str>) = (Vec::new(), Vec::new());
+    for (a, b) in common_lines(ours_middle, theirs_middle) {
+        match (a, b) {
+            (Some(line), Some(_)) => {
+                push_conflict(&mut text, &mut pending, ours_marker, theirs_marker);
+                text.push_str(line);
+                text.push('\n');
+            }
+            (Some(line), None) => pending.0.push(line),
+            (None, Some(line)) => pending.1.push(line),
+            (None, None) => {}
+        }
+    }
+    push_conflict(&mut text, &mut pending, ours_marker, theirs_marker);
+    for line in &ours[ours.len() - suffix..] {
+        text.push_str(line);
+        text.push('\n');
+    }
+    text
+}
+
+fn push_conflict(text: &mut String, pending: &mut (Vec<&str>, Vec<&str>), ours_marker: &str, theirs_marker: &str) {
+    if pending.0.is_empty() && pending.1.is_empty() {
+        return;
+    }
+    text.push_str(ours_marker);
+    text.push('\n');
+    for line in pending.0.drain(..) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push_str(SEPARATOR);
+    text.push('\n');
+    for line in pending.1.drain(..) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push_str(theirs_marker);
+    text.push('\n');
+}
+
+/// Lines of both sequences aligned on a longest common subsequence:
+/// `(Some, Some)` for a common line, otherwise the line of one side
+fn common_lines<'s>(a: &[&'s str], b: &[&'s str]) -> Vec<(Option<&'s str>, Option<&'s str>)> {
+    // lengths[i][j]: length of the longest common subsequence of a[i..] and b[j..]
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    
+    let mut aligned = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            aligned.push((Some(a[i]), Some(b[j])));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            aligned.push((Some(a[i]), None));
+            i += 1;
+        } else {
+            aligned.push((None, Some(b[j])));
+            j += 1;
+        }
+    }
+    aligned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format, Config};
+    
+    #[test]
+    fn test_split_errors() {
+        let unclosed = "a\n<<<<<<< HEAD\nb\n=======\nc\n";
+        assert_eq!(split(unclosed).unwrap_err().line, 2);
+        let diff3 = "<<<<<<< HEAD\nb\n||||||| base\na\n=======\nc\n>>>>>>> x\n";
+        assert_eq!(split(diff3).unwrap_err().line, 3);
+        // A separator alone may be part of a comment
+        assert!(split("/*\n=======\n*/ 1").unwrap().is_none());
+        // Not a marker: no space before the label
+        assert!(split("<<<<<<<<\n").unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_format_both_sides() {
+        let code = "let\n<<<<<<< HEAD\n  Source=Table.FromRows({{1}}),\n=======\n  Source=Table.FromRows({{2}}),\n>>>>>>> feature\n  Kept=Source\nin Kept";
+        let sides = split(code).unwrap().unwrap();
+        let ours = format(&sides.ours, Config::default()).unwrap();
+        let theirs = format(&sides.theirs, Config::default()).unwrap();
+        let joined = join(&ours, &theirs, &sides.ours_marker, &sides.theirs_marker);
+        assert_eq!(
+            joined,
+            "let\n<<<<<<< HEAD\n    Source = Table.FromRows({{1}}),\n=======\n    Source = Table.FromRows({{2}}),\n>>>>>>> feature\n    Kept = Source\nin\n    Kept\n"
+        );
+        
+        // Formatting the result again gives the same text
+        let again = split(&joined).unwrap().unwrap();
+        assert_eq!(join(&again.ours, &again.theirs, &again.ours_marker, &again.theirs_marker), joined);
+    }
+}
@@ -0,0 +1,261 @@
+//! Lossless concrete syntax tree
+//!
+//! The AST keeps what the formatter needs to write code again: comments
+//! become trivia, spacing is dropped, and keywords and punctuation are
+//! implied by the node kinds. A [`SyntaxTree`] keeps everything instead.
+//! Every token of the input, whitespace and comments included, is a leaf
+//! under the innermost node of the AST whose span contains it, with its
+//! original text. The text of the tree is the input, byte for byte, so
+//! tools can change one node and leave the rest of a file as it was
+//! written ([`SyntaxTree::replace`]), or keep constructs the formatter does
+//! not understand exactly as they are.
+//!
+//! # Example
+//!
+//! ```rust
+//! use pqm_formatter::cst::SyntaxTree;
+//! use pqm_formatter::{parse_document, Config};
+//!
+//! let code = "let\n    Total = 1 +  2 // sum\nin Total";
+//! let (document, _) = parse_document(code, &Config::default()).unwrap();
+//! let tree = SyntaxTree::new(&document);
+//! assert_eq!(tree.text(), code);
+//!
+//! // Change the value of `Total` only
+//! let value = tree.node_at(code.find('2').unwrap()).unwrap();
+//! assert_eq!(value.text(), "2");
+//! assert_eq!(tree.replace(value, "3"), "let\n    Total = 1 +  3 // sum\nin Total");
+//! ```
+
+use core::iter::Peekable;
+
+use crate::alloc_prelude::*;
+use crate::ast::{Document, NodeRef};
+use crate::lexer::Lexer;
+use crate::token::{Span, Token, TokenKind};
+
+/// The tokens of a document's source grouped under the nodes of its AST
+#[derive(Debug, Clone)]
+pub struct SyntaxTree<'a> {
+    /// The source of the document
+    pub source: &'a str,
+    /// Trivia before the document expression, its node, and the tokens
+    /// after it
+    pub children: Vec<SyntaxElement<'a>>,
+}
+
+/// A child in a [`SyntaxTree`]
+#[derive(Debug, Clone)]
+pub enum SyntaxElement<'a> {
+    Node(SyntaxNode<'a>),
+    Token(SyntaxToken<'a>),
+}
+
+/// A node of the AST with the tokens of its text
+#[derive(Debug, Clone)]
+pub struct SyntaxNode<'a> {
+    pub node: NodeRef<'a>,
+    /// Child nodes and the tokens between them, in source order
+    pub children: Vec<SyntaxElement<'a>>,
+}
+
+/// A token, trivia included, with its original text
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken<'a> {
+    pub kind: TokenKind,
+    pub span: Span,
+    pub text: &'a str,
+}
+
+impl<'a> SyntaxTree<'a> {
+    /// The tree of `document`, lexing its [`source`](Document::source)
+    /// again. Documents parsed from bare tokens have no source, and give a
+    /// tree with the document expression and no tokens.
+    pub fn new(document: &'a Document) -> Self {
+        let source = document.source.as_str();
+        let mut tokens = Lexer::new(source)
+            .tokenize()
+            .into_iter()
+            .filter(|token| token.kind != TokenKind::Eof)
+            .peekable();
+        let root = NodeRef::Expr(&document.expression);
+        let mut children = Vec::new();
+        while let Some(token) = tokens.next_if(|token| token.span.start < root.span().start) {
+            children.push(SyntaxElement::Token(SyntaxToken::new(token, source)));
+        }
+        children.push(SyntaxElement::Node(build(root, &mut tokens, source)));
+        children.extend(tokens.map(|token| SyntaxElement::Token(SyntaxToken::new(token, source))));
+        Self { source, children }
+    }
+    
+    /// Every token in source order
+    pub fn tokens(&self) -> impl Iterator<Item = &SyntaxToken<'a>> {
+        let mut tokens = Vec::new();
+        collect_tokens(&self.children, &mut tokens);
+        tokens.into_iter()
+    }
+    
+    /// The text of every token, which is the source of the document
+    pub fn text(&self) -> String {
+        self.tokens().map(|token| token.text).collect()
+    }
+    
+    /// The innermost node whose tokens include the one at byte `offset`
+    pub fn node_at(&self, offset: usize) -> Option<&SyntaxNode<'a>> {
+        let mut found = None;
+        let mut children = &self.children;
+        'descend: loop {
+            for child in children {
+                if let SyntaxElement::Node(node) = child {
+                    let span = node.span();
+                    if span.start <= offset && offset < span.end {
+                        found = Some(node);
+                        children = &node.children;
+                        continue 'descend;
+                    }
+                }
+            }
+            return found;
+        }
+    }
+    
+    /// The source with the text of `node` replaced by `text`, and every
+    /// other byte as it was
+    pub fn replace(&self, node: &SyntaxNode<'_>, text: &str) -> String {
+        let span = node.span();
+        let mut result = String::with_capacity(self.source.len() + text.len());
+        result.push_str(&self.source[..span.start]);
+        result.push_str(text);
+        result.push_str(&self.source[span.end..]);
+        result
+    }
+}
+
+impl<'a> SyntaxNode<'a> {
+    /// From the start of the first token to the end of the last one, or the
+    /// span of the AST node if it has no tokens
+    pub fn span(&self) -> Span {
+        let mut tokens = Vec::new();
+        collect_tokens(&self.children, &mut tokens);
+        match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => first.span.merge(last.span),
+            _ => self.node.span(),
+        }
+    }
+    
+    /// The original text of the node, comments and spacing inside it
+    /// included
+    pub fn text(&self) -> String {
+        let mut tokens = Vec::new();
+        collect_tokens(&self.children, &mut tokens);
+        tokens.iter().map(|token| token.text).collect()
+    }
+    
+    /// The tokens directly under this node, outside its child nodes
+    pub fn tokens(&self) -> impl Iterator<Item = &SyntaxToken<'a>> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Token(token) => Some(token),
+            SyntaxElement::Node(_) => None,
+        })
+    }
+    
+    /// The child nodes
+    pub fn nodes(&self) -> impl Iterator<Item = &SyntaxNode<'a>> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+}
+
+impl<'a> SyntaxToken<'a> {
+    fn new(token: Token, source: &'a str) -> Self {
+        Self {
+            text: token.span.slice(source),
+            kind: token.kind,
+            span: token.span,
+        }
+    }
+    
+    /// Whether the token is whitespace, a line break, or a comment
+    pub fn is_trivia(&self) -> bool {
+        self.kind.is_trivia()
+    }
+}
+
+/// The node of `node`, taking the tokens that end inside its span. A token
+/// goes to the child node that starts at or before it, so that the space
+/// between two children stays with their parent.
+fn build<'a, I>(node: NodeRef<'a>, tokens: &mut Peekable<I>, source: &'a str) -> SyntaxNode<'a>
+where
+    I: Iterator<Item = Token>,
+{
+    let end = node.span().end;
+    let mut nodes = node.children().into_iter().peekable();
+    let mut children = Vec::new();
+    loop {
+        match (nodes.peek(), tokens.peek()) {
+            (Some(child), Some(token)) if token.span.start < child.span().start => {
+                let token = tokens.next().unwrap();
+                children.push(SyntaxElement::Token(SyntaxToken::new(token, source)));
+            }
+            (Some(_), _) => {
+                let child = nodes.next().unwrap();
+                children.push(SyntaxElement::Node(build(child, tokens, source)));
+            }
+            (None, Some(token)) if token.span.end <= end => {
+                let token = tokens.next().unwrap();
+                children.push(SyntaxElement::Token(SyntaxToken::new(token, source)));
+            }
+            (None, _) => break,
+        }
+    }
+    SyntaxNode { node, children }
+}
+
+fn collect_tokens<'t, 'a>(children: &'t [SyntaxElement<'a>], tokens: &mut Vec<&'t SyntaxToken<'a>>) {
+    for child in children {
+        match child {
+            SyntaxElement::Node(node) => collect_tokens(&node.children, tokens),
+            SyntaxElement::Token(token) => tokens.push(token),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_document, Config};
+    
+    fn tree_text(code: &str, config: &Config) -> String {
+        let (document, _) = parse_document(code, config).unwrap();
+        SyntaxTree::new(&document).text()
+    }
+    
+    #[test]
+    fn test_round_trip() {
+        let code = "\u{FEFF}// Query\r\nlet\r\n\tA = [a=1,/* x */b = {1 ,2}] ,\r\n    B = (x) =>  x  +A[a]\r\nin\r\n    B(1) // done\r\n";
+        assert_eq!(tree_text(code, &Config::default()), code);
+        
+        // Kept as written by lenient parsing
+        let lenient = Config { lenient: true, ..Config::default() };
+        let code = "let\n    A = 1 +,\n    B = @@ 2\nin\n    A";
+        assert_eq!(tree_text(code, &lenient), code);
+    }
+    
+    #[test]
+    fn test_tokens_under_nodes() {
+        let code = "let  A = 1 in A";
+        let (document, _) = parse_document(code, &Config::default()).unwrap();
+        let tree = SyntaxTree::new(&document);
+        let SyntaxElement::Node(root) = &tree.children[0] else { panic!() };
+        let texts: Vec<_> = root.tokens().map(|token| token.text).collect();
+        assert_eq!(texts, ["let", "  ", " ", "in", " "]);
+        
+        let binding = root.nodes().next().unwrap();
+        assert!(matches!(binding.node, NodeRef::Binding(_)));
+        assert_eq!(binding.text(), "A = 1");
+        assert_eq!(tree.node_at(code.find('1').unwrap()).unwrap().text(), "1");
+        assert!(tree.node_at(code.len()).is_none());
+    }
+}
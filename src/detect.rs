@@ -0,0 +1,165 @@
+//! Guessing whether text is Power Query M
+//!
+//! Clipboard mode and editor integrations receive arbitrary text. Words like
+//! `if`, `and`, and `in` are M keywords but also everyday English, so the
+//! guess rests on constructs prose does not contain (`each`, `=>`,
+//! `Table.SelectRows(`, `#date`, `let ... in`) and on whether the text parses.
+
+use crate::alloc_prelude::*;
+use crate::ast::ExprKind;
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::token::{Token, TokenKind};
+
+/// How likely text is to be M code, from least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Confidence {
+    /// Not M (empty, or prose)
+    None,
+    /// Parses, but would also pass as other text (`x`, `42`,
+    /// `if a then b else c`)
+    Low,
+    /// A record, list, function, call, or parenthesized expression, or
+    /// broken code with M constructs
+    Medium,
+    /// Parses and uses constructs particular to M
+    High,
+}
+
+/// Guess whether `text` is M code.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::detect::{looks_like_m, Confidence};
+///
+/// assert_eq!(looks_like_m("let x = 1 in x"), Confidence::High);
+/// assert_eq!(looks_like_m("Table.SelectRows(Source, each [A] > 1"), Confidence::Medium);
+/// assert_eq!(looks_like_m("x + 1"), Confidence::Low);
+/// assert_eq!(looks_like_m("let me know if this works in production"), Confidence::None);
+/// assert_eq!(looks_like_m("Meeting notes: don't forget"), Confidence::None);
+/// ```
+pub fn looks_like_m(text: &str) -> Confidence {
+    let tokens: Vec<Token> = Lexer::new(text)
+        .tokenize()
+        .into_iter()
+        .filter(|t| !t.kind.is_trivia() && t.kind != TokenKind::Eof)
+        .collect();
+    if tokens.is_empty() {
+        return Confidence::None;
+    }
+    // Apostrophes, backticks and the like: more than a stray one is prose
+    let invalid = tokens.iter().filter(|t| matches!(t.kind, TokenKind::Invalid(_) | TokenKind::UnexpectedChar(_))).count();
+    if invalid * 10 > tokens.len() {
+        return Confidence::None;
+    }
+    
+    let signals = signals(&tokens);
+    match Parser::new(Lexer::new(text).tokenize()).parse() {
+        Ok(_) if signals > 0 => Confidence::High,
+        Ok(document) => match document.expression.kind {
+            ExprKind::Record(_)
+            | ExprKind::List(_)
+            | ExprKind::Function(_)
+            | ExprKind::FunctionCall(_)
+            | ExprKind::Parenthesized(_) => Confidence::Medium,
+            _ => Confidence::Low,
+        },
+        Err(_) if signals >= 2 => Confidence::Medium,
+        Err(_) => Confidence::None,
+    }
+}
+
+/// Number of constructs found in `tokens` that prose does not contain
+fn signals(tokens: &[Token]) -> usize {
+    let mut count = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let next = tokens.get(i + 1).map(|t| &t.kind);
+        count += match &token.kind {
+            TokenKind::Each
+            | TokenKind::FatArrow
+            | TokenKind::Otherwise
+            | TokenKind::QuestionQuestion
+            | TokenKind::QuotedIdentifier(_)
+            | TokenKind::HashBinary
+            | TokenKind::HashDate
+            | TokenKind::HashDatetime
+            | TokenKind::HashDatetimezone
+            | TokenKind::HashDuration
+            | TokenKind::HashInfinity
+            | TokenKind::HashNan
+            | TokenKind::HashSections
+            | TokenKind::HashShared
+            | TokenKind::HashTable
+            | TokenKind::HashTime => 1,
+            // A library function call: `Table.SelectRows(`
+            TokenKind::Identifier(name)
+                if next == Some(&TokenKind::LeftParen)
+                    && name.contains('.')
+                    && name.starts_with(|c: char| c.is_ascii_uppercase()) =>
+            {
+                1
+            }
+            // A binding: `let Source =`
+            TokenKind::Let if matches!(next, Some(TokenKind::Identifier(_) | TokenKind::QuotedIdentifier(_)))
+                && tokens.get(i + 2).map(|t| &t.kind) == Some(&TokenKind::Equal) =>
+            {
+                1
+            }
+            // A section document: `section Name;`, first or after a
+            // `[Version = "1.0"]` attribute record
+            TokenKind::Section
+                if matches!(next, Some(TokenKind::Identifier(_)))
+                    && tokens.get(i + 2).map(|t| &t.kind) == Some(&TokenKind::Semicolon) =>
+            {
+                1
+            }
+            _ => 0,
+        };
+    }
+    // The body of a `let`
+    if let Some(let_index) = tokens.iter().position(|t| t.kind == TokenKind::Let) {
+        if tokens[let_index..].iter().any(|t| t.kind == TokenKind::In) {
+            count += 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_code() {
+        for code in [
+            "let Source = Excel.Workbook(File.Contents(\"a.xlsx\")) in Source",
+            "each [A] > 1",
+            "(x) => x + 1",
+            "#date(2024, 1, 1)",
+            "section Section1;\nshared Query1 = 1;",
+            "[Version = \"1.0.0\"]\nsection Connector;\nshared Connector.Version = 1;",
+        ] {
+            assert_eq!(looks_like_m(code), Confidence::High, "{}", code);
+        }
+        for code in [
+            "[A = 1, B = 2]",
+            "{1, 2, 3}",
+            "(1 + 2)",
+            "let Source = in Source",
+        ] {
+            assert_eq!(looks_like_m(code), Confidence::Medium, "{}", code);
+        }
+    }
+    
+    #[test]
+    fn test_prose() {
+        assert_eq!(looks_like_m(""), Confidence::None);
+        assert_eq!(looks_like_m("  \n "), Confidence::None);
+        assert_eq!(looks_like_m("Hello, world"), Confidence::None);
+        assert_eq!(looks_like_m("It's not what I'd expected, isn't it?"), Confidence::None);
+        assert_eq!(looks_like_m("if it rains then stay else go"), Confidence::None);
+        assert_eq!(looks_like_m("if a then b else c"), Confidence::Low);
+        assert_eq!(looks_like_m("Total"), Confidence::Low);
+    }
+}
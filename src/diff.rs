@@ -0,0 +1,377 @@
+//! Step-level differences between two versions of a query
+//!
+//! Compares the steps of two queries instead of their text, so a review of
+//! a changed query shows which steps were added, removed, renamed, or
+//! changed, and which arguments of a changed call differ. Layout is
+//! ignored: values are compared in their formatted form. Parse without
+//! attached trivia (see [`diff`]) to ignore comments as well.
+
+use core::fmt;
+
+use crate::alloc_prelude::*;
+use crate::analysis::{collect_free_names, display_name, steps_let};
+use crate::ast::{Document, Expr, ExprKind};
+use crate::config::Config;
+use crate::formatter::Formatter;
+use crate::lexer::Lexer;
+use crate::parser::{ParseError, ParseOptions, Parser};
+use crate::refactor::rename_references;
+
+/// Values longer than this are shortened in [`StepChange`]
+const MAX_VALUE_LENGTH: usize = 60;
+
+/// A changed argument of a call to the same function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgumentChange {
+    /// 1-based position of the argument
+    pub position: usize,
+    /// The argument before, or `None` if it was added
+    pub old: Option<String>,
+    /// The argument after, or `None` if it was removed
+    pub new: Option<String>,
+}
+
+/// A difference between two versions of a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepChange {
+    Added { name: String },
+    Removed { name: String },
+    /// Same value under a new name; references to it were updated
+    Renamed { from: String, to: String },
+    Modified {
+        name: String,
+        /// Value before, on one line
+        old: String,
+        /// Value after, on one line
+        new: String,
+        /// Changed arguments, when both values call the same function
+        arguments: Vec<ArgumentChange>,
+        /// Steps the value now refers to
+        dependencies_added: Vec<String>,
+        /// Steps the value no longer refers to
+        dependencies_removed: Vec<String>,
+    },
+    /// The expression after `in` changed
+    ResultChanged { old: String, new: String },
+}
+
+/// Step-level differences between two queries, in the order of the new
+/// query's steps (removed steps first)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryDiff {
+    pub changes: Vec<StepChange>,
+}
+
+impl QueryDiff {
+    /// Compare two parsed queries. Comments attached as trivia are part of
+    /// the compared values.
+    pub fn between(old: &Document, new: &Document) -> Self {
+        let old_steps = steps(&old.expression);
+        let new_steps = steps(&new.expression);
+        let old_names: Vec<String> = old_steps.iter().map(|(name, _)| name.clone()).collect();
+        let new_names: Vec<String> = new_steps.iter().map(|(name, _)| name.clone()).collect();
+        
+        // Pair removed and added steps whose values are equal once the
+        // renames found so far are applied, until no more pairs are found
+        let mut renames: Vec<(String, String)> = Vec::new();
+        loop {
+            let found = old_steps.iter().find_map(|(from, old_value)| {
+                if new_names.contains(from) || renames.iter().any(|(f, _)| f == from) {
+                    return None;
+                }
+                new_steps.iter().find_map(|(to, new_value)| {
+                    if old_names.contains(to) || renames.iter().any(|(_, t)| t == to) {
+                        return None;
+                    }
+                    let mut candidate = renames.clone();
+                    candidate.push((from.clone(), to.clone()));
+                    (canonical(old_value, &candidate) == canonical(new_value, &[]))
+                        .then(|| (from.clone(), to.clone()))
+                })
+            });
+            match found {
+                Some(pair) => renames.push(pair),
+                None => break,
+            }
+        }
+        let renamed = |name: &str| -> String {
+            renames
+                .iter()
+                .find(|(from, _)| from == name)
+                .map_or_else(|| name.to_string(), |(_, to)| to.clone())
+        };
+        
+        let mut changes = Vec::new();
+        for (name, _) in &old_steps {
+            if !new_names.contains(name) && !renames.iter().any(|(from, _)| from == name) {
+                changes.push(StepChange::Removed { name: name.clone() });
+            }
+        }
+        for (name, new_value) in &new_steps {
+            if let Some((from, _)) = renames.iter().find(|(_, to)| to == name) {
+                changes.push(StepChange::Renamed {
+                    from: from.clone(),
+                    to: name.clone(),
+                });
+                continue;
+            }
+            let Some((_, old_value)) = old_steps.iter().find(|(old_name, _)| old_name == name) else {
+                changes.push(StepChange::Added { name: name.clone() });
+                continue;
+            };
+            let old_text = canonical(old_value, &renames);
+            let new_text = canonical(new_value, &[]);
+            if old_text == new_text {
+                continue;
+            }
+            
+            let old_dependencies: Vec<String> = dependencies(old_value, &old_names)
+                .iter()
+                .map(|name| renamed(name))
+                .collect();
+            let new_dependencies = dependencies(new_value, &new_names);
+            changes.push(StepChange::Modified {
+                name: name.clone(),
+                old: shorten(&old_text),
+                new: shorten(&new_text),
+                arguments: argument_changes(old_value, new_value, &renames),
+                dependencies_added: new_dependencies
+                    .iter()
+                    .filter(|d| !old_dependencies.contains(d))
+                    .cloned()
+                    .collect(),
+                dependencies_removed: old_dependencies
+                    .iter()
+                    .filter(|d| !new_dependencies.contains(d))
+                    .cloned()
+                    .collect(),
+            });
+        }
+        
+        let old_result = canonical(result(&old.expression), &renames);
+        let new_result = canonical(result(&new.expression), &[]);
+        if old_result != new_result {
+            changes.push(StepChange::ResultChanged {
+                old: shorten(&old_result),
+                new: shorten(&new_result),
+            });
+        }
+        
+        QueryDiff { changes }
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for StepChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepChange::Added { name } => write!(f, "added: {}", display_name(name)),
+            StepChange::Removed { name } => write!(f, "removed: {}", display_name(name)),
+            StepChange::Renamed { from, to } => {
+                write!(f, "renamed: {} -> {}", display_name(from), display_name(to))
+            }
+            StepChange::Modified {
+                name,
+                old,
+                new,
+                arguments,
+                dependencies_added,
+                dependencies_removed,
+            } => {
+                write!(f, "modified: {}", display_name(name))?;
+                if arguments.is_empty() {
+                    write!(f, "\n    {} -> {}", old, new)?;
+                }
+                for argument in arguments {
+                    write!(
+                        f,
+                        "\n    argument {}: {} -> {}",
+                        argument.position,
+                        argument.old.as_deref().unwrap_or("(none)"),
+                        argument.new.as_deref().unwrap_or("(none)")
+                    )?;
+                }
+                if !dependencies_added.is_empty() {
+                    write!(f, "\n    now uses: {}", names(dependencies_added))?;
+                }
+                if !dependencies_removed.is_empty() {
+                    write!(f, "\n    no longer uses: {}", names(dependencies_removed))?;
+                }
+                Ok(())
+            }
+            StepChange::ResultChanged { old, new } => write!(f, "result: {} -> {}", old, new),
+        }
+    }
+}
+
+impl fmt::Display for QueryDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "No step changes");
+        }
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse both queries, ignoring comments, and compare their steps
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::diff::{diff, StepChange};
+///
+/// let old = "let Source = Csv.Document(File, [Delimiter = \",\"]), Kept = Source in Kept";
+/// let new = "let Source = Csv.Document(File, [Delimiter = \";\"]), Rows = Source in Rows";
+/// let changes = diff(old, new).unwrap().changes;
+/// assert!(matches!(&changes[0], StepChange::Modified { name, .. } if name == "Source"));
+/// assert_eq!(changes[1], StepChange::Renamed { from: "Kept".into(), to: "Rows".into() });
+/// assert_eq!(changes.len(), 2);
+/// ```
+pub fn diff(old: &str, new: &str) -> Result<QueryDiff, Vec<ParseError>> {
+    Ok(QueryDiff::between(&parse(old)?, &parse(new)?))
+}
+
+/// Parse `code` without attaching comments, as [`diff`] does
+pub fn parse(code: &str) -> Result<Document, Vec<ParseError>> {
+    let options = ParseOptions {
+        attach_trivia: false,
+        ..ParseOptions::default()
+    };
+    Parser::with_options(Lexer::new(code).tokenize(), options).parse()
+}
+
+/// The steps of a query; a query without steps has none
+fn steps(expr: &Expr) -> Vec<(String, &Expr)> {
+    steps_let(expr).map_or_else(Vec::new, |l| {
+        l.bindings.iter().map(|b| (b.name.name.clone(), &b.value)).collect()
+    })
+}
+
+/// The expression after `in`, or the whole query if it has no steps
+fn result(expr: &Expr) -> &Expr {
+    steps_let(expr).map_or(expr, |l| &l.body)
+}
+
+/// Compact formatted text of `expr` after applying `renames`
+fn canonical(expr: &Expr, renames: &[(String, String)]) -> String {
+    let mut expr = expr.clone();
+    if !renames.is_empty() {
+        rename_references(&mut expr, renames, &mut Vec::new());
+    }
+    let document = Document {
+        span: expr.span,
+        expression: expr,
+        trailing_trivia: Vec::new(),
+        source: String::new(),
+    };
+    Formatter::new(Config::compact()).format(&document)
+}
+
+/// `text` on one line, shortened to [`MAX_VALUE_LENGTH`] characters
+fn shorten(text: &str) -> String {
+    let line = text.lines().map(str::trim).filter(|l| !l.is_empty()).collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= MAX_VALUE_LENGTH {
+        line
+    } else {
+        let mut short: String = line.chars().take(MAX_VALUE_LENGTH - 3).collect();
+        short.push_str("...");
+        short
+    }
+}
+
+/// Steps among `steps` that `value` refers to
+fn dependencies(value: &Expr, steps: &[String]) -> Vec<String> {
+    let mut free = Vec::new();
+    collect_free_names(value, &mut Vec::new(), &mut free);
+    free.retain(|name| steps.contains(name));
+    free
+}
+
+/// Changed arguments when both values call the same function
+fn argument_changes(old: &Expr, new: &Expr, renames: &[(String, String)]) -> Vec<ArgumentChange> {
+    let (ExprKind::FunctionCall(old_call), ExprKind::FunctionCall(new_call)) = (&old.kind, &new.kind) else {
+        return Vec::new();
+    };
+    if canonical(&old_call.function, renames) != canonical(&new_call.function, &[]) {
+        return Vec::new();
+    }
+    let count = old_call.arguments.len().max(new_call.arguments.len());
+    (0..count)
+        .filter_map(|i| {
+            let old = old_call.arguments.get(i).map(|arg| shorten(&canonical(arg, renames)));
+            let new = new_call.arguments.get(i).map(|arg| shorten(&canonical(arg, &[])));
+            (old != new).then_some(ArgumentChange {
+                position: i + 1,
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+fn names(names: &[String]) -> String {
+    names.iter().map(|name| display_name(name)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_step_changes() {
+        let old = "let\n    Source = Sql.Database(\"a\", \"b\"),\n    Filtered = Table.SelectRows(Source, each [x] > 1),\n    Old = 1\nin\n    Filtered";
+        let new = "let\n    // comment\n    Source = Sql.Database(\"a\", \"c\"),\n    #\"Filtered Rows\" = Table.SelectRows(Source, each [x] > 1),\n    New = Table.RowCount(#\"Filtered Rows\")\nin\n    New";
+        let changes = diff(old, new).unwrap().changes;
+        assert_eq!(
+            changes,
+            [
+                StepChange::Removed { name: "Old".into() },
+                StepChange::Modified {
+                    name: "Source".into(),
+                    old: "Sql.Database(\"a\", \"b\")".into(),
+                    new: "Sql.Database(\"a\", \"c\")".into(),
+                    arguments: vec![ArgumentChange {
+                        position: 2,
+                        old: Some("\"b\"".into()),
+                        new: Some("\"c\"".into()),
+                    }],
+                    dependencies_added: vec![],
+                    dependencies_removed: vec![],
+                },
+                StepChange::Renamed {
+                    from: "Filtered".into(),
+                    to: "Filtered Rows".into(),
+                },
+                StepChange::Added { name: "New".into() },
+                StepChange::ResultChanged {
+                    old: "#\"Filtered Rows\"".into(),
+                    new: "New".into(),
+                },
+            ]
+        );
+    }
+    
+    #[test]
+    fn test_dependencies_and_display() {
+        let old = "let A = 1, B = 2, C = A + 1 in C";
+        let new = "let A = 1, B = 2, C = B + 1 in C";
+        let diff = diff(old, new).unwrap();
+        assert_eq!(
+            diff.to_string(),
+            "modified: C\n    A + 1 -> B + 1\n    now uses: B\n    no longer uses: A"
+        );
+        
+        let same = super::diff("let a = 1 in a", "let\n    a = 1 // one\nin\n    a").unwrap();
+        assert!(same.is_empty());
+        assert_eq!(same.to_string(), "No step changes");
+    }
+}
@@ -0,0 +1,590 @@
+//! dprint plugin interface (feature `dprint`)
+//!
+//! Implements the dprint Wasm plugin protocol (schema version 4) so the
+//! formatter can run from a `dprint.json` alongside other formatters. Build
+//! the plugin with:
+//!
+//! ```text
+//! cargo rustc --release --lib --crate-type cdylib \
+//!     --target wasm32-unknown-unknown --features dprint
+//! ```
+//!
+//! and reference `pqm_formatter.wasm` in `dprint.json`:
+//!
+//! ```json
+//! {
+//!   "lineWidth": 100,
+//!   "pqm": { "preset": "compact", "trailingComma": true },
+//!   "plugins": ["./pqm_formatter.wasm"]
+//! }
+//! ```
+//!
+//! The global `lineWidth`, `indentWidth`, and `useTabs` keys are honored;
+//! keys under `"pqm"` override them and map to [`Config`] fields by their
+//! camelCase names. `preset` selects the base configuration.
+
+use crate::config::{
+    CallWrapStyle, CommentStyle, Config, EachStyle, ListFillStyle, SectionAccessStyle,
+};
+use crate::json::{self, JsonValue};
+
+/// Key of the plugin section in `dprint.json`
+pub const CONFIG_KEY: &str = "pqm";
+
+/// File extensions the plugin claims
+pub const FILE_EXTENSIONS: &[&str] = &["pq", "pqm", "m"];
+
+/// A problem with one configuration property
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDiagnostic {
+    pub property_name: String,
+    pub message: String,
+}
+
+/// Formatter configuration resolved from dprint settings
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    pub config: Config,
+    pub diagnostics: Vec<ConfigDiagnostic>,
+}
+
+impl ResolvedConfig {
+    /// The resolved configuration as a JSON object using dprint key names
+    pub fn to_json(&self) -> String {
+        let c = &self.config;
+        let section_access_style = match c.section_access_style {
+            SectionAccessStyle::Preserve => "preserve",
+            SectionAccessStyle::Bang => "bang",
+            SectionAccessStyle::Bracket => "bracket",
+        };
+        let prefer_each = match c.prefer_each {
+            EachStyle::Preserve => "preserve",
+            EachStyle::Each => "each",
+            EachStyle::Lambda => "lambda",
+        };
+        let call_wrap_style = match c.call_wrap_style {
+            CallWrapStyle::Block => "block",
+            CallWrapStyle::Hanging => "hanging",
+        };
+        let comment_style = match c.comment_style {
+            CommentStyle::Preserve => "preserve",
+            CommentStyle::Line => "line",
+            CommentStyle::Block => "block",
+        };
+        let list_fill_style = match c.list_fill_style {
+            ListFillStyle::OnePerLine => "one-per-line",
+            ListFillStyle::Fill => "fill",
+        };
+        let members = [
+            ("lineWidth", c.max_line_length.to_string()),
+            ("indentWidth", c.indent_size.to_string()),
+            ("useTabs", c.use_tabs.to_string()),
+            ("trailingCommaRecords", c.trailing_comma_records.to_string()),
+            ("trailingCommaLists", c.trailing_comma_lists.to_string()),
+            ("spaceInBrackets", c.space_in_brackets.to_string()),
+            ("spaceInBraces", c.space_in_braces.to_string()),
+            ("spaceInParens", c.space_in_parens.to_string()),
+            ("alignEquals", c.align_equals.to_string()),
+            ("callWrapStyle", json::quote(call_wrap_style)),
+            (
+                "maxCallNestingInline",
+                c.max_call_nesting_inline.map_or_else(|| "null".to_string(), |n| n.to_string()),
+            ),
+            ("multilineThreshold", c.multiline_threshold.to_string()),
+            ("maxInlineArgLength", c.max_inline_arg_length.to_string()),
+            ("alwaysExpandLet", c.always_expand_let.to_string()),
+            ("alwaysExpandRecords", c.always_expand_records.to_string()),
+            ("alwaysExpandLists", c.always_expand_lists.to_string()),
+            ("listFillStyle", json::quote(list_fill_style)),
+            ("alignNestedLists", c.align_nested_lists.to_string()),
+            ("commentStyle", json::quote(comment_style)),
+            ("normalizeNumbers", c.normalize_numbers.to_string()),
+            ("normalizeConstructorNumbers", c.normalize_constructor_numbers.to_string()),
+            ("normalizeTextEscapes", c.normalize_text_escapes.to_string()),
+            ("normalizeIdentifiersNfc", c.normalize_identifiers_nfc.to_string()),
+            (
+                "annotationColumn",
+                c.annotation_column.map_or_else(|| "null".to_string(), |n| n.to_string()),
+            ),
+            ("preserveBlankLines", c.preserve_blank_lines.to_string()),
+            ("maxBlankLines", c.max_blank_lines.to_string()),
+            ("sectionAccessStyle", json::quote(section_access_style)),
+            ("sortSectionMembers", c.sort_section_members.to_string()),
+            ("preferEach", json::quote(prefer_each)),
+            ("lenient", c.lenient.to_string()),
+            ("strict", c.strict.to_string()),
+            ("dialect", json::quote(c.dialect.name())),
+            ("styleEdition", json::quote(c.style_edition.name())),
+        ];
+        let body: Vec<String> = members
+            .iter()
+            .map(|(key, value)| format!("{}:{}", json::quote(key), value))
+            .collect();
+        format!("{{{}}}", body.join(","))
+    }
+}
+
+/// Resolve a configuration from dprint's global and plugin settings.
+///
+/// Both arguments are JSON objects. Unknown keys and invalid values are
+/// reported as diagnostics and otherwise ignored.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::dprint::resolve_config;
+///
+/// let resolved = resolve_config(r#"{"lineWidth": 100}"#, r#"{"useTabs": true}"#);
+/// assert_eq!(resolved.config.max_line_length, 100);
+/// assert!(resolved.config.use_tabs);
+/// assert!(resolved.diagnostics.is_empty());
+/// ```
+pub fn resolve_config(global: &str, plugin: &str) -> ResolvedConfig {
+    let mut diagnostics = Vec::new();
+    let global = parse_object("global", global, &mut diagnostics);
+    let plugin = parse_object("plugin", plugin, &mut diagnostics);
+    resolve_values(&global, &plugin, diagnostics)
+}
+
+/// Format `text` for dprint.
+///
+/// Returns `Ok(None)` when the text is already formatted, and the parse
+/// errors as a single message on failure.
+pub fn format_text(text: &str, config: &Config) -> Result<Option<String>, String> {
+    match crate::format(text, config.clone()) {
+        Ok(formatted) if formatted == text => Ok(None),
+        Ok(formatted) => Ok(Some(formatted)),
+        Err(errors) => Err(errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
+}
+
+/// Plugin information reported to the dprint host
+pub fn plugin_info_json() -> String {
+    format!(
+        "{{\"name\":{},\"version\":{},\"configKey\":{},\"helpUrl\":{},\"configSchemaUrl\":\"\",\"updateUrl\":null}}",
+        json::quote(env!("CARGO_PKG_NAME")),
+        json::quote(env!("CARGO_PKG_VERSION")),
+        json::quote(CONFIG_KEY),
+        json::quote(env!("CARGO_PKG_REPOSITORY")),
+    )
+}
+
+/// File matching information reported to the dprint host
+pub fn file_matching_json() -> String {
+    let extensions: Vec<String> = FILE_EXTENSIONS.iter().map(|e| json::quote(e)).collect();
+    format!("{{\"fileExtensions\":[{}],\"fileNames\":[]}}", extensions.join(","))
+}
+
+fn parse_object(name: &str, text: &str, diagnostics: &mut Vec<ConfigDiagnostic>) -> JsonValue {
+    match json::parse(text) {
+        Ok(value @ JsonValue::Object(_)) => value,
+        Ok(_) => {
+            diagnostics.push(diagnostic(name, "Expected an object"));
+            JsonValue::Object(Vec::new())
+        }
+        Err(message) => {
+            diagnostics.push(diagnostic(name, &message));
+            JsonValue::Object(Vec::new())
+        }
+    }
+}
+
+fn resolve_values(
+    global: &JsonValue,
+    plugin: &JsonValue,
+    mut diagnostics: Vec<ConfigDiagnostic>,
+) -> ResolvedConfig {
+    // The preset is the base every other key is applied on top of
+    let mut config = match plugin.get("preset") {
+        None => Config::default(),
+        Some(JsonValue::String(preset)) if preset == "default" => Config::default(),
+        Some(JsonValue::String(preset)) if preset == "compact" => Config::compact(),
+        Some(JsonValue::String(preset)) if preset == "expanded" => Config::expanded(),
+        Some(_) => {
+            diagnostics.push(diagnostic(
+                "preset",
+                "Expected one of \"default\", \"compact\", \"expanded\"",
+            ));
+            Config::default()
+        }
+    };
+    
+    // Global keys other formatters also understand; the rest are theirs
+    for (key, value) in global.members() {
+        if matches!(key.as_str(), "lineWidth" | "indentWidth" | "useTabs") {
+            if let Err(message) = apply_key(&mut config, key, value) {
+                diagnostics.push(diagnostic(key, &message));
+            }
+        }
+    }
+    
+    for (key, value) in plugin.members() {
+        if key == "preset" {
+            continue;
+        }
+        if let Err(message) = apply_key(&mut config, key, value) {
+            diagnostics.push(diagnostic(key, &message));
+        }
+    }
+    
+    ResolvedConfig { config, diagnostics }
+}
+
+fn apply_key(config: &mut Config, key: &str, value: &JsonValue) -> Result<(), String> {
+    match key {
+        "lineWidth" => config.max_line_length = as_usize(value)?,
+        "indentWidth" => config.indent_size = as_usize(value)?,
+        "useTabs" => config.use_tabs = as_bool(value)?,
+        "trailingComma" => {
+            config.trailing_comma_records = as_bool(value)?;
+            config.trailing_comma_lists = config.trailing_comma_records;
+        }
+        "trailingCommaRecords" => config.trailing_comma_records = as_bool(value)?,
+        "trailingCommaLists" => config.trailing_comma_lists = as_bool(value)?,
+        "spaceInBrackets" => config.space_in_brackets = as_bool(value)?,
+        "spaceInBraces" => config.space_in_braces = as_bool(value)?,
+        "spaceInParens" => config.space_in_parens = as_bool(value)?,
+        "alignEquals" => config.align_equals = as_bool(value)?,
+        "callWrapStyle" => {
+            config.call_wrap_style = match value {
+                JsonValue::String(s) if s == "block" => CallWrapStyle::Block,
+                JsonValue::String(s) if s == "hanging" => CallWrapStyle::Hanging,
+                _ => return Err("Expected one of \"block\", \"hanging\"".to_string()),
+            }
+        }
+        "maxCallNestingInline" => {
+            config.max_call_nesting_inline = match value {
+                JsonValue::Null => None,
+                value => Some(as_usize(value)?),
+            }
+        }
+        "multilineThreshold" => config.multiline_threshold = as_usize(value)?,
+        "maxInlineArgLength" => config.max_inline_arg_length = as_usize(value)?,
+        "alwaysExpandLet" => config.always_expand_let = as_bool(value)?,
+        "alwaysExpandRecords" => config.always_expand_records = as_bool(value)?,
+        "alwaysExpandLists" => config.always_expand_lists = as_bool(value)?,
+        "listFillStyle" => {
+            config.list_fill_style = match value {
+                JsonValue::String(s) if s == "one-per-line" => ListFillStyle::OnePerLine,
+                JsonValue::String(s) if s == "fill" => ListFillStyle::Fill,
+                _ => return Err("Expected one of \"one-per-line\", \"fill\"".to_string()),
+            }
+        }
+        "alignNestedLists" => config.align_nested_lists = as_bool(value)?,
+        "commentStyle" => {
+            config.comment_style = match value {
+                JsonValue::String(s) if s == "preserve" => CommentStyle::Preserve,
+                JsonValue::String(s) if s == "line" => CommentStyle::Line,
+                JsonValue::String(s) if s == "block" => CommentStyle::Block,
+                _ => return Err("Expected one of \"preserve\", \"line\", \"block\"".to_string()),
+            }
+        }
+        "normalizeNumbers" => config.normalize_numbers = as_bool(value)?,
+        "normalizeConstructorNumbers" => config.normalize_constructor_numbers = as_bool(value)?,
+        "normalizeTextEscapes" => config.normalize_text_escapes = as_bool(value)?,
+        "normalizeIdentifiersNfc" => config.normalize_identifiers_nfc = as_bool(value)?,
+        "annotationColumn" => {
+            config.annotation_column = match value {
+                JsonValue::Null => None,
+                value => Some(as_usize(value)?),
+            }
+        }
+        "preserveBlankLines" => config.preserve_blank_lines = as_bool(value)?,
+        "maxBlankLines" => config.max_blank_lines = as_usize(value)?,
+        "sectionAccessStyle" => {
+            config.section_access_style = match value {
+                JsonValue::String(s) if s == "preserve" => SectionAccessStyle::Preserve,
+                JsonValue::String(s) if s == "bang" => SectionAccessStyle::Bang,
+                JsonValue::String(s) if s == "bracket" => SectionAccessStyle::Bracket,
+                _ => return Err("Expected one of \"preserve\", \"bang\", \"bracket\"".to_string()),
+            }
+        }
+        "sortSectionMembers" => config.sort_section_members = as_bool(value)?,
+        "preferEach" => {
+            config.prefer_each = match value {
+                JsonValue::String(s) if s == "preserve" => EachStyle::Preserve,
+                JsonValue::String(s) if s == "each" => EachStyle::Each,
+                JsonValue::String(s) if s == "lambda" => EachStyle::Lambda,
+                _ => return Err("Expected one of \"preserve\", \"each\", \"lambda\"".to_string()),
+            }
+        }
+        "lenient" => config.lenient = as_bool(value)?,
+        "strict" => config.strict = as_bool(value)?,
+        "dialect" => {
+            config.dialect = match value {
+                JsonValue::String(s) => s.parse()?,
+                _ => return Err("Expected one of \"powerbi\", \"excel\", \"legacy\"".to_string()),
+            }
+        }
+        "styleEdition" => {
+            config.style_edition = match value {
+                JsonValue::String(s) => s.parse()?,
+                _ => return Err("Expected one of \"2024\", \"2025\"".to_string()),
+            }
+        }
+        _ => return Err("Unknown property".to_string()),
+    }
+    Ok(())
+}
+
+fn as_bool(value: &JsonValue) -> Result<bool, String> {
+    match value {
+        JsonValue::Bool(b) => Ok(*b),
+        _ => Err("Expected a boolean".to_string()),
+    }
+}
+
+fn as_usize(value: &JsonValue) -> Result<usize, String> {
+    match value {
+        JsonValue::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        _ => Err("Expected a non-negative integer".to_string()),
+    }
+}
+
+fn diagnostic(property_name: &str, message: &str) -> ConfigDiagnostic {
+    ConfigDiagnostic {
+        property_name: property_name.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Exports and shared-buffer plumbing for the Wasm host
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    
+    struct Registered {
+        global: JsonValue,
+        plugin: JsonValue,
+        resolved: ResolvedConfig,
+    }
+    
+    #[derive(Default)]
+    struct State {
+        shared_bytes: Vec<u8>,
+        configs: BTreeMap<u32, Registered>,
+        override_config: Option<JsonValue>,
+        formatted_text: String,
+        error_text: String,
+    }
+    
+    thread_local! {
+        static STATE: RefCell<State> = RefCell::new(State::default());
+    }
+    
+    fn take_shared_string() -> String {
+        STATE.with(|state| {
+            let bytes = std::mem::take(&mut state.borrow_mut().shared_bytes);
+            String::from_utf8_lossy(&bytes).into_owned()
+        })
+    }
+    
+    fn set_shared_string(text: String) -> u32 {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.shared_bytes = text.into_bytes();
+            state.shared_bytes.len() as u32
+        })
+    }
+    
+    fn with_config<T>(config_id: u32, f: impl FnOnce(&Registered) -> T) -> Option<T> {
+        STATE.with(|state| state.borrow().configs.get(&config_id).map(f))
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn dprint_plugin_version_4() -> u32 {
+        4
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_shared_bytes_ptr() -> *const u8 {
+        STATE.with(|state| state.borrow().shared_bytes.as_ptr())
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn clear_shared_bytes(size: u32) -> *const u8 {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.shared_bytes = vec![0; size as usize];
+            state.shared_bytes.as_ptr()
+        })
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn register_config(config_id: u32) {
+        let mut diagnostics = Vec::new();
+        let raw = parse_object("config", &take_shared_string(), &mut diagnostics);
+        let global = raw.get("global").cloned().unwrap_or(JsonValue::Object(Vec::new()));
+        let plugin = raw.get("plugin").cloned().unwrap_or(JsonValue::Object(Vec::new()));
+        let resolved = resolve_values(&global, &plugin, diagnostics);
+        STATE.with(|state| {
+            state
+                .borrow_mut()
+                .configs
+                .insert(config_id, Registered { global, plugin, resolved });
+        });
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn release_config(config_id: u32) {
+        STATE.with(|state| {
+            state.borrow_mut().configs.remove(&config_id);
+        });
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_config_diagnostics(config_id: u32) -> u32 {
+        let diagnostics = with_config(config_id, |registered| {
+            registered
+                .resolved
+                .diagnostics
+                .iter()
+                .map(|d| {
+                    format!(
+                        "{{\"propertyName\":{},\"message\":{}}}",
+                        json::quote(&d.property_name),
+                        json::quote(&d.message)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+        set_shared_string(format!("[{}]", diagnostics))
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_resolved_config(config_id: u32) -> u32 {
+        let resolved = with_config(config_id, |registered| registered.resolved.to_json());
+        set_shared_string(resolved.unwrap_or_else(|| "{}".to_string()))
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_plugin_info() -> u32 {
+        set_shared_string(plugin_info_json())
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_license_text() -> u32 {
+        set_shared_string(include_str!("../LICENSE").to_string())
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_config_file_matching(_config_id: u32) -> u32 {
+        set_shared_string(file_matching_json())
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn set_file_path() {
+        // Every claimed file is formatted the same way
+        take_shared_string();
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn set_override_config() {
+        let text = take_shared_string();
+        let value = json::parse(&text).ok().filter(|v| !v.members().is_empty());
+        STATE.with(|state| state.borrow_mut().override_config = value);
+    }
+    
+    /// 0 = no change, 1 = changed, 2 = error
+    #[no_mangle]
+    pub extern "C" fn format(config_id: u32) -> u8 {
+        let text = take_shared_string();
+        let override_config = STATE.with(|state| state.borrow_mut().override_config.take());
+        let config = with_config(config_id, |registered| match &override_config {
+            None => registered.resolved.config.clone(),
+            Some(JsonValue::Object(overrides)) => {
+                let mut members = registered.plugin.members().to_vec();
+                members.extend(overrides.iter().cloned());
+                resolve_values(&registered.global, &JsonValue::Object(members), Vec::new()).config
+            }
+            Some(_) => registered.resolved.config.clone(),
+        });
+        let result = match config {
+            Some(config) => format_text(&text, &config),
+            None => Err(format!("Unknown config id {}", config_id)),
+        };
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            match result {
+                Ok(None) => 0,
+                Ok(Some(formatted)) => {
+                    state.formatted_text = formatted;
+                    1
+                }
+                Err(message) => {
+                    state.error_text = message;
+                    2
+                }
+            }
+        })
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_formatted_text() -> u32 {
+        let text = STATE.with(|state| std::mem::take(&mut state.borrow_mut().formatted_text));
+        set_shared_string(text)
+    }
+    
+    #[no_mangle]
+    pub extern "C" fn get_error_text() -> u32 {
+        let text = STATE.with(|state| std::mem::take(&mut state.borrow_mut().error_text));
+        set_shared_string(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_resolve_config_keys() {
+        let resolved = resolve_config(
+            r#"{"lineWidth": 80, "indentWidth": 2, "newLineKind": "lf"}"#,
+            r#"{"preset": "expanded", "indentWidth": 3, "trailingComma": true, "sectionAccessStyle": "bang"}"#,
+        );
+        assert!(resolved.diagnostics.is_empty());
+        let config = resolved.config;
+        assert_eq!(config.max_line_length, 80);
+        assert_eq!(config.indent_size, 3);
+        assert!(config.trailing_comma_records && config.trailing_comma_lists);
+        assert!(config.always_expand_lists);
+        assert_eq!(config.section_access_style, SectionAccessStyle::Bang);
+    }
+    
+    #[test]
+    fn test_resolve_config_diagnostics() {
+        let resolved = resolve_config("{}", r#"{"useTabs": 1, "colour": "red", "preset": "tiny"}"#);
+        let names: Vec<&str> = resolved
+            .diagnostics
+            .iter()
+            .map(|d| d.property_name.as_str())
+            .collect();
+        assert_eq!(names, ["preset", "useTabs", "colour"]);
+        assert!(!resolved.config.use_tabs);
+    }
+    
+    #[test]
+    fn test_resolved_config_round_trip() {
+        let resolved = resolve_config("{}", r#"{"preset": "compact", "lenient": true}"#);
+        let again = resolve_config("{}", &resolved.to_json());
+        assert!(again.diagnostics.is_empty());
+        assert_eq!(again.to_json(), resolved.to_json());
+        assert!(again.config.lenient);
+    }
+    
+    #[test]
+    fn test_format_text() {
+        let config = Config::default();
+        assert_eq!(format_text("{1, 2}\n", &config), Ok(None));
+        assert_eq!(format_text("{1,2}", &config), Ok(Some("{1, 2}\n".to_string())));
+        assert!(format_text("let x = in x", &config).unwrap_err().starts_with("Line 1:"));
+    }
+}
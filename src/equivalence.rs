@@ -0,0 +1,253 @@
+//! Comparison of syntax trees that ignores layout
+//!
+//! Two documents are equivalent when their trees have the same shape and
+//! the same names, literals, and operators. Spans, comments, and the
+//! spelling of a value are not compared: `#"Name"` and `Name` are the same
+//! identifier, and `0x10` and `16` the same number. Regions kept verbatim
+//! by lenient parsing are compared by their text.
+
+use crate::alloc_prelude::*;
+use crate::ast::*;
+
+/// Whether `a` and `b` have the same tree
+pub(crate) fn same_document(a: &Document, b: &Document) -> bool {
+    let sources = Sources { a: &a.source, b: &b.source };
+    a.expression.same(&b.expression, &sources)
+}
+
+/// The sources of the two documents, for verbatim regions
+struct Sources<'s> {
+    a: &'s str,
+    b: &'s str,
+}
+
+/// Equality that ignores spans and trivia
+trait Same {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool;
+}
+
+impl<T: Same> Same for Box<T> {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        (**self).same(other, sources)
+    }
+}
+
+impl<T: Same> Same for Option<T> {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        match (self, other) {
+            (Some(a), Some(b)) => a.same(b, sources),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<T: Same> Same for Vec<T> {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.len() == other.len() && self.iter().zip(other).all(|(a, b)| a.same(b, sources))
+    }
+}
+
+impl Same for Expr {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.kind.same(&other.kind, sources)
+    }
+}
+
+impl Same for ExprKind {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        use ExprKind::*;
+        match (self, other) {
+            (Null, Null) | (Underscore, Underscore) | (HashSections, HashSections) | (HashShared, HashShared) => true,
+            (Logical(a), Logical(b)) => a == b,
+            (Number(a), Number(b)) => a.value == b.value || (a.value.is_nan() && b.value.is_nan()),
+            (Text(a), Text(b)) | (HashUnknown(a), HashUnknown(b)) => a == b,
+            (Identifier(a) | QuotedIdentifier(a), Identifier(b) | QuotedIdentifier(b)) => a == b,
+            (InclusiveIdentifier(a), InclusiveIdentifier(b)) => a.same(b, sources),
+            (Let(a), Let(b)) => a.bindings.same(&b.bindings, sources) && a.body.same(&b.body, sources),
+            (If(a), If(b)) => {
+                a.condition.same(&b.condition, sources)
+                    && a.then_branch.same(&b.then_branch, sources)
+                    && a.else_branch.same(&b.else_branch, sources)
+            }
+            (Try(a), Try(b)) => {
+                a.expr.same(&b.expr, sources)
+                    && a.otherwise.same(&b.otherwise, sources)
+                    && a.catch.same(&b.catch, sources)
+            }
+            (Error(a), Error(b)) | (Each(a), Each(b)) | (Parenthesized(a), Parenthesized(b)) => a.same(b, sources),
+            (Function(a), Function(b)) => {
+                a.parameters.same(&b.parameters, sources)
+                    && a.return_type.same(&b.return_type, sources)
+                    && a.body.same(&b.body, sources)
+            }
+            (FunctionCall(a), FunctionCall(b)) => {
+                a.function.same(&b.function, sources) && a.arguments.same(&b.arguments, sources)
+            }
+            (Record(a), Record(b)) => a.fields.same(&b.fields, sources),
+            (List(a), List(b)) => a.items.same(&b.items, sources),
+            (FieldAccess(a), FieldAccess(b)) => {
+                a.expr.same(&b.expr, sources) && a.field.same(&b.field, sources) && a.optional == b.optional
+            }
+            (FieldProjection(a), FieldProjection(b)) => {
+                a.expr.same(&b.expr, sources) && a.fields.same(&b.fields, sources) && a.optional == b.optional
+            }
+            (SectionAccess(a), SectionAccess(b)) => {
+                a.section.same(&b.section, sources) && a.member.same(&b.member, sources)
+            }
+            (ItemAccess(a), ItemAccess(b)) => {
+                a.expr.same(&b.expr, sources) && a.index.same(&b.index, sources) && a.optional == b.optional
+            }
+            (Binary(a), Binary(b)) => {
+                a.operator == b.operator && a.left.same(&b.left, sources) && a.right.same(&b.right, sources)
+            }
+            (Unary(a), Unary(b)) => a.operator == b.operator && a.operand.same(&b.operand, sources),
+            (Type(a), Type(b)) => a.keyword == b.keyword && a.type_annotation.same(&b.type_annotation, sources),
+            (Metadata(a), Metadata(b)) => a.expr.same(&b.expr, sources) && a.metadata.same(&b.metadata, sources),
+            (HashTable(a), HashTable(b)) => a.columns.same(&b.columns, sources) && a.rows.same(&b.rows, sources),
+            (HashBinary(a), HashBinary(b)) => a.value.same(&b.value, sources),
+            (HashDate(a), HashDate(b)) => all_same(&[(&a.year, &b.year), (&a.month, &b.month), (&a.day, &b.day)], sources),
+            (HashTime(a), HashTime(b)) => {
+                all_same(&[(&a.hour, &b.hour), (&a.minute, &b.minute), (&a.second, &b.second)], sources)
+            }
+            (HashDatetime(a), HashDatetime(b)) => all_same(
+                &[
+                    (&a.year, &b.year),
+                    (&a.month, &b.month),
+                    (&a.day, &b.day),
+                    (&a.hour, &b.hour),
+                    (&a.minute, &b.minute),
+                    (&a.second, &b.second),
+                ],
+                sources,
+            ),
+            (HashDatetimezone(a), HashDatetimezone(b)) => all_same(
+                &[
+                    (&a.year, &b.year),
+                    (&a.month, &b.month),
+                    (&a.day, &b.day),
+                    (&a.hour, &b.hour),
+                    (&a.minute, &b.minute),
+                    (&a.second, &b.second),
+                    (&a.offset_hours, &b.offset_hours),
+                    (&a.offset_minutes, &b.offset_minutes),
+                ],
+                sources,
+            ),
+            (HashDuration(a), HashDuration(b)) => all_same(
+                &[
+                    (&a.days, &b.days),
+                    (&a.hours, &b.hours),
+                    (&a.minutes, &b.minutes),
+                    (&a.seconds, &b.seconds),
+                ],
+                sources,
+            ),
+            (Verbatim(a), Verbatim(b)) => a.slice(sources.a) == b.slice(sources.b),
+            (Section(a), Section(b)) => {
+                a.attributes.same(&b.attributes, sources)
+                    && a.name.same(&b.name, sources)
+                    && a.members.same(&b.members, sources)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn all_same(pairs: &[(&Expr, &Expr)], sources: &Sources<'_>) -> bool {
+    pairs.iter().all(|(a, b)| a.same(b, sources))
+}
+
+impl Same for Identifier {
+    fn same(&self, other: &Self, _: &Sources<'_>) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Same for Binding {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.name.same(&other.name, sources)
+            && self.inclusive == other.inclusive
+            && self.value.same(&other.value, sources)
+    }
+}
+
+impl Same for SectionMember {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.attributes.same(&other.attributes, sources)
+            && self.shared == other.shared
+            && self.name.same(&other.name, sources)
+            && self.value.same(&other.value, sources)
+    }
+}
+
+impl Same for RecordField {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.name.same(&other.name, sources) && self.value.same(&other.value, sources)
+    }
+}
+
+impl Same for Parameter {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.name.same(&other.name, sources)
+            && self.optional == other.optional
+            && self.type_annotation.same(&other.type_annotation, sources)
+    }
+}
+
+impl Same for FieldType {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.name.same(&other.name, sources)
+            && self.optional == other.optional
+            && self.type_annotation.same(&other.type_annotation, sources)
+    }
+}
+
+impl Same for TypeAnnotation {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        self.kind.same(&other.kind, sources)
+    }
+}
+
+impl Same for TypeKind {
+    fn same(&self, other: &Self, sources: &Sources<'_>) -> bool {
+        use TypeKind::*;
+        match (self, other) {
+            (List(a), List(b)) => a.same(b, sources),
+            (Record(a), Record(b)) | (Table(a), Table(b)) => a.same(b, sources),
+            (Function(a, a_return), Function(b, b_return)) => a.same(b, sources) && a_return.same(b_return, sources),
+            (Custom(a), Custom(b)) => a == b,
+            (Nullable(a), Nullable(b)) | (Parenthesized(a), Parenthesized(b)) => a.same(b, sources),
+            (Expr(a), Expr(b)) => a.same(b, sources),
+            (
+                List(_) | Record(_) | Table(_) | Function(..) | Custom(_) | Nullable(_) | Parenthesized(_) | Expr(_),
+                _,
+            ) => false,
+            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::semantically_equal;
+    
+    #[test]
+    fn test_layout_and_spelling_ignored() {
+        assert!(semantically_equal("let x=1 in x", "let\n    x = 1 // one\nin\n    x").unwrap());
+        assert!(semantically_equal("[#\"A\" = 0x10, B = .5]", "[A = 16, #\"B\" = 0.5]").unwrap());
+        assert!(semantically_equal("#date(2024, 01, 01)", "#date(2024, 1, 1)").unwrap());
+        assert!(semantically_equal("\"#(0041)\"", "\"A\"").unwrap());
+        assert!(semantically_equal("{#nan}", "{ #nan }").unwrap());
+    }
+    
+    #[test]
+    fn test_differences_found() {
+        assert!(!semantically_equal("let x=1 in x", "let x=2 in x").unwrap());
+        assert!(!semantically_equal("a + b * c", "(a + b) * c").unwrap());
+        assert!(!semantically_equal("r[A]", "r[A]?").unwrap());
+        assert!(!semantically_equal("(x as number) => x", "(x) => x").unwrap());
+        assert!(!semantically_equal("type {number}", "type {text}").unwrap());
+        assert!(!semantically_equal("let @f = 1 in @f", "let f = 1 in f").unwrap());
+    }
+}
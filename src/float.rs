@@ -0,0 +1,20 @@
+//! The `f64` methods the library needs that std provides but `core` does
+//! not, so that it also builds without the `std` feature
+
+/// Whether `n` is a whole number (`n.fract() == 0.0`)
+pub(crate) fn is_whole(n: f64) -> bool {
+    n.is_finite() && (n.abs() >= 4_503_599_627_370_496.0 || n == n as i64 as f64)
+}
+
+/// `n` rounded half away from zero (`n.round()`)
+pub(crate) fn round(n: f64) -> f64 {
+    if !n.is_finite() || n.abs() >= 4_503_599_627_370_496.0 {
+        return n;
+    }
+    let truncated = n as i64 as f64;
+    if (n - truncated).abs() >= 0.5 {
+        truncated + n.signum()
+    } else {
+        truncated
+    }
+}
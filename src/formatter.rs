@@ -1,59 +1,653 @@
 //! Formatter for Power Query M language
 
+use crate::alloc_prelude::*;
+use crate::analysis::StepAnnotation;
 use crate::ast::*;
-use crate::config::Config;
+use crate::config::{directive, CallWrapStyle, Config, EachStyle, ListFillStyle, SectionAccessStyle, StyleEdition};
+use crate::float;
+use crate::lexer::{is_regular_identifier, Lexer};
+use crate::parser::ParseError;
+use crate::postprocess::PostProcessor;
+use crate::token::{Span, TokenKind};
+#[cfg(feature = "transforms")]
+use crate::transform::rewrite_functions;
+use core::cell::Cell;
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use unicode_normalization::UnicodeNormalization;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::fmt;
+
+mod comments;
+mod layout;
+#[cfg(test)]
+#[macro_use]
+mod harness;
+
+use comments::CommentPrinter;
+pub use layout::LayoutDecision;
+
+/// Error returned when code cannot be formatted
+#[derive(Debug, Clone)]
+pub enum FormatError {
+    /// The input has syntax errors
+    Parse(Vec<ParseError>),
+    /// The cancellation token was set (see [`crate::format_with_cancellation`])
+    Cancelled,
+    /// Parsing or formatting took far more work than the size of the input
+    /// warrants, so it was abandoned
+    TooComplex,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Parse(errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                Ok(())
+            }
+            FormatError::Cancelled => write!(f, "Formatting was cancelled"),
+            FormatError::TooComplex => write!(f, "Input is too complex to format"),
+        }
+    }
+}
+
+impl core::error::Error for FormatError {}
+
+impl From<Vec<ParseError>> for FormatError {
+    fn from(errors: Vec<ParseError>) -> Self {
+        FormatError::Parse(errors)
+    }
+}
+
+/// A problem in formatted output that the layout rules could not avoid
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FormatWarning {
+    /// Line `line` (1-based) is `length` characters long, more than
+    /// `max_line_length`: it holds something that cannot be wrapped, such as
+    /// a long URL, string, or identifier
+    LineTooLong { line: usize, length: usize },
+    /// An identifier is `length` characters long as written, so its line is
+    /// longer than `max_line_length` whatever the layout; `span` is its first
+    /// occurrence in the source. Rename it (see
+    /// [`crate::refactor::suggest_step_names`]) to fix the line.
+    IdentifierTooLong { name: String, span: Span, length: usize },
+}
+
+impl fmt::Display for FormatWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatWarning::LineTooLong { line, length } => write!(
+                f,
+                "Line {}: warning [line-too-long]: {} characters, longer than max_line_length",
+                line, length
+            ),
+            FormatWarning::IdentifierTooLong { name, span, length } => write!(
+                f,
+                "Line {}: warning [identifier-too-long]: {} is {} characters, too long for max_line_length",
+                span.line,
+                display_identifier(name),
+                length
+            ),
+        }
+    }
+}
+
+/// Facts about formatted output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatStats {
+    pub lines: usize,
+    /// Length of the longest line, in characters
+    pub longest_line: usize,
+    pub warnings: Vec<FormatWarning>,
+    /// Sum of the line lengths, for the average
+    pub total_length: usize,
+    /// Constructs laid out over several lines, by the rule that broke them
+    /// (`construct/rule`, e.g. `record/line-length`). Filled in from
+    /// [`Formatter::breaks`]; empty when only the text was measured.
+    pub breaks: BTreeMap<String, usize>,
+}
+
+impl FormatStats {
+    /// Measure `formatted`, the output of formatting with `config`.
+    /// Lengths count characters, with a tab as one.
+    /// Scoped directives are not seen here; [`Formatter::stats`] applies
+    /// the limits they set.
+    pub fn of(formatted: &str, config: &Config) -> Self {
+        Self::measure(formatted, |_| config.max_line_length)
+    }
+    
+    /// Measure `formatted`, where line `n` (from 1) may be
+    /// `max_line_length(n)` characters long
+    fn measure(formatted: &str, max_line_length: impl Fn(usize) -> usize) -> Self {
+        let mut stats = Self::default();
+        for (i, line) in formatted.lines().enumerate() {
+            let length = line.chars().count();
+            stats.lines += 1;
+            stats.longest_line = stats.longest_line.max(length);
+            stats.total_length += length;
+            if length > max_line_length(i + 1) {
+                stats.warnings.push(FormatWarning::LineTooLong { line: i + 1, length });
+            }
+        }
+        stats
+    }
+    
+    /// Mean line length in characters (0 for empty output)
+    pub fn average_line_length(&self) -> f64 {
+        if self.lines == 0 {
+            0.0
+        } else {
+            self.total_length as f64 / self.lines as f64
+        }
+    }
+}
+
+/// Visits of each node (formatting it and measuring it for the layout
+/// decisions of its ancestors) allowed per node of a cancellable render
+const WORK_PER_NODE: usize = 1024;
+
+/// Visits always allowed, however small the document
+pub(crate) const MIN_WORK_BUDGET: usize = 1 << 20;
+
+/// Why a render or parse stopped early
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Interruption {
+    Cancelled,
+    OverBudget,
+}
+
+/// Stable 64-bit FNV-1a hash of formatted text.
+///
+/// Unlike `std`'s `DefaultHasher`, the result is the same across platforms
+/// and Rust versions, so it can be stored in caches.
+pub fn fingerprint_text(text: &str) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(text.as_bytes());
+    hasher.finish()
+}
+
+/// Streaming FNV-1a hasher
+#[derive(Debug, Clone, Copy)]
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    
+    fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+    
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+    
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Destination for formatted text: either a buffer or a hasher that
+/// consumes the text without storing it
+#[derive(Debug)]
+enum Output {
+    Buffer(String),
+    Hash { hasher: Fnv1a, last: Option<u8> },
+}
+
+impl Output {
+    fn push_str(&mut self, s: &str) {
+        match self {
+            Output::Buffer(buf) => buf.push_str(s),
+            Output::Hash { hasher, last } => {
+                if let Some(&b) = s.as_bytes().last() {
+                    hasher.write(s.as_bytes());
+                    *last = Some(b);
+                }
+            }
+        }
+    }
+    
+    fn push(&mut self, c: char) {
+        self.push_str(c.encode_utf8(&mut [0; 4]));
+    }
+    
+    fn ends_with(&self, c: char) -> bool {
+        match self {
+            Output::Buffer(buf) => buf.ends_with(c),
+            Output::Hash { last, .. } => c.is_ascii() && *last == Some(c as u8),
+        }
+    }
+    
+    fn clear(&mut self) {
+        match self {
+            Output::Buffer(buf) => buf.clear(),
+            Output::Hash { hasher, last } => {
+                *hasher = Fnv1a::new();
+                *last = None;
+            }
+        }
+    }
+}
 
 /// Formatter for Power Query M code
-pub struct Formatter {
+///
+/// A `Formatter` is `Send` but not `Sync`: it can be moved to another
+/// thread, but threads formatting at the same time each need their own (see
+/// [`format_many`](crate::format_many)). The free functions such as
+/// [`format`](crate::format) create one per call and can be called from any
+/// thread; [`Config`] is `Send` and `Sync`.
+pub struct Formatter<'a> {
     config: Config,
-    output: String,
+    output: Output,
     indent_level: usize,
     current_line_length: usize,
+    /// Characters on the current line, for visual alignment
+    current_column: usize,
+    /// Extra spaces after the indentation, inside hanging call arguments
+    hang: usize,
+    /// Number of enclosing function calls laid out over several lines
+    broken_calls: usize,
+    /// Keep everything on one line (calls nested past `max_call_nesting_inline`)
+    force_inline: bool,
+    /// A line comment ended the last line in the middle of an expression;
+    /// the rest of it starts at the indentation, without a separating space
+    continued: bool,
+    /// Constructs laid out over several lines, by `construct/rule`
+    breaks: BTreeMap<String, usize>,
+    /// Record layout decisions (see [`Formatter::set_explain`])
+    explain: bool,
+    /// Layout decisions of the last render, when recorded
+    decisions: Vec<LayoutDecision>,
+    /// Bytes of the output buffer whose line breaks are counted, and the
+    /// line after them, for placing layout decisions
+    line_scan: (usize, usize),
+    /// Output lines where scoped directives changed `max_line_length` in
+    /// the last render, with the limit from there on
+    line_limits: Vec<(usize, usize)>,
+    /// Write number literals from their values, inside date and time
+    /// constructors with `normalize_constructor_numbers`
+    plain_numbers: bool,
+    /// Identifiers too long for any line, since the last render
+    warnings: Vec<FormatWarning>,
+    /// Token that aborts formatting when set (see [`Formatter::set_cancellation`])
+    cancel: Option<&'a AtomicBool>,
+    /// Expressions visited, formatted or measured, since the last render
+    work: Cell<usize>,
+    /// Visits allowed before the render is abandoned as too complex
+    work_budget: usize,
+    /// Why the current render was abandoned, if it was
+    interrupted: Cell<Option<Interruption>>,
+    /// Original source text, needed to emit verbatim regions
+    source: Option<&'a str>,
+    comments: CommentPrinter,
+    /// Passes over the output of [`Formatter::format`], in order
+    post_processors: Vec<Box<dyn PostProcessor + Send + 'a>>,
 }
 
-impl Formatter {
+impl<'a> Formatter<'a> {
     /// Create a new formatter with the given configuration
     pub fn new(config: Config) -> Self {
         Self {
+            comments: CommentPrinter::new(config.comment_style),
             config,
-            output: String::new(),
+            output: Output::Buffer(String::new()),
             indent_level: 0,
             current_line_length: 0,
+            current_column: 0,
+            hang: 0,
+            broken_calls: 0,
+            force_inline: false,
+            continued: false,
+            breaks: BTreeMap::new(),
+            explain: false,
+            decisions: Vec::new(),
+            line_scan: (0, 1),
+            line_limits: Vec::new(),
+            plain_numbers: false,
+            warnings: Vec::new(),
+            cancel: None,
+            work: Cell::new(0),
+            work_budget: usize::MAX,
+            interrupted: Cell::new(None),
+            source: None,
+            post_processors: Vec::new(),
+        }
+    }
+    
+    /// Create a formatter that can copy regions of the original source
+    /// (such as `ExprKind::Verbatim`) into the output
+    pub fn with_source(config: Config, source: &'a str) -> Self {
+        Self {
+            source: Some(source),
+            ..Self::new(config)
+        }
+    }
+    
+    /// Replace the source text, so one formatter (and its output buffer)
+    /// can be reused across documents
+    pub fn set_source(&mut self, source: &'a str) {
+        self.source = Some(source);
+    }
+    
+    /// Check `token` while formatting: once it is set, [`Formatter::try_format`]
+    /// stops and returns [`FormatError::Cancelled`]. Formatting also stops,
+    /// with [`FormatError::TooComplex`], if it takes much more work than the
+    /// number of nodes in the document warrants.
+    pub fn set_cancellation(&mut self, token: &'a AtomicBool) {
+        self.cancel = Some(token);
+    }
+    
+    /// Format a document, unless cancelled or over budget (see
+    /// [`Formatter::set_cancellation`]). Without a cancellation token this
+    /// is the same as [`Formatter::format`].
+    pub fn try_format(&mut self, doc: &Document) -> Result<String, FormatError> {
+        if self.cancel.is_some() {
+            let mut nodes = 0usize;
+            doc.expression.walk(&mut |_| nodes += 1);
+            self.work_budget = nodes.saturating_mul(WORK_PER_NODE).saturating_add(MIN_WORK_BUDGET);
+        }
+        let formatted = self.format(doc);
+        match self.interrupted.get() {
+            None => Ok(formatted),
+            Some(Interruption::Cancelled) => Err(FormatError::Cancelled),
+            Some(Interruption::OverBudget) => Err(FormatError::TooComplex),
         }
     }
     
+    /// Run `processor` on the output of [`Formatter::format`], after the
+    /// processors added before it. [`Formatter::fingerprint`] hashes the
+    /// text before processing.
+    pub fn add_post_processor(&mut self, processor: impl PostProcessor + Send + 'a) {
+        self.post_processors.push(Box::new(processor));
+    }
+    
     /// Format a document
     pub fn format(&mut self, doc: &Document) -> String {
+        self.render(doc);
+        
+        let formatted = match &self.output {
+            Output::Buffer(buf) => buf.clone(),
+            Output::Hash { .. } => unreachable!("format always runs with a buffer"),
+        };
+        self.post_processors
+            .iter()
+            .fold(formatted, |text, processor| processor.process(text))
+    }
+    
+    /// Why constructs in the last formatted document were laid out over
+    /// several lines: counts keyed by `construct/rule`, such as
+    /// `record/line-length` or `call/nested` (see [`FormatStats::breaks`])
+    pub fn breaks(&self) -> &BTreeMap<String, usize> {
+        &self.breaks
+    }
+    
+    /// Record how each construct is laid out and why, from the next
+    /// [`format`](Self::format) on (see [`Formatter::decisions`])
+    pub fn set_explain(&mut self, explain: bool) {
+        self.explain = explain;
+    }
+    
+    /// Layout decisions of the last [`format`](Self::format), in the order
+    /// they were taken, when [`set_explain`](Self::set_explain) is on (or
+    /// with the `trace` feature, which also logs each one)
+    pub fn decisions(&self) -> &[LayoutDecision] {
+        &self.decisions
+    }
+    
+    /// Problems in the output of the last [`format`](Self::format) that
+    /// depend on the source, such as [`FormatWarning::IdentifierTooLong`].
+    /// [`FormatStats::of`] reports the ones visible in the text.
+    pub fn warnings(&self) -> &[FormatWarning] {
+        &self.warnings
+    }
+    
+    /// `max_line_length` on line `line` (from 1) of the output of the last
+    /// [`format`](Self::format), as changed by scoped directives
+    pub fn max_line_length_at(&self, line: usize) -> usize {
+        match self.line_limits.partition_point(|&(start, _)| start <= line) {
+            0 => self.config.max_line_length,
+            i => self.line_limits[i - 1].1,
+        }
+    }
+    
+    /// Measure `formatted`, the output of the last [`format`](Self::format),
+    /// against the line limit of each line (see
+    /// [`max_line_length_at`](Self::max_line_length_at)), with the
+    /// [`breaks`](Self::breaks) and [`warnings`](Self::warnings) of the render
+    pub fn stats(&self, formatted: &str) -> FormatStats {
+        let mut stats = FormatStats::measure(formatted, |line| self.max_line_length_at(line));
+        stats.breaks = self.breaks.clone();
+        stats.warnings.extend_from_slice(&self.warnings);
+        stats
+    }
+    
+    /// Write the formatted document to the current output
+    fn render(&mut self, doc: &Document) {
+        self.reset();
+        self.format_document_expr(&doc.expression);
+        self.finish_document(&doc.trailing_trivia);
+    }
+    
+    /// Clear the output and the state of the last render
+    fn reset(&mut self) {
         self.output.clear();
         self.indent_level = 0;
         self.current_line_length = 0;
-        
-        self.format_expr(&doc.expression);
+        self.current_column = 0;
+        self.hang = 0;
+        self.broken_calls = 0;
+        self.force_inline = false;
+        self.continued = false;
+        self.breaks.clear();
+        self.decisions.clear();
+        self.line_scan = (0, 1);
+        self.line_limits.clear();
+        self.warnings.clear();
+        self.work.set(0);
+        self.interrupted.set(None);
+    }
+    
+    /// Whether `prefer_each` rewrites functions, which needs the
+    /// `transforms` feature
+    fn rewrites_functions(&self) -> bool {
+        cfg!(feature = "transforms") && self.config.prefer_each != EachStyle::Preserve
+    }
+    
+    /// Format an expression written as a whole (a document, or a part of
+    /// one from [`crate::stream`]), applying `prefer_each`
+    fn format_document_expr(&mut self, expr: &Expr) {
+        if !self.rewrites_functions() {
+            self.format_expr(expr);
+        } else {
+            let mut expression = expr.clone();
+            rewrite_functions(&mut expression, self.config.prefer_each);
+            self.format_expr(&expression);
+        }
+    }
+    
+    /// Write the comments after the document and the final line break
+    fn finish_document(&mut self, trailing_trivia: &[Trivia]) {
+        for t in trailing_trivia {
+            if !self.output.ends_with('\n') {
+                self.newline();
+            }
+            if let Some(comment) = self.comments.comment(t, true) {
+                self.write(&comment);
+            }
+        }
         
         // Ensure file ends with newline
         if !self.output.ends_with('\n') {
             self.output.push('\n');
         }
-        
-        self.output.clone()
+    }
+    
+    /// The text written since the last reset
+    fn take_output(&mut self) -> String {
+        match &mut self.output {
+            Output::Buffer(buf) => core::mem::take(buf),
+            Output::Hash { .. } => unreachable!("parts are always formatted into a buffer"),
+        }
+    }
+    
+    /// The comments before a document that is one `let` laid out over
+    /// several lines, and the `let` (see [`crate::stream`]). Scoped
+    /// directives among the comments apply to the parts formatted after it.
+    pub(crate) fn format_let_start(&mut self, leading_trivia: &[Trivia]) -> String {
+        self.reset();
+        self.format_comments_before(leading_trivia);
+        // The scope is the whole `let`, so it is not left
+        let _ = self.enter_scope(leading_trivia);
+        self.write("let");
+        self.newline();
+        self.take_output()
+    }
+    
+    /// One binding of a `let` laid out over several lines, with its comma
+    /// unless it is the `last`
+    pub(crate) fn format_let_binding(&mut self, binding: &Binding, last: bool) -> String {
+        self.reset();
+        self.indent_level = 1;
+        if !self.rewrites_functions() {
+            self.format_binding(binding, last);
+        } else {
+            let mut binding = binding.clone();
+            rewrite_functions(&mut binding.value, self.config.prefer_each);
+            self.format_binding(&binding, last);
+        }
+        self.take_output()
+    }
+    
+    /// The `in` of a `let` laid out over several lines and its body, the
+    /// expression of `body`, followed by the comments after the document
+    pub(crate) fn format_let_end(&mut self, body: &Document) -> String {
+        self.reset();
+        self.write("in");
+        self.newline();
+        self.indent_level = 1;
+        self.write_indent();
+        // Comments after the body follow the whole `let`, outside its indentation
+        let expression = Expr {
+            trailing_trivia: Vec::new(),
+            ..body.expression.clone()
+        };
+        self.format_document_expr(&expression);
+        self.indent_level = 0;
+        let trailing = &body.expression.trailing_trivia;
+        let ends_line = trailing.iter().any(|t| matches!(t, Trivia::LineComment(_)));
+        self.format_trailing_comments(trailing, ends_line);
+        self.finish_document(&body.trailing_trivia);
+        self.take_output()
+    }
+    
+    /// Compute `fingerprint_text(&self.format(doc))` without building the
+    /// formatted string
+    pub fn fingerprint(&mut self, doc: &Document) -> u64 {
+        let buffer = core::mem::replace(
+            &mut self.output,
+            Output::Hash { hasher: Fnv1a::new(), last: None },
+        );
+        self.render(doc);
+        let hash = match &self.output {
+            Output::Hash { hasher, .. } => hasher.finish(),
+            Output::Buffer(_) => unreachable!("fingerprint always runs with a hasher"),
+        };
+        self.output = buffer;
+        hash
     }
     
     /// Format an expression
     fn format_expr(&mut self, expr: &Expr) {
-        // Format leading trivia (comments)
-        self.format_trivia(&expr.leading_trivia);
+        if self.is_interrupted() {
+            return;
+        }
+        self.format_comments_before(&expr.leading_trivia);
+        let outer = self.enter_scope(&expr.leading_trivia);
+        self.format_expr_kind(expr);
+        self.leave_scope(outer);
+        self.format_comments_after(&expr.trailing_trivia);
+    }
+    
+    /// Comments after an expression. A line comment ends its line; what
+    /// follows continues on the next one at the current indentation.
+    fn format_comments_after(&mut self, trivia: &[Trivia]) {
+        let ends_line = trivia.iter().any(|t| matches!(t, Trivia::LineComment(_)));
+        self.format_trailing_comments(trivia, ends_line);
+        if ends_line {
+            self.newline();
+            self.write_indent();
+            self.continued = true;
+        }
+    }
+    
+    /// Comments before an expression. A line comment ends its line; the
+    /// expression continues on the next one at the current indentation.
+    fn format_comments_before(&mut self, trivia: &[Trivia]) {
+        for t in trivia {
+            let ends_line = matches!(t, Trivia::LineComment(_));
+            if let Some(comment) = self.comments.comment(t, ends_line) {
+                self.write(&comment);
+                if ends_line {
+                    self.newline();
+                    self.write_indent();
+                } else {
+                    self.write(" ");
+                }
+            }
+        }
+    }
+    
+    /// Format an expression without its leading and trailing comments
+    fn format_expr_kind(&mut self, expr: &Expr) {
+        if self.original_text(expr).is_some() {
+            self.format_verbatim(expr.span);
+            return;
+        }
+        
+        if let Some((section, member)) = self.restyled_section_access(expr) {
+            self.format_section_access_as(&section, member);
+            return;
+        }
         
         match &expr.kind {
             ExprKind::Null => self.write("null"),
             ExprKind::Logical(b) => self.write(if *b { "true" } else { "false" }),
-            ExprKind::Number(n) => self.format_number(*n),
-            ExprKind::Text(s) => self.format_text(s),
-            ExprKind::Identifier(name) => self.write(name),
+            ExprKind::Number(n) => {
+                let text = self.number_text(n);
+                self.write(&text);
+            }
+            ExprKind::Text(s) => {
+                let text = self.text_literal(expr, s);
+                self.write(&text);
+            }
+            ExprKind::Identifier(name) => {
+                let name = self.identifier_text(name);
+                self.check_identifier_width(&name, name.chars().count(), expr.span);
+                self.write(&name);
+            }
             ExprKind::QuotedIdentifier(name) => {
+                let name = self.identifier_text(name);
+                let escaped = escape_identifier(&name);
+                self.check_identifier_width(&name, escaped.chars().count() + 3, expr.span);
                 self.write("#\"");
-                self.write(&escape_identifier(name));
+                self.write(&escaped);
                 self.write("\"");
             }
+            ExprKind::InclusiveIdentifier(ident) => {
+                self.write("@");
+                self.format_identifier(ident);
+            }
             ExprKind::Let(let_expr) => self.format_let(let_expr),
             ExprKind::If(if_expr) => self.format_if(if_expr),
             ExprKind::Try(try_expr) => self.format_try(try_expr),
@@ -63,13 +657,22 @@ impl Formatter {
             }
             ExprKind::Each(inner) => {
                 self.write("each ");
-                self.format_expr(inner);
+                if self.config.style_edition >= StyleEdition::Edition2025 {
+                    self.format_predicate(inner);
+                } else {
+                    self.format_expr(inner);
+                }
             }
             ExprKind::Function(func) => self.format_function(func),
             ExprKind::FunctionCall(call) => self.format_function_call(call),
             ExprKind::Record(record) => self.format_record(record),
             ExprKind::List(list) => self.format_list(list),
             ExprKind::FieldAccess(access) => self.format_field_access(access),
+            ExprKind::SectionAccess(access) => {
+                self.format_expr(&access.section);
+                self.write("!");
+                self.format_identifier(&access.member);
+            }
             ExprKind::FieldProjection(proj) => self.format_field_projection(proj),
             ExprKind::ItemAccess(access) => self.format_item_access(access),
             ExprKind::Binary(binary) => self.format_binary(binary),
@@ -83,85 +686,182 @@ impl Formatter {
             ExprKind::Metadata(meta) => self.format_metadata(meta),
             ExprKind::Underscore => self.write("_"),
             ExprKind::HashTable(table) => self.format_hash_table(table),
+            ExprKind::HashBinary(binary) => self.format_hash_binary(binary),
             ExprKind::HashDate(date) => self.format_hash_date(date),
             ExprKind::HashTime(time) => self.format_hash_time(time),
             ExprKind::HashDatetime(dt) => self.format_hash_datetime(dt),
             ExprKind::HashDatetimezone(dtz) => self.format_hash_datetimezone(dtz),
             ExprKind::HashDuration(dur) => self.format_hash_duration(dur),
+            ExprKind::HashSections => self.write("#sections"),
+            ExprKind::HashShared => self.write("#shared"),
+            ExprKind::HashUnknown(name) => {
+                self.write("#");
+                self.write(name);
+            }
+            ExprKind::Verbatim(span) => self.format_verbatim(*span),
+            ExprKind::Section(section) => self.format_section(section),
         }
-        
-        // Format trailing trivia (comments)
-        self.format_trivia(&expr.trailing_trivia);
     }
     
-    /// Format trivia (comments)
-    fn format_trivia(&mut self, trivia: &[Trivia]) {
+    /// Format comments that precede a binding, field, or item, each on its
+    /// own line
+    fn format_leading_comments(&mut self, trivia: &[Trivia]) {
+        for comment in self.comments.leading(trivia) {
+            self.write_indent();
+            self.write(&comment);
+            self.newline();
+        }
+    }
+    
+    /// Format comments that follow a value on the same line; `ends_line`
+    /// if nothing but a line break follows them
+    fn format_trailing_comments(&mut self, trivia: &[Trivia], ends_line: bool) {
+        let text = self.comments.trailing(trivia, self.current_column, ends_line);
+        self.write(&text);
+    }
+    
+    /// Comments after a binding. A lone annotation comment (`// ~2.5s`) is
+    /// normalized and right-aligned at `annotation_column`.
+    fn format_step_comments(&mut self, trivia: &[Trivia]) {
+        let mut comments = trivia.iter().filter(|t| t.is_comment());
+        let annotation = match (comments.next(), comments.next()) {
+            (Some(Trivia::LineComment(text)), None) => StepAnnotation::parse(text),
+            _ => None,
+        };
+        let Some(annotation) = annotation else {
+            self.format_trailing_comments(trivia, true);
+            return;
+        };
+        let text = format!("// {}", annotation);
+        let start = self
+            .config
+            .annotation_column
+            .filter(|_| cfg!(feature = "alignment"))
+            .map_or(0, |column| column.saturating_sub(text.chars().count()));
+        let gap = start.saturating_sub(self.current_column).max(1);
+        self.write(&" ".repeat(gap));
+        self.write(&text);
+    }
+    
+    /// Apply the scoped `// pqmfmt:` directives among `trivia` (see
+    /// [`directive`]) until [`Formatter::leave_scope`], returning the
+    /// configuration to restore then. Settings that cannot be applied were
+    /// reported by the parser and are skipped.
+    fn enter_scope(&mut self, trivia: &[Trivia]) -> Option<Config> {
+        let mut scoped: Option<Config> = None;
         for t in trivia {
-            match t {
-                Trivia::LineComment(content) => {
-                    self.write("//");
-                    if !content.starts_with(' ') && !content.is_empty() {
-                        self.write(" ");
-                    }
-                    self.write(content);
-                    self.newline();
-                }
-                Trivia::BlockComment(content) => {
-                    self.write("/*");
-                    self.write(content);
-                    self.write("*/");
-                }
-                Trivia::Newline => {
-                    // Handled separately
-                }
-                Trivia::Whitespace(_) => {
-                    // Ignored - we control whitespace
+            if let Trivia::LineComment(text) = t {
+                if let Some(settings) = directive::scoped(text) {
+                    directive::apply_settings(settings, scoped.get_or_insert_with(|| self.config.clone()));
                 }
             }
         }
+        let outer = scoped.map(|config| core::mem::replace(&mut self.config, config))?;
+        if outer.max_line_length != self.config.max_line_length {
+            if let Some(line) = self.output_line() {
+                self.line_limits.push((line, self.config.max_line_length));
+            }
+        }
+        Some(outer)
     }
     
-    /// Format a number
-    fn format_number(&mut self, n: f64) {
-        if n.is_infinite() {
-            if n.is_sign_positive() {
-                self.write("#infinity");
-            } else {
-                self.write("-#infinity");
+    fn leave_scope(&mut self, outer: Option<Config>) {
+        let Some(config) = outer else {
+            return;
+        };
+        // The scope ends with its line
+        if config.max_line_length != self.config.max_line_length {
+            if let Some(line) = self.output_line() {
+                self.line_limits.push((line + 1, config.max_line_length));
             }
-        } else if n.is_nan() {
-            self.write("#nan");
-        } else if n.fract() == 0.0 && n.abs() < 1e15 {
-            // Integer-like number
-            self.write(&format!("{}", n as i64));
-        } else {
-            // Format with appropriate precision
-            let s = format!("{}", n);
-            self.write(&s);
         }
+        self.config = config;
     }
     
-    /// Format a text literal
-    fn format_text(&mut self, s: &str) {
-        self.write("\"");
-        self.write(&escape_text(s));
-        self.write("\"");
+    /// Source text of a node marked `verbatim`, if the source is available
+    fn original_text(&self, expr: &Expr) -> Option<&'a str> {
+        if !expr.verbatim {
+            return None;
+        }
+        self.source
+            .and_then(|s| s.get(expr.span.start..expr.span.end))
+            .filter(|text| !text.is_empty())
+    }
+    
+    /// Copy a source region into the output unchanged
+    fn format_verbatim(&mut self, span: Span) {
+        let text = self.source.and_then(|s| s.get(span.start..span.end));
+        match text {
+            Some(text) => match text.rfind('\n') {
+                Some(last_newline) => {
+                    self.output.push_str(text);
+                    self.current_line_length = text.len() - last_newline - 1;
+                    self.current_column = text[last_newline + 1..].chars().count();
+                }
+                None => self.write(text),
+            },
+            // Without the source there is nothing faithful to print
+            None => self.write("/* unparsed */"),
+        }
+    }
+    
+    /// Text of a number literal: as written (normalized if
+    /// `normalize_numbers`), or from its value for numbers without text
+    /// and in date and time constructors with `normalize_constructor_numbers`
+    fn number_text(&self, n: &NumberLiteral) -> String {
+        match n.text.as_str() {
+            _ if self.plain_numbers => number_from_value(n.value),
+            "" => number_from_value(n.value),
+            text if self.config.normalize_numbers => normalize_number(text),
+            text => text.to_string(),
+        }
+    }
+    
+    /// A text literal: as written in the source unless
+    /// `normalize_text_escapes`, or with only the characters that must be
+    /// escaped escaped
+    fn text_literal(&self, expr: &Expr, s: &str) -> String {
+        match self.source_text(expr, s) {
+            Some(text) if !self.config.normalize_text_escapes => text.to_string(),
+            _ => format!("\"{}\"", escape_text(s)),
+        }
+    }
+    
+    /// Source text of a text literal, if the span covers exactly a literal
+    /// with this value (it may have been rewritten since it was parsed)
+    fn source_text(&self, expr: &Expr, s: &str) -> Option<&'a str> {
+        let text = self.source?.get(expr.span.start..expr.span.end)?;
+        let mut lexer = Lexer::new(text);
+        match (lexer.next_token().kind, lexer.next_token().kind) {
+            (TokenKind::Text(value), TokenKind::Eof) if value == s => Some(text),
+            _ => None,
+        }
     }
     
     /// Format let expression
     fn format_let(&mut self, let_expr: &LetExpr) {
         // In compact mode with always_expand_let=false, try to fit on single line
         let estimated_len = self.estimate_let_length(let_expr);
-        let single_line = !self.config.always_expand_let 
-            && estimated_len <= self.config.max_line_length
-            && !let_expr.bindings.iter().any(|b| self.is_complex_expr(&b.value))
-            && let_expr.bindings.iter().all(|b| b.leading_trivia.is_empty() && b.trailing_trivia.is_empty());
+        let reason = break_reason(&[
+            ("always-expand", self.config.always_expand_let),
+            ("line-length", estimated_len > self.config.max_line_length),
+            ("nested", let_expr.bindings.iter().any(|b| self.is_complex_expr(&b.value))),
+            (
+                "comments",
+                let_expr.bindings.iter().any(|b| !b.leading_trivia.is_empty() || !b.trailing_trivia.is_empty())
+                    || !let_expr.body.leading_trivia.is_empty(),
+            ),
+        ]);
         
-        if single_line {
-            self.format_let_single_line(let_expr);
-        } else {
-            self.format_let_multi_line(let_expr);
+        let layout = self.begin_layout("let", reason, reason.is_some(), estimated_len);
+        match reason {
+            None => self.format_let_single_line(let_expr),
+            Some(reason) => {
+                self.count_break("let", reason);
+                self.format_let_multi_line(let_expr);
+            }
         }
+        self.end_layout(layout);
     }
     
     fn format_let_single_line(&mut self, let_expr: &LetExpr) {
@@ -171,7 +871,7 @@ impl Formatter {
             if i > 0 {
                 self.write(", ");
             }
-            self.format_identifier(&binding.name);
+            self.format_binding_name(binding);
             self.write(" = ");
             self.format_expr(&binding.value);
         }
@@ -186,81 +886,7 @@ impl Formatter {
         self.indent_level += 1;
         
         for (i, binding) in let_expr.bindings.iter().enumerate() {
-            // Format leading trivia (comments) for binding
-            if !binding.leading_trivia.is_empty() {
-                for t in &binding.leading_trivia {
-                    self.write_indent();
-                    match t {
-                        Trivia::LineComment(content) => {
-                            self.write("//");
-                            if !content.starts_with(' ') && !content.is_empty() {
-                                self.write(" ");
-                            }
-                            self.write(content);
-                            self.newline();
-                        }
-                        Trivia::BlockComment(content) => {
-                            self.write("/*");
-                            self.write(content);
-                            self.write("*/");
-                            self.newline();
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            
-            self.write_indent();
-            self.format_identifier(&binding.name);
-            self.write(" = ");
-            
-            // Special handling for function expressions
-            // Put function on same line as `=`, let the function handle its own formatting
-            if matches!(&binding.value.kind, ExprKind::Function(_)) {
-                self.format_expr(&binding.value);
-            } else {
-                // Check if value needs to be on new line
-                let value_complex = self.is_complex_expr(&binding.value);
-                let value_length = self.estimate_expr_length(&binding.value);
-                let current_pos = self.current_line_length;
-                let would_exceed = current_pos + value_length > self.config.max_line_length;
-                
-                if value_complex || would_exceed {
-                    self.newline();
-                    self.indent_level += 1;
-                    self.write_indent();
-                    self.format_expr(&binding.value);
-                    self.indent_level -= 1;
-                } else {
-                    self.format_expr(&binding.value);
-                }
-            }
-            
-            // Format trailing trivia (comments after value, on same line)
-            for t in &binding.trailing_trivia {
-                match t {
-                    Trivia::LineComment(content) => {
-                        self.write(" //");
-                        if !content.starts_with(' ') && !content.is_empty() {
-                            self.write(" ");
-                        }
-                        self.write(content);
-                    }
-                    Trivia::BlockComment(content) => {
-                        self.write(" /*");
-                        self.write(content);
-                        self.write("*/");
-                    }
-                    _ => {}
-                }
-            }
-            
-            // Add comma if not last binding
-            if i < let_expr.bindings.len() - 1 {
-                self.write(",");
-            }
-            
-            self.newline();
+            self.format_binding(binding, i == let_expr.bindings.len() - 1);
         }
         
         self.indent_level -= 1;
@@ -273,17 +899,187 @@ impl Formatter {
         self.indent_level -= 1;
     }
     
+    /// Write a binding of a `let` laid out over several lines, on lines of
+    /// its own
+    fn format_binding(&mut self, binding: &Binding, last: bool) {
+        self.format_leading_comments(&binding.leading_trivia);
+        let outer = self.enter_scope(&binding.leading_trivia);
+        
+        self.write_indent();
+        self.format_binding_name(binding);
+        
+        // Special handling for function expressions
+        // Put function on same line as `=`, let the function handle its own formatting
+        if matches!(&binding.value.kind, ExprKind::Function(_)) {
+            self.write(" = ");
+            self.format_expr(&binding.value);
+        } else {
+            // Check if value needs to be on new line
+            let value_complex = self.is_complex_expr(&binding.value);
+            let value_length = self.estimate_expr_length(&binding.value);
+            let current_pos = self.current_line_length + 3; // " = "
+            let would_exceed = current_pos + value_length > self.config.max_line_length;
+            
+            let reason = break_reason(&[("nested", value_complex), ("line-length", would_exceed)]);
+            let layout = self.begin_layout("binding", reason, reason.is_some(), value_length + 3);
+            self.format_assigned_value(&binding.value, reason.is_some());
+            self.end_layout(layout);
+        }
+        self.leave_scope(outer);
+        
+        // Add comma if not last binding
+        if !last {
+            self.write(",");
+        }
+        
+        // Comments after the value, on the same line
+        self.format_step_comments(&binding.trailing_trivia);
+        
+        self.newline();
+    }
+    
+    /// Format a section document, with a blank line before each member
+    fn format_section(&mut self, section: &Section) {
+        if let Some(attributes) = &section.attributes {
+            self.format_expr(attributes);
+            self.newline();
+        }
+        self.write("section ");
+        self.format_identifier(&section.name);
+        self.write(";");
+        self.format_trailing_comments(&section.trailing_trivia, true);
+        for member in self.member_order(section) {
+            self.newline();
+            self.newline();
+            self.format_section_member(member);
+        }
+    }
+    
+    /// Members of `section` in the order to write them: as written, or with
+    /// the shared ones sorted by name if `sort_section_members` is set.
+    /// Sorted members take the places of shared members; the others, and
+    /// members after a `keep-order` directive, stay where they are.
+    fn member_order<'s>(&self, section: &'s Section) -> Vec<&'s SectionMember> {
+        let mut members: Vec<&SectionMember> = section.members.iter().collect();
+        if !self.config.sort_section_members {
+            return members;
+        }
+        let movable = |member: &SectionMember| {
+            member.shared
+                && !member
+                    .leading_trivia
+                    .iter()
+                    .any(|t| matches!(t, Trivia::LineComment(text) if directive::keeps_order(text)))
+        };
+        let places: Vec<usize> = (0..members.len()).filter(|&i| movable(members[i])).collect();
+        let mut sorted: Vec<&SectionMember> = places.iter().map(|&i| members[i]).collect();
+        sorted.sort_by(|a, b| {
+            let (a, b) = (&a.name.name, &b.name.name);
+            a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b))
+        });
+        for (place, member) in places.into_iter().zip(sorted) {
+            members[place] = member;
+        }
+        members
+    }
+    
+    /// Write a section member, from its comments to its `;`. The value
+    /// starts after `=` and is laid out as a query of its own.
+    fn format_section_member(&mut self, member: &SectionMember) {
+        self.format_leading_comments(&member.leading_trivia);
+        let outer = self.enter_scope(&member.leading_trivia);
+        if let Some(attributes) = &member.attributes {
+            self.format_expr(attributes);
+            self.newline();
+        }
+        if member.shared {
+            self.write("shared ");
+        }
+        self.format_identifier(&member.name);
+        self.write(" = ");
+        self.format_expr(&member.value);
+        self.write(";");
+        self.leave_scope(outer);
+        self.format_trailing_comments(&member.trailing_trivia, true);
+    }
+    
+    /// Write `=` and the value of a binding or field, on the next line,
+    /// indented, if `break_line`
+    fn format_assigned_value(&mut self, value: &Expr, break_line: bool) {
+        if !break_line {
+            self.write(" = ");
+            self.format_expr(value);
+            return;
+        }
+        
+        if self.config.style_edition >= StyleEdition::Edition2025 {
+            self.write(" =");
+        } else {
+            self.write(" = ");
+        }
+        self.newline();
+        self.indent_level += 1;
+        self.write_indent();
+        self.format_expr(value);
+        self.indent_level -= 1;
+    }
+    
     /// Format if expression
+    /// Format an `if` and the `else if` links after it. The links are
+    /// written in a loop, so a chain of any length needs no more stack
+    /// than one `if`.
     fn format_if(&mut self, if_expr: &IfExpr) {
-        let single_line = self.estimate_if_length(if_expr) <= self.config.max_line_length
-            && !self.is_complex_expr(&if_expr.condition)
-            && !self.is_complex_expr(&if_expr.then_branch)
-            && !self.is_complex_expr(&if_expr.else_branch);
+        // Each link still open: its layout decision, and the `else`
+        // branch holding the next link, with the scope it entered
+        let mut open = Vec::new();
+        let mut link = if_expr;
+        loop {
+            let estimated_len = self.estimate_if_length(link);
+            let reason = break_reason(&[
+                ("line-length", estimated_len > self.config.max_line_length),
+                (
+                    "nested",
+                    self.is_complex_expr(&link.condition)
+                        || self.is_complex_expr(&link.then_branch)
+                        || self.is_complex_expr(&link.else_branch),
+                ),
+            ]);
+            
+            let layout = self.begin_layout("if", reason, reason.is_some(), estimated_len);
+            let Some(reason) = reason else {
+                self.format_if_single_line(link);
+                self.end_layout(layout);
+                break;
+            };
+            self.count_break("if", reason);
+            self.format_if_head(link);
+            
+            let else_branch = &link.else_branch;
+            match &else_branch.kind {
+                ExprKind::If(next) => {
+                    self.write("else ");
+                    if self.is_interrupted() || self.original_text(else_branch).is_some() {
+                        self.format_expr(else_branch);
+                        self.end_layout(layout);
+                        break;
+                    }
+                    self.format_comments_before(&else_branch.leading_trivia);
+                    let outer = self.enter_scope(&else_branch.leading_trivia);
+                    open.push((layout, else_branch, outer));
+                    link = next;
+                }
+                _ => {
+                    self.format_else_block(else_branch);
+                    self.end_layout(layout);
+                    break;
+                }
+            }
+        }
         
-        if single_line {
-            self.format_if_single_line(if_expr);
-        } else {
-            self.format_if_multi_line(if_expr);
+        while let Some((layout, else_branch, outer)) = open.pop() {
+            self.leave_scope(outer);
+            self.format_comments_after(&else_branch.trailing_trivia);
+            self.end_layout(layout);
         }
     }
     
@@ -296,7 +1092,9 @@ impl Formatter {
         self.format_expr(&if_expr.else_branch);
     }
     
-    fn format_if_multi_line(&mut self, if_expr: &IfExpr) {
+    /// The condition and `then` branch of an `if` laid out over several
+    /// lines, up to the `else`
+    fn format_if_head(&mut self, if_expr: &IfExpr) {
         self.write("if ");
         self.format_expr(&if_expr.condition);
         self.write(" then");
@@ -307,19 +1105,16 @@ impl Formatter {
         self.indent_level -= 1;
         self.newline();
         self.write_indent();
-        
-        // Check for else-if chain
-        if let ExprKind::If(_) = &if_expr.else_branch.kind {
-            self.write("else ");
-            self.format_expr(&if_expr.else_branch);
-        } else {
-            self.write("else");
-            self.newline();
-            self.indent_level += 1;
-            self.write_indent();
-            self.format_expr(&if_expr.else_branch);
-            self.indent_level -= 1;
-        }
+    }
+    
+    /// An `else` branch that does not continue the chain, on lines of its own
+    fn format_else_block(&mut self, else_branch: &Expr) {
+        self.write("else");
+        self.newline();
+        self.indent_level += 1;
+        self.write_indent();
+        self.format_expr(else_branch);
+        self.indent_level -= 1;
     }
     
     /// Format try expression
@@ -331,26 +1126,47 @@ impl Formatter {
             self.write(" otherwise ");
             self.format_expr(otherwise);
         }
+        
+        if let Some(ref catch) = try_expr.catch {
+            self.write(" catch ");
+            self.format_expr(catch);
+        }
     }
     
     /// Format function expression
     fn format_function(&mut self, func: &FunctionExpr) {
         self.write("(");
         
-        for (i, param) in func.parameters.iter().enumerate() {
-            if i > 0 {
-                self.write(", ");
-            }
+        // Line comments need lines of their own
+        let line_comments = func.parameters.iter().any(|p| {
+            p.leading_trivia.iter().chain(&p.trailing_trivia).any(|t| matches!(t, Trivia::LineComment(_)))
+        });
+        if line_comments {
+            self.newline();
+            self.indent_level += 1;
             
-            if param.optional {
-                self.write("optional ");
+            for (i, param) in func.parameters.iter().enumerate() {
+                self.format_leading_comments(&param.leading_trivia);
+                self.write_indent();
+                self.format_type_parameter(param);
+                
+                if i < func.parameters.len() - 1 {
+                    self.write(",");
+                }
+                self.format_trailing_comments(&param.trailing_trivia, true);
+                self.newline();
             }
             
-            self.format_identifier(&param.name);
-            
-            if let Some(ref type_ann) = param.type_annotation {
-                self.write(" as ");
-                self.format_type_annotation(type_ann);
+            self.indent_level -= 1;
+            self.write_indent();
+        } else {
+            for (i, param) in func.parameters.iter().enumerate() {
+                if i > 0 {
+                    self.write(", ");
+                }
+                self.format_comments_before(&param.leading_trivia);
+                self.format_type_parameter(param);
+                self.format_trailing_comments(&param.trailing_trivia, false);
             }
         }
         
@@ -421,25 +1237,65 @@ impl Formatter {
         // Check if all arguments are simple
         let all_simple = call.arguments.iter().all(|arg| self.is_simple_expr(arg));
         
+        // Calls nested too deep inside broken calls stay on one line
+        let saved_force_inline = self.force_inline;
+        if self.config.max_call_nesting_inline.is_some_and(|n| self.broken_calls >= n)
+            && call.arguments.iter().all(|a| self.can_inline(a))
+        {
+            self.force_inline = true;
+        }
+        
         // Decide whether to expand
         // Don't expand if all arguments are simple and would fit on line
-        let multiline = call.arguments.iter().any(|a| self.is_complex_expr(a))
-            || (!all_simple && call.arguments.len() > self.config.multiline_threshold)
-            || self.would_exceed_line_length(args_length + 1); // +1 for ")"
+        let reason = break_reason(&[
+            ("nested", call.arguments.iter().any(|a| self.is_complex_expr(a))),
+            ("threshold", !all_simple && call.arguments.len() > self.config.multiline_threshold),
+            ("line-length", self.would_exceed_line_length(args_length + 1)), // +1 for ")"
+        ]);
+        let multiline = !self.force_inline && reason.is_some();
+        let layout = self.begin_layout("call", reason, multiline, args_length + 1);
+        
+        if let (true, Some(reason)) = (multiline, reason) {
+            self.count_break("call", reason);
+            self.broken_calls += 1;
+        }
         
-        if multiline && !call.arguments.is_empty() {
+        // Commented arguments need lines of their own
+        let has_comments = call.arguments.iter()
+            .any(|a| !a.leading_trivia.is_empty() || !a.trailing_trivia.is_empty());
+        if multiline && !call.arguments.is_empty() && !has_comments
+            && self.config.call_wrap_style == CallWrapStyle::Hanging
+            && cfg!(feature = "wrapping")
+        {
+            // Align continuation lines with the first argument
+            let saved_hang = self.hang;
+            self.hang = self
+                .current_column
+                .saturating_sub(self.config.indent_at(self.indent_level).chars().count());
+            
+            for (i, arg) in call.arguments.iter().enumerate() {
+                if i > 0 {
+                    self.write(",");
+                    self.newline();
+                    self.write_indent();
+                }
+                self.format_expr(arg);
+            }
+            
+            self.hang = saved_hang;
+        } else if multiline && !call.arguments.is_empty() {
             self.newline();
             self.indent_level += 1;
             
             for (i, arg) in call.arguments.iter().enumerate() {
+                self.format_leading_comments(&arg.leading_trivia);
                 self.write_indent();
-                self.format_expr(arg);
+                self.format_expr_kind(arg);
                 
                 if i < call.arguments.len() - 1 {
                     self.write(",");
-                } else if self.config.trailing_comma {
-                    self.write(",");
                 }
+                self.format_trailing_comments(&arg.trailing_trivia, true);
                 self.newline();
             }
             
@@ -454,7 +1310,13 @@ impl Formatter {
             }
         }
         
+        if multiline {
+            self.broken_calls -= 1;
+        }
+        self.force_inline = saved_force_inline;
+        
         self.write(")");
+        self.end_layout(layout);
     }
     
     /// Format record expression
@@ -477,11 +1339,18 @@ impl Formatter {
             .any(|f| !f.leading_trivia.is_empty() || !f.trailing_trivia.is_empty());
         
         // Decide whether to expand
-        let multiline = self.config.always_expand_records
-            || record.fields.len() > self.config.multiline_threshold
-            || record.fields.iter().any(|f| self.is_complex_expr(&f.value))
-            || has_comments
-            || self.would_exceed_line_length(fields_length + 2); // +2 for "[]"
+        let reason = break_reason(&[
+            ("always-expand", self.config.always_expand_records),
+            ("threshold", record.fields.len() > self.config.multiline_threshold),
+            ("nested", record.fields.iter().any(|f| self.is_complex_expr(&f.value))),
+            ("comments", has_comments),
+            ("line-length", self.would_exceed_line_length(fields_length + 2)), // +2 for "[]"
+        ]);
+        let multiline = !self.force_inline && reason.is_some();
+        if let (true, Some(reason)) = (multiline, reason) {
+            self.count_break("record", reason);
+        }
+        let layout = self.begin_layout("record", reason, multiline, fields_length + 2);
         
         self.write("[");
         
@@ -490,74 +1359,35 @@ impl Formatter {
             self.indent_level += 1;
             
             for (i, field) in record.fields.iter().enumerate() {
-                // Format leading trivia (comments before field)
-                if !field.leading_trivia.is_empty() {
-                    for t in &field.leading_trivia {
-                        self.write_indent();
-                        match t {
-                            Trivia::LineComment(content) => {
-                                self.write("//");
-                                if !content.starts_with(' ') && !content.is_empty() {
-                                    self.write(" ");
-                                }
-                                self.write(content);
-                                self.newline();
-                            }
-                            Trivia::BlockComment(content) => {
-                                self.write("/*");
-                                self.write(content);
-                                self.write("*/");
-                                self.newline();
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+                self.format_leading_comments(&field.leading_trivia);
+                let outer = self.enter_scope(&field.leading_trivia);
                 
                 self.write_indent();
-                self.format_identifier(&field.name);
-                self.write(" = ");
-                
-                // Check if field value needs to be on new line
-                let value_complex = self.is_complex_expr(&field.value);
-                let value_length = self.estimate_expr_length(&field.value);
-                let would_exceed = self.current_line_length + value_length > self.config.max_line_length;
-                
-                if value_complex || would_exceed {
-                    self.newline();
-                    self.indent_level += 1;
-                    self.write_indent();
-                    self.format_expr(&field.value);
-                    self.indent_level -= 1;
+                if field.verbatim {
+                    self.format_verbatim(field.span);
                 } else {
-                    self.format_expr(&field.value);
-                }
-                
-                // Format trailing trivia (comments after value, on same line)
-                for t in &field.trailing_trivia {
-                    match t {
-                        Trivia::LineComment(content) => {
-                            self.write("  //");
-                            if !content.starts_with(' ') && !content.is_empty() {
-                                self.write(" ");
-                            }
-                            self.write(content);
-                        }
-                        Trivia::BlockComment(content) => {
-                            self.write(" /*");
-                            self.write(content);
-                            self.write("*/");
-                        }
-                        _ => {}
-                    }
+                    self.format_identifier(&field.name);
+                    
+                    // Check if field value needs to be on new line
+                    let value_complex = self.is_complex_expr(&field.value);
+                    let value_length = self.estimate_expr_length(&field.value);
+                    let would_exceed =
+                        self.current_line_length + 3 + value_length > self.config.max_line_length;
+                    
+                    let reason = break_reason(&[("nested", value_complex), ("line-length", would_exceed)]);
+                    let layout = self.begin_layout("field", reason, reason.is_some(), value_length + 3);
+                    self.format_assigned_value(&field.value, reason.is_some());
+                    self.end_layout(layout);
                 }
+                self.leave_scope(outer);
                 
-                if i < record.fields.len() - 1 {
-                    self.write(",");
-                } else if self.config.trailing_comma {
+                if i < record.fields.len() - 1 || self.config.trailing_comma_records {
                     self.write(",");
                 }
                 
+                // Comments after the value, on the same line
+                self.format_trailing_comments(&field.trailing_trivia, true);
+                
                 self.newline();
             }
             
@@ -572,6 +1402,10 @@ impl Formatter {
                 if i > 0 {
                     self.write(", ");
                 }
+                if field.verbatim {
+                    self.format_verbatim(field.span);
+                    continue;
+                }
                 self.format_identifier(&field.name);
                 self.write(" = ");
                 self.format_expr(&field.value);
@@ -583,6 +1417,7 @@ impl Formatter {
         }
         
         self.write("]");
+        self.end_layout(layout);
     }
     
     /// Format list expression
@@ -605,26 +1440,72 @@ impl Formatter {
         
         // Decide whether to expand
         // Keep simple short lists on one line if they fit
-        let multiline = self.config.always_expand_lists
-            || list.items.iter().any(|i| self.is_complex_expr(i))
-            || (!all_simple && list.items.len() > self.config.multiline_threshold)
-            || self.would_exceed_line_length(items_length + 2); // +2 for "{}"
+        let reason = break_reason(&[
+            ("always-expand", self.config.always_expand_lists),
+            ("nested", list.items.iter().any(|i| self.is_complex_expr(i))),
+            ("threshold", !all_simple && list.items.len() > self.config.multiline_threshold),
+            ("line-length", self.would_exceed_line_length(items_length + 2)), // +2 for "{}"
+        ]);
+        let multiline = !self.force_inline && reason.is_some();
+        if let (true, Some(reason)) = (multiline, reason) {
+            self.count_break("list", reason);
+        }
+        let layout = self.begin_layout("list", reason, multiline, items_length + 2);
+        
+        if multiline && self.config.align_nested_lists && cfg!(feature = "alignment") {
+            if let Some(widths) = self.row_column_widths(list) {
+                self.format_aligned_rows(list, &widths);
+                self.end_layout(layout);
+                return;
+            }
+        }
         
         self.write("{");
         
-        if multiline {
+        if multiline && self.config.list_fill_style == ListFillStyle::Fill
+            && cfg!(feature = "wrapping")
+            && all_simple && !list.items.iter().any(has_comments)
+        {
             self.newline();
             self.indent_level += 1;
+            self.write_indent();
             
+            let last = list.items.len() - 1;
             for (i, item) in list.items.iter().enumerate() {
-                self.write_indent();
+                if i > 0 {
+                    self.write(",");
+                    // The item and the comma after it must fit
+                    let comma = usize::from(i < last || self.config.trailing_comma_lists);
+                    let width = self.estimate_expr_length(item) + comma;
+                    if self.current_line_length + 1 + width > self.config.max_line_length {
+                        self.newline();
+                        self.write_indent();
+                    } else {
+                        self.write(" ");
+                    }
+                }
                 self.format_expr(item);
+            }
+            if self.config.trailing_comma_lists {
+                self.write(",");
+            }
+            self.newline();
+            
+            self.indent_level -= 1;
+            self.write_indent();
+        } else if multiline {
+            self.newline();
+            self.indent_level += 1;
+            
+            for (i, item) in list.items.iter().enumerate() {
+                self.format_leading_comments(&item.leading_trivia);
+                self.write_indent();
+                self.format_expr_kind(item);
                 
-                if i < list.items.len() - 1 {
-                    self.write(",");
-                } else if self.config.trailing_comma {
+                if i < list.items.len() - 1 || self.config.trailing_comma_lists {
                     self.write(",");
                 }
+                self.format_trailing_comments(&item.trailing_trivia, true);
                 self.newline();
             }
             
@@ -648,6 +1529,68 @@ impl Formatter {
         }
         
         self.write("}");
+        self.end_layout(layout);
+    }
+    
+    /// Width of each column when `list` is a list of rows (lists of the same
+    /// length, at least two, of simple items without comments) that fit on
+    /// their lines once aligned
+    fn row_column_widths(&self, list: &ListExpr) -> Option<Vec<usize>> {
+        let mut widths: Vec<usize> = Vec::new();
+        for item in &list.items {
+            let ExprKind::List(row) = &item.kind else { return None };
+            if has_comments(item)
+                || row.items.len() < 2
+                || (!widths.is_empty() && row.items.len() != widths.len())
+                || row.items.iter().any(|cell| !self.is_simple_expr(cell) || has_comments(cell))
+            {
+                return None;
+            }
+            widths.resize(row.items.len(), 0);
+            for (width, cell) in widths.iter_mut().zip(&row.items) {
+                *width = (*width).max(self.estimate_expr_length(cell));
+            }
+        }
+        
+        // Indent, braces and their spaces, ", " separators, and the comma
+        let braces = if self.config.space_in_braces { 4 } else { 2 };
+        let row_length = self.config.indent_at(self.indent_level + 1).len()
+            + widths.iter().sum::<usize>()
+            + 2 * (widths.len() - 1)
+            + braces
+            + 1;
+        (row_length <= self.config.max_line_length).then_some(widths)
+    }
+    
+    /// Format a list of rows one per line, padding each cell to its
+    /// column's width
+    fn format_aligned_rows(&mut self, list: &ListExpr, widths: &[usize]) {
+        self.write("{");
+        self.newline();
+        self.indent_level += 1;
+        
+        for (i, item) in list.items.iter().enumerate() {
+            let ExprKind::List(row) = &item.kind else { unreachable!() };
+            self.write_indent();
+            self.write(if self.config.space_in_braces { "{ " } else { "{" });
+            for (j, cell) in row.items.iter().enumerate() {
+                self.format_expr(cell);
+                if j < row.items.len() - 1 {
+                    let padding = widths[j].saturating_sub(self.estimate_expr_length(cell));
+                    self.write(",");
+                    self.write(&" ".repeat(padding + 1));
+                }
+            }
+            self.write(if self.config.space_in_braces { " }" } else { "}" });
+            if i < list.items.len() - 1 || self.config.trailing_comma_lists {
+                self.write(",");
+            }
+            self.newline();
+        }
+        
+        self.indent_level -= 1;
+        self.write_indent();
+        self.write("}");
     }
     
     /// Format field access
@@ -661,6 +1604,54 @@ impl Formatter {
         }
     }
     
+    /// The section and member of a section access that `section_access_style`
+    /// writes in the other form: `Section1!Member`, `#sections[Section1]!Member`,
+    /// or `#sections[Section1][Member]`. Accesses with comments inside are
+    /// kept as written.
+    fn restyled_section_access<'e>(&self, expr: &'e Expr) -> Option<(Identifier, &'e Identifier)> {
+        let (section, member, bang) = match &expr.kind {
+            ExprKind::SectionAccess(access) => (&access.section, &access.member, true),
+            ExprKind::FieldAccess(access) if !access.optional => (&access.expr, &access.field, false),
+            _ => return None,
+        };
+        let restyle = match self.config.section_access_style {
+            SectionAccessStyle::Preserve => return None,
+            SectionAccessStyle::Bang => !bang || !matches!(section.kind, ExprKind::Identifier(_) | ExprKind::QuotedIdentifier(_)),
+            SectionAccessStyle::Bracket => bang,
+        };
+        let has_comments = |e: &Expr| !e.leading_trivia.is_empty() || !e.trailing_trivia.is_empty();
+        let name = match &section.kind {
+            ExprKind::Identifier(name) if bang => Identifier::new(name.clone(), false, section.span),
+            ExprKind::QuotedIdentifier(name) if bang => Identifier::new(name.clone(), true, section.span),
+            ExprKind::FieldAccess(sections)
+                if !sections.optional
+                    && matches!(sections.expr.kind, ExprKind::HashSections)
+                    && !has_comments(&sections.expr) =>
+            {
+                let quoted = sections.field.quoted || !is_regular_identifier(&sections.field.name);
+                Identifier::new(sections.field.name.clone(), quoted, sections.field.span)
+            }
+            _ => return None,
+        };
+        (restyle && !has_comments(section)).then_some((name, member))
+    }
+    
+    /// Write a section access in the form `section_access_style` asks for
+    fn format_section_access_as(&mut self, section: &Identifier, member: &Identifier) {
+        if self.config.section_access_style == SectionAccessStyle::Bracket {
+            self.write("#sections[");
+            self.format_identifier(section);
+            self.write("][");
+            self.format_identifier(member);
+            self.write("]");
+        } else {
+            self.format_identifier(section);
+            self.write("!");
+            let quoted = member.quoted || !is_regular_identifier(&member.name);
+            self.format_identifier(&Identifier::new(member.name.clone(), quoted, member.span));
+        }
+    }
+    
     /// Format field projection
     fn format_field_projection(&mut self, proj: &FieldProjectionExpr) {
         self.format_expr(&proj.expr);
@@ -708,9 +1699,13 @@ impl Formatter {
         self.write(binary.operator.as_str());
         self.write(" ");
         
-        // For 'as' and 'is' operators, format the type annotation directly (without 'type' keyword)
+        // For 'as' and 'is' operators, format the type annotation directly
+        // (with the 'type' keyword only if it was written)
         if matches!(binary.operator, BinaryOp::As | BinaryOp::Is) {
             if let ExprKind::Type(type_expr) = &binary.right.kind {
+                if type_expr.keyword {
+                    self.write("type ");
+                }
                 self.format_type_annotation(&type_expr.type_annotation);
                 return;
             }
@@ -725,6 +1720,47 @@ impl Formatter {
         }
     }
     
+    /// Format a condition (such as the body of `each`). An `and`/`or` chain
+    /// too long for the line gets one clause per line, each after the first
+    /// indented one level and starting with the operator:
+    ///
+    /// ```text
+    /// each _[Name] <> null
+    ///     and _[Amount] > 1000
+    /// ```
+    fn format_predicate(&mut self, expr: &Expr) {
+        let (op, clauses) = match logical_clauses(expr) {
+            Some((op, clauses))
+                if self.would_exceed_line_length(self.estimate_expr_length(expr))
+                    && !clauses.iter().any(|c| has_comments(c)) =>
+            {
+                (op, clauses)
+            }
+            _ => return self.format_expr(expr),
+        };
+        self.count_break("each", "line-length");
+        let layout = self.begin_layout("each", Some("line-length"), true, self.estimate_expr_length(expr));
+        self.indent_level += 1;
+        for (i, clause) in clauses.iter().enumerate() {
+            if i > 0 {
+                self.newline();
+                self.write_indent();
+                self.write(op.as_str());
+                self.write(" ");
+            }
+            if self.needs_parens_for_binary(clause, &op, i == 0) {
+                self.write("(");
+                self.format_expr(clause);
+                self.write(")");
+            } else {
+                // The `and`s of a clause of an `or` chain
+                self.format_predicate(clause);
+            }
+        }
+        self.indent_level -= 1;
+        self.end_layout(layout);
+    }
+    
     fn needs_parens_for_binary(&self, expr: &Expr, parent_op: &BinaryOp, is_left: bool) -> bool {
         if let ExprKind::Binary(inner) = &expr.kind {
             let inner_prec = inner.operator.precedence();
@@ -770,6 +1806,7 @@ impl Formatter {
     fn format_type_annotation(&mut self, type_ann: &TypeAnnotation) {
         match &type_ann.kind {
             TypeKind::Any => self.write("any"),
+            TypeKind::AnyNonNull => self.write("anynonnull"),
             TypeKind::None => self.write("none"),
             TypeKind::Null => self.write("null"),
             TypeKind::Logical => self.write("logical"),
@@ -782,6 +1819,11 @@ impl Formatter {
             TypeKind::Text => self.write("text"),
             TypeKind::Binary => self.write("binary"),
             TypeKind::Type => self.write("type"),
+            TypeKind::Action => self.write("action"),
+            TypeKind::AnyRecord => self.write("record"),
+            TypeKind::AnyTable => self.write("table"),
+            TypeKind::AnyFunction => self.write("function"),
+            TypeKind::List(None) => self.write("list"),
             TypeKind::List(inner) => {
                 self.write("{");
                 if let Some(inner) = inner {
@@ -827,11 +1869,32 @@ impl Formatter {
             }
             TypeKind::Function(params, ret) => {
                 self.write("function (");
-                for (i, param) in params.iter().enumerate() {
-                    if i > 0 {
-                        self.write(", ");
+                // Documented parameters carry metadata records; give each its own line
+                let multiline = !params.is_empty()
+                    && (params.iter().any(|p| matches!(
+                        p.type_annotation.as_ref().map(|t| &t.kind),
+                        Some(TypeKind::Expr(_))
+                    )) || self.would_exceed_line_length(self.estimate_type_length(type_ann)));
+                if multiline {
+                    self.indent_level += 1;
+                    for (i, param) in params.iter().enumerate() {
+                        if i > 0 {
+                            self.write(",");
+                        }
+                        self.newline();
+                        self.write_indent();
+                        self.format_type_parameter(param);
+                    }
+                    self.indent_level -= 1;
+                    self.newline();
+                    self.write_indent();
+                } else {
+                    for (i, param) in params.iter().enumerate() {
+                        if i > 0 {
+                            self.write(", ");
+                        }
+                        self.format_type_parameter(param);
                     }
-                    self.format_type_annotation(param);
                 }
                 self.write(") as ");
                 self.format_type_annotation(ret);
@@ -841,6 +1904,28 @@ impl Formatter {
                 self.write("nullable ");
                 self.format_type_annotation(inner);
             }
+            TypeKind::Parenthesized(inner) => {
+                self.write("(");
+                self.format_type_annotation(inner);
+                self.write(")");
+            }
+            TypeKind::Expr(expr) => {
+                self.write("(");
+                self.format_expr(expr);
+                self.write(")");
+            }
+        }
+    }
+    
+    /// Format a parameter of a function type
+    fn format_type_parameter(&mut self, param: &Parameter) {
+        if param.optional {
+            self.write("optional ");
+        }
+        self.format_identifier(&param.name);
+        if let Some(ref type_ann) = param.type_annotation {
+            self.write(" as ");
+            self.format_type_annotation(type_ann);
         }
     }
     
@@ -860,8 +1945,17 @@ impl Formatter {
         self.write(")");
     }
     
+    /// Format #binary constructor. Byte lists keep their numbers as
+    /// written, often in hexadecimal.
+    fn format_hash_binary(&mut self, binary: &HashBinaryExpr) {
+        self.write("#binary(");
+        self.format_expr(&binary.value);
+        self.write(")");
+    }
+    
     /// Format #date constructor
     fn format_hash_date(&mut self, date: &HashDateExpr) {
+        let plain = core::mem::replace(&mut self.plain_numbers, self.config.normalize_constructor_numbers);
         self.write("#date(");
         self.format_expr(&date.year);
         self.write(", ");
@@ -869,10 +1963,12 @@ impl Formatter {
         self.write(", ");
         self.format_expr(&date.day);
         self.write(")");
+        self.plain_numbers = plain;
     }
     
     /// Format #time constructor
     fn format_hash_time(&mut self, time: &HashTimeExpr) {
+        let plain = core::mem::replace(&mut self.plain_numbers, self.config.normalize_constructor_numbers);
         self.write("#time(");
         self.format_expr(&time.hour);
         self.write(", ");
@@ -880,10 +1976,12 @@ impl Formatter {
         self.write(", ");
         self.format_expr(&time.second);
         self.write(")");
+        self.plain_numbers = plain;
     }
     
     /// Format #datetime constructor
     fn format_hash_datetime(&mut self, dt: &HashDatetimeExpr) {
+        let plain = core::mem::replace(&mut self.plain_numbers, self.config.normalize_constructor_numbers);
         self.write("#datetime(");
         self.format_expr(&dt.year);
         self.write(", ");
@@ -897,10 +1995,12 @@ impl Formatter {
         self.write(", ");
         self.format_expr(&dt.second);
         self.write(")");
+        self.plain_numbers = plain;
     }
     
     /// Format #datetimezone constructor
     fn format_hash_datetimezone(&mut self, dtz: &HashDatetimezoneExpr) {
+        let plain = core::mem::replace(&mut self.plain_numbers, self.config.normalize_constructor_numbers);
         self.write("#datetimezone(");
         self.format_expr(&dtz.year);
         self.write(", ");
@@ -918,10 +2018,12 @@ impl Formatter {
         self.write(", ");
         self.format_expr(&dtz.offset_minutes);
         self.write(")");
+        self.plain_numbers = plain;
     }
     
     /// Format #duration constructor
     fn format_hash_duration(&mut self, dur: &HashDurationExpr) {
+        let plain = core::mem::replace(&mut self.plain_numbers, self.config.normalize_constructor_numbers);
         self.write("#duration(");
         self.format_expr(&dur.days);
         self.write(", ");
@@ -931,35 +2033,89 @@ impl Formatter {
         self.write(", ");
         self.format_expr(&dur.seconds);
         self.write(")");
+        self.plain_numbers = plain;
+    }
+    
+    /// Format the name of a let binding, with its `@` if it has one
+    fn format_binding_name(&mut self, binding: &Binding) {
+        if binding.inclusive {
+            self.write("@");
+        }
+        self.format_identifier(&binding.name);
     }
     
     /// Format identifier
     fn format_identifier(&mut self, ident: &Identifier) {
+        let name = self.identifier_text(&ident.name);
         if ident.quoted {
+            let escaped = escape_identifier(&name);
+            self.check_identifier_width(&name, escaped.chars().count() + 3, ident.span);
             self.write("#\"");
-            self.write(&escape_identifier(&ident.name));
+            self.write(&escaped);
             self.write("\"");
         } else {
-            self.write(&ident.name);
+            self.check_identifier_width(&name, name.chars().count(), ident.span);
+            self.write(&name);
+        }
+    }
+    
+    /// `name` in normalization form C if `normalize_identifiers_nfc` is set
+    fn identifier_text<'n>(&self, name: &'n str) -> Cow<'n, str> {
+        if self.config.normalize_identifiers_nfc && !name.is_ascii() {
+            Cow::Owned(name.nfc().collect())
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+    
+    /// Warn once per name about an identifier `width` characters long as
+    /// written that does not fit within `max_line_length` even alone on an
+    /// indented line
+    fn check_identifier_width(&mut self, name: &str, width: usize, span: Span) {
+        let indent = self.config.indent_at(self.indent_level).chars().count() + self.hang;
+        if indent + width <= self.config.max_line_length {
+            return;
+        }
+        let reported = self
+            .warnings
+            .iter()
+            .any(|w| matches!(w, FormatWarning::IdentifierTooLong { name: n, .. } if n == name));
+        if !reported {
+            self.warnings.push(FormatWarning::IdentifierTooLong {
+                name: name.to_string(),
+                span,
+                length: width,
+            });
         }
     }
     
     // Helper methods
     
     fn write(&mut self, s: &str) {
+        let s = if self.continued && !s.is_empty() {
+            self.continued = false;
+            s.trim_start_matches(' ')
+        } else {
+            s
+        };
         self.output.push_str(s);
         self.current_line_length += s.len();
+        self.current_column += s.chars().count();
     }
     
     fn newline(&mut self) {
+        self.continued = false;
         self.output.push('\n');
         self.current_line_length = 0;
+        self.current_column = 0;
     }
     
     fn write_indent(&mut self) {
-        let indent = self.config.indent_at(self.indent_level);
+        let mut indent = self.config.indent_at(self.indent_level);
+        indent.push_str(&" ".repeat(self.hang));
         self.output.push_str(&indent);
         self.current_line_length = indent.len();
+        self.current_column = indent.chars().count();
     }
     
     /// Check if expression is simple (suitable for single-line formatting)
@@ -969,33 +2125,73 @@ impl Formatter {
             | ExprKind::Text(_) 
             | ExprKind::Identifier(_)
             | ExprKind::QuotedIdentifier(_)
+            | ExprKind::InclusiveIdentifier(_)
             | ExprKind::Null
             | ExprKind::Logical(_)
             | ExprKind::Type(_)
+            | ExprKind::HashSections
+            | ExprKind::HashShared
+            | ExprKind::HashUnknown(_)
             | ExprKind::Underscore => true,
             // Field access like _[Name] is simple
             ExprKind::FieldAccess(fa) => self.is_simple_expr(&fa.expr),
+            ExprKind::SectionAccess(sa) => self.is_simple_expr(&sa.section),
             // Item access like list{0} is simple if both parts are simple
             ExprKind::ItemAccess(ia) => self.is_simple_expr(&ia.expr) && self.is_simple_expr(&ia.index),
             _ => false,
         }
     }
     
+    /// Whether `expr` can be printed on one line: no comments and no
+    /// constructs that always break (let, if, try, functions)
+    fn can_inline(&self, expr: &Expr) -> bool {
+        let has_comments = has_comments(expr);
+        if let Some(text) = self.original_text(expr) {
+            return !has_comments && !text.contains('\n');
+        }
+        let breaks = match &expr.kind {
+            ExprKind::Let(_) | ExprKind::If(_) | ExprKind::Try(_) | ExprKind::Function(_) => true,
+            ExprKind::Record(r) => r
+                .fields
+                .iter()
+                .any(|f| f.leading_trivia.iter().chain(&f.trailing_trivia).any(Trivia::is_comment)),
+            _ => false,
+        };
+        !has_comments && !breaks && expr.children().into_iter().all(|c| self.can_inline(c))
+    }
+    
     fn is_complex_expr(&self, expr: &Expr) -> bool {
-        matches!(
+        self.is_complex(expr, true)
+    }
+    
+    /// [`is_complex_expr`](Self::is_complex_expr), looking for line comments
+    /// inside `expr` only if `find_line_comments`: one walk from the top
+    /// covers them all, so the recursion into lists and calls does not
+    /// walk the same nodes again at every level
+    fn is_complex(&self, expr: &Expr, find_line_comments: bool) -> bool {
+        // A commented expression needs a line of its own, and so does
+        // original text spanning several lines
+        let multiline_original = self.original_text(expr).is_some_and(|text| text.contains('\n'));
+        // The walk comes after the checks of this node, which settle most
+        // `if` and `let` chains without it
+        has_comments(expr) || multiline_original || matches!(
             &expr.kind,
             ExprKind::Let(_)
                 | ExprKind::If(_)
                 | ExprKind::Try(_)
                 | ExprKind::Function(_)
-        ) || match &expr.kind {
+        ) || find_line_comments && contains_line_comment(expr) || match &expr.kind {
             ExprKind::Record(r) => r.fields.len() > self.config.multiline_threshold,
             // Lists are complex only if they contain complex items
-            ExprKind::List(l) => l.items.iter().any(|i| self.is_complex_expr(i)),
+            ExprKind::List(l) => l.items.iter().any(|i| self.is_complex(i, false)),
             ExprKind::FunctionCall(c) => {
                 c.arguments.len() > self.config.multiline_threshold
-                    || c.arguments.iter().any(|a| self.is_complex_expr(a))
-                    || c.arguments.iter().any(|a| self.estimate_expr_length(a) > 30)
+                    || c.arguments.iter().any(|a| self.is_complex(a, false))
+                    || c.arguments.iter().any(|a| {
+                        // Long column names shouldn't force a call to expand
+                        !matches!(a.kind, ExprKind::Text(_))
+                            && self.estimate_expr_length(a) > self.config.max_inline_arg_length
+                    })
             }
             _ => false,
         }
@@ -1005,7 +2201,7 @@ impl Formatter {
         // Rough estimate
         let mut len = 8; // "let " + " in "
         for binding in &let_expr.bindings {
-            len += binding.name.name.len() + 3; // " = "
+            len += binding.name.name.len() + usize::from(binding.inclusive) + 3; // " = "
             len += self.estimate_expr_length(&binding.value);
             len += 2; // ", "
         }
@@ -1021,17 +2217,32 @@ impl Formatter {
     }
     
     fn estimate_expr_length(&self, expr: &Expr) -> usize {
+        if self.is_interrupted() {
+            return 0;
+        }
+        if let Some(text) = self.original_text(expr) {
+            return if text.contains('\n') { 200 } else { text.len() };
+        }
         match &expr.kind {
             ExprKind::Null => 4,
             ExprKind::Logical(b) => if *b { 4 } else { 5 },
-            ExprKind::Number(n) => format!("{}", n).len(),
-            ExprKind::Text(s) => s.len() + 2,
+            ExprKind::Number(n) => self.number_text(n).len(),
+            ExprKind::Text(s) => self.text_literal(expr, s).len(),
             ExprKind::Identifier(s) => s.len(),
             ExprKind::QuotedIdentifier(s) => s.len() + 3,
+            ExprKind::InclusiveIdentifier(ident) => ident.name.len() + if ident.quoted { 4 } else { 1 },
             ExprKind::Underscore => 1,
+            ExprKind::HashSections => 9,
+            ExprKind::HashShared => 7,
+            ExprKind::HashUnknown(s) => s.len() + 1,
+            ExprKind::HashBinary(b) => 9 + self.estimate_expr_length(&b.value),
+            ExprKind::Verbatim(span) => span.end - span.start,
             ExprKind::FieldAccess(access) => {
                 self.estimate_expr_length(&access.expr) + access.field.name.len() + 2
             }
+            ExprKind::SectionAccess(access) => {
+                self.estimate_expr_length(&access.section) + access.member.name.len() + 1
+            }
             ExprKind::ItemAccess(access) => {
                 self.estimate_expr_length(&access.expr) + self.estimate_expr_length(&access.index) + 2
             }
@@ -1067,7 +2278,12 @@ impl Formatter {
                 len
             }
             ExprKind::Binary(binary) => {
-                self.estimate_expr_length(&binary.left) + 3 + self.estimate_expr_length(&binary.right)
+                // Edition 2024 counts every operator as one character
+                let operator = match self.config.style_edition {
+                    StyleEdition::Edition2024 => 1,
+                    _ => binary.operator.as_str().len(),
+                };
+                self.estimate_expr_length(&binary.left) + operator + 2 + self.estimate_expr_length(&binary.right)
             }
             ExprKind::Unary(unary) => {
                 1 + self.estimate_expr_length(&unary.operand)
@@ -1075,6 +2291,9 @@ impl Formatter {
             ExprKind::Parenthesized(inner) => {
                 2 + self.estimate_expr_length(inner)
             }
+            ExprKind::Type(type_expr) if !type_expr.keyword => {
+                self.estimate_type_length(&type_expr.type_annotation)
+            }
             ExprKind::Type(type_expr) => {
                 5 + self.estimate_type_length(&type_expr.type_annotation) // "type "
             }
@@ -1093,6 +2312,7 @@ impl Formatter {
     fn estimate_type_length(&self, type_ann: &TypeAnnotation) -> usize {
         match &type_ann.kind {
             TypeKind::Any => 3,
+            TypeKind::AnyNonNull => 10,
             TypeKind::None => 4,
             TypeKind::Null => 4,
             TypeKind::Logical => 7,
@@ -1105,6 +2325,10 @@ impl Formatter {
             TypeKind::Text => 4,
             TypeKind::Binary => 6,
             TypeKind::Type => 4,
+            TypeKind::Action => 6,
+            TypeKind::AnyRecord => 6,
+            TypeKind::AnyTable => 5,
+            TypeKind::AnyFunction => 8,
             TypeKind::List(inner) => {
                 4 + inner.as_ref().map(|i| self.estimate_type_length(i)).unwrap_or(0)
             }
@@ -1120,8 +2344,25 @@ impl Formatter {
                 }
                 len
             }
-            TypeKind::Function(_, _) => 10,
+            TypeKind::Function(params, ret) => {
+                let mut len = 14; // "function (" + ") as "
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        len += 2; // ", "
+                    }
+                    if param.optional {
+                        len += 9; // "optional "
+                    }
+                    len += param.name.name.len();
+                    if let Some(ref type_ann) = param.type_annotation {
+                        len += 4 + self.estimate_type_length(type_ann); // " as "
+                    }
+                }
+                len + self.estimate_type_length(ret)
+            }
             TypeKind::Nullable(inner) => 9 + self.estimate_type_length(inner),
+            TypeKind::Parenthesized(inner) => 2 + self.estimate_type_length(inner),
+            TypeKind::Expr(expr) => 2 + self.estimate_expr_length(expr),
             TypeKind::Custom(name) => name.len(),
         }
     }
@@ -1130,6 +2371,133 @@ impl Formatter {
     fn would_exceed_line_length(&self, estimated_len: usize) -> bool {
         self.current_line_length + estimated_len > self.config.max_line_length
     }
+    
+    /// Count a unit of work and whether the render should stop; the output
+    /// of an interrupted render is incomplete and is not returned
+    fn is_interrupted(&self) -> bool {
+        let Some(token) = self.cancel else {
+            return false;
+        };
+        if self.interrupted.get().is_some() {
+            return true;
+        }
+        self.work.set(self.work.get() + 1);
+        if token.load(Ordering::Relaxed) {
+            self.interrupted.set(Some(Interruption::Cancelled));
+        } else if self.work.get() > self.work_budget {
+            self.interrupted.set(Some(Interruption::OverBudget));
+        }
+        self.interrupted.get().is_some()
+    }
+    
+    fn count_break(&mut self, construct: &str, rule: &str) {
+        *self.breaks.entry(format!("{}/{}", construct, rule)).or_insert(0) += 1;
+    }
+    
+    /// Record the layout chosen for `construct`, estimated to be `width`
+    /// characters on one line. Returns the decision to complete with
+    /// [`end_layout`](Self::end_layout) once the construct is written.
+    fn begin_layout(
+        &mut self,
+        construct: &'static str,
+        rule: Option<&'static str>,
+        broken: bool,
+        width: usize,
+    ) -> Option<usize> {
+        if !self.explain && !cfg!(feature = "trace") {
+            return None;
+        }
+        let line = self.output_line()?;
+        self.decisions.push(LayoutDecision {
+            construct,
+            rule,
+            broken,
+            line,
+            column: self.current_column + 1,
+            end_line: line,
+            estimated_width: width,
+            actual_width: None,
+            max_line_length: self.config.max_line_length,
+        });
+        Some(self.decisions.len() - 1)
+    }
+    
+    /// Complete a decision from [`begin_layout`](Self::begin_layout) with
+    /// where the construct ended
+    fn end_layout(&mut self, decision: Option<usize>) {
+        let (Some(index), Some(line)) = (decision, self.output_line()) else {
+            return;
+        };
+        let column = self.current_column + 1;
+        let decision = &mut self.decisions[index];
+        decision.end_line = line;
+        if line == decision.line && !decision.broken {
+            decision.actual_width = Some(column - decision.column);
+        }
+        #[cfg(feature = "trace")]
+        log::trace!("{}", decision);
+    }
+    
+    /// Output line being written, from 1, or `None` if the output is hashed
+    fn output_line(&mut self) -> Option<usize> {
+        let Output::Buffer(buf) = &self.output else {
+            return None;
+        };
+        let (scanned, line) = self.line_scan;
+        let line = line + buf[scanned..].matches('\n').count();
+        self.line_scan = (buf.len(), line);
+        Some(line)
+    }
+}
+
+/// Stands in for [`transform::rewrite_functions`](crate::transform) without
+/// the `transforms` feature, which [`Formatter::rewrites_functions`] checks
+#[cfg(not(feature = "transforms"))]
+fn rewrite_functions(_: &mut Expr, _: EachStyle) {}
+
+/// The first of `rules` that applies, the reason a construct is broken
+/// over several lines
+fn break_reason(rules: &[(&'static str, bool)]) -> Option<&'static str> {
+    rules.iter().find(|(_, applies)| *applies).map(|(rule, _)| *rule)
+}
+
+/// The operands of a chain of `and`s (or of `or`s), `a and b and c`, in order
+fn logical_clauses(expr: &Expr) -> Option<(BinaryOp, Vec<&Expr>)> {
+    let ExprKind::Binary(binary) = &expr.kind else {
+        return None;
+    };
+    let op = binary.operator;
+    if !matches!(op, BinaryOp::And | BinaryOp::Or) {
+        return None;
+    }
+    // The operators are left-associative, so the chain grows to the left
+    let mut clauses = vec![&binary.right];
+    let mut left = &binary.left;
+    while let ExprKind::Binary(inner) = &left.kind {
+        if inner.operator != op || has_comments(left) {
+            break;
+        }
+        clauses.push(&inner.right);
+        left = &inner.left;
+    }
+    clauses.push(left);
+    clauses.reverse();
+    Some((op, clauses))
+}
+
+/// Whether comments are attached before or after `expr`
+fn has_comments(expr: &Expr) -> bool {
+    expr.leading_trivia.iter().chain(&expr.trailing_trivia).any(Trivia::is_comment)
+}
+
+/// Whether a line comment is attached anywhere inside `expr`. The comment
+/// ends its line, so whatever contains it cannot be laid out on one line.
+fn contains_line_comment(expr: &Expr) -> bool {
+    let mut found = false;
+    expr.walk(&mut |e| {
+        found |= e.leading_trivia.iter().chain(&e.trailing_trivia).any(|t| matches!(t, Trivia::LineComment(_)));
+    });
+    found
 }
 
 /// Escape special characters in text literals
@@ -1152,34 +2520,437 @@ fn escape_identifier(s: &str) -> String {
     s.replace('"', "\"\"")
 }
 
+/// Number literal for a value: integers without a fraction, very large and
+/// very small numbers with an exponent
+fn number_from_value(n: f64) -> String {
+    if n.is_infinite() {
+        if n.is_sign_positive() { "#infinity" } else { "-#infinity" }.to_string()
+    } else if n.is_nan() {
+        "#nan".to_string()
+    } else if float::is_whole(n) && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else if n != 0.0 && (n.abs() >= 1e15 || n.abs() < 1e-6) {
+        format!("{:e}", n)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// A number literal in the style of `normalize_numbers`: a `0` before a
+/// leading `.`, lowercase `e` and `0x`, no `+` in exponents, uppercase hex
+/// digits
+fn normalize_number(text: &str) -> String {
+    if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return format!("0x{}", digits.to_uppercase());
+    }
+    let mut normalized = String::with_capacity(text.len() + 1);
+    if text.starts_with('.') {
+        normalized.push('0');
+    }
+    normalized.push_str(&text.replace('E', "e").replace("e+", "e"));
+    normalized
+}
+
+/// `name` as written in code
+fn display_identifier(name: &str) -> String {
+    if is_regular_identifier(name) {
+        name.to_string()
+    } else {
+        format!("#\"{}\"", escape_identifier(name))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::CommentStyle;
     use crate::lexer::Lexer;
     use crate::parser::Parser;
     
     fn format_code(code: &str) -> String {
+        format_with(code, Config::default())
+    }
+    
+    fn format_with(code: &str, config: Config) -> String {
         let mut lexer = Lexer::new(code);
         let tokens = lexer.tokenize();
         let mut parser = Parser::new(tokens);
         let doc = parser.parse().unwrap();
-        let mut formatter = Formatter::new(Config::default());
+        let mut formatter = Formatter::new(config);
         formatter.format(&doc)
     }
     
     #[test]
     fn test_format_simple_let() {
-        let input = "let x=1,y=2 in x+y";
-        let output = format_code(input);
-        assert!(output.contains("let"));
-        assert!(output.contains("in"));
+        assert_format!("let x=1,y=2 in x+y", "let\n    x = 1,\n    y = 2\nin\n    x + y\n");
     }
     
     #[test]
     fn test_format_record() {
-        let input = "[A=1,B=2]";
-        let output = format_code(input);
-        assert!(output.contains("["));
-        assert!(output.contains("]"));
+        assert_format!("[A=1,B=2]", "[\n    A = 1,\n    B = 2\n]\n");
+    }
+    
+    #[test]
+    fn test_cancellation() {
+        let doc = Parser::new(Lexer::new("let x = {1, 2, 3} in x").tokenize()).parse().unwrap();
+        let running = AtomicBool::new(false);
+        let mut formatter = Formatter::new(Config::default());
+        formatter.set_cancellation(&running);
+        assert_eq!(formatter.try_format(&doc).unwrap(), format_code("let x = {1, 2, 3} in x"));
+        
+        running.store(true, Ordering::Relaxed);
+        assert!(matches!(formatter.try_format(&doc), Err(FormatError::Cancelled)));
+        
+        // A render that outgrows its budget is abandoned
+        running.store(false, Ordering::Relaxed);
+        formatter.work_budget = 5;
+        formatter.format(&doc);
+        assert!(matches!(formatter.interrupted.get(), Some(Interruption::OverBudget)));
+    }
+    
+    #[test]
+    fn test_hash_constructor_numbers() {
+        let code = "{#date(0x7E8, 01, 01), #datetimezone(2024.0, 1, 1, 09, 05, 00.500, -05, -30), #duration(0, 0, 0, 1.50)}";
+        assert_format!(
+            code,
+            "{\n    #date(0x7E8, 01, 01),\n    #datetimezone(2024.0, 1, 1, 09, 05, 00.500, -05, -30),\n    #duration(0, 0, 0, 1.50)\n}\n"
+        );
+        
+        // Generated code pads and decorates these; written plainly on request
+        let config = Config { normalize_constructor_numbers: true, ..Config::default() };
+        assert_format!(
+            code,
+            "{\n    #date(2024, 1, 1),\n    #datetimezone(2024, 1, 1, 9, 5, 0.5, -5, -30),\n    #duration(0, 0, 0, 1.5)\n}\n",
+            config.clone()
+        );
+        assert_format!("{01, #time(09, 05, 00)}", "{\n    01,\n    #time(9, 5, 0)\n}\n", config);
+    }
+    
+    #[test]
+    fn test_number_literal_style() {
+        let code = "{.5, 1E+06, 2.5e-3, 0XfF, 1.0}";
+        assert_format!(code, "{.5, 1E+06, 2.5e-3, 0XfF, 1.0}\n");
+        let config = Config {
+            normalize_numbers: true,
+            ..Config::default()
+        };
+        assert_format!(code, "{0.5, 1e06, 2.5e-3, 0xFF, 1.0}\n", config);
+        assert_eq!(number_from_value(1e300), "1e300");
+        
+        // Kept without the source text too, unless the number has no text
+        assert_eq!(format_code("{0xFF, 1.50, 1E3}"), "{0xFF, 1.50, 1E3}\n");
+        let document = Document {
+            expression: Expr::new(ExprKind::Number(NumberLiteral::from(1.5)), Span::default()),
+            span: Span::default(),
+            trailing_trivia: Vec::new(),
+            source: String::new(),
+        };
+        assert_eq!(Formatter::new(Config::default()).format(&document), "1.5\n");
+    }
+    
+    #[test]
+    fn test_text_escapes() {
+        let code = "{\"#(0041)#(tab)\", \"a#(cr,lf)b\", \"say \"\"hi\"\"\"}";
+        assert_format!(code, "{\"#(0041)#(tab)\", \"a#(cr,lf)b\", \"say \"\"hi\"\"\"}\n");
+        let config = Config {
+            normalize_text_escapes: true,
+            ..Config::default()
+        };
+        assert_format!(code, "{\"A#(tab)\", \"a#(cr)#(lf)b\", \"say \"\"hi\"\"\"}\n", config);
+        
+        // Without the source text
+        assert_eq!(format_code("\"#(0041)\""), "\"A\"\n");
+    }
+    
+    #[test]
+    fn test_explain() {
+        let code = "let Source = Table.SelectRows(Data, each [Amount] > 100 and [Region] = \"West\"), Rows = {1, 2} in Rows";
+        let config = Config {
+            max_line_length: 40,
+            style_edition: StyleEdition::Edition2025,
+            ..Config::default()
+        };
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let mut formatter = Formatter::new(config);
+        formatter.format(&document);
+        assert_eq!(formatter.decisions().is_empty(), !cfg!(feature = "trace"));
+        
+        formatter.set_explain(true);
+        let formatted = formatter.format(&document);
+        let line = formatted.lines().position(|l| l.trim() == "and _[Region] = \"West\"").unwrap() + 1;
+        let covering: Vec<(&str, Option<&str>, bool)> = formatter
+            .decisions()
+            .iter()
+            .filter(|d| d.covers(line))
+            .map(|d| (d.construct, d.rule, d.broken))
+            .collect();
+        assert_eq!(
+            covering,
+            [
+                ("let", Some("always-expand"), true),
+                ("binding", Some("nested"), true),
+                ("call", Some("threshold"), true),
+                ("each", Some("line-length"), true),
+            ]
+        );
+        
+        let rows = formatter.decisions().iter().find(|d| d.construct == "list").unwrap();
+        assert_eq!((rows.rule, rows.actual_width), (None, Some(6)));
+        assert_eq!(
+            rows.to_string(),
+            format!("Line {}, column 12: list kept on one line; estimated width 6, actual 6, max_line_length 40", rows.line)
+        );
+    }
+    
+    #[test]
+    fn test_section_access_style() {
+        let code = "{Section1!Query, #sections[Section1]!Query, #sections[Section1][Query], Section1!Query[Column]}";
+        assert_format!(
+            code,
+            "{Section1!Query, #sections[Section1]!Query, #sections[Section1][Query], Section1!Query[Column]}\n",
+            Config::compact()
+        );
+        let bang = Config {
+            section_access_style: SectionAccessStyle::Bang,
+            ..Config::compact()
+        };
+        assert_format!(code, "{Section1!Query, Section1!Query, Section1!Query, Section1!Query[Column]}\n", bang);
+        let bracket = Config {
+            section_access_style: SectionAccessStyle::Bracket,
+            ..Config::compact()
+        };
+        assert_format!(
+            code,
+            "{#sections[Section1][Query], #sections[Section1][Query], #sections[Section1][Query], #sections[Section1][Query][Column]}\n",
+            bracket.clone()
+        );
+        assert_format!("#shared[Query]", "#shared[Query]\n", bracket.clone());
+        assert_format!("/* here */ Section1!Query", "/* here */ #sections[Section1][Query]\n", bracket);
+    }
+    
+    #[test]
+    fn test_sort_section_members() {
+        let code = "section Connector;\n\n// The entry point\nshared Zeta = 1;\n\nhelper = 2;\n\n[DataSource.Kind = \"Connector\"]\nshared alpha = 3;\n\n// pqmfmt: keep-order\nshared Omega = 4;\n\nshared Beta = 5; // last\n";
+        let config = Config {
+            sort_section_members: true,
+            ..Config::default()
+        };
+        assert_format!(
+            code,
+            "section Connector;\n\n[DataSource.Kind = \"Connector\"]\nshared alpha = 3;\n\nhelper = 2;\n\nshared Beta = 5; // last\n\n// pqmfmt: keep-order\nshared Omega = 4;\n\n// The entry point\nshared Zeta = 1;\n",
+            config
+        );
+        assert_format!(code, code, Config::default());
+    }
+    
+    #[test]
+    fn test_identifiers_nfc() {
+        // Decomposed: e followed by a combining acute accent
+        let code = "let Cafe\u{301} = 1, #\"Ne\u{301}e\" = [Re\u{301}sume\u{301} = Cafe\u{301}] in #\"Ne\u{301}e\"[Re\u{301}sume\u{301}]";
+        let config = Config {
+            normalize_identifiers_nfc: true,
+            ..Config::compact()
+        };
+        assert_format!(
+            code,
+            "let Caf\u{e9} = 1, #\"N\u{e9}e\" = [R\u{e9}sum\u{e9} = Caf\u{e9}] in #\"N\u{e9}e\"[R\u{e9}sum\u{e9}]\n",
+            config.clone()
+        );
+        // Text literals keep their characters
+        assert_format!("\"e\u{301}\"", "\"e\u{301}\"\n", config);
+    }
+    
+    #[test]
+    fn test_long_string_argument_stays_inline() {
+        assert_format_snapshot!(
+            "long_string_argument_stays_inline",
+            "let x = Table.RemoveColumns(Source[#\"Some very long column name here\"]), y = Text.Upper(\"A string literal much longer than thirty characters\") in y"
+        );
+    }
+    
+    #[test]
+    fn test_max_inline_arg_length() {
+        let input = "let x = Foo(Source[#\"Some very long column name\"]) in x";
+        assert_format!(input, "let\n    x = \n        Foo(Source[#\"Some very long column name\"])\nin\n    x\n");
+        let config = Config {
+            max_inline_arg_length: 40,
+            ..Config::default()
+        };
+        assert_format!(input, "let\n    x = Foo(Source[#\"Some very long column name\"])\nin\n    x\n", config);
+    }
+    
+    #[test]
+    #[cfg(feature = "wrapping")]
+    fn test_hanging_call_arguments() {
+        let config = Config {
+            call_wrap_style: CallWrapStyle::Hanging,
+            ..Config::default()
+        };
+        let input = "let 売上 = Table.SelectRows(Source, each [Value] > 1), y = Table.Group(売上, {\"Name\"}, [A = 1, B = 2]) in y";
+        assert_eq!(
+            format_with(input, config),
+            "let\n    売上 = \n        Table.SelectRows(Source,\n                         each _[Value] > 1),\n    y = \n        Table.Group(売上,\n                    {\"Name\"},\n                    [\n                        A = 1,\n                        B = 2\n                    ])\nin\n    y\n"
+        );
+    }
+    
+    #[test]
+    fn test_identifier_too_long() {
+        let config = Config {
+            max_line_length: 20,
+            ..Config::default()
+        };
+        let code = "let #\"Rows without an amount\" = 1, Next = #\"Rows without an amount\" in Next";
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let mut formatter = Formatter::new(config);
+        formatter.format(&document);
+        assert_eq!(formatter.warnings().len(), 1);
+        assert_eq!(
+            formatter.warnings()[0].to_string(),
+            "Line 1: warning [identifier-too-long]: #\"Rows without an amount\" is 25 characters, too long for max_line_length"
+        );
+    }
+    
+    #[test]
+    #[cfg(feature = "alignment")]
+    fn test_step_annotations() {
+        let input = "let\n    Source = 1, //~2.5 sec\n    Counted = Source + 1 // ROWS:12000\n    , Kept = Counted // keep\nin\n    Kept";
+        assert_eq!(
+            format_with(input, Config::default()),
+            "let\n    Source = 1, // ~2.5s\n    Counted = Source + 1, // rows: 12000\n    Kept = Counted // keep\nin\n    Kept\n"
+        );
+        
+        let config = Config {
+            annotation_column: Some(30),
+            ..Config::default()
+        };
+        assert_eq!(
+            format_with(input, config),
+            "let\n    Source = 1,       // ~2.5s\n    Counted = Source + 1, // rows: 12000\n    Kept = Counted // keep\nin\n    Kept\n"
+        );
+    }
+    
+    #[test]
+    fn test_max_call_nesting_inline() {
+        let input = "let x = Table.A(Table.B(Table.C(Source, {\"a\", \"b\"}, [X = 1, Y = 2]), \"col\"), Table.D(Source, 2)) in x";
+        let config = Config {
+            max_call_nesting_inline: Some(1),
+            ..Config::default()
+        };
+        assert_eq!(
+            format_with(input, config.clone()),
+            "let\n    x = \n        Table.A(\n            Table.B(Table.C(Source, {\"a\", \"b\"}, [X = 1, Y = 2]), \"col\"),\n            Table.D(Source, 2)\n        )\nin\n    x\n"
+        );
+        
+        // Inner calls that contain a function still break
+        assert_format_snapshot!(
+            "max_call_nesting_inline_breaks_functions",
+            "Table.A(Table.B(Source, (r) => let y = r in y), 1)",
+            config
+        );
+    }
+    
+    #[test]
+    fn test_verbatim_nodes_keep_original_text() {
+        let code = "let\n    Keep = {1,2,   3},\n    Tidy = {1,2,   3}\nin\n    Keep";
+        let (mut document, _) = crate::parse_document(code, &Config::default()).unwrap();
+        let ExprKind::Let(l) = &mut document.expression.kind else { unreachable!() };
+        l.bindings[0].value.verbatim = true;
+        
+        let formatted = Formatter::with_source(Config::default(), code).format(&document);
+        assert_eq!(formatted, "let\n    Keep = {1,2,   3},\n    Tidy = {1, 2, 3}\nin\n    Keep\n");
+        
+        // Without the source the node is formatted as usual
+        let formatted = Formatter::new(Config::default()).format(&document);
+        assert!(formatted.contains("Keep = {1, 2, 3}"));
+    }
+    
+    #[test]
+    fn test_multiline_verbatim_node_breaks_container() {
+        let code = "Foo(1, {1,\n  2})";
+        let (mut document, _) = crate::parse_document(code, &Config::default()).unwrap();
+        let ExprKind::FunctionCall(call) = &mut document.expression.kind else { unreachable!() };
+        call.arguments[1].verbatim = true;
+        let formatted = Formatter::with_source(Config::default(), code).format(&document);
+        assert_eq!(formatted, "Foo(\n    1,\n    {1,\n  2}\n)\n");
+    }
+    
+    #[test]
+    fn test_style_edition_2025_drops_space_before_break() {
+        let code = "let a = [A = 1, B = [C = 2, D = 3]] in a";
+        let edition_2024 = format_code(code);
+        assert!(edition_2024.contains("    a = \n        [\n"));
+        assert!(edition_2024.contains("            B = \n                [\n"));
+        
+        let config = Config {
+            style_edition: StyleEdition::Edition2025,
+            ..Config::default()
+        };
+        let edition_2025 = format_with(code, config);
+        assert!(!edition_2025.lines().any(|line| line.ends_with(' ')));
+        assert_eq!(edition_2025.replace(" =\n", " = \n"), edition_2024);
+    }
+    
+    #[test]
+    fn test_trailing_comma_per_construct() {
+        let config = Config {
+            trailing_comma_lists: true,
+            ..Config::expanded()
+        };
+        assert_format_snapshot!("trailing_comma_per_construct", "F(1, {1, 2}, [A = 1, B = 2])", config);
+    }
+    
+    #[test]
+    #[cfg(feature = "wrapping")]
+    fn test_list_fill_style() {
+        let code = format!("{{{}}}", (1..=30).map(|n| n.to_string()).collect::<Vec<_>>().join(","));
+        let config = Config {
+            list_fill_style: ListFillStyle::Fill,
+            max_line_length: 40,
+            ..Config::default()
+        };
+        assert_format_snapshot!("list_fill_style", &code, config);
+        
+        // Lists with comments keep one item per line
+        let config = Config {
+            list_fill_style: ListFillStyle::Fill,
+            ..Config::expanded()
+        };
+        assert_format!("{1, // one\n2}", "{\n    1, // one\n    2\n}\n", config);
+    }
+    
+    #[test]
+    #[cfg(feature = "alignment")]
+    fn test_align_nested_lists() {
+        let config = Config {
+            align_nested_lists: true,
+            ..Config::expanded()
+        };
+        assert_format_snapshot!(
+            "align_nested_lists",
+            "#table({\"Name\", \"Qty\"}, {{\"Apples\", 1}, {\"Kiwi\", 200}, {\"Fig\", 33}})",
+            config.clone()
+        );
+        
+        // Rows of different lengths are laid out as usual
+        assert_format!("{{1, 2}, {3}}", "{\n    {\n        1,\n        2\n    },\n    {\n        3\n    }\n}\n", config);
+    }
+    
+    #[test]
+    fn test_comment_style() {
+        let code = "//   heading\nlet a = 1, //one\n    b = 2 /* two */\nin a\n/* end */";
+        for (name, comment_style) in [
+            ("comment_style_preserve", CommentStyle::Preserve),
+            ("comment_style_line", CommentStyle::Line),
+            ("comment_style_block", CommentStyle::Block),
+        ] {
+            assert_format_snapshot!(name, code, Config { comment_style, ..Config::default() });
+        }
+        
+        // A block comment followed by code on its line stays a block comment
+        let config = Config {
+            comment_style: CommentStyle::Line,
+            ..Config::default()
+        };
+        assert_format!("let a = /* x */ 1 in a", "let\n    a = \n        /* x */ 1\nin\n    a\n", config);
     }
 }
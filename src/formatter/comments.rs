@@ -0,0 +1,106 @@
+//! Comment emission
+//!
+//! Every comment the formatter writes goes through [`CommentPrinter`], so
+//! style conversion and spacing are decided in one place.
+
+use crate::alloc_prelude::*;
+use crate::ast::Trivia;
+use crate::config::CommentStyle;
+
+/// Renders comments in the configured [`CommentStyle`]
+#[derive(Debug, Clone)]
+pub(crate) struct CommentPrinter {
+    style: CommentStyle,
+    /// Column trailing comments start at when the code before them is
+    /// shorter, so that comments on consecutive lines line up
+    pub(crate) align_column: Option<usize>,
+}
+
+impl CommentPrinter {
+    pub(crate) fn new(style: CommentStyle) -> Self {
+        Self {
+            style,
+            align_column: None,
+        }
+    }
+    
+    /// Text of a comment. A block comment only becomes a line comment if it
+    /// is on one line and `ends_line` (no code follows it on its line); a
+    /// line comment only becomes a block comment if it does not contain
+    /// `*/`. Returns `None` for trivia that is not a comment.
+    pub(crate) fn comment(&self, trivia: &Trivia, ends_line: bool) -> Option<String> {
+        match trivia {
+            Trivia::LineComment(content) => {
+                let text = content.trim();
+                Some(match self.style {
+                    CommentStyle::Block if !text.is_empty() && !text.contains("*/") => {
+                        format!("/* {} */", text)
+                    }
+                    CommentStyle::Preserve if content.starts_with(' ') || content.is_empty() => {
+                        format!("//{}", content)
+                    }
+                    CommentStyle::Preserve => format!("// {}", content),
+                    // Exactly one space after `//`
+                    _ => line_comment(text),
+                })
+            }
+            Trivia::BlockComment(content) => Some(
+                if self.style == CommentStyle::Line && ends_line && !content.contains('\n') {
+                    line_comment(content.trim())
+                } else {
+                    format!("/*{}*/", content)
+                },
+            ),
+            _ => None,
+        }
+    }
+    
+    /// Comments written on lines of their own, one per line (without the
+    /// indentation, which depends on where they are written)
+    pub(crate) fn leading(&self, trivia: &[Trivia]) -> Vec<String> {
+        trivia.iter().filter_map(|t| self.comment(t, true)).collect()
+    }
+    
+    /// Comments after code on the same line, which ends at `column`,
+    /// separated from it by one space; `ends_line` if nothing but a line
+    /// break follows them
+    pub(crate) fn trailing(&self, trivia: &[Trivia], column: usize, ends_line: bool) -> String {
+        let comments: Vec<&Trivia> = trivia.iter().filter(|t| t.is_comment()).collect();
+        let mut text = String::new();
+        for (i, t) in comments.iter().enumerate() {
+            match self.align_column {
+                Some(align) if i == 0 && column + 1 < align => {
+                    text.push_str(&" ".repeat(align - column));
+                }
+                _ => text.push(' '),
+            }
+            text.extend(self.comment(t, ends_line && i == comments.len() - 1));
+        }
+        text
+    }
+}
+
+/// `//` and the text, with one space between them unless it is empty
+fn line_comment(text: &str) -> String {
+    if text.is_empty() {
+        "//".to_string()
+    } else {
+        format!("// {}", text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_trailing_alignment() {
+        let trivia = [Trivia::LineComment("note".to_string())];
+        let mut printer = CommentPrinter::new(CommentStyle::Preserve);
+        assert_eq!(printer.trailing(&trivia, 10, true), " // note");
+        
+        printer.align_column = Some(16);
+        assert_eq!(printer.trailing(&trivia, 10, true), "      // note");
+        assert_eq!(printer.trailing(&trivia, 20, true), " // note");
+    }
+}
@@ -0,0 +1,96 @@
+//! Assertions for formatter tests
+//!
+//! `assert_format!(input, expected)` (or `assert_format!(input, expected,
+//! config)`) checks the whole formatted output and prints a line diff when
+//! it differs. For longer expectations, `assert_format_snapshot!(name,
+//! input)` compares against `src/formatter/snapshots/<name>.snap`; run the
+//! tests with `PQMFMT_BLESS=1` to create or update snapshots after an
+//! intended change, and review them like any other diff.
+
+use crate::Config;
+use std::fs;
+use std::path::PathBuf;
+
+macro_rules! assert_format {
+    ($input:expr, $expected:expr $(,)?) => {
+        $crate::formatter::harness::assert_formats_as($input, $expected, $crate::Config::default())
+    };
+    ($input:expr, $expected:expr, $config:expr $(,)?) => {
+        $crate::formatter::harness::assert_formats_as($input, $expected, $config)
+    };
+}
+
+macro_rules! assert_format_snapshot {
+    ($name:expr, $input:expr $(,)?) => {
+        $crate::formatter::harness::assert_snapshot($name, $input, $crate::Config::default())
+    };
+    ($name:expr, $input:expr, $config:expr $(,)?) => {
+        $crate::formatter::harness::assert_snapshot($name, $input, $config)
+    };
+}
+
+/// Format `input`, failing the test if it does not parse
+#[track_caller]
+fn format(input: &str, config: Config) -> String {
+    crate::format(input, config).unwrap_or_else(|errors| {
+        panic!("input does not parse:\n{}\n{:?}", input, errors)
+    })
+}
+
+#[track_caller]
+pub(crate) fn assert_formats_as(input: &str, expected: &str, config: Config) {
+    let actual = format(input, config);
+    if actual != expected {
+        panic!("formatted output differs (- expected, + actual):\n{}", line_diff(expected, &actual));
+    }
+}
+
+#[track_caller]
+pub(crate) fn assert_snapshot(name: &str, input: &str, config: Config) {
+    let actual = format(input, config);
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/formatter/snapshots")
+        .join(format!("{}.snap", name));
+    // The input goes in a header, so the snapshot can be reviewed on its own
+    let snapshot = format!("---\ninput: {:?}\n---\n{}", input, actual);
+    
+    if std::env::var("PQMFMT_BLESS").is_ok() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, snapshot).unwrap();
+        return;
+    }
+    let stored = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!("missing snapshot {} (run with PQMFMT_BLESS=1 to create it)", path.display())
+    });
+    let stored = stored.replace("\r\n", "\n");
+    if stored != snapshot {
+        panic!(
+            "snapshot {} differs (- stored, + actual):\n{}\n(run with PQMFMT_BLESS=1 if the change is intended)",
+            name,
+            line_diff(&stored, &snapshot)
+        );
+    }
+}
+
+/// Lines of both texts side by side: unchanged lines indented, differing
+/// ones marked `-` (expected) and `+` (actual). Line ends are shown, as
+/// trailing spaces are often the difference.
+fn line_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.split('\n').collect();
+    let actual: Vec<&str> = actual.split('\n').collect();
+    let mut diff = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => diff.push_str(&format!("  {}$\n", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    diff.push_str(&format!("- {}$\n", e));
+                }
+                if let Some(a) = a {
+                    diff.push_str(&format!("+ {}$\n", a));
+                }
+            }
+        }
+    }
+    diff
+}
@@ -0,0 +1,75 @@
+//! Records of the layout decisions of a render, for explaining why a line
+//! broke where it did
+
+use core::fmt;
+
+/// How the formatter laid out a construct and why, recorded when
+/// [`Formatter::set_explain`](super::Formatter::set_explain) is on (or
+/// logged with the `trace` feature)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutDecision {
+    /// `let`, `if`, `call`, `record`, `list`, `each`, or the value of a
+    /// `binding` or record `field`, which can start on the line after `=`
+    pub construct: &'static str,
+    /// The first rule that asks for several lines, as in
+    /// [`FormatStats::breaks`](super::FormatStats::breaks); `None` if none
+    /// does
+    pub rule: Option<&'static str>,
+    /// Whether the construct was laid out over several lines. `false` with
+    /// a rule when `max_call_nesting_inline` kept it on one line.
+    pub broken: bool,
+    /// Output line and column (both from 1) where the decision was taken
+    pub line: usize,
+    pub column: usize,
+    /// Output line where the construct ends
+    pub end_line: usize,
+    /// Width the construct was estimated to take on one line (for the value
+    /// of a binding or field, with the ` = ` before it)
+    pub estimated_width: usize,
+    /// Width it took, when it was kept on one line
+    pub actual_width: Option<usize>,
+    pub max_line_length: usize,
+}
+
+impl LayoutDecision {
+    /// Whether the construct takes up output line `line`
+    pub fn covers(&self, line: usize) -> bool {
+        self.line <= line && line <= self.end_line
+    }
+}
+
+impl fmt::Display for LayoutDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}, column {}: {} ", self.line, self.column, self.construct)?;
+        match (self.rule, self.broken) {
+            (Some(rule), true) => write!(f, "broken over several lines ({}: {})", rule, describe(rule, self.construct))?,
+            (Some(rule), false) => write!(
+                f,
+                "kept on one line, nested deeper than max_call_nesting_inline (otherwise {}: {})",
+                rule,
+                describe(rule, self.construct)
+            )?,
+            (None, _) => write!(f, "kept on one line")?,
+        }
+        write!(f, "; estimated width {}", self.estimated_width)?;
+        if let Some(actual) = self.actual_width {
+            write!(f, ", actual {}", actual)?;
+        }
+        write!(f, ", max_line_length {}", self.max_line_length)
+    }
+}
+
+/// What rule `rule` means for `construct`
+fn describe(rule: &str, construct: &str) -> &'static str {
+    match (rule, construct) {
+        ("line-length", _) => "too long for the rest of the line",
+        ("nested", "binding" | "field") => "the value is laid out over several lines",
+        ("nested", _) => "contains a construct laid out over several lines",
+        ("threshold", _) => "more items than multiline_threshold",
+        ("comments", _) => "contains comments",
+        ("always-expand", "let") => "always_expand_let is set",
+        ("always-expand", "record") => "always_expand_records is set",
+        ("always-expand", _) => "always_expand_lists is set",
+        _ => "",
+    }
+}
@@ -0,0 +1,249 @@
+//! Minimal JSON reading and writing
+//!
+//! Just enough JSON for plugin and tool interfaces without pulling in a
+//! serialization framework. Object members keep their source order.
+
+use crate::alloc_prelude::*;
+use core::fmt::Write;
+
+/// A parsed JSON value
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// Look up a member of an object
+    pub(crate) fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    
+    /// Members of an object, or an empty slice for any other value
+    pub(crate) fn members(&self) -> &[(String, JsonValue)] {
+        match self {
+            JsonValue::Object(members) => members,
+            _ => &[],
+        }
+    }
+}
+
+/// Parse a complete JSON document
+pub(crate) fn parse(text: &str) -> Result<JsonValue, String> {
+    let mut reader = Reader { chars: text.char_indices().peekable(), text };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    match reader.chars.next() {
+        None => Ok(value),
+        Some((pos, _)) => Err(format!("Unexpected trailing characters at offset {}", pos)),
+    }
+}
+
+/// Write `s` as a quoted JSON string
+pub(crate) fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+struct Reader<'a> {
+    chars: core::iter::Peekable<core::str::CharIndices<'a>>,
+    text: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some((_, ' ' | '\t' | '\n' | '\r'))) {
+            self.chars.next();
+        }
+    }
+    
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((pos, c)) => Err(format!("Expected '{}', found '{}' at offset {}", expected, c, pos)),
+            None => Err(format!("Expected '{}', found end of input", expected)),
+        }
+    }
+    
+    fn value(&mut self) -> Result<JsonValue, String> {
+        self.skip_whitespace();
+        let (pos, c) = match self.chars.peek() {
+            Some(&(pos, c)) => (pos, c),
+            None => return Err("Unexpected end of input".to_string()),
+        };
+        match c {
+            '{' => self.object(),
+            '[' => self.array(),
+            '"' => self.string().map(JsonValue::String),
+            't' => self.literal("true", JsonValue::Bool(true)),
+            'f' => self.literal("false", JsonValue::Bool(false)),
+            'n' => self.literal("null", JsonValue::Null),
+            '-' | '0'..='9' => self.number(),
+            _ => Err(format!("Unexpected character '{}' at offset {}", c, pos)),
+        }
+    }
+    
+    fn literal(&mut self, word: &str, value: JsonValue) -> Result<JsonValue, String> {
+        for expected in word.chars() {
+            match self.chars.next() {
+                Some((_, c)) if c == expected => {}
+                _ => return Err(format!("Invalid literal, expected '{}'", word)),
+            }
+        }
+        Ok(value)
+    }
+    
+    fn number(&mut self) -> Result<JsonValue, String> {
+        let start = self.chars.peek().map(|&(pos, _)| pos).unwrap_or(self.text.len());
+        let mut end = start;
+        while let Some(&(pos, c)) = self.chars.peek() {
+            if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E') {
+                end = pos + 1;
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+        let lexeme = &self.text[start..end];
+        lexeme
+            .parse::<f64>()
+            .map(JsonValue::Number)
+            .map_err(|_| format!("Invalid number '{}'", lexeme))
+    }
+    
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err("Unterminated string".to_string()),
+                Some((_, '"')) => return Ok(out),
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, '"')) => out.push('"'),
+                    Some((_, '\\')) => out.push('\\'),
+                    Some((_, '/')) => out.push('/'),
+                    Some((_, 'b')) => out.push('\u{8}'),
+                    Some((_, 'f')) => out.push('\u{c}'),
+                    Some((_, 'n')) => out.push('\n'),
+                    Some((_, 'r')) => out.push('\r'),
+                    Some((_, 't')) => out.push('\t'),
+                    Some((_, 'u')) => {
+                        let mut code = self.hex4()?;
+                        if (0xD800..0xDC00).contains(&code) {
+                            // Surrogate pair
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex4()?;
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                        }
+                        out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                    }
+                    _ => return Err("Invalid escape sequence".to_string()),
+                },
+                Some((_, c)) => out.push(c),
+            }
+        }
+    }
+    
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .chars
+                .next()
+                .and_then(|(_, c)| c.to_digit(16))
+                .ok_or_else(|| "Invalid \\u escape".to_string())?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+    
+    fn array(&mut self) -> Result<JsonValue, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some((_, ']'))) {
+            self.chars.next();
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, ']')) => return Ok(JsonValue::Array(items)),
+                _ => return Err("Expected ',' or ']' in array".to_string()),
+            }
+        }
+    }
+    
+    fn object(&mut self) -> Result<JsonValue, String> {
+        self.expect('{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some((_, '}'))) {
+            self.chars.next();
+            return Ok(JsonValue::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            let value = self.value()?;
+            members.push((key, value));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, '}')) => return Ok(JsonValue::Object(members)),
+                _ => return Err("Expected ',' or '}' in object".to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_parse_object() {
+        let value = parse(r#"{"a": 1, "b": [true, null], "c": "x\nyé"}"#).unwrap();
+        assert_eq!(value.get("a"), Some(&JsonValue::Number(1.0)));
+        assert_eq!(
+            value.get("b"),
+            Some(&JsonValue::Array(vec![JsonValue::Bool(true), JsonValue::Null]))
+        );
+        assert_eq!(value.get("c"), Some(&JsonValue::String("x\nyé".to_string())));
+        assert!(parse("{\"a\": }").is_err());
+        assert!(parse("[1] x").is_err());
+    }
+    
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a\"b\\c\n"), r#""a\"b\\c\n""#);
+        assert_eq!(parse(&quote("tab\there")).unwrap(), JsonValue::String("tab\there".to_string()));
+    }
+}
@@ -1,11 +1,17 @@
 //! Lexer for Power Query M language
 
-use crate::token::{Span, Token, TokenKind};
+use crate::alloc_prelude::*;
+use crate::token::{NumberLiteral, Span, Token, TokenKind};
+
+/// Deepest nesting of block comments (`/* /* */ */`) accepted. A comment
+/// nested deeper is returned, with the rest of the input, as one `Invalid`
+/// token.
+pub const MAX_COMMENT_NESTING: usize = 64;
 
 /// Lexer for tokenizing Power Query M source code
 pub struct Lexer<'a> {
     input: &'a str,
-    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    chars: core::iter::Peekable<core::str::CharIndices<'a>>,
     position: usize,
     line: usize,
     column: usize,
@@ -58,6 +64,11 @@ impl<'a> Lexer<'a> {
             Some(c) => match c {
                 // Whitespace
                 ' ' | '\t' => self.lex_whitespace(),
+                // A byte order mark is only meaningful at the start of the text
+                '\u{FEFF}' if self.position == 0 => {
+                    self.advance();
+                    TokenKind::Whitespace('\u{FEFF}'.to_string())
+                }
                 '\r' | '\n' => self.lex_newline(),
                 
                 // String literal
@@ -100,7 +111,7 @@ impl<'a> Lexer<'a> {
                 // Unknown character
                 c => {
                     self.advance();
-                    TokenKind::Invalid(c.to_string())
+                    TokenKind::UnexpectedChar(c)
                 }
             }
         };
@@ -169,10 +180,14 @@ impl<'a> Lexer<'a> {
     fn lex_string(&mut self) -> TokenKind {
         self.advance(); // consume opening "
         let mut result = String::new();
+        // After an error the rest of the literal is still consumed, so that
+        // lexing resumes after its closing quote
+        let mut error = None;
         
         loop {
             match self.peek_char() {
                 None => {
+                    // The rest of the input is the string
                     return TokenKind::Invalid("Unterminated string".to_string());
                 }
                 Some('"') => {
@@ -192,7 +207,9 @@ impl<'a> Lexer<'a> {
                         self.advance();
                         match self.lex_escape_sequence() {
                             Ok(s) => result.push_str(&s),
-                            Err(e) => return TokenKind::Invalid(e),
+                            Err(e) => {
+                                error.get_or_insert(e);
+                            }
                         }
                     } else {
                         result.push('#');
@@ -205,14 +222,18 @@ impl<'a> Lexer<'a> {
             }
         }
         
-        TokenKind::Text(result)
+        match error {
+            Some(e) => TokenKind::Invalid(e),
+            None => TokenKind::Text(result),
+        }
     }
     
     fn lex_escape_sequence(&mut self) -> Result<String, String> {
         let mut result = String::new();
         
         loop {
-            let escape_content = self.advance_while(|c| c != ',' && c != ')');
+            // A quote ends the string even inside a malformed escape
+            let escape_content = self.advance_while(|c| c != ',' && c != ')' && c != '"');
             
             let unescaped = match escape_content.as_str() {
                 "cr" => "\r".to_string(),
@@ -298,10 +319,10 @@ impl<'a> Lexer<'a> {
                     "shared" => TokenKind::HashShared,
                     "table" => TokenKind::HashTable,
                     "time" => TokenKind::HashTime,
-                    _ => TokenKind::Invalid(format!("Unknown hash keyword: #{}", ident)),
+                    _ => TokenKind::HashUnknown(ident),
                 }
             }
-            _ => TokenKind::Invalid("#".to_string()),
+            _ => TokenKind::UnexpectedChar('#'),
         }
     }
     
@@ -318,7 +339,7 @@ impl<'a> Lexer<'a> {
                     return TokenKind::Invalid("Invalid hex number".to_string());
                 }
                 if let Ok(value) = i64::from_str_radix(&hex_digits, 16) {
-                    return TokenKind::Number(value as f64);
+                    return TokenKind::Number(NumberLiteral::new(value as f64, &self.input[start..self.position]));
                 } else {
                     return TokenKind::Invalid("Hex number out of range".to_string());
                 }
@@ -338,13 +359,13 @@ impl<'a> Lexer<'a> {
         let mut number_str = whole;
         
         // Fractional part
-        if self.peek_char() == Some('.') {
-            if self.peek_next_char().map(|c| c.is_ascii_digit()).unwrap_or(false) {
-                self.advance(); // consume .
-                let frac = self.advance_while(|c| c.is_ascii_digit());
-                number_str.push('.');
-                number_str.push_str(&frac);
-            }
+        if self.peek_char() == Some('.')
+            && self.peek_next_char().map(|c| c.is_ascii_digit()).unwrap_or(false)
+        {
+            self.advance(); // consume .
+            let frac = self.advance_while(|c| c.is_ascii_digit());
+            number_str.push('.');
+            number_str.push_str(&frac);
         }
         
         // Exponent part
@@ -364,7 +385,7 @@ impl<'a> Lexer<'a> {
         }
         
         match number_str.parse::<f64>() {
-            Ok(n) => TokenKind::Number(n),
+            Ok(n) => TokenKind::Number(NumberLiteral::new(n, &self.input[start..self.position])),
             Err(_) => TokenKind::Invalid(format!("Invalid number: {}", number_str)),
         }
     }
@@ -407,6 +428,14 @@ impl<'a> Lexer<'a> {
                             if self.peek_char() == Some('*') {
                                 self.advance();
                                 depth += 1;
+                                if depth > MAX_COMMENT_NESTING {
+                                    // The rest of the input is the comment
+                                    while self.advance().is_some() {}
+                                    return TokenKind::Invalid(format!(
+                                        "Block comment nested more than {} levels deep",
+                                        MAX_COMMENT_NESTING
+                                    ));
+                                }
                                 content.push_str("/*");
                             } else {
                                 content.push('/');
@@ -533,12 +562,32 @@ impl<'a> Lexer<'a> {
     }
 }
 
+/// Whether `name` can be written as a regular identifier, without `#"..."`
+pub fn is_regular_identifier(name: &str) -> bool {
+    let tokens = Lexer::new(name).tokenize();
+    match tokens.as_slice() {
+        [token, eof] => {
+            eof.kind == TokenKind::Eof
+                && matches!(&token.kind, TokenKind::Identifier(ident) if ident == name)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `name` is a reserved word such as `type` or `each`
+pub fn is_keyword(name: &str) -> bool {
+    let tokens = Lexer::new(name).tokenize();
+    matches!(tokens.as_slice(), [token, eof] if token.kind.is_keyword() && eof.kind == TokenKind::Eof)
+}
+
 fn is_identifier_start(c: char) -> bool {
     c.is_alphabetic() || c == '_'
 }
 
+/// Letters, digits, `_`, and combining marks, such as the accent of a
+/// decomposed `é` (`e` + U+0301)
 fn is_identifier_continue(c: char) -> bool {
-    c.is_alphanumeric() || c == '_'
+    c.is_alphanumeric() || c == '_' || unicode_normalization::char::is_combining_mark(c)
 }
 
 #[cfg(test)]
@@ -555,7 +604,7 @@ mod tests {
         assert_eq!(tokens[0].kind, TokenKind::Let);
         assert_eq!(tokens[1].kind, TokenKind::Identifier("x".to_string()));
         assert_eq!(tokens[2].kind, TokenKind::Equal);
-        assert_eq!(tokens[3].kind, TokenKind::Number(1.0));
+        assert_eq!(tokens[3].kind, TokenKind::Number(NumberLiteral::new(1.0, "1")));
         assert_eq!(tokens[4].kind, TokenKind::In);
         assert_eq!(tokens[5].kind, TokenKind::Identifier("x".to_string()));
     }
@@ -578,7 +627,7 @@ mod tests {
     fn test_hex_number() {
         let mut lexer = Lexer::new("0xff");
         let token = lexer.next_non_trivia_token();
-        assert_eq!(token.kind, TokenKind::Number(255.0));
+        assert_eq!(token.kind, TokenKind::Number(NumberLiteral::new(255.0, "0xff")));
     }
     
     #[test]
@@ -595,4 +644,78 @@ mod tests {
         assert_eq!(tokens[4].kind, TokenKind::QuestionQuestion);
         assert_eq!(tokens[5].kind, TokenKind::DotDotDot);
     }
+    
+    #[test]
+    fn test_is_regular_identifier() {
+        assert!(is_regular_identifier("FilteredRows"));
+        assert!(is_regular_identifier("Table.SelectRows"));
+        assert!(is_regular_identifier("売上"));
+        assert!(!is_regular_identifier("Filtered Rows"));
+        assert!(!is_regular_identifier("each"));
+        assert!(!is_regular_identifier("1st"));
+        assert!(!is_regular_identifier(""));
+    }
+    
+    #[test]
+    fn test_unknown_hash_keyword() {
+        let mut lexer = Lexer::new("#customthing");
+        let token = lexer.next_non_trivia_token();
+        assert_eq!(token.kind, TokenKind::HashUnknown("customthing".to_string()));
+    }
+    
+    #[test]
+    fn test_invalid_spans() {
+        fn invalid(source: &str) -> (String, &str) {
+            let token = Lexer::new(source).tokenize().into_iter()
+                .find(|t| matches!(t.kind, TokenKind::Invalid(_)))
+                .unwrap();
+            (token.kind.to_string(), token.span.slice(source))
+        }
+        
+        // Unterminated constructs take the rest of the input
+        assert_eq!(invalid("x & \"abc\n in x"), ("Unterminated string".to_string(), "\"abc\n in x"));
+        assert_eq!(invalid("/* a /* b */"), ("Unterminated block comment".to_string(), "/* a /* b */"));
+        assert_eq!(invalid("x & \""), ("Unterminated string".to_string(), "\""));
+        
+        // Characters M does not use are a kind of their own
+        assert_eq!(Lexer::new("`").next_token().kind, TokenKind::UnexpectedChar('`'));
+        assert_eq!(Lexer::new("#1").next_token().kind, TokenKind::UnexpectedChar('#'));
+        
+        // A bad escape spoils its string only; lexing resumes after it
+        let source = "\"a #(bogus) b\" & \"#(cr\" & c";
+        assert_eq!(invalid(source), ("Unknown escape sequence: bogus".to_string(), "\"a #(bogus) b\""));
+        let kinds: Vec<_> = Lexer::new(source).tokenize().into_iter()
+            .filter(|t| !t.kind.is_trivia())
+            .map(|t| t.kind)
+            .collect();
+        assert_eq!(kinds.len(), 6);
+        assert_eq!(kinds[2], TokenKind::Invalid("Unterminated escape sequence".to_string()));
+        assert_eq!(kinds[4], TokenKind::Identifier("c".to_string()));
+    }
+    
+    #[test]
+    fn test_limits() {
+        let nested = "/*".repeat(MAX_COMMENT_NESTING) + &"*/".repeat(MAX_COMMENT_NESTING);
+        assert!(matches!(Lexer::new(&nested).next_token().kind, TokenKind::BlockComment(_)));
+        let too_deep = format!("/*{} x", nested);
+        let token = Lexer::new(&too_deep).next_token();
+        assert!(matches!(token.kind, TokenKind::Invalid(_)));
+        assert_eq!(token.span.end, too_deep.len());
+        
+        // Text literals are as long as the input makes them
+        let text = "a".repeat(1 << 24 | 1);
+        let tokens = Lexer::new(&format!("\"{}\" & x", text)).tokenize();
+        assert_eq!(tokens[0].kind, TokenKind::Text(text));
+    }
+    
+    #[test]
+    fn test_span_slice() {
+        let source = r#"x = #"Sales 売上" & "text""#;
+        let texts: Vec<&str> = Lexer::new(source).tokenize().iter()
+            .filter(|t| !t.kind.is_trivia() && t.kind != TokenKind::Eof)
+            .map(|t| t.span.slice(source))
+            .collect();
+        assert_eq!(texts, ["x", "=", "#\"Sales 売上\"", "&", "\"text\""]);
+        assert_eq!(Span::new(5, 50, 1, 6).slice(source), "");
+    }
 }
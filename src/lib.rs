@@ -28,18 +28,153 @@
 //! - **Default**: Standard formatting with reasonable line breaks
 //! - **Compact**: Minimizes line breaks, keeps simple expressions on one line
 //! - **Expanded**: Maximizes readability by expanding all structures
+//!
+//! `examples/gallery.html` shows each mode applied to the queries in
+//! `tests/corpus` (regenerate it with `cargo run --example gallery`).
+//!
+//! ## Cookbook
+//!
+//! Keep short structures on one line, as the compact preset does:
+//!
+//! ```rust
+//! use pqm_formatter::{format, Config};
+//!
+//! let formatted = format("let x = [A=1, B=2] in x", Config::compact()).unwrap();
+//! assert_eq!(formatted, "let x = [A = 1, B = 2] in x\n");
+//! ```
+//!
+//! Start from a preset and adjust single settings by name, as in
+//! configuration files:
+//!
+//! ```rust
+//! use pqm_formatter::{format, Config};
+//!
+//! let mut config = Config::default();
+//! config.set("indent_size", "2").unwrap();
+//! config.set("trailing_comma", "true").unwrap();
+//! let formatted = format("[A=1, B={1, 2}]", config).unwrap();
+//! assert_eq!(formatted, "[\n  A = 1,\n  B = {1, 2},\n]\n");
+//! ```
+//!
+//! Give one wide value a longer line limit with a scoped directive (see
+//! [`config::directive`]):
+//!
+//! ```rust
+//! use pqm_formatter::format_default;
+//!
+//! let code = "let\n    // pqmfmt: max_line_length=200\n    Codes = {\"north-east\", \"north-west\", \"south-east\", \"south-west\", \"central\", \"overseas\", \"unassigned\", \"retired\"},\n    Count = List.Count(Codes)\nin\n    Count";
+//! let formatted = format_default(code).unwrap();
+//! assert!(formatted.contains("    Codes = {\"north-east\","));
+//! ```
+//!
+//! Show warnings, such as an unknown `#keyword` kept as written, next to
+//! the result:
+//!
+//! ```rust
+//! use pqm_formatter::{format_with_diagnostics, Config};
+//!
+//! let (formatted, warnings) = format_with_diagnostics("#custom(1)", Config::default()).unwrap();
+//! assert_eq!(formatted, "#custom(1)\n");
+//! assert_eq!(warnings[0].code, "unknown-hash-keyword");
+//! ```
+//!
+//! Add a generated-by banner and Windows line endings to the output (see
+//! [`postprocess`]):
+//!
+//! ```rust
+//! use pqm_formatter::postprocess::{Banner, NewlinePolicy};
+//! use pqm_formatter::{Config, Formatter, NewlineKind, Parser, Lexer};
+//!
+//! let document = Parser::new(Lexer::new("let a = 1 in a").tokenize()).parse().unwrap();
+//! let mut formatter = Formatter::new(Config::compact());
+//! formatter.add_post_processor(Banner::new("Generated by sync-queries"));
+//! formatter.add_post_processor(NewlinePolicy { line_ending: NewlineKind::CrLf, final_newlines: 1 });
+//! assert_eq!(formatter.format(&document), "// Generated by sync-queries\r\nlet a = 1 in a\r\n");
+//! ```
+//!
+//! ## Determinism
+//!
+//! Output depends only on the input text and the [`Config`]: there is no
+//! hash-map iteration, locale, or platform-specific number printing involved.
+//! The `stability-tests` feature checks this against golden hashes of a corpus.
+//!
+//! ## Without std
+//!
+//! Without the default `std` feature the library needs only `core` and
+//! `alloc`, for build scripts, procedural macros, and targets without an
+//! operating system. Everything but `config::file`, which reads
+//! configuration files, and the process-wide `set_default_config` is
+//! available. The `pqmfmt` binary and the `dprint`, `parallel`, and
+//! `schema` features need std.
+//!
+//! ## Minimal builds
+//!
+//! Parts of the library that embedded builds, such as a Wasm playground,
+//! may not need are default features:
+//!
+//! - `alignment`: column alignment of list rows and step annotations
+//!   (`align_nested_lists`, `annotation_column`)
+//! - `wrapping`: hanging call arguments and filled lists (`call_wrap_style`,
+//!   `list_fill_style`)
+//! - `transforms`: the `transform`, `refactor`, and `diff` modules, and
+//!   `prefer_each`
+//! - `lints`: the `lint` module, lint warnings from the parser, and
+//!   `config::file` (implies `transforms`)
+//!
+//! With `default-features = false` the settings of a missing feature are
+//! accepted but have no effect, so a configuration gives the same output
+//! with any feature set apart from them. The `pqmfmt` binary needs `lints`.
+//! `ci/wasm-size.sh` checks the size of the minimal dprint plugin.
 
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+
+extern crate alloc;
+
+mod alloc_prelude;
+pub mod analysis;
 pub mod ast;
+pub mod compat;
 pub mod config;
+pub mod conflict;
+pub mod cst;
+pub mod detect;
+pub mod diagnostic;
+#[cfg(feature = "transforms")]
+pub mod diff;
+#[cfg(feature = "dprint")]
+pub mod dprint;
+mod equivalence;
+mod float;
 pub mod formatter;
+mod json;
 pub mod lexer;
+#[cfg(feature = "lints")]
+pub mod lint;
 pub mod parser;
+pub mod postprocess;
+pub mod prelude;
+#[cfg(feature = "transforms")]
+pub mod refactor;
+pub mod render;
+pub mod repair;
+pub mod report;
+pub mod strict;
+pub mod stream;
+pub mod text;
 pub mod token;
+#[cfg(feature = "transforms")]
+pub mod transform;
 
-pub use config::Config;
-pub use formatter::Formatter;
+pub use config::{
+    CallWrapStyle, CommentStyle, Config, Dialect, EachStyle, ListFillStyle, SectionAccessStyle, StyleEdition,
+};
+pub use diagnostic::{Diagnostic, Severity};
+pub use formatter::{fingerprint_text, FormatError, FormatStats, FormatWarning, Formatter, LayoutDecision};
 pub use lexer::Lexer;
-pub use parser::{ParseError, Parser};
+pub use parser::{NewlineKind, ParseError, ParseOptions, ParseOutput, Parser, StartRule};
+
+use crate::alloc_prelude::*;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// Format Power Query M code with the given configuration.
 ///
@@ -65,16 +200,211 @@ pub use parser::{ParseError, Parser};
 /// println!("{}", formatted);
 /// ```
 pub fn format(code: &str, config: Config) -> Result<String, Vec<ParseError>> {
+    format_with_diagnostics(code, config).map(|(formatted, _)| formatted)
+}
+
+/// Format Power Query M code and also return non-fatal diagnostics.
+///
+/// Warnings (for example an unknown `#keyword` kept verbatim, or invisible
+/// characters reported by [`lint::invisibles::check`]) do not prevent
+/// formatting; they are returned alongside the formatted code.
+///
+/// Like every `format*` function, this honors a `// pqmfmt: ...` directive
+/// on the first line of `code` (see [`config::directive`]).
+pub fn format_with_diagnostics(
+    code: &str,
+    mut config: Config,
+) -> Result<(String, Vec<Diagnostic>), Vec<ParseError>> {
+    let mut diagnostics = config::directive::apply(code, &mut config);
+    #[cfg(feature = "lints")]
+    diagnostics.extend(lint::invisibles::check(code));
+    let (document, warnings) = parse_document(code, &config)?;
+    diagnostics.extend(warnings);
+    let mut formatter = Formatter::with_source(config, code);
+    Ok((formatter.format(&document), diagnostics))
+}
+
+/// Format Power Query M code and measure the result, reporting lines the
+/// formatter could not keep within `max_line_length` (as scoped directives
+/// set it for each region) and identifiers too long for any line.
+///
+/// ```rust
+/// use pqm_formatter::{format_with_stats, Config, FormatWarning};
+///
+/// let mut config = Config::default();
+/// config.max_line_length = 30;
+/// let code = r#"Web.Contents("https://example.com/a/very/long/path")"#;
+/// let (_, stats) = format_with_stats(code, config).unwrap();
+/// assert!(matches!(stats.warnings[..], [FormatWarning::LineTooLong { line: 2, .. }]));
+/// ```
+pub fn format_with_stats(code: &str, mut config: Config) -> Result<(String, FormatStats), Vec<ParseError>> {
+    config::directive::apply(code, &mut config);
+    let (document, _) = parse_document(code, &config)?;
+    let mut formatter = Formatter::with_source(config, code);
+    let formatted = formatter.format(&document);
+    let stats = formatter.stats(&formatted);
+    Ok((formatted, stats))
+}
+
+/// Parse `code` the way [`format`] would for `config`.
+///
+/// For callers that rewrite the AST (see [`transform`]) before handing it to
+/// a [`Formatter`], or that quote the original text of nodes
+/// ([`ast::Document::source_of`]). Non-fatal diagnostics are returned with
+/// the document. A first-line [`config::directive`] is not applied; callers
+/// that honor it apply it to `config` first.
+pub fn parse_document(
+    code: &str,
+    config: &Config,
+) -> Result<(ast::Document, Vec<Diagnostic>), Vec<ParseError>> {
     let mut lexer = Lexer::new(code);
     let tokens = lexer.tokenize();
     
-    let mut parser = Parser::new(tokens);
-    let document = parser.parse()?;
+    let mut parser = Parser::with_options(tokens, parse_options_for(config));
+    let mut document = parser.parse()?;
+    document.source = code.to_string();
+    Ok((document, parser.warnings().to_vec()))
+}
+
+/// Parser options implied by a formatter configuration
+pub(crate) fn parse_options_for(config: &Config) -> ParseOptions {
+    ParseOptions {
+        lenient: config.lenient,
+        strict: config.strict,
+        dialect: config.dialect,
+        ..ParseOptions::default()
+    }
+}
+
+/// Format many independent pieces of code with a shared configuration.
+///
+/// Intended for services that format large catalogs of stored queries: the
+/// formatter and its output buffer are reused across items. With the
+/// `parallel` feature, items are formatted on the rayon thread pool. Results
+/// are returned in input order either way.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{format_many, Config};
+///
+/// let queries = ["let a=1 in a", "{1,2,3}"];
+/// let results = format_many(queries, Config::default());
+/// assert!(results.iter().all(|r| r.is_ok()));
+/// ```
+pub fn format_many<'a>(
+    items: impl IntoIterator<Item = &'a str>,
+    config: Config,
+) -> Vec<Result<String, FormatError>> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        
+        let items: Vec<&str> = items.into_iter().collect();
+        items
+            .par_iter()
+            .map_init(
+                || Formatter::new(config.clone()),
+                |formatter, code| format_reusing(formatter, code, &config),
+            )
+            .collect()
+    }
     
-    let mut formatter = Formatter::new(config);
+    #[cfg(not(feature = "parallel"))]
+    {
+        let mut formatter = Formatter::new(config.clone());
+        items
+            .into_iter()
+            .map(|code| format_reusing(&mut formatter, code, &config))
+            .collect()
+    }
+}
+
+/// Format one item with an existing formatter
+fn format_reusing<'a>(
+    formatter: &mut Formatter<'a>,
+    code: &'a str,
+    config: &Config,
+) -> Result<String, FormatError> {
+    // A file with its own settings needs a formatter of its own
+    if config::directive::find(code).is_some() {
+        let mut config = config.clone();
+        config::directive::apply(code, &mut config);
+        let tokens = Lexer::new(code).tokenize();
+        let document = Parser::with_options(tokens, parse_options_for(&config)).parse()?;
+        return Ok(Formatter::with_source(config, code).format(&document));
+    }
+    
+    let tokens = Lexer::new(code).tokenize();
+    let document = Parser::with_options(tokens, parse_options_for(config)).parse()?;
+    formatter.set_source(code);
     Ok(formatter.format(&document))
 }
 
+/// Fingerprint of the formatted form of `code`, for cache layers.
+///
+/// Equal to `fingerprint_text(&format(code, config)?)`, but the formatted
+/// string is streamed into the hasher instead of being built, so services can
+/// cheaply check whether a stored query's canonical form has changed.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{fingerprint_text, format, format_fingerprint, Config};
+///
+/// let code = "let x=1 in x";
+/// let formatted = format(code, Config::default()).unwrap();
+/// let hash = format_fingerprint(code, Config::default()).unwrap();
+/// assert_eq!(hash, fingerprint_text(&formatted));
+/// ```
+pub fn format_fingerprint(code: &str, mut config: Config) -> Result<u64, FormatError> {
+    config::directive::apply(code, &mut config);
+    let tokens = Lexer::new(code).tokenize();
+    let document = Parser::with_options(tokens, parse_options_for(&config)).parse()?;
+    Ok(Formatter::with_source(config, code).fingerprint(&document))
+}
+
+/// Format Power Query M code, giving up when `token` is set.
+///
+/// For hosts such as language servers that must not block on adversarial
+/// or enormous inputs: another thread (or a timer) sets `token` to abort,
+/// and this returns [`FormatError::Cancelled`] shortly after. The token is
+/// checked after lexing and throughout parsing (recovery included) and
+/// formatting. Parsing or formatting that takes far more work than the size
+/// of the document warrants stops with [`FormatError::TooComplex`].
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{format_with_cancellation, Config, FormatError};
+/// use std::sync::atomic::AtomicBool;
+///
+/// let token = AtomicBool::new(false);
+/// let formatted = format_with_cancellation("let x=1 in x", Config::default(), &token).unwrap();
+/// assert_eq!(formatted, "let\n    x = 1\nin\n    x\n");
+///
+/// let cancelled = AtomicBool::new(true);
+/// let result = format_with_cancellation("let x=1 in x", Config::default(), &cancelled);
+/// assert!(matches!(result, Err(FormatError::Cancelled)));
+/// ```
+pub fn format_with_cancellation(code: &str, mut config: Config, token: &AtomicBool) -> Result<String, FormatError> {
+    let cancelled = || token.load(Ordering::Relaxed);
+    config::directive::apply(code, &mut config);
+    if cancelled() {
+        return Err(FormatError::Cancelled);
+    }
+    let tokens = Lexer::new(code).tokenize();
+    if cancelled() {
+        return Err(FormatError::Cancelled);
+    }
+    let mut parser = Parser::with_options(tokens, parse_options_for(&config));
+    parser.set_cancellation(token);
+    let document = parser.try_parse()?;
+    let mut formatter = Formatter::with_source(config, code);
+    formatter.set_cancellation(token);
+    formatter.try_format(&document)
+}
+
 /// Format Power Query M code with default configuration.
 ///
 /// Convenience function equivalent to `format(code, Config::default())`.
@@ -82,6 +412,50 @@ pub fn format_default(code: &str) -> Result<String, Vec<ParseError>> {
     format(code, Config::default())
 }
 
+/// Configuration set with [`set_default_config`]
+#[cfg(feature = "std")]
+static DEFAULT_CONFIG: std::sync::OnceLock<Config> = std::sync::OnceLock::new();
+
+/// Set the configuration [`format_with_default`] uses, once per process.
+///
+/// For host applications that configure the formatter at startup and format
+/// from many places without passing a [`Config`] around. The configuration
+/// cannot be changed later: if one was already set, it stays and `config`
+/// is returned as the error. Calls that need other settings pass their own
+/// [`Config`] to [`format`].
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{format_with_default, set_default_config, Config};
+///
+/// set_default_config(Config::compact()).unwrap();
+/// assert_eq!(format_with_default("let x=1 in x").unwrap(), "let x = 1 in x\n");
+///
+/// // Other threads see the same configuration
+/// let formatted = std::thread::spawn(|| format_with_default("[A=1]").unwrap()).join().unwrap();
+/// assert_eq!(formatted, "[A = 1]\n");
+/// assert!(set_default_config(Config::expanded()).is_err());
+/// ```
+#[cfg(feature = "std")]
+pub fn set_default_config(config: Config) -> Result<(), Config> {
+    DEFAULT_CONFIG.set(config)
+}
+
+/// The configuration set with [`set_default_config`], or
+/// [`Config::default`] if none was set
+#[cfg(feature = "std")]
+pub fn default_config() -> Config {
+    DEFAULT_CONFIG.get().cloned().unwrap_or_default()
+}
+
+/// Format Power Query M code with the configuration set by
+/// [`set_default_config`], or the default configuration if none was set.
+#[cfg(feature = "std")]
+pub fn format_with_default(code: &str) -> Result<String, Vec<ParseError>> {
+    format(code, default_config())
+}
+
 /// Validate Power Query M code syntax without formatting.
 ///
 /// # Returns
@@ -97,6 +471,104 @@ pub fn validate(code: &str) -> Result<(), Vec<ParseError>> {
     Ok(())
 }
 
+/// Validate like [`validate`], and also reject constructs the Power Query
+/// engine rejects even though they parse (see [`strict::check`]), so a
+/// successful result can be trusted before pasting code into Power BI.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{validate, validate_strict};
+///
+/// assert!(validate("[Name = 1, type = 2]").is_ok());
+/// assert!(validate_strict("[Name = 1, type = 2]").is_err());
+/// assert!(validate_strict("[Name = 1, #\"type\" = 2]").is_ok());
+/// ```
+pub fn validate_strict(code: &str) -> Result<(), Vec<ParseError>> {
+    let options = ParseOptions {
+        strict: true,
+        ..ParseOptions::default()
+    };
+    Parser::with_options(Lexer::new(code).tokenize(), options).parse()?;
+    Ok(())
+}
+
+/// Whether two pieces of code parse to the same tree.
+///
+/// The trees are compared node by node, ignoring layout, comments, and how
+/// names and literals are spelled (`#"Name"` and `Name`, `0x10` and `16`),
+/// so this is true exactly when the two differ only in ways a formatter
+/// may change.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::semantically_equal;
+///
+/// assert!(semantically_equal("let x=1 in x", "let\n    x = 1 // one\nin\n    x").unwrap());
+/// assert!(!semantically_equal("let x=1 in x", "let x=2 in x").unwrap());
+/// ```
+pub fn semantically_equal(a: &str, b: &str) -> Result<bool, Vec<ParseError>> {
+    let (a, _) = parse_document(a, &Config::default())?;
+    let (b, _) = parse_document(b, &Config::default())?;
+    Ok(equivalence::same_document(&a, &b))
+}
+
+/// Split text copied from the Power Query editor with several queries
+/// selected into `(name, code)` pairs.
+///
+/// Each query is introduced by a `// Name` header line at the start of the
+/// text or after a blank line. Returns `None` unless there are at least two
+/// such queries, so a single query that merely starts with a comment is not
+/// split.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::split_copied_queries;
+///
+/// let copied = "// Sales\nlet a = 1 in a\n\n// Costs\nlet b = 2 in b\n";
+/// let queries = split_copied_queries(copied).unwrap();
+/// assert_eq!(queries, [("Sales", "let a = 1 in a\n\n"), ("Costs", "let b = 2 in b\n")]);
+/// assert!(split_copied_queries("// Sales\nlet a = 1 in a").is_none());
+/// ```
+pub fn split_copied_queries(text: &str) -> Option<Vec<(&str, &str)>> {
+    // (name, offset of the line after the header, offset of the header)
+    let mut headers = Vec::new();
+    let mut offset = 0;
+    let mut after_blank = true;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+        if after_blank && content.starts_with("//") {
+            let name = content[2..].trim();
+            if !name.is_empty() {
+                headers.push((name, offset + line.len(), offset));
+            }
+        }
+        after_blank = content.trim().is_empty();
+        offset += line.len();
+    }
+    
+    // Anything before the first header means this is not a query list
+    let first = headers.first()?;
+    if headers.len() < 2 || !text[..first.2].trim().is_empty() {
+        return None;
+    }
+    
+    let queries: Vec<(&str, &str)> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, &(name, start, _))| {
+            let end = headers.get(i + 1).map_or(text.len(), |next| next.2);
+            (name, &text[start..end])
+        })
+        .collect();
+    if queries.iter().any(|(_, code)| code.trim().is_empty()) {
+        return None;
+    }
+    Some(queries)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -156,6 +628,99 @@ in
         assert!(result.is_ok());
     }
     
+    #[test]
+    fn test_split_copied_queries() {
+        let copied = "// Sales\r\nlet\r\n    // keep\r\n    a = 1\r\nin\r\n    a\r\n\r\n// Costs\r\n1\r\n";
+        let queries = split_copied_queries(copied).unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].0, "Sales");
+        assert!(queries[0].1.contains("// keep"));
+        assert_eq!(queries[1], ("Costs", "1\r\n"));
+        
+        // Blank lines between bindings are not query boundaries
+        assert!(split_copied_queries("let\n    a = 1,\n\n    // b\n    b = 2\nin\n    b").is_none());
+        // Code before the first header
+        assert!(split_copied_queries("1\n\n// A\n2\n\n// B\n3").is_none());
+    }
+    
+    #[test]
+    fn test_format_honors_directive() {
+        let code = "// pqmfmt: indent_size=2\nlet a = 1 in a";
+        let expected = "// pqmfmt: indent_size=2\nlet\n  a = 1\nin\n  a\n";
+        assert_eq!(format_default(code).unwrap(), expected);
+        assert_eq!(format_many([code, "{}"], Config::default())[0].as_deref().unwrap(), expected);
+        assert_eq!(
+            format_fingerprint(code, Config::default()).unwrap(),
+            fingerprint_text(expected)
+        );
+    }
+    
+    #[test]
+    fn test_scoped_directive() {
+        let code = "let\n    // pqmfmt: max_line_length=20\n    A = {1, 2, 3, 4, 5, 6, 7},\n    B = {1, 2, 3, 4, 5, 6, 7}\nin\n    A";
+        let expected = "let\n    // pqmfmt: max_line_length=20\n    A = \n        {\n            1,\n            2,\n            3,\n            4,\n            5,\n            6,\n            7\n        },\n    B = {1, 2, 3, 4, 5, 6, 7}\nin\n    A\n";
+        assert_eq!(format_default(code).unwrap(), expected);
+        
+        // A nested directive applies on top of the enclosing one
+        let code = "// pqmfmt: max_line_length=30\n[\n    // pqmfmt: max_line_length=200\n    A = [\n        // pqmfmt: trailing_comma=true\n        B = {\"a long text\", \"another long text\"},\n        C = 1\n    ],\n    D = {\"a long text\", \"another long text\"}\n]";
+        let formatted = format_default(code).unwrap();
+        assert!(formatted.contains("    B = {\"a long text\", \"another long text\"},\n"), "{}", formatted);
+        assert!(formatted.contains("\n    D = \n        {\n"), "{}", formatted);
+        
+        // Long lines are measured against the limit of their region
+        let code = "[\n    // pqmfmt: max_line_length=200\n    A = {\"a long text\", \"another long text\"},\n    B = \"a text much longer than the limit\"\n]";
+        let config = Config { max_line_length: 40, ..Config::default() };
+        let (formatted, stats) = format_with_stats(code, config).unwrap();
+        assert!(formatted.contains("    A = {\"a long text\", \"another long text\"},\n"), "{}", formatted);
+        assert!(matches!(stats.warnings[..], [FormatWarning::LineTooLong { line: 5, .. }]), "{:?}", stats.warnings);
+        
+        let (_, warnings) = format_with_diagnostics("[\n    // pqmfmt: max_line_length=x\n    A = 1\n]", Config::default()).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, "invalid-directive");
+        assert_eq!((warnings[0].span.line, warnings[0].span.column), (2, 16));
+    }
+    
+    #[test]
+    fn test_format_many() {
+        let items = ["let x=1 in x", "let x = in x", "{1,2}"];
+        let results = format_many(items.iter().copied(), Config::default());
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &format_default(items[0]).unwrap());
+        assert!(matches!(results[1], Err(FormatError::Parse(_))));
+        assert_eq!(results[2].as_ref().unwrap(), "{1, 2}\n");
+    }
+    
+    #[test]
+    fn test_format_fingerprint() {
+        let code = "let\n    // note\n    x = [A=1, B={1,2}]\nin\n    x";
+        let formatted = format_default(code).unwrap();
+        let hash = format_fingerprint(code, Config::default()).unwrap();
+        assert_eq!(hash, fingerprint_text(&formatted));
+        
+        // Same canonical form, same fingerprint
+        let reformatted = format_fingerprint(&formatted, Config::default()).unwrap();
+        assert_eq!(hash, reformatted);
+        assert_ne!(hash, format_fingerprint(code, Config::compact()).unwrap());
+    }
+    
+    #[test]
+    fn test_semantically_equal() {
+        let code = "let\r\n    // note\r\n    x = {1,2} in x";
+        let formatted = format_default(code).unwrap();
+        assert!(semantically_equal(code, &formatted).unwrap());
+        assert!(!semantically_equal(code, "let x = {1, 2, 3} in x").unwrap());
+        assert!(semantically_equal(code, "let x = in x").is_err());
+    }
+    
+    #[test]
+    fn test_thread_safety() {
+        fn send<T: Send>() {}
+        fn send_sync<T: Send + Sync>() {}
+        send::<Formatter<'static>>();
+        send_sync::<Config>();
+        send_sync::<ast::Document>();
+    }
+    
     #[test]
     fn test_compact_mode() {
         let code = "let x = 1, y = 2 in x + y";
@@ -0,0 +1,334 @@
+//! Checks for code that parses but fails when the query is refreshed
+//!
+//! Out-of-range literal arguments to `#date`, `#time`, `#datetime` and
+//! `#datetimezone` (`#date(2024, 13, 1)`, a typo for `#date(2024, 1, 13)`)
+//! are only rejected by the mashup engine when the query is evaluated.
+//! `#duration` accepts any numbers (`#duration(0, 36, 0, 0)` is a day and a
+//! half), so it is not checked.
+
+use crate::alloc_prelude::*;
+use crate::ast::{Document, Expr, ExprKind, UnaryOp};
+use crate::config::{directive, Config};
+use crate::diagnostic::Diagnostic;
+use crate::float;
+use crate::parser::ParseError;
+use crate::token::Span;
+
+pub mod columns;
+pub mod invisibles;
+pub mod names;
+pub mod policy;
+
+/// A check supplied by the caller, such as [`columns::ColumnSchema`], run on
+/// parsed documents in addition to the built-in ones.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::ast::{Document, ExprKind};
+/// use pqm_formatter::diagnostic::Diagnostic;
+/// use pqm_formatter::lint::Validator;
+/// use pqm_formatter::{Lexer, Parser};
+///
+/// struct NoWebSources;
+///
+/// impl Validator for NoWebSources {
+///     fn validate(&self, document: &Document) -> Vec<Diagnostic> {
+///         let mut diagnostics = Vec::new();
+///         document.expression.walk(&mut |expr| {
+///             if matches!(&expr.kind, ExprKind::Identifier(name) if name == "Web.Contents") {
+///                 diagnostics.push(Diagnostic::warning("web-source", "Web sources are not allowed", expr.span));
+///             }
+///         });
+///         diagnostics
+///     }
+/// }
+///
+/// let document = Parser::new(Lexer::new("Web.Contents(Url)").tokenize()).parse().unwrap();
+/// assert_eq!(NoWebSources.validate(&document)[0].code, "web-source");
+/// ```
+pub trait Validator {
+    fn validate(&self, document: &Document) -> Vec<Diagnostic>;
+}
+
+/// A lint rule shipped outside this crate, such as an organization's naming
+/// conventions or banned functions. Rules are [`Validator`]s, so they also
+/// run wherever validators do; [`lint_with_rules`] runs them with the
+/// built-in checks.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::ast::{Document, ExprKind};
+/// use pqm_formatter::diagnostic::Diagnostic;
+/// use pqm_formatter::lint::{lint_with_rules, LintRule};
+///
+/// /// `Web.Contents` with a single argument, so without `RelativePath`
+/// struct WebContentsRelativePath;
+///
+/// impl LintRule for WebContentsRelativePath {
+///     fn name(&self) -> &str {
+///         "Web.Contents needs RelativePath"
+///     }
+///
+///     fn code(&self) -> &'static str {
+///         "web-contents-relative-path"
+///     }
+///
+///     fn check(&self, document: &Document, diagnostics: &mut Vec<Diagnostic>) {
+///         document.expression.walk(&mut |expr| {
+///             let ExprKind::FunctionCall(call) = &expr.kind else { return };
+///             if matches!(&call.function.kind, ExprKind::Identifier(f) if f == "Web.Contents")
+///                 && call.arguments.len() < 2
+///             {
+///                 let message = "Web.Contents without options cannot be refreshed in the service";
+///                 diagnostics.push(Diagnostic::warning(self.code(), message, expr.span));
+///             }
+///         });
+///     }
+/// }
+///
+/// let diagnostics = lint_with_rules("Web.Contents(\"https://example.com/api\")", &[&WebContentsRelativePath]).unwrap();
+/// assert_eq!(diagnostics[0].code, "web-contents-relative-path");
+/// ```
+pub trait LintRule {
+    /// Human-readable name of the rule
+    fn name(&self) -> &str;
+    
+    /// Code of the diagnostics the rule reports
+    fn code(&self) -> &'static str;
+    
+    /// Add the rule's diagnostics about `document` to `diagnostics`
+    fn check(&self, document: &Document, diagnostics: &mut Vec<Diagnostic>);
+}
+
+impl<R: LintRule> Validator for R {
+    fn validate(&self, document: &Document) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        self.check(document, &mut diagnostics);
+        diagnostics
+    }
+}
+
+/// Diagnostics about `code` from the built-in checks (those
+/// [`format_with_diagnostics`](crate::format_with_diagnostics) reports,
+/// [`check`] among them) and from `rules`, in source order. A first-line
+/// [`directive`] applies, as when formatting.
+pub fn lint_with_rules(code: &str, rules: &[&dyn LintRule]) -> Result<Vec<Diagnostic>, Vec<ParseError>> {
+    let mut config = Config::default();
+    let mut diagnostics = directive::apply(code, &mut config);
+    diagnostics.extend(invisibles::check(code));
+    let (document, warnings) = crate::parse_document(code, &config)?;
+    diagnostics.extend(warnings);
+    for rule in rules {
+        rule.check(&document, &mut diagnostics);
+    }
+    diagnostics.sort_by_key(|d| d.span.start);
+    Ok(diagnostics)
+}
+
+/// Report literal date/time constructor arguments out of range.
+///
+/// Only number literals are checked; an argument computed at refresh time
+/// cannot be. Every diagnostic uses the code `datetime-out-of-range`.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{lint, Lexer, Parser};
+///
+/// let document = Parser::new(Lexer::new("#time(9, 75, 0)").tokenize()).parse().unwrap();
+/// let diagnostics = lint::check(&document);
+/// assert_eq!(diagnostics[0].message, "#time minute 75 is out of range (0 to 59)");
+/// ```
+pub fn check(document: &Document) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    document.expression.walk(&mut |expr| match &expr.kind {
+        ExprKind::HashDate(d) => {
+            check_date("#date", &d.year, &d.month, &d.day, &mut diagnostics);
+        }
+        ExprKind::HashTime(t) => {
+            check_time("#time", &t.hour, &t.minute, &t.second, &mut diagnostics);
+        }
+        ExprKind::HashDatetime(d) => {
+            check_date("#datetime", &d.year, &d.month, &d.day, &mut diagnostics);
+            check_time("#datetime", &d.hour, &d.minute, &d.second, &mut diagnostics);
+        }
+        ExprKind::HashDatetimezone(d) => {
+            let name = "#datetimezone";
+            check_date(name, &d.year, &d.month, &d.day, &mut diagnostics);
+            check_time(name, &d.hour, &d.minute, &d.second, &mut diagnostics);
+            let hours = check_range(name, "offset hours", &d.offset_hours, -14.0, 14.0, &mut diagnostics);
+            let minutes = check_range(name, "offset minutes", &d.offset_minutes, -59.0, 59.0, &mut diagnostics);
+            if let (Some(hours), Some(minutes)) = (hours, minutes) {
+                if (hours * 60.0 + minutes).abs() > 14.0 * 60.0 {
+                    diagnostics.push(out_of_range(
+                        format!("{} offset is beyond ±14 hours", name),
+                        d.offset_hours.span.merge(d.offset_minutes.span),
+                    ));
+                }
+            }
+        }
+        _ => {}
+    });
+    diagnostics
+}
+
+fn check_date(name: &str, year: &Expr, month: &Expr, day: &Expr, diagnostics: &mut Vec<Diagnostic>) {
+    let year = check_range(name, "year", year, 1.0, 9999.0, diagnostics);
+    let month = check_range(name, "month", month, 1.0, 12.0, diagnostics);
+    let days = match (year, month) {
+        (Some(year), Some(month)) => days_in_month(year as i64, month as u32),
+        _ => 31,
+    };
+    check_range(name, "day", day, 1.0, days as f64, diagnostics);
+}
+
+fn check_time(name: &str, hour: &Expr, minute: &Expr, second: &Expr, diagnostics: &mut Vec<Diagnostic>) {
+    let hour_value = check_range(name, "hour", hour, 0.0, 24.0, diagnostics);
+    let minute_value = check_range(name, "minute", minute, 0.0, 59.0, diagnostics);
+    let second_value = literal(second);
+    match second_value {
+        Some(value) if !(0.0..60.0).contains(&value) => {
+            diagnostics.push(out_of_range(
+                format!("{} second {} is out of range (0 to less than 60)", name, value),
+                second.span,
+            ));
+        }
+        _ => {}
+    }
+    // 24 is only accepted for the end of the day, 24:00:00
+    if hour_value == Some(24.0) && (minute_value.unwrap_or(0.0) != 0.0 || second_value.unwrap_or(0.0) != 0.0) {
+        diagnostics.push(out_of_range(
+            format!("{} hour 24 is only valid as 24:00:00", name),
+            hour.span,
+        ));
+    }
+}
+
+/// Report `expr` if it is a literal other than a whole number in
+/// `min..=max`; the value if it is a literal in range
+fn check_range(
+    name: &str,
+    what: &str,
+    expr: &Expr,
+    min: f64,
+    max: f64,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<f64> {
+    let value = literal(expr)?;
+    if value < min || value > max || !float::is_whole(value) {
+        diagnostics.push(out_of_range(
+            format!("{} {} {} is out of range ({} to {})", name, what, value, min, max),
+            expr.span,
+        ));
+        return None;
+    }
+    Some(value)
+}
+
+/// Value of a number literal, possibly negated
+fn literal(expr: &Expr) -> Option<f64> {
+    match &expr.kind {
+        ExprKind::Number(n) => Some(n.value),
+        ExprKind::Unary(u) if u.operator == UnaryOp::Negate => literal(&u.operand).map(|n| -n),
+        ExprKind::Unary(u) if u.operator == UnaryOp::Positive => literal(&u.operand),
+        ExprKind::Parenthesized(inner) => literal(inner),
+        _ => None,
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn out_of_range(message: String, span: Span) -> Diagnostic {
+    Diagnostic::warning("datetime-out-of-range", message, span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    
+    fn messages(code: &str) -> Vec<String> {
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        check(&document).into_iter().map(|d| d.message).collect()
+    }
+    
+    struct Banned(&'static str);
+    
+    impl LintRule for Banned {
+        fn name(&self) -> &str {
+            self.0
+        }
+        
+        fn code(&self) -> &'static str {
+            "banned-function"
+        }
+        
+        fn check(&self, document: &Document, diagnostics: &mut Vec<Diagnostic>) {
+            document.expression.walk(&mut |expr| {
+                if matches!(&expr.kind, ExprKind::Identifier(name) if name == self.0) {
+                    diagnostics.push(Diagnostic::warning(self.code(), format!("{} is banned", self.0), expr.span));
+                }
+            });
+        }
+    }
+    
+    #[test]
+    fn test_lint_with_rules() {
+        let code = "{Web.Contents(Url), #date(2024, 13, 1), Sql.Database(Server, Db)}";
+        let diagnostics = lint_with_rules(code, &[&Banned("Sql.Database"), &Banned("Web.Contents")]).unwrap();
+        let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, ["banned-function", "datetime-out-of-range", "banned-function"]);
+        assert!(lint_with_rules("{1,", &[]).is_err());
+        
+        // Rules are validators too
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        assert_eq!(Banned("Web.Contents").validate(&document).len(), 1);
+    }
+    
+    #[test]
+    fn test_dates() {
+        assert!(messages("{#date(2024, 2, 29), #datetime(2023, 12, 31, 23, 59, 59.5)}").is_empty());
+        assert_eq!(
+            messages("{#date(2024, 13, 1), #date(2023, 2, 29), #date(0, 1, 1)}"),
+            [
+                "#date month 13 is out of range (1 to 12)",
+                "#date day 29 is out of range (1 to 28)",
+                "#date year 0 is out of range (1 to 9999)",
+            ]
+        );
+    }
+    
+    #[test]
+    fn test_times() {
+        assert_eq!(messages("{#time(24, 0, 0), #time(Hour, 75, 0)}").len(), 1);
+        assert_eq!(
+            messages("{#time(9, 75, 0), #time(24, 0, 1), #time(1, 2, 60)}"),
+            [
+                "#time minute 75 is out of range (0 to 59)",
+                "#time hour 24 is only valid as 24:00:00",
+                "#time second 60 is out of range (0 to less than 60)",
+            ]
+        );
+    }
+    
+    #[test]
+    fn test_offsets() {
+        assert!(messages("#datetimezone(2024, 1, 1, 0, 0, 0, -14, 0)").is_empty());
+        assert_eq!(
+            messages("{#datetimezone(2024, 1, 1, 0, 0, 0, 15, 0), #datetimezone(2024, 1, 1, 0, 0, 0, 14, 30)}"),
+            [
+                "#datetimezone offset hours 15 is out of range (-14 to 14)",
+                "#datetimezone offset is beyond ±14 hours",
+            ]
+        );
+    }
+}
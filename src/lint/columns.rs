@@ -0,0 +1,246 @@
+//! Column names checked against a known schema
+//!
+//! A column renamed at the source breaks every step that refers to the old
+//! name, but only when the query is refreshed. Given the columns the
+//! sources provide, [`ColumnSchema`] reports the names a query uses that
+//! are not among them: row field accesses (`each [Amount]`, `_[Amount]`)
+//! and the column name literals passed to `Table.SelectColumns`,
+//! `Table.RemoveColumns`, and `Table.RenameColumns`. Columns the query adds
+//! itself (`Table.AddColumn`, the new names of `Table.RenameColumns`, ...)
+//! count as known.
+
+use alloc::collections::BTreeSet;
+
+use super::Validator;
+use crate::alloc_prelude::*;
+use crate::ast::{Document, Expr, ExprKind};
+use crate::diagnostic::Diagnostic;
+use crate::json::{self, JsonValue};
+use crate::token::Span;
+
+/// Functions whose second argument lists existing columns
+const COLUMN_LIST_FUNCTIONS: &[&str] = &["Table.SelectColumns", "Table.RemoveColumns", "Table.ReorderColumns"];
+
+/// Functions that add a column, with the position of its name argument
+const ADDED_COLUMN_FUNCTIONS: &[(&str, usize)] = &[
+    ("Table.AddColumn", 1),
+    ("Table.AddIndexColumn", 1),
+    ("Table.DuplicateColumn", 2),
+    ("Table.ExpandRecordColumn", 2),
+    ("Table.ExpandRecordColumn", 3),
+    ("Table.ExpandTableColumn", 2),
+    ("Table.ExpandTableColumn", 3),
+];
+
+/// Known column names, usually exported from the data model
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnSchema {
+    columns: BTreeSet<String>,
+}
+
+impl ColumnSchema {
+    pub fn new<I, S>(columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        ColumnSchema {
+            columns: columns.into_iter().map(Into::into).collect(),
+        }
+    }
+    
+    /// Read a schema from JSON: an array of column names, or an object
+    /// mapping table names to such arrays (all of whose columns are known).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use pqm_formatter::lint::columns::ColumnSchema;
+    ///
+    /// let schema = ColumnSchema::from_json(r#"{"Sales": ["Amount"], "Costs": ["Cost"]}"#).unwrap();
+    /// assert!(schema.contains("Amount") && schema.contains("Cost"));
+    /// assert!(ColumnSchema::from_json("[1]").is_err());
+    /// ```
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        fn names(value: &JsonValue, columns: &mut BTreeSet<String>) -> Result<(), String> {
+            let JsonValue::Array(items) = value else {
+                return Err("expected an array of column names".to_string());
+            };
+            for item in items {
+                match item {
+                    JsonValue::String(name) => columns.insert(name.clone()),
+                    _ => return Err("column names must be strings".to_string()),
+                };
+            }
+            Ok(())
+        }
+        
+        let value = json::parse(text)?;
+        let mut columns = BTreeSet::new();
+        match &value {
+            JsonValue::Object(tables) => {
+                for (table, value) in tables {
+                    names(value, &mut columns).map_err(|e| format!("table \"{}\": {}", table, e))?;
+                }
+            }
+            value => names(value, &mut columns)?,
+        }
+        Ok(ColumnSchema { columns })
+    }
+    
+    pub fn contains(&self, column: &str) -> bool {
+        self.columns.contains(column)
+    }
+}
+
+impl Validator for ColumnSchema {
+    /// Report column names not in the schema, with the code `unknown-column`.
+    /// Optional field accesses (`[Amount]?`) are not reported.
+    fn validate(&self, document: &Document) -> Vec<Diagnostic> {
+        let mut added = BTreeSet::new();
+        document.expression.walk(&mut |expr| {
+            let Some((function, arguments)) = call(expr) else {
+                return;
+            };
+            for (name, position) in ADDED_COLUMN_FUNCTIONS {
+                if function == *name {
+                    if let Some(argument) = arguments.get(*position) {
+                        added.extend(texts(argument).into_iter().map(|(text, _)| text.to_string()));
+                    }
+                }
+            }
+            if function == "Table.RenameColumns" {
+                if let Some(argument) = arguments.get(1) {
+                    for (_, new) in rename_pairs(argument) {
+                        added.extend(texts(new).into_iter().map(|(text, _)| text.to_string()));
+                    }
+                }
+            }
+        });
+        let known = |name: &str| self.columns.contains(name) || added.contains(name);
+        
+        let mut diagnostics = Vec::new();
+        let mut report = |name: &str, span: Span| {
+            if !known(name) {
+                diagnostics.push(Diagnostic::warning(
+                    "unknown-column",
+                    format!("Column \"{}\" is not in the schema", name),
+                    span,
+                ));
+            }
+        };
+        document.expression.walk(&mut |expr| match &expr.kind {
+            ExprKind::FieldAccess(access) if !access.optional && matches!(access.expr.kind, ExprKind::Underscore) => {
+                report(&access.field.name, access.field.span);
+            }
+            ExprKind::FieldProjection(projection)
+                if !projection.optional && matches!(projection.expr.kind, ExprKind::Underscore) =>
+            {
+                for field in &projection.fields {
+                    report(&field.name, field.span);
+                }
+            }
+            _ => {
+                let Some((function, arguments)) = call(expr) else {
+                    return;
+                };
+                let Some(argument) = arguments.get(1) else {
+                    return;
+                };
+                if COLUMN_LIST_FUNCTIONS.contains(&function) {
+                    for (name, span) in texts(argument) {
+                        report(name, span);
+                    }
+                } else if function == "Table.RenameColumns" {
+                    for (old, _) in rename_pairs(argument) {
+                        for (name, span) in texts(old) {
+                            report(name, span);
+                        }
+                    }
+                }
+            }
+        });
+        diagnostics
+    }
+}
+
+/// Name and arguments of a call to a library function
+fn call(expr: &Expr) -> Option<(&str, &[Expr])> {
+    match &expr.kind {
+        ExprKind::FunctionCall(call) => match &call.function.kind {
+            ExprKind::Identifier(name) => Some((name, &call.arguments)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Text literals of a column name argument: a single text or a list of them
+fn texts(expr: &Expr) -> Vec<(&str, Span)> {
+    match &expr.kind {
+        ExprKind::Text(text) => vec![(text.as_str(), expr.span)],
+        ExprKind::List(list) => list
+            .items
+            .iter()
+            .filter_map(|item| match &item.kind {
+                ExprKind::Text(text) => Some((text.as_str(), item.span)),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `(old, new)` pairs of a `Table.RenameColumns` argument: `{"a", "b"}` or
+/// `{{"a", "b"}, {"c", "d"}}`
+fn rename_pairs(expr: &Expr) -> Vec<(&Expr, &Expr)> {
+    let ExprKind::List(list) = &expr.kind else {
+        return Vec::new();
+    };
+    match list.items.as_slice() {
+        [old, new] if matches!(old.kind, ExprKind::Text(_)) => vec![(old, new)],
+        items => items.iter().filter_map(pair).collect(),
+    }
+}
+
+fn pair(expr: &Expr) -> Option<(&Expr, &Expr)> {
+    match &expr.kind {
+        ExprKind::List(pair) if pair.items.len() == 2 => Some((&pair.items[0], &pair.items[1])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    
+    fn unknown(code: &str) -> Vec<String> {
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let schema = ColumnSchema::new(["Amount", "Region", "Date"]);
+        schema.validate(&document).into_iter().map(|d| d.message).collect()
+    }
+    
+    #[test]
+    fn test_field_access() {
+        let code = "Table.SelectRows(Source, each [Amount] > 0 and _[Regoin] = \"EU\" and [Note]? <> null)";
+        assert_eq!(unknown(code), ["Column \"Regoin\" is not in the schema"]);
+        // Record fields are not columns
+        assert!(unknown("let r = [Delimiter = \",\"] in r[Delimiter]").is_empty());
+    }
+    
+    #[test]
+    fn test_column_arguments() {
+        let code = r#"let
+    Selected = Table.SelectColumns(Source, {"Amount", "Amt"}),
+    Renamed = Table.RenameColumns(Selected, {{"Amount", "Total"}, {"Cost", "Price"}}),
+    Added = Table.AddColumn(Renamed, "Double", each [Total] * 2),
+    Kept = Table.RemoveColumns(Added, "Double")
+in
+    Kept"#;
+        assert_eq!(
+            unknown(code),
+            ["Column \"Amt\" is not in the schema", "Column \"Cost\" is not in the schema"]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Invisible characters and mixed indentation
+//!
+//! Code copied from web pages and chat tools often carries characters that
+//! look like ordinary spacing: non-breaking spaces (U+00A0) where a space
+//! belongs, or zero-width spaces inside names. M rejects the first and
+//! treats the second as part of the name, so the query fails although it
+//! reads correctly in every editor. [`check`] reports them, along with
+//! indentation that mixes tabs and spaces, before formatting; [`fix`]
+//! replaces the spaces with ordinary ones and removes the zero-width
+//! characters. Text literals and comments are left alone.
+
+use crate::alloc_prelude::*;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Lexer;
+use crate::token::{Span, Token, TokenKind};
+
+/// A character that looks like spacing, or like nothing
+struct Invisible {
+    name: &'static str,
+    /// Text [`fix`] writes instead
+    replacement: &'static str,
+}
+
+fn invisible(c: char) -> Option<Invisible> {
+    let (name, replacement) = match c {
+        '\u{00A0}' => ("Non-breaking space", " "),
+        '\u{2007}' => ("Figure space", " "),
+        '\u{202F}' => ("Narrow non-breaking space", " "),
+        '\u{3000}' => ("Ideographic space", " "),
+        '\u{2000}'..='\u{200A}' | '\u{205F}' => ("Unicode space", " "),
+        '\u{200B}' => ("Zero-width space", ""),
+        '\u{200C}' => ("Zero-width non-joiner", ""),
+        '\u{200D}' => ("Zero-width joiner", ""),
+        '\u{2060}' => ("Word joiner", ""),
+        '\u{FEFF}' => ("Zero-width no-break space", ""),
+        '\u{00AD}' => ("Soft hyphen", ""),
+        _ => return None,
+    };
+    Some(Invisible { name, replacement })
+}
+
+/// Report invisible characters outside text literals and comments, with
+/// the code `invisible-character`, and indentation mixing tabs and spaces,
+/// with the code `mixed-indentation`.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::lint::invisibles::check;
+///
+/// let diagnostics = check("let\u{00A0}x = 1 in x");
+/// assert_eq!(diagnostics[0].code, "invisible-character");
+/// assert_eq!(diagnostics[0].message, "Non-breaking space (U+00A0)");
+/// assert!(check("let x = \"\u{00A0}\" in x").is_empty());
+/// ```
+pub fn check(code: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut indentation = Indentation::default();
+    for token in tokens(code) {
+        match &token.kind {
+            _ if is_unexpected_character(&token) => {
+                report(&code[token.span.start..token.span.end], token.span, "", &mut diagnostics);
+            }
+            TokenKind::QuotedIdentifier(name) => {
+                let visible: String = name.chars().filter(|&c| invisible(c).is_none()).collect();
+                let context = format!(" in #\"{}\"", visible);
+                report(&code[token.span.start..token.span.end], token.span, &context, &mut diagnostics);
+            }
+            _ => {}
+        }
+        indentation.check(&token, &mut diagnostics);
+    }
+    diagnostics.sort_by_key(|d| d.span.start);
+    diagnostics
+}
+
+/// The tokens of `code`, lexed as they are needed, so that large inputs are
+/// checked without holding all of their tokens
+fn tokens(code: &str) -> impl Iterator<Item = Token> + '_ {
+    let mut lexer = Lexer::new(code);
+    core::iter::from_fn(move || Some(lexer.next_token()).filter(|t| t.kind != TokenKind::Eof))
+}
+
+/// Whether `token` is a character the lexer does not accept
+fn is_unexpected_character(token: &Token) -> bool {
+    matches!(token.kind, TokenKind::UnexpectedChar(_))
+}
+
+fn report(text: &str, span: Span, context: &str, diagnostics: &mut Vec<Diagnostic>) {
+    for (offset, c) in text.char_indices() {
+        if let Some(invisible) = invisible(c) {
+            let start = span.start + offset;
+            diagnostics.push(Diagnostic::warning(
+                "invisible-character",
+                format!("{} (U+{:04X}){}", invisible.name, c as u32, context),
+                Span::new(start, start + c.len_utf8(), span.line, span.column + text[..offset].chars().count()),
+            ));
+        }
+    }
+}
+
+/// Lines whose indentation mixes tabs and spaces, and the first line
+/// indented differently from the ones before it, token by token
+struct Indentation {
+    line_start: bool,
+    /// Whether the first indented line uses tabs, and its line number
+    first: Option<(bool, usize)>,
+    reported_inconsistent: bool,
+}
+
+impl Default for Indentation {
+    fn default() -> Self {
+        Indentation {
+            line_start: true,
+            first: None,
+            reported_inconsistent: false,
+        }
+    }
+}
+
+impl Indentation {
+    fn check(&mut self, token: &Token, diagnostics: &mut Vec<Diagnostic>) {
+        match &token.kind {
+            TokenKind::Whitespace(text) if self.line_start => {
+                let tabs = text.contains('\t');
+                if tabs && text.contains(' ') {
+                    diagnostics.push(Diagnostic::warning(
+                        "mixed-indentation",
+                        "Indentation mixes tabs and spaces",
+                        token.span,
+                    ));
+                } else if let Some((first_tabs, first_line)) = self.first {
+                    if tabs != first_tabs && !self.reported_inconsistent {
+                        self.reported_inconsistent = true;
+                        let (this, other) = if tabs { ("tabs", "spaces") } else { ("spaces", "tabs") };
+                        diagnostics.push(Diagnostic::warning(
+                            "mixed-indentation",
+                            format!("Indented with {}, but line {} is indented with {}", this, first_line, other),
+                            token.span,
+                        ));
+                    }
+                } else {
+                    self.first = Some((tabs, token.span.line));
+                }
+            }
+            _ => {}
+        }
+        self.line_start = token.kind == TokenKind::Newline;
+    }
+}
+
+/// Replace the invisible spaces [`check`] reports with ordinary ones and
+/// remove the zero-width characters
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::lint::invisibles::fix;
+///
+/// assert_eq!(fix("let\u{00A0}#\"Amount\u{200B}\" = 1 in 1"), "let #\"Amount\" = 1 in 1");
+/// // Text literals keep their characters
+/// assert_eq!(fix("\"a\u{00A0}b\""), "\"a\u{00A0}b\"");
+/// ```
+pub fn fix(code: &str) -> String {
+    let mut fixed = String::with_capacity(code.len());
+    let mut end = 0;
+    for token in tokens(code) {
+        let text = &code[token.span.start..token.span.end];
+        fixed.push_str(&code[end..token.span.start]);
+        end = token.span.end;
+        if is_unexpected_character(&token) || matches!(token.kind, TokenKind::QuotedIdentifier(_)) {
+            for c in text.chars() {
+                match invisible(c) {
+                    Some(invisible) => fixed.push_str(invisible.replacement),
+                    None => fixed.push(c),
+                }
+            }
+        } else {
+            fixed.push_str(text);
+        }
+    }
+    fixed.push_str(&code[end..]);
+    fixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_invisible_characters() {
+        let code = "let\n    #\"Unit\u{200B}Price\" = 1,\n    y\u{3000}= 2 // a\u{00A0}comment\nin\n    y";
+        let messages: Vec<(usize, String)> = check(code).into_iter().map(|d| (d.span.line, d.message)).collect();
+        assert_eq!(
+            messages,
+            [
+                (2, "Zero-width space (U+200B) in #\"UnitPrice\"".to_string()),
+                (3, "Ideographic space (U+3000)".to_string()),
+            ]
+        );
+        let fixed = fix(code);
+        assert_eq!(fixed, "let\n    #\"UnitPrice\" = 1,\n    y = 2 // a\u{00A0}comment\nin\n    y");
+        assert!(check(&fixed).is_empty());
+        // A byte order mark at the start is not reported
+        assert!(check("\u{FEFF}1").is_empty());
+    }
+    
+    #[test]
+    fn test_mixed_indentation() {
+        let code = "let\n    a = 1,\n\tb = 2,\n\t    c = 3,\n\td = \"\n \tx\"\nin\n    a";
+        let messages: Vec<(usize, String)> = check(code).into_iter().map(|d| (d.span.line, d.message)).collect();
+        assert_eq!(
+            messages,
+            [
+                (3, "Indented with tabs, but line 2 is indented with spaces".to_string()),
+                (4, "Indentation mixes tabs and spaces".to_string()),
+            ]
+        );
+    }
+}
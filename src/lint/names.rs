@@ -0,0 +1,294 @@
+//! Names that read the same but differ
+//!
+//! Step names are usually typed in the Power Query editor and copied into
+//! references by hand. `#"Changed  Type"`, with two spaces, is a different
+//! name from `#"Changed Type"`, so a reference that differs from its step
+//! only in spacing fails with "name not recognized" although both read the
+//! same. [`check`] reports quoted names with repeated, leading, or trailing
+//! whitespace, or with tabs or line breaks, and references that differ
+//! from a step only in whitespace; [`fix`] renames the steps and their
+//! references to names with single spaces. Other invisible characters are
+//! reported by [`invisibles`](super::invisibles).
+//!
+//! An accented letter can also be one character (`é`, as Windows keyboards
+//! type it) or a letter and a combining accent (`e` + U+0301, as macOS
+//! often produces): [`check`] reports names that differ from another name
+//! of the query only this way, and the `normalize_identifiers_nfc` option
+//! writes every name in the composed form.
+
+use crate::alloc_prelude::*;
+use crate::analysis::steps_let;
+use crate::ast::{Document, ExprKind, Identifier, LetExpr, NodeRef};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::is_regular_identifier;
+use crate::refactor::{apply_step_renames, new_step_names};
+use crate::token::Span;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Code of the diagnostics [`check`] reports about whitespace
+pub const CODE: &str = "quoted-name-whitespace";
+
+/// Code of the diagnostics [`check`] reports about Unicode normalization
+pub const NORMALIZATION_CODE: &str = "identifier-normalization";
+
+/// `name` with single spaces between its words, or `None` if it has no
+/// other whitespace
+fn normalized(name: &str) -> Option<String> {
+    let words: Vec<&str> = name
+        .split([' ', '\t', '\r', '\n'])
+        .filter(|word| !word.is_empty())
+        .collect();
+    let normal = words.join(" ");
+    (normal != name).then_some(normal)
+}
+
+/// Report quoted names whose whitespace is not a single space between words,
+/// where they are bound (by a `let` or a section member), and references
+/// whose name differs from a bound one only in whitespace, with the code
+/// [`CODE`]; and names that differ from another only in Unicode
+/// normalization, with the code [`NORMALIZATION_CODE`]. The names are
+/// legal, so these are always warnings.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::lint::names::check;
+/// use pqm_formatter::{Lexer, Parser};
+///
+/// let code = "let #\"Changed Type\" = 1, Result = #\"Changed  Type\" in Result";
+/// let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+/// assert_eq!(
+///     check(&document)[0].message,
+///     "#\"Changed  Type\" differs from #\"Changed Type\" only in whitespace"
+/// );
+/// ```
+pub fn check(document: &Document) -> Vec<Diagnostic> {
+    let mut bound: Vec<&str> = Vec::new();
+    let mut diagnostics = Vec::new();
+    document.expression.walk(&mut |expr| {
+        let names: Vec<&Identifier> = match &expr.kind {
+            ExprKind::Let(l) => l.bindings.iter().map(|b| &b.name).collect(),
+            ExprKind::Section(s) => s.members.iter().map(|m| &m.name).collect(),
+            _ => return,
+        };
+        for name in names {
+            bound.push(&name.name);
+            if name.quoted && normalized(&name.name).is_some() {
+                diagnostics.push(unusual(&name.name, name.span));
+            }
+        }
+    });
+    document.expression.walk(&mut |expr| {
+        let ExprKind::QuotedIdentifier(name) = &expr.kind else {
+            return;
+        };
+        if bound.contains(&name.as_str()) {
+            return;
+        }
+        let key = normalized(name).unwrap_or_else(|| name.clone());
+        let similar = bound
+            .iter()
+            .find(|other| normalized(other).as_deref().unwrap_or(other) == key);
+        match similar {
+            Some(other) => diagnostics.push(Diagnostic::warning(
+                CODE,
+                format!("#\"{}\" differs from #\"{}\" only in whitespace", escaped(name), escaped(other)),
+                expr.span,
+            )),
+            None if key != *name => diagnostics.push(unusual(name, expr.span)),
+            None => {}
+        }
+    });
+    check_normalization(document, &mut diagnostics);
+    diagnostics.sort_by_key(|d| d.span.start);
+    diagnostics
+}
+
+/// Report names that differ from one used earlier only in Unicode
+/// normalization (composed and decomposed accents)
+fn check_normalization(document: &Document, diagnostics: &mut Vec<Diagnostic>) {
+    let mut names: Vec<(&str, Span)> = Vec::new();
+    for (node, span, _) in document.nodes() {
+        let identifiers: Vec<&Identifier> = match node {
+            NodeRef::Expr(expr) => match &expr.kind {
+                ExprKind::Identifier(name) | ExprKind::QuotedIdentifier(name) => {
+                    names.push((name, span));
+                    continue;
+                }
+                ExprKind::InclusiveIdentifier(ident) => vec![ident],
+                ExprKind::Section(s) => vec![&s.name],
+                ExprKind::FieldAccess(a) => vec![&a.field],
+                ExprKind::SectionAccess(a) => vec![&a.member],
+                ExprKind::FieldProjection(p) => p.fields.iter().collect(),
+                _ => continue,
+            },
+            NodeRef::Binding(b) => vec![&b.name],
+            NodeRef::SectionMember(m) => vec![&m.name],
+            NodeRef::RecordField(f) => vec![&f.name],
+            NodeRef::Parameter(p) => vec![&p.name],
+        };
+        names.extend(identifiers.into_iter().map(|i| (i.name.as_str(), i.span)));
+    }
+    names.sort_by_key(|(_, span)| span.start);
+    
+    // The first spelling of each name, by its composed form
+    let mut first: Vec<(String, &str, Span)> = Vec::new();
+    for (name, span) in names {
+        if name.is_ascii() {
+            continue;
+        }
+        let composed: String = name.nfc().collect();
+        match first.iter().find(|(key, _, _)| *key == composed) {
+            Some((_, earlier, at)) if *earlier != name => diagnostics.push(Diagnostic::warning(
+                NORMALIZATION_CODE,
+                format!(
+                    "{} differs from {} on line {} only in Unicode normalization ({} here, {} there)",
+                    display_identifier(name),
+                    display_identifier(earlier),
+                    at.line,
+                    form(name),
+                    form(earlier)
+                ),
+                span,
+            )),
+            Some(_) => {}
+            None => first.push((composed, name, span)),
+        }
+    }
+}
+
+/// `name` as written in code
+fn display_identifier(name: &str) -> String {
+    if is_regular_identifier(name) {
+        name.to_string()
+    } else {
+        format!("#\"{}\"", escaped(name))
+    }
+}
+
+fn form(name: &str) -> &'static str {
+    if is_nfc(name) {
+        "composed"
+    } else {
+        "decomposed"
+    }
+}
+
+fn unusual(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::warning(
+        CODE,
+        format!("#\"{}\" has whitespace other than single spaces between words", escaped(name)),
+        span,
+    )
+}
+
+/// `name` as written between the quotes of `#""`, with line breaks and tabs
+/// escaped so that the message stays on one line
+fn escaped(name: &str) -> String {
+    name.replace('"', "\"\"")
+        .replace('\t', "#(tab)")
+        .replace('\r', "#(cr)")
+        .replace('\n', "#(lf)")
+}
+
+/// Rename the steps of a parsed query whose names [`check`] reports to
+/// names with single spaces between words, and update the references to
+/// them, including references that differ from them only in whitespace.
+/// Names that would collide get a numeric suffix, as with
+/// [`rename_steps`](crate::refactor::rename_steps).
+///
+/// Returns the `(old, new)` pairs of the steps that changed.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::lint::names::fix;
+/// use pqm_formatter::{parse_document, Config, Formatter};
+///
+/// let code = "let #\"Changed  Type\" = 1, Result = #\"Changed Type \" in Result";
+/// let (mut document, _) = parse_document(code, &Config::default()).unwrap();
+/// fix(&mut document);
+/// let formatted = Formatter::new(Config::compact()).format(&document);
+/// assert_eq!(formatted, "let #\"Changed Type\" = 1, Result = #\"Changed Type\" in Result\n");
+/// ```
+pub fn fix(document: &mut Document) -> Vec<(String, String)> {
+    // References first, so that their names are not taken by others
+    let variants = match steps_let(&document.expression) {
+        Some(steps) => variant_references(document, steps),
+        None => return Vec::new(),
+    };
+    apply_step_renames(document, &variants);
+    let renames = new_step_names(document, normalized);
+    apply_step_renames(document, &renames);
+    renames
+}
+
+/// `(reference, step)` pairs for the names referenced that are not steps
+/// but differ from exactly one step only in whitespace
+fn variant_references(document: &Document, steps: &LetExpr) -> Vec<(String, String)> {
+    let key = |name: &str| normalized(name).unwrap_or_else(|| name.to_string());
+    let mut variants: Vec<(String, String)> = Vec::new();
+    document.expression.walk(&mut |expr| {
+        let ExprKind::QuotedIdentifier(name) = &expr.kind else {
+            return;
+        };
+        if variants.iter().any(|(old, _)| old == name) || steps.bindings.iter().any(|b| b.name.name == *name) {
+            return;
+        }
+        let mut similar = steps.bindings.iter().filter(|b| key(&b.name.name) == key(name));
+        if let (Some(step), None) = (similar.next(), similar.next()) {
+            variants.push((name.clone(), step.name.name.clone()));
+        }
+    });
+    variants
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    
+    fn parse(code: &str) -> Document {
+        Parser::new(Lexer::new(code).tokenize()).parse().unwrap()
+    }
+    
+    #[test]
+    fn test_check() {
+        let code = "let\n    #\" Source\" = 1,\n    #\"Changed\tType\" = #\" Source\",\n    Result = #\"Other  Query\"\nin\n    Result";
+        let messages: Vec<(usize, String)> = check(&parse(code)).into_iter().map(|d| (d.span.line, d.message)).collect();
+        assert_eq!(
+            messages,
+            [
+                (2, "#\" Source\" has whitespace other than single spaces between words".to_string()),
+                (3, "#\"Changed#(tab)Type\" has whitespace other than single spaces between words".to_string()),
+                (4, "#\"Other  Query\" has whitespace other than single spaces between words".to_string()),
+            ]
+        );
+        assert!(check(&parse("let #\"Changed Type\" = 1 in #\"Changed Type\"")).is_empty());
+    }
+    
+    #[test]
+    fn test_normalization() {
+        let code = "let\n    Caf\u{e9} = 1,\n    Total = Cafe\u{301} + [Caf\u{e9} = 2][Cafe\u{301}]\nin\n    Total";
+        let messages: Vec<(usize, String)> = check(&parse(code)).into_iter().map(|d| (d.span.line, d.message)).collect();
+        let message = "Cafe\u{301} differs from Caf\u{e9} on line 2 only in Unicode normalization (decomposed here, composed there)";
+        assert_eq!(messages, [(3, message.to_string()), (3, message.to_string())]);
+    }
+    
+    #[test]
+    fn test_fix() {
+        let code = "let\n    #\"Changed  Type\" = 1,\n    Result = #\"Changed Type\" + #\"Changed   Type\"\nin\n    Result";
+        let mut document = parse(code);
+        assert_eq!(fix(&mut document), [("Changed  Type".to_string(), "Changed Type".to_string())]);
+        let formatted = crate::Formatter::new(crate::Config::compact()).format(&document);
+        assert_eq!(
+            formatted,
+            "let #\"Changed Type\" = 1, Result = #\"Changed Type\" + #\"Changed Type\" in Result\n"
+        );
+        assert!(check(&document).is_empty());
+        
+        // The second step already has the name the first would get
+        let mut document = parse("let #\"A  B\" = 1, #\"A B\" = #\"A  B\" in #\"A B\"");
+        assert_eq!(fix(&mut document), [("A  B".to_string(), "A B2".to_string())]);
+    }
+}
@@ -0,0 +1,270 @@
+//! Functions a team does not allow, or allows only inside another
+//!
+//! Data-governance rules are often about sources: no `Web.Contents`, so
+//! that reports only read approved systems, or `Sql.Database` only through
+//! `Value.NativeQuery`, so that every database query is written out and can
+//! be reviewed. A [`Policy`], usually read from the `[policy]` table of a
+//! [configuration file](crate::config::file), reports code that breaks such
+//! rules:
+//!
+//! ```toml
+//! [policy]
+//! banned_functions = ["Web.Contents"]
+//! required_wrappers = { "Sql.Database" = "Value.NativeQuery" }
+//! ```
+//!
+//! A call counts as wrapped when it is inside an argument of the wrapper,
+//! or when it is the value of a step that is only referred to inside
+//! arguments of the wrapper:
+//!
+//! ```text
+//! Source = Sql.Database("server", "db"),
+//! Orders = Value.NativeQuery(Source, "select * from Orders")
+//! ```
+
+use super::LintRule;
+use crate::alloc_prelude::*;
+use crate::ast::{Document, Expr, ExprKind};
+use crate::diagnostic::Diagnostic;
+use crate::token::Span;
+
+/// Code of the diagnostics about banned functions
+pub const BANNED_CODE: &str = "banned-function";
+
+/// Code of the diagnostics about calls outside their required wrapper
+pub const WRAPPER_CODE: &str = "missing-wrapper";
+
+/// Functions that may not be used, and functions that may only be called
+/// inside another. Violations are errors.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::lint::policy::Policy;
+/// use pqm_formatter::lint::Validator;
+/// use pqm_formatter::{Lexer, Parser};
+///
+/// let mut policy = Policy::default();
+/// policy.require_wrapper("Sql.Database", "Value.NativeQuery");
+/// let code = "let Source = Sql.Database(\"server\", \"db\") in Source";
+/// let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+/// assert_eq!(
+///     policy.validate(&document)[0].message,
+///     "Sql.Database must be called inside Value.NativeQuery"
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Functions that may not be referred to at all
+    pub banned_functions: Vec<String>,
+    /// `(function, wrapper)` pairs: calls to `function` must be inside a
+    /// call to `wrapper`
+    pub required_wrappers: Vec<(String, String)>,
+}
+
+impl Policy {
+    /// Whether the policy has no rules
+    pub fn is_empty(&self) -> bool {
+        self.banned_functions.is_empty() && self.required_wrappers.is_empty()
+    }
+    
+    pub fn ban(&mut self, function: impl Into<String>) {
+        let function = function.into();
+        if !self.banned_functions.contains(&function) {
+            self.banned_functions.push(function);
+        }
+    }
+    
+    /// Require calls to `function` to be inside a call to `wrapper`,
+    /// replacing any wrapper required before
+    pub fn require_wrapper(&mut self, function: impl Into<String>, wrapper: impl Into<String>) {
+        let (function, wrapper) = (function.into(), wrapper.into());
+        match self.required_wrappers.iter_mut().find(|(f, _)| *f == function) {
+            Some(pair) => pair.1 = wrapper,
+            None => self.required_wrappers.push((function, wrapper)),
+        }
+    }
+    
+    /// Add the rules of `other`, whose wrappers take precedence
+    pub fn extend(&mut self, other: &Policy) {
+        for function in &other.banned_functions {
+            self.ban(function.as_str());
+        }
+        for (function, wrapper) in &other.required_wrappers {
+            self.require_wrapper(function.as_str(), wrapper.as_str());
+        }
+    }
+    
+    fn wrapper(&self, function: &str) -> Option<&str> {
+        self.required_wrappers
+            .iter()
+            .find(|(f, _)| f == function)
+            .map(|(_, wrapper)| wrapper.as_str())
+    }
+    
+    fn is_wrapper(&self, function: &str) -> bool {
+        self.required_wrappers.iter().any(|(_, wrapper)| wrapper == function)
+    }
+}
+
+impl LintRule for Policy {
+    fn name(&self) -> &str {
+        "policy"
+    }
+    
+    fn code(&self) -> &'static str {
+        BANNED_CODE
+    }
+    
+    /// Report references to banned functions with the code
+    /// [`BANNED_CODE`], and calls outside their wrapper with the code
+    /// [`WRAPPER_CODE`]
+    fn check(&self, document: &Document, diagnostics: &mut Vec<Diagnostic>) {
+        let start = diagnostics.len();
+        let mut scan = Scan::default();
+        self.scan(&document.expression, &mut Vec::new(), &mut scan);
+        for (name, span) in &scan.banned {
+            diagnostics.push(Diagnostic::error(
+                BANNED_CODE,
+                format!("{} is banned by the policy", name),
+                *span,
+            ));
+        }
+        for call in &scan.calls {
+            if call.wrapped || scan.step_wrapped(call.span, call.wrapper) {
+                continue;
+            }
+            diagnostics.push(Diagnostic::error(
+                WRAPPER_CODE,
+                format!("{} must be called inside {}", call.function, call.wrapper),
+                call.span,
+            ));
+        }
+        diagnostics[start..].sort_by_key(|d| d.span.start);
+    }
+}
+
+/// A call to a function that needs a wrapper
+struct Call<'a> {
+    function: &'a str,
+    wrapper: &'a str,
+    span: Span,
+    wrapped: bool,
+}
+
+/// What [`Policy::scan`] found
+#[derive(Default)]
+struct Scan<'a> {
+    banned: Vec<(&'a str, Span)>,
+    calls: Vec<Call<'a>>,
+    /// Names referred to, with the wrappers whose arguments they are in
+    references: Vec<(&'a str, Vec<&'a str>)>,
+    /// Step names, with the span of their values
+    steps: Vec<(&'a str, Span)>,
+}
+
+impl Scan<'_> {
+    /// Whether the expression at `span` is the value of a step that is
+    /// referred to, and only inside arguments of `wrapper`
+    fn step_wrapped(&self, span: Span, wrapper: &str) -> bool {
+        let Some((name, _)) = self.steps.iter().find(|(_, value)| *value == span) else {
+            return false;
+        };
+        let mut references = self.references.iter().filter(|(n, _)| n == name).peekable();
+        references.peek().is_some() && references.all(|(_, inside)| inside.contains(&wrapper))
+    }
+}
+
+impl Policy {
+    /// Collect banned references, calls that need wrappers, and references
+    /// and steps under `expr`; `inside` holds the wrappers whose arguments
+    /// `expr` is in
+    fn scan<'a>(&'a self, expr: &'a Expr, inside: &mut Vec<&'a str>, scan: &mut Scan<'a>) {
+        match &expr.kind {
+            ExprKind::Identifier(name) | ExprKind::QuotedIdentifier(name) => {
+                if self.banned_functions.contains(name) {
+                    scan.banned.push((name, expr.span));
+                }
+                scan.references.push((name, inside.clone()));
+            }
+            ExprKind::InclusiveIdentifier(ident) => scan.references.push((&ident.name, inside.clone())),
+            ExprKind::Let(l) => {
+                scan.steps.extend(l.bindings.iter().map(|b| (b.name.name.as_str(), b.value.span)));
+            }
+            ExprKind::FunctionCall(call) => {
+                if let ExprKind::Identifier(function) = &call.function.kind {
+                    if let Some(wrapper) = self.wrapper(function) {
+                        scan.calls.push(Call {
+                            function,
+                            wrapper,
+                            span: expr.span,
+                            wrapped: inside.contains(&wrapper),
+                        });
+                    }
+                    if self.is_wrapper(function) {
+                        self.scan(&call.function, inside, scan);
+                        inside.push(function);
+                        for argument in &call.arguments {
+                            self.scan(argument, inside, scan);
+                        }
+                        inside.pop();
+                        return;
+                    }
+                }
+            }
+            _ => {}
+        }
+        for child in expr.children() {
+            self.scan(child, inside, scan);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lint::Validator;
+    use crate::{Lexer, Parser};
+    
+    fn policy() -> Policy {
+        let mut policy = Policy::default();
+        policy.ban("Web.Contents");
+        policy.require_wrapper("Sql.Database", "Value.NativeQuery");
+        policy
+    }
+    
+    fn codes(code: &str) -> Vec<(&'static str, usize)> {
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        policy().validate(&document).into_iter().map(|d| (d.code, d.span.line)).collect()
+    }
+    
+    #[test]
+    fn test_banned() {
+        assert_eq!(codes("let\n    Get = Web.Contents,\n    Page = Get(Url)\nin\n    Page"), [(BANNED_CODE, 2)]);
+        assert!(codes("Json.Document(Binary.Buffer(Source))").is_empty());
+    }
+    
+    #[test]
+    fn test_wrappers() {
+        assert!(codes("Value.NativeQuery(Sql.Database(\"s\", \"d\"), \"select 1\")").is_empty());
+        assert!(codes("let\n    Source = Sql.Database(\"s\", \"d\"),\n    Q = Value.NativeQuery(Source, \"select 1\")\nin\n    Q").is_empty());
+        
+        // Also used outside the wrapper, or not used
+        let code = "let\n    Source = Sql.Database(\"s\", \"d\"),\n    Q = Value.NativeQuery(Source, \"select 1\")\nin\n    Source";
+        assert_eq!(codes(code), [(WRAPPER_CODE, 2)]);
+        assert_eq!(codes("let Source = Sql.Database(\"s\", \"d\") in 1"), [(WRAPPER_CODE, 1)]);
+        assert_eq!(codes("Table.First(Sql.Database(\"s\", \"d\"))"), [(WRAPPER_CODE, 1)]);
+    }
+    
+    #[test]
+    fn test_extend() {
+        let mut base = policy();
+        let mut other = Policy::default();
+        other.ban("Web.Contents");
+        other.ban("Odbc.Query");
+        other.require_wrapper("Sql.Database", "Sql.Reviewed");
+        base.extend(&other);
+        assert_eq!(base.banned_functions, ["Web.Contents", "Odbc.Query"]);
+        assert_eq!(base.wrapper("Sql.Database"), Some("Sql.Reviewed"));
+    }
+}
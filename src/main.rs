@@ -5,34 +5,131 @@
 //!
 //! Options:
 //!   -c, --check      Check if the file is formatted (exit 1 if not)
+//!   --check-mode M   How --check compares: strict, loose (default), semantic
 //!   -w, --write      Write formatted output back to file
 //!   -o, --output     Write output to specified file
 //!   --stdin          Read from stdin
+//!   --config FILE    Use FILE instead of a discovered pqmfmt.toml/pqmfmt.json
+//!   --no-config      Ignore configuration files
+//!
+//! PQMFMT_CONFIG and PQMFMT_<OPTION> environment variables sit between the
+//! configuration file and the options.
+//!   --best-effort    With --stdin, print unparseable input unchanged and exit 0
+//!   --lossy          Replace invalid UTF-8 with U+FFFD and warn instead of failing
+//!   --warn-long-lines  Report output lines longer than the line length limit
+//!   --force          In clipboard mode, format even text that does not look like M
+//!   --report FILE    Add statistics about this run to a local JSON usage report
+//!   --columns FILE   Warn about column names not in the JSON schema FILE
+//!   --lint-plugin CODE=COMMAND  Run COMMAND as a lint rule reporting CODE
+//!   --lint           Exit 1 if a lint rule or the [policy] of the configuration reports anything
+//!   --filter MODE    Run as a git clean or smudge filter (stdin to stdout)
 //!   --compact        Use compact formatting
 //!   --expanded       Use expanded formatting
 //!   --indent SIZE    Set indent size (default: 4)
 //!   --tabs           Use tabs for indentation
+//!   --lenient        Keep code that cannot be parsed as written
+//!   --strict         Reject constructs the Power Query engine rejects
+//!   --dialect HOST   Target host: powerbi (default), excel, or legacy
+//!   --style-edition E Layout rules edition: 2024 (default) or 2025
+//!   --redact         Replace connector string arguments with placeholders
+//!   --fix-invisibles Replace non-breaking spaces, remove zero-width characters
+//!   --fix-name-whitespace Rename steps to single spaces between words
+//!   --fix-syntax     Add closing brackets missing at the end of the input
+//!   --max-memory MB  Format a large let a binding at a time to stay within MB
+//!   --explain LINE   Print why output line LINE was broken where it was
+//!   --rename-steps S Rename steps to PascalCase, camelCase, or keep-spaces
+//!   --suggest-rename Print shorter names for steps too long for a line
+//!   --emit KIND      Print steps-json or steps-csv instead of formatted code,
+//!                    highlighted html or ansi, or config-schema
+//!   --theme NAME     Colors for --emit html/ansi: light (default) or dark
+//!   --preview        Print with line numbers, wrapped to the terminal width
 //!   -h, --help       Print help
 //!   -V, --version    Print version
+//!   --               Treat every later argument as a file
+//!
+//! Subcommands:
+//!   install-hook     Install a git pre-commit hook running `pqmfmt --check`
+//!   install-service  Install a macOS Quick Action formatting the selected text
+//!   install-shell-ext  Add "Format with pqmfmt" to the Windows Explorer menus
+//!   self-update      Replace this executable with the latest release
+//!   describe FILE... Summarize steps, data sources, and external references
+//!   diff OLD NEW     Report added, removed, renamed, and changed steps
+//!   docstub FILE     Print a `Value.ReplaceType` documentation wrapper for a function
+//!   bundle DIR       Combine the .pq/.m files in DIR into a section document
+//!   split FILE       Write each member of a section document to its own file
+//!
+//! A subcommand is only recognized as the first argument; `pqmfmt -- diff`
+//! formats a file named `diff`.
 //!
-//! If no file is specified, reads from clipboard (if content starts with "let")
-//! and writes formatted result back to clipboard.
+//! If no file is specified, reads from clipboard (if content looks like M
+//! code) and writes formatted result back to clipboard.
 
-use pqm_formatter::{format, Config};
+mod cli;
+
+use pqm_formatter::ast::Document;
+use pqm_formatter::config::file::ConfigFile;
+use pqm_formatter::config::{self, directive};
+use pqm_formatter::conflict;
+use pqm_formatter::diagnostic::Diagnostic;
+use pqm_formatter::lexer::is_regular_identifier;
+use pqm_formatter::lint::columns::ColumnSchema;
+use pqm_formatter::lint::invisibles;
+use pqm_formatter::lint::names;
+use pqm_formatter::lint::policy::Policy;
+use pqm_formatter::lint::{LintRule, Validator};
+use pqm_formatter::refactor::{self, StepNameStyle};
+use pqm_formatter::render::{self, Theme};
+use pqm_formatter::repair;
+use pqm_formatter::report::UsageReport;
+use pqm_formatter::stream;
+use pqm_formatter::text::{self, LineIndex};
+use pqm_formatter::token::Span;
+use pqm_formatter::{
+    analysis, parse_document, semantically_equal, transform, Config, Dialect,
+    FormatStats, Formatter, LayoutDecision, StyleEdition,
+};
+use std::cell::{Cell, RefCell};
 use std::env;
 use std::fs;
-use std::io::{self, Read};
+use std::io::{self, IsTerminal, Read};
 use std::process::{self, Command};
-
-#[cfg(any(target_os = "macos", target_os = "linux"))]
-use std::io::Write;
-#[cfg(any(target_os = "macos", target_os = "linux"))]
-use std::process::Stdio;
+use std::sync::OnceLock;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Alternative output selected with `--emit`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    StepsJson,
+    StepsCsv,
+    /// Highlighted formatted code
+    Html,
+    Ansi,
+}
+
+/// How `--check` decides whether a file is formatted
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CheckMode {
+    /// Byte-for-byte identical to the formatted output
+    Strict,
+    /// Identical up to line endings and trailing whitespace
+    Loose,
+    /// Parses to the same tree as the formatted output
+    Semantic,
+}
+
+/// Which side of a git filter `--filter` runs as
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FilterMode {
+    /// Working tree to repository: store the canonical form
+    Clean,
+    /// Repository to working tree: apply personal style options, if any
+    Smudge,
+}
+
 struct Options {
     check: bool,
+    check_mode: CheckMode,
     write: bool,
     output: Option<String>,
     stdin: bool,
@@ -40,13 +137,55 @@ struct Options {
     expanded: bool,
     indent_size: Option<usize>,
     use_tabs: bool,
+    lenient: bool,
+    strict: bool,
+    dialect: Option<Dialect>,
+    style_edition: Option<StyleEdition>,
+    redact: bool,
+    fix_invisibles: bool,
+    fix_name_whitespace: bool,
+    fix_syntax: bool,
+    /// Memory limit in bytes (`--max-memory`)
+    max_memory: Option<usize>,
+    /// Output line whose layout decisions are printed (`--explain`)
+    explain: Option<usize>,
+    best_effort: bool,
+    lossy: bool,
+    warn_long_lines: bool,
+    force: bool,
+    /// Usage report file (`--report`)
+    report: Option<String>,
+    /// Statistics read from the report file, plus this run's
+    usage: RefCell<UsageReport>,
+    /// Checks run on every parsed input (`--columns`, and the rules of
+    /// `--lint-plugin` once `main` has stored them)
+    validators: Vec<Box<dyn Validator>>,
+    /// `--lint-plugin` rules, in order
+    lint_plugins: Vec<PluginRule>,
+    /// Policy of the configuration file of the input being formatted
+    policy: RefCell<Policy>,
+    /// Fail when the validators or the policy report anything (`--lint`)
+    lint: bool,
+    /// Diagnostics the validators and the policy reported
+    lint_findings: Cell<usize>,
+    /// Format without printing warnings, running the validators, or adding
+    /// to the report, while `--preview` formats an input again
+    quiet: Cell<bool>,
+    filter: Option<FilterMode>,
+    config: Option<String>,
+    no_config: bool,
+    rename_steps: Option<StepNameStyle>,
+    suggest_rename: bool,
+    emit: Option<Emit>,
+    theme: Theme,
+    preview: bool,
     files: Vec<String>,
 }
 
-fn parse_args() -> Options {
-    let args: Vec<String> = env::args().skip(1).collect();
+fn parse_args(args: &[String]) -> Options {
     let mut opts = Options {
         check: false,
+        check_mode: CheckMode::Loose,
         write: false,
         output: None,
         stdin: false,
@@ -54,6 +193,36 @@ fn parse_args() -> Options {
         expanded: false,
         indent_size: None,
         use_tabs: false,
+        lenient: false,
+        strict: false,
+        dialect: None,
+        style_edition: None,
+        redact: false,
+        fix_invisibles: false,
+        fix_name_whitespace: false,
+        fix_syntax: false,
+        max_memory: None,
+        explain: None,
+        best_effort: false,
+        lossy: false,
+        warn_long_lines: false,
+        force: false,
+        report: None,
+        usage: RefCell::new(UsageReport::default()),
+        validators: Vec::new(),
+        lint_plugins: Vec::new(),
+        policy: RefCell::new(Policy::default()),
+        lint: false,
+        lint_findings: Cell::new(0),
+        quiet: Cell::new(false),
+        filter: None,
+        config: None,
+        no_config: false,
+        rename_steps: None,
+        suggest_rename: false,
+        emit: None,
+        theme: Theme::Light,
+        preview: false,
         files: Vec::new(),
     };
     
@@ -69,6 +238,19 @@ fn parse_args() -> Options {
                 process::exit(0);
             }
             "-c" | "--check" => opts.check = true,
+            "--check-mode" => {
+                i += 1;
+                opts.check_mode = match args.get(i).map(String::as_str) {
+                    Some("strict") => CheckMode::Strict,
+                    Some("loose") => CheckMode::Loose,
+                    Some("semantic") => CheckMode::Semantic,
+                    _ => {
+                        eprintln!("Error: --check-mode requires one of strict, loose, semantic");
+                        process::exit(1);
+                    }
+                };
+                opts.check = true;
+            }
             "-w" | "--write" => opts.write = true,
             "-o" | "--output" => {
                 i += 1;
@@ -80,6 +262,75 @@ fn parse_args() -> Options {
                 }
             }
             "--stdin" => opts.stdin = true,
+            "--config" => {
+                i += 1;
+                match args.get(i) {
+                    Some(path) => opts.config = Some(path.clone()),
+                    None => {
+                        eprintln!("Error: --config requires a file path");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--no-config" => opts.no_config = true,
+            "--best-effort" => opts.best_effort = true,
+            "--lossy" => opts.lossy = true,
+            "--warn-long-lines" => opts.warn_long_lines = true,
+            "--force" => opts.force = true,
+            "--report" => {
+                i += 1;
+                match args.get(i) {
+                    Some(path) => opts.report = Some(path.clone()),
+                    None => {
+                        eprintln!("Error: --report requires a file path");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--columns" => {
+                i += 1;
+                let Some(path) = args.get(i) else {
+                    eprintln!("Error: --columns requires a file path");
+                    process::exit(1);
+                };
+                let schema = fs::read_to_string(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| ColumnSchema::from_json(&text));
+                match schema {
+                    Ok(schema) => opts.validators.push(Box::new(schema)),
+                    Err(e) => {
+                        eprintln!("Error reading {}: {}", path, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            "--lint-plugin" => {
+                i += 1;
+                match args.get(i).and_then(|s| s.split_once('=')) {
+                    Some((code, command)) if !code.is_empty() && !command.trim().is_empty() => {
+                        opts.lint_plugins.push(PluginRule { code: code.to_string(), command: command.to_string() });
+                    }
+                    _ => {
+                        eprintln!("Error: --lint-plugin requires CODE=COMMAND");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--lint" => opts.lint = true,
+            "--filter" => {
+                i += 1;
+                opts.filter = match args.get(i).map(String::as_str) {
+                    Some("clean") => Some(FilterMode::Clean),
+                    Some("smudge") => Some(FilterMode::Smudge),
+                    _ => {
+                        eprintln!("Error: --filter requires one of clean, smudge");
+                        process::exit(1);
+                    }
+                };
+                // A failing filter would block checkouts and commits
+                opts.stdin = true;
+                opts.best_effort = true;
+            }
             "--compact" => opts.compact = true,
             "--expanded" => opts.expanded = true,
             "--indent" => {
@@ -92,6 +343,110 @@ fn parse_args() -> Options {
                 }
             }
             "--tabs" => opts.use_tabs = true,
+            "--lenient" => opts.lenient = true,
+            "--strict" => opts.strict = true,
+            "--style-edition" => {
+                i += 1;
+                match args.get(i).map(|s| s.parse::<StyleEdition>()) {
+                    Some(Ok(edition)) => opts.style_edition = Some(edition),
+                    Some(Err(e)) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    }
+                    None => {
+                        eprintln!("Error: --style-edition requires an edition");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--dialect" => {
+                i += 1;
+                match args.get(i).map(|s| s.parse::<Dialect>()) {
+                    Some(Ok(dialect)) => opts.dialect = Some(dialect),
+                    Some(Err(e)) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    }
+                    None => {
+                        eprintln!("Error: --dialect requires a host");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--redact" => opts.redact = true,
+            "--fix-invisibles" => opts.fix_invisibles = true,
+            "--fix-name-whitespace" => opts.fix_name_whitespace = true,
+            "--fix-syntax" => opts.fix_syntax = true,
+            "--max-memory" => {
+                i += 1;
+                match args.get(i).and_then(|s| s.parse::<usize>().ok()) {
+                    Some(mb) if mb > 0 => opts.max_memory = Some(mb.saturating_mul(1 << 20)),
+                    _ => {
+                        eprintln!("Error: --max-memory requires a number of megabytes");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--explain" => {
+                i += 1;
+                match args.get(i).and_then(|s| s.parse::<usize>().ok()) {
+                    Some(line) if line > 0 => opts.explain = Some(line),
+                    _ => {
+                        eprintln!("Error: --explain requires a line number");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--emit" => {
+                i += 1;
+                opts.emit = match args.get(i).map(String::as_str) {
+                    Some("steps-json") => Some(Emit::StepsJson),
+                    Some("steps-csv") => Some(Emit::StepsCsv),
+                    Some("html") => Some(Emit::Html),
+                    Some("ansi") => Some(Emit::Ansi),
+                    Some("config-schema") => emit_config_schema(),
+                    _ => {
+                        eprintln!("Error: --emit requires one of steps-json, steps-csv, html, ansi, config-schema");
+                        process::exit(1);
+                    }
+                };
+            }
+            "--theme" => {
+                i += 1;
+                match args.get(i).map(|s| s.parse::<Theme>()) {
+                    Some(Ok(theme)) => opts.theme = theme,
+                    Some(Err(e)) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    }
+                    None => {
+                        eprintln!("Error: --theme requires light or dark");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--suggest-rename" => opts.suggest_rename = true,
+            "--preview" => opts.preview = true,
+            "--rename-steps" => {
+                i += 1;
+                match args.get(i).map(|s| s.parse::<StepNameStyle>()) {
+                    Some(Ok(style)) => opts.rename_steps = Some(style),
+                    Some(Err(e)) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    }
+                    None => {
+                        eprintln!("Error: --rename-steps requires a style");
+                        process::exit(1);
+                    }
+                }
+            }
+            // Files only from here on, even if named like an option or
+            // a subcommand
+            "--" => {
+                opts.files.extend_from_slice(&args[i + 1..]);
+                break;
+            }
             arg if arg.starts_with('-') => {
                 eprintln!("Unknown option: {}", arg);
                 process::exit(1);
@@ -101,6 +456,22 @@ fn parse_args() -> Options {
         i += 1;
     }
     
+    if let Some(ref path) = opts.report {
+        let mut usage = match fs::read_to_string(path) {
+            Ok(text) => UsageReport::from_json(&text).unwrap_or_else(|e| {
+                eprintln!("Error reading {}: {}", path, e);
+                process::exit(1);
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => UsageReport::default(),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        usage.runs += 1;
+        opts.usage = RefCell::new(usage);
+    }
+    
     opts
 }
 
@@ -113,23 +484,141 @@ USAGE:
 
 OPTIONS:
     -c, --check       Check if files are formatted (exit 1 if not)
+    --check-mode MODE How --check compares (implies --check):
+                        strict    byte-for-byte
+                        loose     ignore line endings and trailing
+                                  whitespace (default)
+                        semantic  same syntax tree; only layout and
+                                  comments may differ
     -w, --write       Write formatted output back to files
     -o, --output FILE Write output to specified file
     --stdin           Read from standard input
+    --config FILE     Read settings from FILE instead of the pqmfmt.toml,
+                      .pqmfmt.toml, pqmfmt.json, or .pqmfmtrc.json found
+                      next to the input or in a parent directory
+    --no-config       Ignore configuration files
+    --best-effort     With --stdin, print the input unchanged (diagnostics on
+                      stderr) and exit 0 when it cannot be formatted
+    --lossy           Replace invalid UTF-8 in the input with U+FFFD, with a
+                      warning giving the byte offsets, instead of failing
+    --warn-long-lines Report lines of the output that are still longer than
+                      the line length limit (long URLs, strings, names),
+                      and identifiers too long for any line
+    --force           In clipboard mode, format the clipboard even if it
+                      does not look like M code
+    --report FILE     Add this run's statistics (files formatted and
+                      changed, line lengths, which rules broke constructs
+                      over several lines) to the JSON report FILE; kept
+                      locally, never sent anywhere
+    --columns FILE    Warn about column names not listed in FILE, a JSON
+                      array of names or an object of table names to arrays:
+                      row field accesses ([Amount]) and the names passed to
+                      Table.SelectColumns, RemoveColumns, and RenameColumns
+    --lint-plugin CODE=COMMAND
+                      Run COMMAND as a lint rule: it reads the code on stdin
+                      and prints LINE:COLUMN: MESSAGE for each problem,
+                      reported as a warning [CODE]; may be repeated
+    --lint            Exit 1 if --columns, --lint-plugin, or the [policy]
+                      table of the configuration file (banned_functions,
+                      required_wrappers) report anything; with --check,
+                      for CI
+    --filter MODE     Run as a git filter on stdin/stdout (implies
+                      --best-effort):
+                        clean   format to the canonical form for storage
+                        smudge  reformat with the given style options
+                                (--compact, --indent, --tabs, ...) on
+                                checkout; unchanged without them
     --compact         Use compact formatting style
     --expanded        Use expanded formatting style
     --indent SIZE     Set indent size (default: 4)
     --tabs            Use tabs for indentation
+    --lenient         Keep code that cannot be parsed as written
+    --strict          Reject constructs the Power Query engine rejects
+    --dialect HOST    Target host: powerbi (default), excel, or legacy
+    --style-edition EDITION
+                      Layout rules to apply: 2024 (default) or 2025
+    --redact          Replace server names, URLs, and paths passed to
+                      connector functions with placeholders
+    --fix-invisibles  Replace non-breaking and other Unicode spaces with
+                      spaces and remove zero-width characters outside text
+                      literals and comments
+    --fix-name-whitespace
+                      Rename steps whose quoted names have repeated,
+                      leading, or trailing whitespace to names with single
+                      spaces, with references that differ only in spacing
+    --fix-syntax      Add the closing ), ] and }} missing at the end of input
+                      that was cut off (as chat tools do), reporting them
+    --max-memory MB   Stay within about MB megabytes: an input that needs
+                      more, and is a single let, is parsed and formatted
+                      one binding at a time
+    --explain LINE    Print to stderr why line LINE of the output was laid
+                      out as it is: every construct on it, whether it was
+                      broken over several lines and by which rule, and its
+                      estimated and actual widths
+    --rename-steps STYLE
+                      Rename steps and their references: PascalCase,
+                      camelCase, or keep-spaces (#"Filtered Rows")
+    --suggest-rename  Print shorter unique names for the steps whose names
+                      are too long to fit within the line length limit,
+                      instead of formatted code
+    --emit KIND       Print every step with its comment and line instead of
+                      formatted code: steps-json or steps-csv; the
+                      formatted code highlighted with line numbers: html
+                      or ansi; or config-schema for the configuration
+                      JSON Schema
+    --theme NAME      Colors for --emit html and ansi: light (default) or
+                      dark
+    --preview         Print the formatted code with line numbers, with the
+                      line length limit set to the width of the terminal
+                      (COLUMNS, or the terminal's size when stdout or stderr
+                      is one), so it reads well on narrow terminals and in a
+                      pager; configuration files are not changed
     -h, --help        Print help information
     -V, --version     Print version information
+    --                Treat every later argument as a file, even one named
+                      like an option or a subcommand (pqmfmt -- diff)
+
+SUBCOMMANDS:
+    install-hook [--force] [--pre-commit-config]
+                      Install .git/hooks/pre-commit checking staged .pq/.m
+                      files, or print a pre-commit framework config snippet
+    install-service [--uninstall]
+                      Install (or remove) the macOS Quick Action "Format
+                      Power Query M", which formats the selected text in any
+                      app from the Services menu
+    install-shell-ext [--uninstall]
+                      Add (or remove) "Format with pqmfmt" in the Windows
+                      Explorer context menu of .pq/.m files and in Send To
+    self-update [--check]
+                      Download the latest release for this platform from
+                      GitHub, verify its SHA-256 checksum, and replace this
+                      executable (--check only reports whether one exists)
+    describe FILE...  Summarize steps, data sources, parameters, and
+                      references to other queries
+    diff OLD NEW      Compare two versions of a query step by step: added,
+                      removed, renamed, and changed steps, changed call
+                      arguments, and changed references between steps
+    docstub FILE      Print the function in FILE wrapped in Value.ReplaceType
+                      with a Documentation.* metadata skeleton
+    bundle DIR [-o FILE] [--name SECTION]
+                      Combine the .pq/.m files in DIR into a section document
+                      with one shared member per file (default: Section1)
+    split FILE [-d DIR]
+                      Write each member of a section document to DIR/<name>.pq
 
 CLIPBOARD MODE:
     If no file is specified, pqmfmt reads from clipboard.
-    If clipboard content starts with "let", it formats the code
-    and writes the result back to clipboard.
+    If clipboard content looks like M code (or with --force), it
+    formats the code and writes the result back to clipboard.
     On error, clipboard will contain the error message followed by
     the original code.
 
+ENVIRONMENT:
+    PQMFMT_CONFIG     Configuration file to use when --config is not given
+    PQMFMT_<OPTION>   Any setting by its upper-case name, applied after the
+                      configuration file and before options
+                      (e.g. PQMFMT_MAX_LINE_LENGTH=100)
+
 EXAMPLES:
     pqmfmt query.pq              Format and print to stdout
     pqmfmt -w query.pq           Format and write back to file
@@ -140,7 +629,136 @@ EXAMPLES:
     );
 }
 
-fn build_config(opts: &Options) -> Config {
+/// The configuration file that applies to `input`: `--config`, or the one
+/// discovered from the input's directory (the current one for stdin)
+fn config_file(opts: &Options, input: Option<&str>) -> Option<ConfigFile> {
+    if opts.no_config {
+        return None;
+    }
+    let path = match opts.config.clone().or_else(|| env::var("PQMFMT_CONFIG").ok()) {
+        Some(path) => std::path::PathBuf::from(path),
+        None => {
+            let dir = match input.and_then(|file| std::path::Path::new(file).parent()) {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => env::current_dir().ok()?,
+            };
+            let found = config::file::discover(&dir)?;
+            for shadowed in &found.shadowed {
+                eprintln!(
+                    "Warning: ignoring {} ({} takes precedence)",
+                    shadowed.display(),
+                    found.path.display()
+                );
+            }
+            found.path
+        }
+    };
+    match ConfigFile::load(&path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Environment variables that are not settings
+const ENV_NOT_SETTINGS: [&str; 2] = ["PQMFMT_CONFIG", "PQMFMT_BLESS"];
+
+/// Apply `PQMFMT_<OPTION>` environment variables (e.g.
+/// `PQMFMT_MAX_LINE_LENGTH=100`) to `config`, in name order
+fn apply_env(config: &mut Config) {
+    let mut vars: Vec<(String, String)> = env::vars()
+        .filter(|(name, _)| name.starts_with("PQMFMT_") && !ENV_NOT_SETTINGS.contains(&name.as_str()))
+        .collect();
+    vars.sort();
+    for (name, value) in vars {
+        let key = name["PQMFMT_".len()..].to_ascii_lowercase();
+        match config.set(&key, &value) {
+            Ok(()) => {}
+            Err(_) if !Config::is_option(&key) => eprintln!("Warning: ignoring {} (unknown option)", name),
+            Err(e) => {
+                eprintln!("Error: {}: {}", name, e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// A lint rule run as a separate program (`--lint-plugin CODE=COMMAND`)
+struct PluginRule {
+    code: String,
+    command: String,
+}
+
+/// The `--lint-plugin` rules, kept for the rest of the process because
+/// diagnostic codes are `&'static str`
+static LINT_PLUGINS: OnceLock<Vec<PluginRule>> = OnceLock::new();
+
+impl LintRule for &'static PluginRule {
+    fn name(&self) -> &str {
+        &self.command
+    }
+    
+    fn code(&self) -> &'static str {
+        &self.code
+    }
+    
+    /// Run the command with the source on stdin; each `LINE:COLUMN: MESSAGE`
+    /// line it prints is a warning
+    fn check(&self, document: &Document, diagnostics: &mut Vec<Diagnostic>) {
+        let failed = |message: String| Diagnostic::error(self.code(), message, Span::default());
+        let output = match run_plugin(&self.command, &document.source) {
+            Ok(output) => output,
+            Err(e) => return diagnostics.push(failed(format!("Lint plugin `{}` failed: {}", self.command, e))),
+        };
+        let index = LineIndex::new(&document.source);
+        for line in output.lines().filter(|l| !l.trim().is_empty()) {
+            let finding = line.split_once(':').and_then(|(line, rest)| {
+                let (column, message) = rest.split_once(':')?;
+                Some((line.trim().parse::<usize>().ok()?, column.trim().parse::<usize>().ok()?, message.trim()))
+            });
+            diagnostics.push(match finding {
+                Some((line, column, message)) => {
+                    let start = index.offset(line, column).unwrap_or(0);
+                    Diagnostic::warning(self.code(), message, Span::new(start, start, line, column))
+                }
+                None => failed(format!("Lint plugin `{}` printed `{}`, not LINE:COLUMN: MESSAGE", self.command, line)),
+            });
+        }
+    }
+}
+
+/// Run `command` (a program and its arguments) with `input` on stdin and
+/// return what it prints
+fn run_plugin(command: &str, input: &str) -> Result<String, String> {
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or("empty command")?;
+    let mut child = Command::new(program)
+        .args(words)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    // Written from another thread so that a plugin printing before it has
+    // read everything cannot block on a full pipe
+    let mut stdin = child.stdin.take().ok_or("no stdin")?;
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || io::Write::write_all(&mut stdin, input.as_bytes()));
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    // A plugin may exit without reading all of its input
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(format!("{}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    String::from_utf8(output.stdout).map_err(|e| e.to_string())
+}
+
+/// Configuration for `input` (a file path, or `None` for stdin and the
+/// clipboard): the preset, then the configuration file, then `PQMFMT_*`
+/// environment variables, then the options
+fn build_config(opts: &Options, input: Option<&str>) -> Config {
     let mut config = if opts.compact {
         Config::compact()
     } else if opts.expanded {
@@ -149,6 +767,16 @@ fn build_config(opts: &Options) -> Config {
         Config::default()
     };
     
+    let file = config_file(opts, input);
+    if let Some(file) = &file {
+        if let Err(e) = file.apply(&mut config) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+    opts.policy.replace(file.map(|file| file.policy()).unwrap_or_default());
+    apply_env(&mut config);
+    
     if let Some(size) = opts.indent_size {
         config.indent_size = size;
     }
@@ -157,263 +785,543 @@ fn build_config(opts: &Options) -> Config {
         config.use_tabs = true;
     }
     
+    if opts.lenient {
+        config.lenient = true;
+    }
+    if opts.strict {
+        config.strict = true;
+    }
+    if let Some(dialect) = opts.dialect {
+        config.dialect = dialect;
+    }
+    if let Some(edition) = opts.style_edition {
+        config.style_edition = edition;
+    }
+    
     config
 }
 
-fn format_content(content: &str, config: Config) -> Result<String, String> {
-    format(content, config).map_err(|errors| {
-        errors
+fn format_content(content: &str, mut config: Config, opts: &Options) -> Result<String, String> {
+    let fixed;
+    let content = if opts.fix_invisibles {
+        fixed = invisibles::fix(content);
+        fixed.as_str()
+    } else {
+        content
+    };
+    let repaired;
+    let content = match opts.fix_syntax.then(|| repair::close_brackets(content)).flatten() {
+        Some(closed) => {
+            warn(opts, format_args!("Added missing closing brackets at the end: {}", closed.added));
+            repaired = closed.code;
+            repaired.as_str()
+        }
+        None => content,
+    };
+    match conflict::split(content) {
+        Ok(Some(sides)) => {
+            let ours = format_content(&sides.ours, config.clone(), opts)
+                .map_err(|e| format!("In our side of the conflicts:\n{}", e))?;
+            let theirs = format_content(&sides.theirs, config, opts)
+                .map_err(|e| format!("In their side of the conflicts:\n{}", e))?;
+            return Ok(conflict::join(&ours, &theirs, &sides.ours_marker, &sides.theirs_marker));
+        }
+        Ok(None) => {}
+        Err(e) => return Err(e.to_string()),
+    }
+    for warning in directive::apply(content, &mut config) {
+        warn(opts, warning);
+    }
+    for warning in invisibles::check(content) {
+        warn(opts, warning);
+    }
+    if let Some(limit) = opts.max_memory {
+        if stream::estimated_memory(content.len()) > limit {
+            return format_streaming(content, &config, opts, limit);
+        }
+    }
+    match parse_document(content, &config) {
+        Ok((mut document, warnings)) => {
+            // Reported after fixing, for the names left as they are
+            for warning in warnings.iter().filter(|w| !(opts.fix_name_whitespace && w.code == names::CODE)) {
+                warn(opts, warning);
+            }
+            if opts.fix_name_whitespace {
+                names::fix(&mut document);
+                for warning in names::check(&document) {
+                    warn(opts, warning);
+                }
+            }
+            let policy = opts.policy.borrow();
+            let policy = (!policy.is_empty()).then_some(&*policy as &dyn Validator);
+            for validator in opts.validators.iter().map(Box::as_ref).chain(policy).filter(|_| !opts.quiet.get()) {
+                for diagnostic in validator.validate(&document) {
+                    eprintln!("{}", diagnostic);
+                    opts.lint_findings.set(opts.lint_findings.get() + 1);
+                }
+            }
+            if opts.redact {
+                transform::redact_sources(&mut document);
+            }
+            if let Some(style) = opts.rename_steps {
+                refactor::rename_steps(&mut document, style);
+            }
+            let mut formatter = Formatter::with_source(config, content);
+            formatter.set_explain(opts.explain.is_some());
+            let formatted = formatter.format(&document);
+            if opts.quiet.get() {
+                return Ok(formatted);
+            }
+            if let Some(line) = opts.explain {
+                explain_line(&formatted, formatter.decisions(), line);
+            }
+            if opts.warn_long_lines || opts.report.is_some() {
+                let stats = formatter.stats(&formatted);
+                if opts.warn_long_lines {
+                    for warning in &stats.warnings {
+                        eprintln!("{}", warning);
+                    }
+                }
+                if let Some(ref path) = opts.report {
+                    record_usage(path, &opts.usage, &stats, formatted != content);
+                }
+            }
+            Ok(formatted)
+        }
+        Err(errors) => Err(errors
             .iter()
-            .map(|e| format!("Line {}: {}", e.span.line, e.message))
+            .map(|e| e.to_string())
             .collect::<Vec<_>>()
-            .join("\n")
+            .join("\n")),
+    }
+}
+
+/// Print a warning about the input being formatted, unless `opts.quiet`
+fn warn(opts: &Options, warning: impl std::fmt::Display) {
+    if !opts.quiet.get() {
+        eprintln!("{}", warning);
+    }
+}
+
+/// Print the layout decisions behind output line `line` (`--explain`),
+/// outermost construct first
+fn explain_line(formatted: &str, decisions: &[LayoutDecision], line: usize) {
+    let Some(text) = formatted.lines().nth(line - 1) else {
+        eprintln!("Line {}: the output has only {} lines", line, formatted.lines().count());
+        return;
+    };
+    eprintln!("Line {}: {}", line, text.trim());
+    let covering: Vec<&LayoutDecision> = decisions.iter().filter(|d| d.covers(line)).collect();
+    if covering.is_empty() {
+        eprintln!("  no construct on this line had a layout to choose");
+    }
+    for decision in covering {
+        eprintln!("  {}", decision);
+    }
+}
+
+/// Format `content`, too large to parse whole within `limit` bytes, one
+/// binding at a time (`--max-memory`)
+fn format_streaming(content: &str, config: &Config, opts: &Options, limit: usize) -> Result<String, String> {
+    let megabytes = |bytes: usize| bytes.div_ceil(1 << 20);
+    let too_large = format!(
+        "Formatting this input needs about {} MB, over --max-memory {}",
+        megabytes(stream::estimated_memory(content.len())),
+        megabytes(limit)
+    );
+    if opts.redact
+        || opts.rename_steps.is_some()
+        || opts.fix_name_whitespace
+        || !opts.validators.is_empty()
+        || !opts.policy.borrow().is_empty()
+        || opts.report.is_some()
+        || opts.explain.is_some()
+    {
+        return Err(format!(
+            "{}; --redact, --rename-steps, --fix-name-whitespace, --columns, --lint-plugin, a [policy], --report, and --explain need the whole document",
+            too_large
+        ));
+    }
+    let Some(parts) = stream::split_let(content) else {
+        return Err(format!("{}; only a single let can be formatted a binding at a time", too_large));
+    };
+    let largest = stream::estimated_memory(parts.largest());
+    if largest > limit {
+        return Err(format!(
+            "Formatting the largest binding of this input needs about {} MB, over --max-memory {}",
+            megabytes(largest),
+            megabytes(limit)
+        ));
+    }
+    let mut formatted = String::with_capacity(content.len());
+    stream::format_let(content, &parts, config, &mut formatted).map_err(|e| e.to_string())?;
+    Ok(formatted)
+}
+
+/// Add one formatted input to the usage report and save it. The report is
+/// rewritten after every input, so it is complete whichever way the run ends.
+fn record_usage(path: &str, usage: &RefCell<UsageReport>, stats: &FormatStats, changed: bool) {
+    let mut usage = usage.borrow_mut();
+    usage.add(stats, changed);
+    if let Err(e) = fs::write(path, usage.to_json()) {
+        eprintln!("Error writing {}: {}", path, e);
+    }
+}
+
+/// Whether `content` counts as formatted under `mode`
+fn is_formatted(content: &str, formatted: &str, mode: CheckMode) -> bool {
+    match mode {
+        CheckMode::Strict => content == formatted,
+        CheckMode::Loose => normalize_whitespace(content) == normalize_whitespace(formatted),
+        CheckMode::Semantic => semantically_equal(content, formatted).unwrap_or(false),
+    }
+}
+
+/// Another style edition under which `content` counts as formatted, if any
+fn formatted_edition(content: &str, config: &Config, mode: CheckMode) -> Option<StyleEdition> {
+    StyleEdition::ALL.into_iter().filter(|&e| e != config.style_edition).find(|&edition| {
+        let mut config = config.clone();
+        config.style_edition = edition;
+        pqm_formatter::format(content, config).is_ok_and(|formatted| is_formatted(content, &formatted, mode))
     })
 }
 
-/// Get clipboard content using native commands
-fn get_clipboard() -> Result<String, String> {
-    #[cfg(target_os = "windows")]
+/// Text with `\r\n` line endings, trailing whitespace, and leading/trailing
+/// blank lines removed
+fn normalize_whitespace(text: &str) -> String {
+    text.lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_matches('\n')
+        .to_string()
+}
+
+/// `--emit config-schema`
+fn emit_config_schema() -> ! {
+    #[cfg(feature = "schema")]
     {
-        // Use Windows Forms Clipboard API and save to temp file with UTF-8
-        let temp_path = std::env::temp_dir().join("pqmfmt_clipboard_in.txt");
-        let temp_path_str = temp_path.to_string_lossy().replace('\\', "\\\\");
-        
-        let ps_script = format!(r#"
-Add-Type -AssemblyName System.Windows.Forms
-$text = [System.Windows.Forms.Clipboard]::GetText()
-[System.IO.File]::WriteAllText('{}', $text, [System.Text.Encoding]::UTF8)
-"#, temp_path_str);
-        
-        let output = Command::new("powershell")
-            .args(["-NoProfile", "-STA", "-Command", &ps_script])
-            .output()
-            .map_err(|e| format!("Failed to execute PowerShell: {}", e))?;
-        
-        if !output.status.success() {
-            return Err(format!("PowerShell error: {}", String::from_utf8_lossy(&output.stderr)));
+        println!("{}", Config::json_schema());
+        process::exit(0);
+    }
+    #[cfg(not(feature = "schema"))]
+    {
+        eprintln!("Error: this pqmfmt was built without the `schema` feature");
+        process::exit(1);
+    }
+}
+
+/// Inputs of `option` (`--emit`, `--suggest-rename`): stdin or the files,
+/// with their paths, and whether any file could not be read
+fn emit_inputs<'o>(opts: &'o Options, option: &str) -> (Vec<(Option<&'o str>, String)>, bool) {
+    let mut inputs = Vec::new();
+    if opts.stdin {
+        let mut input = Vec::new();
+        if let Err(e) = io::stdin().read_to_end(&mut input) {
+            eprintln!("Error reading stdin: {}", e);
+            process::exit(1);
         }
-        
-        // Read the UTF-8 file
-        let content = std::fs::read_to_string(&temp_path)
-            .map_err(|e| format!("Failed to read clipboard content: {}", e))?;
-        
-        // Clean up temp file
-        let _ = std::fs::remove_file(&temp_path);
-        
-        // Remove BOM if present
-        let content = content.strip_prefix('\u{FEFF}').unwrap_or(&content);
-        Ok(content.to_string())
+        let content = match String::from_utf8(input) {
+            Ok(content) => content,
+            Err(e) if opts.lossy => decode_lossy(e.as_bytes(), "<stdin>"),
+            Err(e) => {
+                eprintln!("Error reading stdin: {}", e.utf8_error());
+                process::exit(1);
+            }
+        };
+        inputs.push((None, content));
+    } else if opts.files.is_empty() {
+        eprintln!("Error: {} requires input files or --stdin", option);
+        process::exit(1);
     }
     
-    #[cfg(target_os = "macos")]
-    {
-        let output = Command::new("pbpaste")
-            .output()
-            .map_err(|e| format!("Failed to execute pbpaste: {}", e))?;
-        
-        if output.status.success() {
-            String::from_utf8(output.stdout)
-                .map_err(|e| format!("Invalid UTF-8 in clipboard: {}", e))
-        } else {
-            Err(format!("pbpaste error: {}", String::from_utf8_lossy(&output.stderr)))
+    let mut has_errors = false;
+    for file_path in &opts.files {
+        match read_source(file_path, opts) {
+            Ok(content) => inputs.push((Some(file_path.as_str()), content)),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", file_path, e);
+                has_errors = true;
+            }
+        }
+    }
+    (inputs, has_errors)
+}
+
+/// `--suggest-rename`: shorter names for the steps of each input whose
+/// names do not fit on a line, as `file: old -> new`
+fn suggest_renames(opts: &Options) {
+    let (inputs, mut has_errors) = emit_inputs(opts, "--suggest-rename");
+    for (file, content) in &inputs {
+        let mut config = build_config(opts, *file);
+        directive::apply(content, &mut config);
+        match parse_document(content, &config) {
+            Ok((document, _)) => {
+                for (old, new) in refactor::suggest_step_names(&document, &config) {
+                    println!("{}: {} -> {}", file.unwrap_or("<stdin>"), written_name(&old), written_name(&new));
+                }
+            }
+            Err(errors) => {
+                eprintln!("Error in {}:", file.unwrap_or("<stdin>"));
+                for e in &errors {
+                    eprintln!("{}", e);
+                }
+                has_errors = true;
+            }
         }
     }
     
-    #[cfg(target_os = "linux")]
-    {
-        // Try xclip first, then xsel
-        let output = Command::new("xclip")
-            .args(["-selection", "clipboard", "-o"])
-            .output()
-            .or_else(|_| {
-                Command::new("xsel")
-                    .args(["--clipboard", "--output"])
-                    .output()
-            })
-            .map_err(|e| format!("Failed to execute xclip/xsel: {}", e))?;
-        
-        if output.status.success() {
-            String::from_utf8(output.stdout)
-                .map_err(|e| format!("Invalid UTF-8 in clipboard: {}", e))
-        } else {
-            Err(format!("Clipboard error: {}", String::from_utf8_lossy(&output.stderr)))
+    if has_errors {
+        process::exit(1);
+    }
+}
+
+/// `name` as an identifier in code
+fn written_name(name: &str) -> String {
+    if is_regular_identifier(name) {
+        name.to_string()
+    } else {
+        format!("#\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+/// `--emit html|ansi`: the formatted code of each input, highlighted
+fn emit_rendered(opts: &Options, emit: Emit) {
+    let (inputs, mut has_errors) = emit_inputs(opts, "--emit");
+    for (file, content) in &inputs {
+        match format_content(content, build_config(opts, *file), opts) {
+            Ok(formatted) if emit == Emit::Html => print!("{}", render::to_html(&formatted, opts.theme)),
+            Ok(formatted) => print!("{}", render::to_ansi(&formatted, opts.theme)),
+            Err(e) => {
+                eprintln!("Error in {}:\n{}", file.unwrap_or("<stdin>"), e);
+                has_errors = true;
+            }
         }
     }
     
-    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
-    {
-        Err("Clipboard not supported on this platform".to_string())
+    if has_errors {
+        process::exit(1);
     }
 }
 
-/// Set clipboard content using native commands
-fn set_clipboard(content: &str) -> Result<(), String> {
-    #[cfg(target_os = "windows")]
-    {
-        // Write content to a temp file with UTF-8 encoding
-        let temp_path = std::env::temp_dir().join("pqmfmt_clipboard_out.txt");
-        
-        std::fs::write(&temp_path, content.as_bytes())
-            .map_err(|e| format!("Failed to write temp file: {}", e))?;
-        
-        let temp_path_str = temp_path.to_string_lossy().replace('\\', "\\\\");
-        
-        // Use Windows Forms Clipboard API for proper Unicode support
-        let ps_script = format!(r#"
-Add-Type -AssemblyName System.Windows.Forms
-$text = [System.IO.File]::ReadAllText('{}', [System.Text.Encoding]::UTF8)
-[System.Windows.Forms.Clipboard]::SetText($text)
-"#, temp_path_str);
-        
-        let output = Command::new("powershell")
-            .args(["-NoProfile", "-STA", "-Command", &ps_script])
-            .output()
-            .map_err(|e| format!("Failed to execute PowerShell: {}", e))?;
-        
-        // Clean up temp file
-        let _ = std::fs::remove_file(&temp_path);
-        
-        if output.status.success() {
-            Ok(())
-        } else {
-            Err(format!("PowerShell Set-Clipboard failed: {}", 
-                String::from_utf8_lossy(&output.stderr)))
+/// `--preview`: the formatted inputs with line numbers, wrapped to the
+/// terminal width less the line numbers
+fn preview(opts: &Options) {
+    if opts.check || opts.write || opts.output.is_some() {
+        eprintln!("Error: --preview prints to the terminal and cannot be combined with --check, --write, or --output");
+        process::exit(1);
+    }
+    let (inputs, mut has_errors) = emit_inputs(opts, "--preview");
+    let width = terminal_width();
+    let dim = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+    for (file, content) in &inputs {
+        let mut config = build_config(opts, *file);
+        let formatted = match width {
+            Some(width) => {
+                // Numbers as wide as the input's lines first; the output
+                // rarely needs more. Its warnings were printed the first time.
+                let mut gutter = render::gutter_width(content.lines().count());
+                let formatted = loop {
+                    config.max_line_length = width.saturating_sub(gutter).max(MIN_PREVIEW_WIDTH);
+                    let formatted = format_content(content, config.clone(), opts);
+                    match &formatted {
+                        Ok(text) if render::gutter_width(text.lines().count()) > gutter => {
+                            gutter = render::gutter_width(text.lines().count());
+                            opts.quiet.set(true);
+                        }
+                        _ => break formatted,
+                    }
+                };
+                opts.quiet.set(false);
+                formatted
+            }
+            None => format_content(content, config, opts),
+        };
+        match formatted {
+            Ok(formatted) => {
+                if inputs.len() > 1 {
+                    println!("{}:", file.unwrap_or("<stdin>"));
+                }
+                print!("{}", render::to_numbered(&formatted, dim));
+            }
+            Err(e) => {
+                eprintln!("Error in {}:\n{}", file.unwrap_or("<stdin>"), e);
+                has_errors = true;
+            }
         }
     }
     
-    #[cfg(target_os = "macos")]
+    if has_errors {
+        process::exit(1);
+    }
+}
+
+/// Narrowest line length `--preview` formats to, however narrow the terminal
+const MIN_PREVIEW_WIDTH: usize = 20;
+
+/// Columns of the terminal: `COLUMNS` if set, or the size of the terminal
+/// when stdout or stderr is one, so that it is found while stdout is piped
+/// into a pager
+fn terminal_width() -> Option<usize> {
+    if let Some(columns) = env::var("COLUMNS").ok().and_then(|c| c.trim().parse().ok()).filter(|&c| c > 0) {
+        return Some(columns);
+    }
+    if !io::stdout().is_terminal() && !io::stderr().is_terminal() {
+        return None;
+    }
+    
+    #[cfg(unix)]
     {
-        let mut child = Command::new("pbcopy")
-            .stdin(Stdio::piped())
-            .spawn()
-            .map_err(|e| format!("Failed to execute pbcopy: {}", e))?;
-        
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(content.as_bytes())
-                .map_err(|e| format!("Failed to write to pbcopy: {}", e))?;
-        }
-        
-        let status = child.wait()
-            .map_err(|e| format!("Failed to wait for pbcopy: {}", e))?;
-        
-        if status.success() {
-            Ok(())
-        } else {
-            Err("pbcopy failed".to_string())
-        }
+        // `stty size` prints "rows columns" for the terminal on its stdin
+        let tty = fs::File::open("/dev/tty").ok()?;
+        let output = Command::new("stty").arg("size").stdin(tty).output().ok()?;
+        String::from_utf8_lossy(&output.stdout).split_whitespace().nth(1)?.parse().ok()
     }
     
-    #[cfg(target_os = "linux")]
+    #[cfg(windows)]
     {
-        // Try xclip first, then xsel
-        let result = Command::new("xclip")
-            .args(["-selection", "clipboard"])
-            .stdin(Stdio::piped())
-            .spawn();
-        
-        let mut child = match result {
-            Ok(child) => child,
-            Err(_) => {
-                Command::new("xsel")
-                    .args(["--clipboard", "--input"])
-                    .stdin(Stdio::piped())
-                    .spawn()
-                    .map_err(|e| format!("Failed to execute xclip/xsel: {}", e))?
-            }
-        };
-        
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(content.as_bytes())
-                .map_err(|e| format!("Failed to write to clipboard: {}", e))?;
-        }
-        
-        let status = child.wait()
-            .map_err(|e| format!("Failed to wait for clipboard command: {}", e))?;
-        
-        if status.success() {
-            Ok(())
-        } else {
-            Err("Clipboard command failed".to_string())
-        }
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-Command", "$Host.UI.RawUI.WindowSize.Width"])
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
     }
     
-    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+    #[cfg(not(any(unix, windows)))]
     {
-        Err("Clipboard not supported on this platform".to_string())
+        None
     }
 }
 
-/// Process clipboard: read, format, and write back
-fn process_clipboard(config: Config) {
-    let content = match get_clipboard() {
-        Ok(text) => text,
-        Err(e) => {
-            eprintln!("Error reading clipboard: {}", e);
-            process::exit(1);
-        }
-    };
+/// `--emit steps-json|steps-csv`: one entry per step across all inputs
+fn emit_steps(opts: &Options, csv: bool) {
+    let (inputs, mut has_errors) = emit_inputs(opts, "--emit");
     
-    // Check if content looks like Power Query M code
-    let trimmed = content.trim();
-    let lower = trimmed.to_lowercase();
-    let is_pqm = lower.starts_with("let")
-        || lower.starts_with("section")
-        || trimmed.starts_with('(')   // Function expression
-        || trimmed.starts_with('[')   // Record expression
-        || trimmed.starts_with('{');  // List expression
+    let mut rows = Vec::new();
+    for (file, content) in &inputs {
+        match parse_document(content, &build_config(opts, *file)) {
+            Ok((document, _)) => {
+                for step in analysis::step_infos(&document) {
+                    rows.push(if csv {
+                        step.to_csv_row(file.unwrap_or(""))
+                    } else {
+                        step.to_json(*file)
+                    });
+                }
+            }
+            Err(errors) => {
+                eprintln!("Error in {}:", file.unwrap_or("<stdin>"));
+                for e in &errors {
+                    eprintln!("{}", e);
+                }
+                has_errors = true;
+            }
+        }
+    }
     
-    if !is_pqm {
-        eprintln!("Clipboard does not contain Power Query M code");
-        eprintln!("(Expected to start with 'let', '(', '[', '{{', or 'section')");
-        if !trimmed.is_empty() {
-            eprintln!("Clipboard content preview: {}...", 
-                &trimmed.chars().take(50).collect::<String>());
+    if csv {
+        println!("file,name,comment,line");
+        for row in &rows {
+            println!("{}", row);
         }
+    } else if rows.is_empty() {
+        println!("[]");
+    } else {
+        println!("[\n    {}\n]", rows.join(",\n    "));
+    }
+    
+    if has_errors {
         process::exit(1);
     }
+}
+
+/// The contents of `file_path`; with `--lossy`, invalid UTF-8 is replaced
+/// with U+FFFD and reported instead of failing
+fn read_source(file_path: &str, opts: &Options) -> io::Result<String> {
+    if !opts.lossy {
+        return fs::read_to_string(file_path);
+    }
+    Ok(decode_lossy(&fs::read(file_path)?, file_path))
+}
+
+/// `input` with invalid UTF-8 replaced, reporting each replacement in
+/// `source` on stderr
+fn decode_lossy(input: &[u8], source: &str) -> String {
+    let (content, warnings) = text::decode_lossy(input);
+    for warning in warnings {
+        eprintln!("{}: {}", source, warning);
+    }
+    content
+}
+
+/// Write the input unchanged to `--output` or stdout, for `--best-effort`
+fn pass_through(input: &[u8], opts: &Options) {
+    use std::io::Write as _;
     
-    match format_content(&content, config) {
-        Ok(formatted) => {
-            if let Err(e) = set_clipboard(&formatted) {
-                eprintln!("Error writing to clipboard: {}", e);
-                process::exit(1);
-            }
-            eprintln!("Formatted code copied to clipboard.");
-        }
-        Err(error_msg) => {
-            // On error, put error message + original code in clipboard
-            let error_output = format!(
-                "// Format Error:\n// {}\n\n{}",
-                error_msg.replace('\n', "\n// "),
-                content
-            );
-            if let Err(e) = set_clipboard(&error_output) {
-                eprintln!("Error writing to clipboard: {}", e);
-                process::exit(1);
-            }
-            eprintln!("Format error. Error message and original code copied to clipboard.");
-            eprintln!("{}", error_msg);
+    if let Some(ref output_path) = opts.output {
+        if let Err(e) = fs::write(output_path, input) {
+            eprintln!("Error writing to {}: {}", output_path, e);
             process::exit(1);
         }
+    } else if let Err(e) = io::stdout().write_all(input) {
+        eprintln!("Error writing to stdout: {}", e);
+        process::exit(1);
     }
 }
 
 fn main() {
-    let opts = parse_args();
-    let config = build_config(&opts);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if cli::run_subcommand(&args) {
+        return;
+    }
     
+    let mut opts = parse_args(&args);
+    let plugins = LINT_PLUGINS.get_or_init(|| std::mem::take(&mut opts.lint_plugins));
+    opts.validators.extend(plugins.iter().map(|rule| Box::new(rule) as Box<dyn Validator>));
+    if opts.suggest_rename {
+        return suggest_renames(&opts);
+    }
+    if opts.preview {
+        return preview(&opts);
+    }
+    match opts.emit {
+        Some(emit @ (Emit::Html | Emit::Ansi)) => return emit_rendered(&opts, emit),
+        Some(emit) => return emit_steps(&opts, emit == Emit::StepsCsv),
+        None => {}
+    }
     if opts.stdin {
         // Read from stdin
-        let mut content = String::new();
-        if let Err(e) = io::stdin().read_to_string(&mut content) {
+        let mut input = Vec::new();
+        if let Err(e) = io::stdin().read_to_end(&mut input) {
             eprintln!("Error reading stdin: {}", e);
             process::exit(1);
         }
+        // Without style options, smudge keeps the stored canonical form
+        let has_style_options =
+            opts.compact || opts.expanded || opts.indent_size.is_some() || opts.use_tabs;
+        if opts.filter == Some(FilterMode::Smudge) && !has_style_options {
+            pass_through(&input, &opts);
+            return;
+        }
         
-        match format_content(&content, config) {
+        let content = match String::from_utf8(input) {
+            Ok(content) => content,
+            Err(e) if opts.lossy => decode_lossy(e.as_bytes(), "<stdin>"),
+            Err(e) if opts.best_effort && !opts.check => {
+                eprintln!("Error reading stdin: {}", e.utf8_error());
+                pass_through(e.as_bytes(), &opts);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error reading stdin: {}", e.utf8_error());
+                process::exit(1);
+            }
+        };
+        
+        match format_content(&content, build_config(&opts, None), &opts) {
             Ok(formatted) => {
                 if opts.check {
-                    if formatted.trim() != content.trim() {
+                    if !is_formatted(&content, &formatted, opts.check_mode) {
                         eprintln!("Input is not formatted");
                         process::exit(1);
                     }
@@ -426,17 +1334,22 @@ fn main() {
                     print!("{}", formatted);
                 }
             }
+            Err(e) if opts.best_effort && !opts.check => {
+                eprintln!("Parse error:\n{}", e);
+                pass_through(content.as_bytes(), &opts);
+            }
             Err(e) => {
                 eprintln!("Parse error:\n{}", e);
                 process::exit(1);
             }
         }
+        exit_on_lint_findings(&opts);
         return;
     }
     
     // No files specified - use clipboard mode
     if opts.files.is_empty() {
-        process_clipboard(config);
+        cli::clipboard::process_clipboard(build_config(&opts, None), &opts);
         return;
     }
     
@@ -444,7 +1357,7 @@ fn main() {
     let mut not_formatted = false;
     
     for file_path in &opts.files {
-        let content = match fs::read_to_string(file_path) {
+        let content = match read_source(file_path, &opts) {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Error reading {}: {}", file_path, e);
@@ -453,11 +1366,20 @@ fn main() {
             }
         };
         
-        match format_content(&content, config.clone()) {
+        let config = build_config(&opts, Some(file_path));
+        match format_content(&content, config.clone(), &opts) {
             Ok(formatted) => {
                 if opts.check {
-                    if formatted.trim() != content.trim() {
-                        eprintln!("{}: not formatted", file_path);
+                    if !is_formatted(&content, &formatted, opts.check_mode) {
+                        match formatted_edition(&content, &config, opts.check_mode) {
+                            Some(edition) => eprintln!(
+                                "{}: not formatted (formatted with style edition {}, expected {})",
+                                file_path,
+                                edition.name(),
+                                config.style_edition.name()
+                            ),
+                            None => eprintln!("{}: not formatted", file_path),
+                        }
                         not_formatted = true;
                     }
                 } else if opts.write {
@@ -486,8 +1408,39 @@ fn main() {
     if has_errors {
         process::exit(1);
     }
+    exit_on_lint_findings(&opts);
     
     if not_formatted {
         process::exit(1);
     }
 }
+
+/// With `--lint`, exit with status 1 if the validators or the policy
+/// reported anything
+fn exit_on_lint_findings(opts: &Options) {
+    let findings = opts.lint_findings.get();
+    if opts.lint && findings > 0 {
+        eprintln!("{} lint finding{}", findings, if findings == 1 { "" } else { "s" });
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+    
+    #[test]
+    fn test_files_named_like_subcommands() {
+        let escaped = args(&["--", "diff", "-w"]);
+        assert!(!cli::run_subcommand(&escaped));
+        assert_eq!(parse_args(&escaped).files, ["diff", "-w"]);
+        
+        let opts = parse_args(&args(&["-w", "--", "split"]));
+        assert!(opts.write);
+        assert_eq!(opts.files, ["split"]);
+    }
+}
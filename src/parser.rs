@@ -1,6 +1,16 @@
 //! Parser for Power Query M language
 
+use core::cell::Cell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::alloc_prelude::*;
 use crate::ast::*;
+use crate::config::{directive, Config, Dialect};
+use crate::diagnostic::Diagnostic;
+use crate::formatter::{FormatError, Interruption, MIN_WORK_BUDGET};
+use crate::json;
+use crate::text::LineIndex;
 use crate::token::{Span, Token, TokenKind};
 
 /// Parser errors
@@ -8,6 +18,16 @@ use crate::token::{Span, Token, TokenKind};
 pub struct ParseError {
     pub message: String,
     pub span: Span,
+    /// Other places involved, such as the bracket a missing closing bracket
+    /// belongs to
+    pub labels: Vec<Label>,
+}
+
+/// A secondary place of a [`ParseError`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub message: String,
+    pub span: Span,
 }
 
 impl ParseError {
@@ -15,90 +35,725 @@ impl ParseError {
         Self {
             message: message.into(),
             span,
+            labels: Vec::new(),
+        }
+    }
+    
+    pub fn with_label(mut self, message: impl Into<String>, span: Span) -> Self {
+        self.labels.push(Label { message: message.into(), span });
+        self
+    }
+    
+    /// The error as a Language Server Protocol `Diagnostic` object for the
+    /// document at `uri` (of which `index` indexes the text), with the
+    /// labels as its `relatedInformation`.
+    ///
+    /// ```rust
+    /// use pqm_formatter::text::LineIndex;
+    /// use pqm_formatter::validate;
+    ///
+    /// let code = "[\n    A = 1\n";
+    /// let error = &validate(code).unwrap_err()[0];
+    /// let json = error.to_lsp_json("file:///q.pq", &LineIndex::new(code));
+    /// assert!(json.contains(r#""relatedInformation":[{"location":{"uri":"file:///q.pq","range":{"start":{"line":0,"character":0},"end":{"line":0,"character":1}}},"message":"unclosed `[` opened here"}]"#));
+    /// ```
+    pub fn to_lsp_json(&self, uri: &str, index: &LineIndex) -> String {
+        let mut json = format!(
+            "{{\"range\":{},\"severity\":1,\"source\":\"pqmfmt\",\"message\":{}",
+            lsp_range(self.span, index),
+            json::quote(&self.message)
+        );
+        if !self.labels.is_empty() {
+            let related: Vec<String> = self
+                .labels
+                .iter()
+                .map(|label| {
+                    format!(
+                        "{{\"location\":{{\"uri\":{},\"range\":{}}},\"message\":{}}}",
+                        json::quote(uri),
+                        lsp_range(label.span, index),
+                        json::quote(&label.message)
+                    )
+                })
+                .collect();
+            json.push_str(&format!(",\"relatedInformation\":[{}]", related.join(",")));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// `span` as a Language Server Protocol `Range` object
+fn lsp_range(span: Span, index: &LineIndex) -> String {
+    let position = |offset| {
+        let position = index.to_utf16(offset);
+        format!("{{\"line\":{},\"character\":{}}}", position.line, position.character)
+    };
+    format!("{{\"start\":{},\"end\":{}}}", position(span.start), position(span.end))
+}
+
+/// `Line 5: message`, followed by an indented line for each label
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.span.line, self.message)?;
+        for label in &self.labels {
+            write!(f, "\n    Line {}: {}", label.span.line, label.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Grammar rule the parser starts from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StartRule {
+    /// A single expression document (a query)
+    Expression,
+    /// A section document: `section Name; shared A = ...;`
+    Section,
+    /// Pick `Section` if the input starts with `section` (possibly after
+    /// an attribute record), otherwise `Expression`
+    #[default]
+    Any,
+}
+
+/// Options controlling parser behavior
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Attach comments to AST nodes as leading/trailing trivia
+    pub attach_trivia: bool,
+    
+    /// Maximum expression nesting depth before parsing is aborted. Each
+    /// nested bracket, operand, or body counts; the `if` after an `else`
+    /// does not, so `else if` chains are not limited by it. The default,
+    /// 1000, leaves room to parse and format on the 8 MiB main thread of a
+    /// release build on Linux or macOS. Lower it on threads with smaller
+    /// stacks (2 MiB for spawned threads, 1 MiB for the Windows main thread).
+    pub max_depth: usize,
+    
+    /// Grammar rule to start parsing from
+    pub start: StartRule,
+    
+    /// Capture unparseable regions as `ExprKind::Verbatim` instead of failing
+    pub lenient: bool,
+    
+    /// Also reject constructs the Power Query engine rejects but this
+    /// parser accepts (see [`crate::strict::check`]), and make the
+    /// warnings of [`crate::lint::check`] errors
+    pub strict: bool,
+    
+    /// Host the code targets. Constructs it does not support (see
+    /// [`crate::compat::check`]) are warnings, or errors in strict mode.
+    pub dialect: Dialect,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            attach_trivia: true,
+            max_depth: 1000,
+            start: StartRule::Any,
+            lenient: false,
+            strict: false,
+            dialect: Dialect::PowerBI,
         }
     }
 }
 
+/// An element of a comma-separated production with its comments
+struct Element<T> {
+    leading_trivia: Vec<Trivia>,
+    value: T,
+    trailing_trivia: Vec<Trivia>,
+    /// Whether a comma followed, so more elements may come
+    separated: bool,
+}
+
+/// Line ending style of a document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NewlineKind {
+    /// `\n`
+    #[default]
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+/// A parsed document together with the file-level facts needed to write it
+/// back faithfully
+#[derive(Debug, Clone)]
+pub struct ParseOutput {
+    pub document: Document,
+    /// Every comment in source order as written (`// ...` or `/* ... */`),
+    /// whether or not it was attached to a node
+    pub comments: Vec<(Span, String)>,
+    /// Line ending of the first line break (`Lf` if there is none)
+    pub newline_kind: NewlineKind,
+    /// Whether the text started with a UTF-8 byte order mark
+    pub had_bom: bool,
+}
+
+/// A failed production; its errors are in the `errors` of the parser
+#[derive(Debug)]
+struct Failed;
+
+type PResult<T> = Result<T, Failed>;
+
 /// Parser for Power Query M
-pub struct Parser {
+pub struct Parser<'a> {
     tokens: Vec<Token>,
     pos: usize,
     errors: Vec<ParseError>,
+    warnings: Vec<Diagnostic>,
+    options: ParseOptions,
+    depth: usize,
+    /// Indices of the comment tokens attached as trivia so far
+    attached: Vec<usize>,
+    /// The record fields and list items being parsed, outermost first
+    contexts: Vec<Context>,
+    /// For each token, whether it is an opening bracket that is closed
+    /// later (see [`Parser::closed_later`]); computed on first recovery
+    closed_brackets: Option<Vec<bool>>,
+    /// For each `(`, the index of its `)`; computed on first use
+    matching_parens: Option<Vec<Option<usize>>>,
+    /// Token that aborts parsing when set (see [`Parser::set_cancellation`])
+    cancel: Option<&'a AtomicBool>,
+    /// Expressions parsed and tokens skipped in recovery so far
+    work: Cell<usize>,
+    /// Steps allowed before parsing is abandoned as too complex
+    work_budget: usize,
+    /// Why parsing was abandoned, if it was
+    interrupted: Cell<Option<Interruption>>,
 }
 
-impl Parser {
+/// Errors reported before the parser stops recovering and gives up
+pub const MAX_ERRORS: usize = 100;
+
+/// Parsing steps allowed per token with a cancellation token; parsing
+/// takes a few per token, so only runaway recovery reaches the budget
+const WORK_PER_TOKEN: usize = 1024;
+
+/// A record field or list item being parsed, named in the errors inside it
+/// so that they can be found in long records and lists
+#[derive(Debug, Clone, Copy)]
+struct Context {
+    /// `"field"` or `"item"`
+    element: &'static str,
+    /// `"record"` or `"list"`
+    container: &'static str,
+    /// Position of the element, from 1
+    index: usize,
+    /// Line where the record or list starts
+    line: usize,
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} of {} starting at line {}",
+            self.element, self.index, self.container, self.line
+        )
+    }
+}
+
+impl<'a> Parser<'a> {
     /// Create a new parser from tokens
     pub fn new(tokens: Vec<Token>) -> Self {
+        Self::with_options(tokens, ParseOptions::default())
+    }
+    
+    /// Create a new parser from tokens with the given options
+    pub fn with_options(tokens: Vec<Token>, options: ParseOptions) -> Self {
         Self {
             tokens,
             pos: 0,
             errors: Vec::new(),
+            warnings: Vec::new(),
+            options,
+            depth: 0,
+            attached: Vec::new(),
+            contexts: Vec::new(),
+            closed_brackets: None,
+            matching_parens: None,
+            cancel: None,
+            work: Cell::new(0),
+            work_budget: usize::MAX,
+            interrupted: Cell::new(None),
         }
     }
     
-    /// Parse the document
+    /// Check `token` while parsing: once it is set, [`Parser::try_parse`]
+    /// stops and returns [`FormatError::Cancelled`]. Parsing also stops,
+    /// with [`FormatError::TooComplex`], if it takes much more work than the
+    /// number of tokens warrants.
+    pub fn set_cancellation(&mut self, token: &'a AtomicBool) {
+        self.cancel = Some(token);
+        self.work_budget = self.tokens.len().saturating_mul(WORK_PER_TOKEN).saturating_add(MIN_WORK_BUDGET);
+    }
+    
+    /// Non-fatal diagnostics collected by the last parse
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+    
+    /// Parse the document along with its comments, line ending style, and
+    /// byte order mark
+    pub fn parse_output(&mut self) -> Result<ParseOutput, Vec<ParseError>> {
+        let document = self.parse()?;
+        
+        let comments = self
+            .tokens
+            .iter()
+            .filter_map(|t| match &t.kind {
+                TokenKind::LineComment(text) => Some((t.span, format!("//{}", text))),
+                TokenKind::BlockComment(text) => Some((t.span, format!("/*{}*/", text))),
+                _ => None,
+            })
+            .collect();
+        let newline_kind = match self.tokens.iter().find(|t| t.kind == TokenKind::Newline) {
+            Some(t) if t.span.end - t.span.start == 2 => NewlineKind::CrLf,
+            _ => NewlineKind::Lf,
+        };
+        let had_bom = self
+            .tokens
+            .first()
+            .is_some_and(|t| matches!(&t.kind, TokenKind::Whitespace(ws) if ws.starts_with('\u{FEFF}')));
+        
+        Ok(ParseOutput {
+            document,
+            comments,
+            newline_kind,
+            had_bom,
+        })
+    }
+    
+    /// Parse the document. If parsing was cancelled or over budget (see
+    /// [`Parser::set_cancellation`]), the one error says so.
     pub fn parse(&mut self) -> Result<Document, Vec<ParseError>> {
-        self.skip_trivia();
+        let result = self.parse_document();
+        if let Some(interruption) = self.interrupted.get() {
+            let message = match interruption {
+                Interruption::Cancelled => "Parsing was cancelled",
+                Interruption::OverBudget => "Input is too complex to parse",
+            };
+            return Err(vec![ParseError::new(message, self.current_span())]);
+        }
+        result.map_err(|Failed| core::mem::take(&mut self.errors))
+    }
+    
+    /// Parse the document, unless cancelled or over budget (see
+    /// [`Parser::set_cancellation`])
+    pub fn try_parse(&mut self) -> Result<Document, FormatError> {
+        self.parse().map_err(|errors| match self.interrupted.get() {
+            None => FormatError::Parse(errors),
+            Some(Interruption::Cancelled) => FormatError::Cancelled,
+            Some(Interruption::OverBudget) => FormatError::TooComplex,
+        })
+    }
+    
+    fn parse_document(&mut self) -> PResult<Document> {
+        let leading_trivia = self.take_comments();
         let start_span = self.current_span();
         
-        let expression = self.parse_expression()?;
+        let is_section = match self.options.start {
+            StartRule::Expression => false,
+            StartRule::Section => true,
+            StartRule::Any => self.starts_section(),
+        };
         
-        self.skip_trivia();
+        let mut expression = if is_section {
+            self.parse_section()?
+        } else {
+            let mut expression = self.parse_expression_or_verbatim()?;
+            // Looking for a postfix operator may have skipped past the end
+            // of the expression's last line
+            while self.pos > 0 && self.tokens[self.pos - 1].kind.is_trivia() {
+                self.pos -= 1;
+            }
+            expression.trailing_trivia.extend(self.take_same_line_comments());
+            expression
+        };
+        expression.leading_trivia.splice(0..0, leading_trivia);
+        let trailing_trivia = self.take_comments();
+        
+        if self.options.lenient && !self.is_at_end() {
+            // No boundary can split a document: keep all of it as written
+            let span = start_span.merge(self.tokens[self.tokens.len() - 1].span);
+            self.warnings.push(Diagnostic::warning(
+                "lenient-verbatim",
+                "Unexpected token after expression; document kept as written",
+                self.current_span(),
+            ));
+            expression = Expr::new(ExprKind::Verbatim(span), span);
+            self.pos = self.tokens.len() - 1;
+        }
         if !self.is_at_end() {
-            self.errors.push(ParseError::new(
+            self.push_error(
                 "Unexpected token after expression",
                 self.current_span(),
-            ));
+            );
         }
         
-        if self.errors.is_empty() {
-            Ok(Document {
-                expression,
-                span: start_span.merge(self.current_span()),
-            })
+        if !self.errors.is_empty() {
+            return Err(Failed);
+        }
+        
+        let mut trailing_trivia = trailing_trivia;
+        if self.options.attach_trivia {
+            self.place_comments(&mut expression, &mut trailing_trivia);
+        }
+        let document = Document {
+            expression,
+            span: start_span.merge(self.current_span()),
+            trailing_trivia,
+            source: String::new(),
+        };
+        self.check_scoped_directives();
+        #[allow(unused_mut)]
+        let mut incompatible = crate::compat::check(&document, self.options.dialect);
+        #[cfg(feature = "lints")]
+        incompatible.extend(crate::lint::check(&document));
+        if self.options.strict {
+            self.errors = crate::strict::check(&document);
+            self.errors.extend(incompatible.into_iter().map(|d| ParseError::new(d.message, d.span)));
+            if !self.errors.is_empty() {
+                return Err(Failed);
+            }
         } else {
-            Err(self.errors.clone())
+            self.warnings.extend(incompatible);
+        }
+        // Such names are legal, so not errors even in strict mode
+        #[cfg(feature = "lints")]
+        self.warnings.extend(crate::lint::names::check(&document));
+        Ok(document)
+    }
+    
+    /// Attach the comments no production took as trivia (between an
+    /// operator and its operand, inside empty brackets, ...) to the nearest
+    /// expression, so that every comment is written back
+    fn place_comments(&self, expression: &mut Expr, trailing_trivia: &mut Vec<Trivia>) {
+        let mut attached = self.attached.clone();
+        attached.sort_unstable();
+        let (before, rest): (Vec<usize>, Vec<usize>) = (0..self.tokens.len())
+            .filter(|&i| {
+                matches!(self.tokens[i].kind, TokenKind::LineComment(_) | TokenKind::BlockComment(_))
+                    && attached.binary_search(&i).is_err()
+            })
+            .partition(|&i| self.tokens[i].span.start < expression.span.start);
+        let (inside, after): (Vec<usize>, Vec<usize>) =
+            rest.into_iter().partition(|&i| self.tokens[i].span.end <= expression.span.end);
+        expression.leading_trivia.splice(0..0, before.iter().map(|&i| self.trivia_at(i)));
+        self.place_comments_in(expression, inside);
+        trailing_trivia.extend(after.iter().map(|&i| self.trivia_at(i)));
+    }
+    
+    /// Place comments inside `expr`: on the innermost expression containing
+    /// them, as trailing trivia of the child they directly follow, or else
+    /// as leading trivia of the next child (of `expr` itself if there is
+    /// none, as in `f(/* none */)`)
+    fn place_comments_in(&self, expr: &mut Expr, comments: Vec<usize>) {
+        if comments.is_empty() || matches!(expr.kind, ExprKind::Verbatim(_)) {
+            return;
+        }
+        let mut own = Vec::new();
+        let mut children = expr.children_mut();
+        let mut inside = vec![Vec::new(); children.len()];
+        let mut leading = vec![Vec::new(); children.len()];
+        let mut trailing = vec![Vec::new(); children.len()];
+        for index in comments {
+            let span = self.tokens[index].span;
+            let next = children.iter().position(|child| child.span.end > span.start);
+            match next {
+                Some(i) if children[i].span.start <= span.start => inside[i].push(index),
+                _ => {
+                    let previous = next.unwrap_or(children.len()).checked_sub(1);
+                    match previous.filter(|&i| self.directly_follows(children[i].span.end, index)) {
+                        Some(i) => trailing[i].push(self.trivia_at(index)),
+                        None => match next {
+                            Some(i) => leading[i].push(self.trivia_at(index)),
+                            None => own.push(self.trivia_at(index)),
+                        },
+                    }
+                }
+            }
+        }
+        for (i, child) in children.iter_mut().enumerate() {
+            child.leading_trivia.splice(0..0, core::mem::take(&mut leading[i]));
+            child.trailing_trivia.append(&mut trailing[i]);
+            self.place_comments_in(child, core::mem::take(&mut inside[i]));
+        }
+        expr.leading_trivia.extend(own);
+    }
+    
+    /// Whether only trivia lies between the offset `end` and the token at
+    /// `index`
+    fn directly_follows(&self, end: usize, index: usize) -> bool {
+        self.tokens[..index]
+            .iter()
+            .rev()
+            .take_while(|t| t.span.start >= end)
+            .all(|t| t.kind.is_trivia())
+    }
+    
+    fn trivia_at(&self, index: usize) -> Trivia {
+        self.tokens_to_trivia(core::slice::from_ref(&self.tokens[index])).remove(0)
+    }
+    
+    /// Warn about settings that cannot be applied in scoped `// pqmfmt:`
+    /// directives (see [`crate::config::directive`]). The first-line
+    /// directive is checked where it is applied.
+    fn check_scoped_directives(&mut self) {
+        let mut first_line = true;
+        for token in &self.tokens {
+            let TokenKind::LineComment(text) = &token.kind else {
+                first_line &= matches!(token.kind, TokenKind::Whitespace(_));
+                continue;
+            };
+            if core::mem::take(&mut first_line) {
+                continue;
+            }
+            let Some(settings) = directive::scoped(text) else {
+                continue;
+            };
+            let offset = text.len() - settings.len();
+            for (at, message) in directive::apply_settings(settings, &mut Config::default()) {
+                let length = settings[at..].split_whitespace().next().map_or(0, str::len);
+                let start = token.span.start + 2 + offset + at;
+                let column = token.span.column + 2 + text[..offset + at].chars().count();
+                self.warnings.push(Diagnostic::warning(
+                    "invalid-directive",
+                    message,
+                    Span::new(start, start + length, token.span.line, column),
+                ));
+            }
         }
     }
     
     /// Parse an expression
-    fn parse_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    fn parse_expression(&mut self) -> PResult<Expr> {
         self.parse_or_expression()
     }
     
+    /// Parse an expression in a position followed by a recovery boundary
+    /// (`,`, `in`, `;`, or a closing delimiter).
+    ///
+    /// Input that fails to parse (or leaves unexpected tokens before the
+    /// boundary) is skipped up to the boundary and captured as an
+    /// `ExprKind::Verbatim` region, so the rest of the document is still
+    /// parsed. The problem is an error (the document still fails, with the
+    /// errors of every such region reported), or a warning in lenient mode.
+    fn parse_expression_or_verbatim(&mut self) -> PResult<Expr> {
+        self.parse_or_verbatim(false)
+    }
+    
+    /// [`parse_expression_or_verbatim`](Self::parse_expression_or_verbatim)
+    /// for the value of a let binding or record field. A value followed by
+    /// the start of another one (`a = 1 b = 2`) is only missing its comma,
+    /// which is reported without skipping the next binding or field.
+    fn parse_value_or_verbatim(&mut self) -> PResult<Expr> {
+        self.parse_or_verbatim(true)
+    }
+    
+    fn parse_or_verbatim(&mut self, in_bindings: bool) -> PResult<Expr> {
+        if self.gave_up() {
+            return Err(Failed);
+        }
+        let start_pos = self.pos;
+        let error_count = self.errors.len();
+        let attached_count = self.attached.len();
+        let message = match self.parse_expression() {
+            Ok(expr) if self.at_recovery_boundary() => return Ok(expr),
+            Ok(expr) if in_bindings && !self.options.lenient && self.starts_binding() => {
+                self.skip_trivia();
+                let message = format!("Expected Comma, found {:?}", self.current_kind());
+                self.push_error(message, self.current_span());
+                return Ok(expr);
+            }
+            Ok(_) => {
+                self.skip_trivia();
+                let message = "Unexpected token after expression";
+                self.push_error(message, self.current_span());
+                message.to_string()
+            }
+            Err(Failed) => self
+                .errors
+                .get(error_count)
+                .map(|e| e.message.clone())
+                .unwrap_or_default(),
+        };
+        
+        self.attached.truncate(attached_count);
+        self.pos = start_pos;
+        let expr = self.skip_to_recovery_boundary()?;
+        if self.options.lenient {
+            self.errors.truncate(error_count);
+            self.warnings.push(Diagnostic::warning(
+                "lenient-verbatim",
+                format!("{}; region kept as written", message),
+                expr.span,
+            ));
+        }
+        Ok(expr)
+    }
+    
+    /// Whether the next non-trivia tokens are the name and `=` of a let
+    /// binding or record field
+    fn starts_binding(&mut self) -> bool {
+        let saved_pos = self.pos;
+        self.skip_trivia();
+        if self.current_kind() == TokenKind::At {
+            self.advance();
+            self.skip_trivia();
+        }
+        let result = self.skip_generalized_identifier() && {
+            self.skip_trivia();
+            self.current_kind() == TokenKind::Equal
+        };
+        self.pos = saved_pos;
+        result
+    }
+    
+    /// Check whether the next non-trivia token ends an expression region
+    fn at_recovery_boundary(&mut self) -> bool {
+        let saved_pos = self.pos;
+        self.skip_trivia();
+        let result = matches!(
+            self.current_kind(),
+            TokenKind::Comma
+                | TokenKind::In
+                | TokenKind::Semicolon
+                | TokenKind::RightParen
+                | TokenKind::RightBracket
+                | TokenKind::RightBrace
+                | TokenKind::Eof
+        );
+        self.pos = saved_pos;
+        result
+    }
+    
+    /// Consume tokens up to the next recovery boundary at nesting depth 0
+    /// and return them as a verbatim region, unless there were too many
+    /// errors to go on
+    fn skip_to_recovery_boundary(&mut self) -> PResult<Expr> {
+        if self.gave_up() {
+            return Err(Failed);
+        }
+        self.skip_trivia();
+        let start_span = self.current_span();
+        let mut end_pos = self.pos;
+        let mut depth = 0usize;
+        let mut let_depth = 0usize;
+        
+        while !self.is_at_end() {
+            if self.is_interrupted() {
+                return Err(Failed);
+            }
+            match self.current_kind() {
+                // A bracket that is never closed does not nest: the `,` and
+                // `in` after it still end the region
+                TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace if self.closed_later(self.pos) => {
+                    depth += 1
+                }
+                TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                }
+                TokenKind::Let => let_depth += 1,
+                TokenKind::In if let_depth > 0 => let_depth -= 1,
+                // Only a let ends at `in`, so one outside any let in the
+                // region ends it even inside brackets
+                TokenKind::In => break,
+                TokenKind::Comma | TokenKind::Semicolon if depth == 0 => break,
+                _ => {}
+            }
+            self.advance();
+            if !self.tokens[self.pos - 1].kind.is_trivia() {
+                end_pos = self.pos;
+            }
+        }
+        
+        // Leave trailing comments for the enclosing production
+        self.pos = end_pos;
+        let span = if end_pos > 0 && self.tokens[end_pos - 1].span.end > start_span.start {
+            start_span.merge(self.tokens[end_pos - 1].span)
+        } else {
+            Span::new(start_span.start, start_span.start, start_span.line, start_span.column)
+        };
+        Ok(Expr::new(ExprKind::Verbatim(span), span))
+    }
+    
+    /// Whether the bracket at token `index` is closed before the end of the
+    /// input, or an `in` outside any let opened after it
+    fn closed_later(&mut self, index: usize) -> bool {
+        let tokens = &self.tokens;
+        self.closed_brackets.get_or_insert_with(|| Self::match_brackets(tokens))[index]
+    }
+    
+    /// [`closed_later`](Self::closed_later) for every token, in one pass.
+    /// Any closing bracket closes the innermost open one. An `in` cuts off
+    /// the brackets still open after the innermost unmatched `let` before
+    /// it (all of them if there is none), which it then matches.
+    fn match_brackets(tokens: &[Token]) -> Vec<bool> {
+        let mut closed = vec![false; tokens.len()];
+        let mut cut_off = vec![false; tokens.len()];
+        let mut open = Vec::new();
+        // Open brackets not yet cut off, in source order
+        let mut pending: Vec<usize> = Vec::new();
+        let mut lets = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match token.kind {
+                TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => {
+                    open.push(i);
+                    pending.push(i);
+                }
+                TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                    if let Some(opener) = open.pop() {
+                        closed[opener] = !cut_off[opener];
+                    }
+                }
+                TokenKind::Let => lets.push(i),
+                TokenKind::In => {
+                    let let_pos = lets.pop();
+                    while let Some(&opener) = pending.last() {
+                        if let_pos.is_some_and(|l| opener < l) {
+                            break;
+                        }
+                        cut_off[opener] = true;
+                        pending.pop();
+                    }
+                }
+                _ => {}
+            }
+        }
+        closed
+    }
+    
     /// Parse or expression (lowest precedence binary)
-    fn parse_or_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    fn parse_or_expression(&mut self) -> PResult<Expr> {
         self.parse_binary_expression(0)
     }
     
     /// Parse binary expression with precedence climbing
-    fn parse_binary_expression(&mut self, min_prec: u8) -> Result<Expr, Vec<ParseError>> {
+    fn parse_binary_expression(&mut self, min_prec: u8) -> PResult<Expr> {
         let mut left = self.parse_unary_expression()?;
         
         loop {
             self.skip_whitespace_only();  // Don't consume comments here
             
-            let op = match self.current_kind() {
-                TokenKind::Or => BinaryOp::Or,
-                TokenKind::And => BinaryOp::And,
-                TokenKind::Equal => BinaryOp::Equal,
-                TokenKind::NotEqual => BinaryOp::NotEqual,
-                TokenKind::LessThan => BinaryOp::LessThan,
-                TokenKind::LessThanEqual => BinaryOp::LessThanOrEqual,
-                TokenKind::GreaterThan => BinaryOp::GreaterThan,
-                TokenKind::GreaterThanEqual => BinaryOp::GreaterThanOrEqual,
-                TokenKind::Plus => BinaryOp::Add,
-                TokenKind::Minus => BinaryOp::Subtract,
-                TokenKind::Star => BinaryOp::Multiply,
-                TokenKind::Slash => BinaryOp::Divide,
-                TokenKind::Ampersand => BinaryOp::Concatenate,
-                TokenKind::QuestionQuestion => BinaryOp::Coalesce,
-                TokenKind::Meta => BinaryOp::Meta,
-                TokenKind::Is => BinaryOp::Is,
-                TokenKind::As => BinaryOp::As,
-                // If we see a comment, don't consume it - break out
-                TokenKind::LineComment(_) | TokenKind::BlockComment(_) => break,
-                _ => break,
+            if matches!(self.current_kind(), TokenKind::LineComment(_) | TokenKind::BlockComment(_)) {
+                // A comment ends the expression unless an operator follows
+                // it; it is then placed after parsing (see `place_comments`)
+                let saved_pos = self.pos;
+                self.skip_trivia();
+                if self.binary_operator().is_none_or(|op| op.precedence() < min_prec) {
+                    self.pos = saved_pos;
+                    break;
+                }
+            }
+            let Some(op) = self.binary_operator() else {
+                break;
             };
             
             let prec = op.precedence();
@@ -110,13 +765,17 @@ impl Parser {
             self.skip_trivia();
             
             // For 'as' and 'is', parse type annotation instead of expression
-            let right = if op == BinaryOp::As || op == BinaryOp::Is {
+            // (`x as type [A = number]` spells out the `type` keyword)
+            let right = if (op == BinaryOp::As || op == BinaryOp::Is) && self.at_type_keyword_operand() {
+                self.parse_type_expression()?
+            } else if op == BinaryOp::As || op == BinaryOp::Is {
                 let type_ann = self.parse_type_annotation()?;
                 // Convert TypeAnnotation to TypeExpr
                 let span = type_ann.span;
                 Expr::new(
                     ExprKind::Type(Box::new(TypeExpr {
                         type_annotation: type_ann,
+                        keyword: false,
                     })),
                     span,
                 )
@@ -138,11 +797,55 @@ impl Parser {
         Ok(left)
     }
     
+    /// The binary operator at the current token, if it is one
+    fn binary_operator(&self) -> Option<BinaryOp> {
+        let op = match self.current_kind() {
+            TokenKind::Or => BinaryOp::Or,
+            TokenKind::And => BinaryOp::And,
+            TokenKind::Equal => BinaryOp::Equal,
+            TokenKind::NotEqual => BinaryOp::NotEqual,
+            TokenKind::LessThan => BinaryOp::LessThan,
+            TokenKind::LessThanEqual => BinaryOp::LessThanOrEqual,
+            TokenKind::GreaterThan => BinaryOp::GreaterThan,
+            TokenKind::GreaterThanEqual => BinaryOp::GreaterThanOrEqual,
+            TokenKind::Plus => BinaryOp::Add,
+            TokenKind::Minus => BinaryOp::Subtract,
+            TokenKind::Star => BinaryOp::Multiply,
+            TokenKind::Slash => BinaryOp::Divide,
+            TokenKind::Ampersand => BinaryOp::Concatenate,
+            TokenKind::QuestionQuestion => BinaryOp::Coalesce,
+            TokenKind::Meta => BinaryOp::Meta,
+            TokenKind::Is => BinaryOp::Is,
+            TokenKind::As => BinaryOp::As,
+            _ => return None,
+        };
+        Some(op)
+    }
+    
     /// Parse unary expression
-    fn parse_unary_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    fn parse_unary_expression(&mut self) -> PResult<Expr> {
         self.skip_trivia();
         let span = self.current_span();
+        if self.is_interrupted() {
+            return Err(Failed);
+        }
+        
+        // Every nested expression passes through here, so this bounds recursion
+        if self.depth >= self.options.max_depth {
+            self.push_error(
+                format!("Maximum nesting depth of {} exceeded", self.options.max_depth),
+                span,
+            );
+            return Err(Failed);
+        }
         
+        self.depth += 1;
+        let result = self.parse_unary_expression_inner(span);
+        self.depth -= 1;
+        result
+    }
+    
+    fn parse_unary_expression_inner(&mut self, span: Span) -> PResult<Expr> {
         match self.current_kind() {
             TokenKind::Not => {
                 self.advance();
@@ -188,7 +891,7 @@ impl Parser {
     }
     
     /// Parse postfix expression (field access, item access, function call, field projection)
-    fn parse_postfix_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    fn parse_postfix_expression(&mut self) -> PResult<Expr> {
         let mut expr = self.parse_primary_expression()?;
         
         loop {
@@ -203,7 +906,7 @@ impl Parser {
                         self.advance();
                         self.skip_trivia();
                         
-                        let field = self.parse_identifier()?;
+                        let field = self.parse_generalized_identifier()?;
                         
                         self.skip_trivia();
                         self.expect(TokenKind::RightBracket)?;
@@ -224,6 +927,18 @@ impl Parser {
                         );
                     }
                 }
+                TokenKind::Bang => {
+                    // Section access Section1!Member
+                    self.advance();
+                    self.skip_whitespace_only();
+                    let member = self.parse_identifier()?;
+                    
+                    let span = expr.span.merge(self.prev_span());
+                    expr = Expr::new(
+                        ExprKind::SectionAccess(Box::new(SectionAccessExpr { section: expr, member })),
+                        span,
+                    );
+                }
                 TokenKind::LeftBrace => {
                     self.advance();
                     self.skip_trivia();
@@ -251,7 +966,7 @@ impl Parser {
                 }
                 TokenKind::LeftParen => {
                     self.advance();
-                    self.skip_trivia();
+                    self.skip_whitespace_only();
                     
                     // Function call
                     let arguments = self.parse_argument_list()?;
@@ -276,7 +991,8 @@ impl Parser {
     }
     
     /// Parse field projection postfix: [[field1], [field2], ...]
-    fn parse_field_projection_postfix(&mut self, base_expr: Expr) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_field_projection_postfix(&mut self, base_expr: Expr) -> PResult<Expr> {
         self.advance(); // consume first '['
         self.skip_trivia();
         
@@ -286,7 +1002,7 @@ impl Parser {
             self.advance(); // consume '['
             self.skip_trivia();
             
-            let field = self.parse_identifier()?;
+            let field = self.parse_generalized_identifier()?;
             fields.push(field);
             
             self.skip_trivia();
@@ -329,13 +1045,24 @@ impl Parser {
         let saved_pos = self.pos;
         self.advance();
         self.skip_trivia();
-        let result = std::mem::discriminant(&self.current_kind()) == std::mem::discriminant(&kind);
+        let result = core::mem::discriminant(&self.current_kind()) == core::mem::discriminant(&kind);
         self.pos = saved_pos;
         result
     }
     
+    /// Check if the operand of `as`/`is` is a type expression with its `type`
+    /// keyword written out, rather than the primitive type `type`
+    fn at_type_keyword_operand(&mut self) -> bool {
+        self.current_kind() == TokenKind::Type
+            && (self.peek_next_is(TokenKind::LeftBracket)
+                || self.peek_next_is(TokenKind::LeftBrace)
+                || self.peek_next_is(TokenKind::LeftParen)
+                || self.peek_next_is(TokenKind::Identifier(String::new())))
+    }
+    
     /// Parse primary expression
-    fn parse_primary_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_primary_expression(&mut self) -> PResult<Expr> {
         self.skip_trivia();
         let span = self.current_span();
         
@@ -353,7 +1080,7 @@ impl Parser {
                 Ok(Expr::new(ExprKind::Logical(false), span))
             }
             TokenKind::Number(n) => {
-                let n = n;
+                let n = n.clone();
                 self.advance();
                 Ok(Expr::new(ExprKind::Number(n), span))
             }
@@ -384,11 +1111,7 @@ impl Parser {
                 self.skip_trivia();
                 let ident = self.parse_identifier()?;
                 let end_span = ident.span;
-                // @ prefix for inclusive identifier reference
-                Ok(Expr::new(
-                    ExprKind::Identifier(format!("@{}", ident.name)),
-                    span.merge(end_span),
-                ))
+                Ok(Expr::new(ExprKind::InclusiveIdentifier(ident), span.merge(end_span)))
             }
             TokenKind::Let => self.parse_let_expression(),
             TokenKind::If => self.parse_if_expression(),
@@ -400,6 +1123,7 @@ impl Parser {
             TokenKind::LeftBrace => self.parse_list_expression(),
             TokenKind::Type => self.parse_type_expression(),
             TokenKind::HashTable => self.parse_hash_table(),
+            TokenKind::HashBinary => self.parse_hash_binary(),
             TokenKind::HashDate => self.parse_hash_date(),
             TokenKind::HashTime => self.parse_hash_time(),
             TokenKind::HashDatetime => self.parse_hash_datetime(),
@@ -407,22 +1131,149 @@ impl Parser {
             TokenKind::HashDuration => self.parse_hash_duration(),
             TokenKind::HashInfinity => {
                 self.advance();
-                Ok(Expr::new(ExprKind::Number(f64::INFINITY), span))
+                Ok(Expr::new(ExprKind::Number(NumberLiteral::new(f64::INFINITY, "#infinity")), span))
             }
             TokenKind::HashNan => {
                 self.advance();
-                Ok(Expr::new(ExprKind::Number(f64::NAN), span))
+                Ok(Expr::new(ExprKind::Number(NumberLiteral::new(f64::NAN, "#nan")), span))
+            }
+            TokenKind::HashSections => {
+                self.advance();
+                Ok(Expr::new(ExprKind::HashSections, span))
+            }
+            TokenKind::HashShared => {
+                self.advance();
+                Ok(Expr::new(ExprKind::HashShared, span))
+            }
+            TokenKind::HashUnknown(name) => {
+                self.advance();
+                self.warnings.push(Diagnostic::warning(
+                    "unknown-hash-keyword",
+                    format!("Unknown keyword #{} is kept as written", name),
+                    span,
+                ));
+                Ok(Expr::new(ExprKind::HashUnknown(name), span))
+            }
+            TokenKind::UnexpectedChar(c) => {
+                self.push_error(format!("Unexpected character '{}'", c), span);
+                Err(Failed)
+            }
+            // The lexer's description of what is wrong, at the token's start
+            TokenKind::Invalid(problem) => {
+                self.push_error(problem, span);
+                Err(Failed)
             }
             _ => {
                 let msg = format!("Unexpected token: {:?}", self.current_kind());
-                self.errors.push(ParseError::new(&msg, span));
-                Err(self.errors.clone())
+                self.push_error(&msg, span);
+                Err(Failed)
             }
         }
     }
     
+    /// Whether a section document starts here: `section`, possibly after a
+    /// literal attribute record
+    fn starts_section(&self) -> bool {
+        let mut tokens = self.tokens[self.pos..].iter().filter(|t| !t.kind.is_trivia());
+        if tokens.clone().next().is_some_and(|t| t.kind == TokenKind::LeftBracket) {
+            let mut depth = 0usize;
+            for token in tokens.by_ref() {
+                match token.kind {
+                    TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => depth += 1,
+                    TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                        depth = depth.saturating_sub(1);
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        tokens.next().is_some_and(|t| t.kind == TokenKind::Section)
+    }
+    
+    /// Parse a section document up to its last member. Comments after it
+    /// are left for the document.
+    fn parse_section(&mut self) -> PResult<Expr> {
+        let start_span = self.current_span();
+        let attributes = self.parse_attributes()?;
+        self.expect(TokenKind::Section)?;
+        self.skip_trivia();
+        let name = self.parse_identifier()?;
+        self.skip_trivia();
+        self.expect(TokenKind::Semicolon)?;
+        let trailing_trivia = self.take_same_line_comments();
+        
+        let mut members = Vec::new();
+        loop {
+            let saved_pos = self.pos;
+            self.skip_trivia();
+            if self.is_at_end() {
+                self.pos = saved_pos;
+                break;
+            }
+            self.pos = saved_pos;
+            let leading_trivia = self.take_comments();
+            let mut member = self.parse_section_member()?;
+            member.leading_trivia = leading_trivia;
+            member.trailing_trivia.extend(self.take_same_line_comments());
+            members.push(member);
+        }
+        
+        Ok(Expr::new(
+            ExprKind::Section(Box::new(Section {
+                attributes,
+                name,
+                trailing_trivia,
+                members,
+            })),
+            start_span.merge(self.prev_span()),
+        ))
+    }
+    
+    /// Parse a literal attribute record and the trivia after it, if there
+    /// is one
+    fn parse_attributes(&mut self) -> PResult<Option<Expr>> {
+        if self.current_kind() != TokenKind::LeftBracket {
+            return Ok(None);
+        }
+        let attributes = self.parse_record_expression()?;
+        self.skip_trivia();
+        Ok(Some(attributes))
+    }
+    
+    /// Parse a section member up to its `;`, with the comments before the `;`
+    fn parse_section_member(&mut self) -> PResult<SectionMember> {
+        let start_span = self.current_span();
+        let attributes = self.parse_attributes()?;
+        let shared = self.current_kind() == TokenKind::Shared;
+        if shared {
+            self.advance();
+            self.skip_trivia();
+        }
+        let name = self.parse_identifier()?;
+        self.skip_trivia();
+        self.expect(TokenKind::Equal)?;
+        
+        let value = self.parse_with_comments(Self::parse_expression_or_verbatim)?;
+        let trailing_trivia = self.take_comments();
+        self.expect(TokenKind::Semicolon)?;
+        
+        Ok(SectionMember {
+            attributes,
+            shared,
+            name,
+            value,
+            span: start_span.merge(self.prev_span()),
+            leading_trivia: Vec::new(),
+            trailing_trivia,
+        })
+    }
+    
     /// Parse let expression
-    fn parse_let_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_let_expression(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume 'let'
         self.skip_whitespace_only(); // Only skip whitespace, not comments
@@ -430,37 +1281,21 @@ impl Parser {
         let mut bindings = Vec::new();
         
         // Parse bindings
-        loop {
-            // Collect leading trivia (comments before binding)
-            let leading_trivia = self.collect_trivia();
-            
-            if self.current_kind() == TokenKind::In {
-                break;
-            }
-            
-            let mut binding = self.parse_binding()?;
-            binding.leading_trivia = self.tokens_to_trivia(&leading_trivia);
+        while let Some(element) = self.parse_with_trivia(TokenKind::In, Self::parse_binding_recovering)? {
+            let mut binding = element.value;
+            binding.leading_trivia = element.leading_trivia;
+            binding.trailing_trivia = element.trailing_trivia;
             bindings.push(binding);
             
-            // Collect trailing trivia after binding value
-            let trailing = self.collect_trivia();
-            if let Some(last) = bindings.last_mut() {
-                last.trailing_trivia = self.tokens_to_trivia(&trailing);
-            }
-            
-            if self.current_kind() == TokenKind::Comma {
-                self.advance();
-                self.skip_whitespace_only(); // Skip whitespace after comma
-            } else {
+            if !element.separated && !self.starts_binding() {
                 break;
             }
         }
         
         self.skip_trivia();
         self.expect(TokenKind::In)?;
-        self.skip_trivia();
         
-        let body = self.parse_expression()?;
+        let body = self.parse_with_comments(Self::parse_expression)?;
         let end_span = body.span;
         
         Ok(Expr::new(
@@ -472,20 +1307,78 @@ impl Parser {
         ))
     }
     
+    /// Parse one binding of a `let` with its comments and the comma after
+    /// it, from tokens holding nothing else (see [`crate::stream`])
+    pub(crate) fn parse_let_binding(&mut self) -> Result<Binding, Vec<ParseError>> {
+        self.parse_lone_binding().map_err(|Failed| core::mem::take(&mut self.errors))
+    }
+    
+    fn parse_lone_binding(&mut self) -> PResult<Binding> {
+        let Some(element) = self.parse_with_trivia(TokenKind::In, Self::parse_binding)? else {
+            self.push_error("Expected binding", self.current_span());
+            return Err(Failed);
+        };
+        let mut binding = element.value;
+        binding.leading_trivia = element.leading_trivia;
+        binding.trailing_trivia = element.trailing_trivia;
+        
+        self.skip_trivia();
+        if !self.is_at_end() {
+            self.push_error("Unexpected token after binding", self.current_span());
+        }
+        if !self.errors.is_empty() {
+            return Err(Failed);
+        }
+        if self.options.attach_trivia {
+            self.place_comments(&mut binding.value, &mut binding.trailing_trivia);
+        }
+        Ok(binding)
+    }
+    
+    /// Parse a binding; one whose name or `=` cannot be parsed is skipped
+    /// up to the next `,` or `in` with an error, so the other bindings are
+    /// still parsed and their errors reported too. Lenient mode has no
+    /// verbatim bindings, so there the error ends the parse as before.
+    fn parse_binding_recovering(&mut self) -> PResult<Binding> {
+        let start_pos = self.pos;
+        let attached_count = self.attached.len();
+        match self.parse_binding() {
+            Err(_) if !self.options.lenient => {}
+            result => return result,
+        }
+        
+        self.pos = start_pos;
+        self.attached.truncate(attached_count);
+        let value = self.skip_to_recovery_boundary()?;
+        Ok(Binding {
+            name: Identifier::new(String::new(), false, value.span),
+            inclusive: false,
+            span: value.span,
+            value,
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
+        })
+    }
+    
     /// Parse a binding
-    fn parse_binding(&mut self) -> Result<Binding, Vec<ParseError>> {
+    fn parse_binding(&mut self) -> PResult<Binding> {
         let start_span = self.current_span();
+        let inclusive = self.current_kind() == TokenKind::At;
+        if inclusive {
+            self.advance();
+            self.skip_trivia();
+        }
         let name = self.parse_identifier()?;
         
         self.skip_trivia();
         self.expect(TokenKind::Equal)?;
-        self.skip_trivia();
         
-        let value = self.parse_expression()?;
+        let value = self.parse_with_comments(Self::parse_value_or_verbatim)?;
         let end_span = value.span;
         
         Ok(Binding {
             name,
+            inclusive,
             value,
             span: start_span.merge(end_span),
             leading_trivia: Vec::new(),
@@ -494,63 +1387,98 @@ impl Parser {
     }
     
     /// Parse if expression
-    fn parse_if_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
-        let start_span = self.current_span();
-        self.advance(); // consume 'if'
-        self.skip_trivia();
-        
-        let condition = self.parse_expression()?;
-        
-        self.skip_trivia();
-        self.expect(TokenKind::Then)?;
-        self.skip_trivia();
-        
-        let then_branch = self.parse_expression()?;
-        
-        self.skip_trivia();
-        self.expect(TokenKind::Else)?;
-        self.skip_trivia();
-        
-        let else_branch = self.parse_expression()?;
-        let end_span = else_branch.span;
+    #[inline(never)]
+    fn parse_if_expression(&mut self) -> PResult<Expr> {
+        // An `else if` chain is parsed in a loop rather than recursively, so
+        // its length does not count toward `max_depth`: each `if` after an
+        // `else` ends where the whole chain ends
+        let mut links = Vec::new();
+        let mut leading_trivia = Vec::new();
+        let mut else_branch = loop {
+            let start_span = self.current_span();
+            self.advance(); // consume 'if'
+            
+            let condition = self.parse_with_comments(Self::parse_expression)?;
+            
+            self.skip_trivia();
+            self.expect(TokenKind::Then)?;
+            
+            let then_branch = self.parse_with_comments(Self::parse_expression)?;
+            
+            self.skip_trivia();
+            self.expect(TokenKind::Else)?;
+            
+            links.push((start_span, core::mem::take(&mut leading_trivia), condition, then_branch));
+            leading_trivia = self.take_comments();
+            if self.current_kind() != TokenKind::If {
+                let mut else_branch = self.parse_expression()?;
+                else_branch.leading_trivia.splice(0..0, core::mem::take(&mut leading_trivia));
+                break else_branch;
+            }
+        };
         
-        Ok(Expr::new(
-            ExprKind::If(Box::new(IfExpr {
-                condition,
-                then_branch,
-                else_branch,
-            })),
-            start_span.merge(end_span),
-        ))
+        while let Some((start_span, leading_trivia, condition, then_branch)) = links.pop() {
+            let end_span = else_branch.span;
+            else_branch = Expr::new(
+                ExprKind::If(Box::new(IfExpr {
+                    condition,
+                    then_branch,
+                    else_branch,
+                })),
+                start_span.merge(end_span),
+            );
+            else_branch.leading_trivia = leading_trivia;
+        }
+        Ok(else_branch)
     }
     
     /// Parse try expression
-    fn parse_try_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_try_expression(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume 'try'
-        self.skip_trivia();
         
-        let expr = self.parse_expression()?;
+        let expr = self.parse_with_comments(Self::parse_expression)?;
         
         self.skip_trivia();
-        let otherwise = if self.current_kind() == TokenKind::Otherwise {
-            self.advance();
-            self.skip_trivia();
-            Some(self.parse_expression()?)
-        } else {
-            None
-        };
+        let mut otherwise = None;
+        let mut catch = None;
+        match self.current_kind() {
+            TokenKind::Otherwise => {
+                self.advance();
+                otherwise = Some(self.parse_with_comments(Self::parse_expression)?);
+            }
+            // `catch` is contextual: it is only special right after a try body
+            TokenKind::Identifier(name) if name == "catch" => {
+                let catch_span = self.current_span();
+                self.advance();
+                let handler = self.parse_with_comments(Self::parse_expression)?;
+                match &handler.kind {
+                    ExprKind::Function(function) if function.parameters.len() <= 1 => {}
+                    _ => {
+                        self.push_error(
+                            "Expected a function with at most one parameter after 'catch'",
+                            catch_span.merge(handler.span),
+                        );
+                        return Err(Failed);
+                    }
+                }
+                catch = Some(handler);
+            }
+            _ => {}
+        }
         
-        let end_span = otherwise.as_ref().map(|e| e.span).unwrap_or(expr.span);
+        let end_span = otherwise.as_ref().or(catch.as_ref()).map(|e| e.span).unwrap_or(expr.span);
         
         Ok(Expr::new(
-            ExprKind::Try(Box::new(TryExpr { expr, otherwise })),
+            ExprKind::Try(Box::new(TryExpr { expr, otherwise, catch })),
             start_span.merge(end_span),
         ))
     }
     
     /// Parse error expression
-    fn parse_error_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_error_expression(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume 'error'
         self.skip_trivia();
@@ -565,12 +1493,12 @@ impl Parser {
     }
     
     /// Parse each expression
-    fn parse_each_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_each_expression(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume 'each'
-        self.skip_trivia();
         
-        let expr = self.parse_expression()?;
+        let expr = self.parse_with_comments(Self::parse_expression)?;
         let end_span = expr.span;
         
         Ok(Expr::new(
@@ -580,24 +1508,26 @@ impl Parser {
     }
     
     /// Parse parenthesized expression or function definition
-    fn parse_parenthesized_or_function(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_parenthesized_or_function(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
+        let opener = self.pos;
         self.advance(); // consume '('
-        self.skip_trivia();
+        self.skip_whitespace_only();
         
         // Check if this is a function definition
         // Save position for backtracking
         let saved_pos = self.pos;
         
         // Try to parse as parameters
-        if self.is_function_definition() {
+        if self.is_function_definition(opener) {
             self.pos = saved_pos;
             return self.parse_function_expression(start_span);
         }
         
         // It's a parenthesized expression
         self.pos = saved_pos;
-        let expr = self.parse_expression()?;
+        let expr = self.parse_with_comments(Self::parse_expression)?;
         
         self.skip_trivia();
         self.expect(TokenKind::RightParen)?;
@@ -609,54 +1539,66 @@ impl Parser {
         ))
     }
     
-    /// Check if current position starts a function definition
-    fn is_function_definition(&mut self) -> bool {
+    /// Check if the parenthesis at token `opener` starts a function
+    /// definition
+    fn is_function_definition(&mut self, opener: usize) -> bool {
         // Look for pattern: (params) => or (params) as type =>
-        // We're positioned after the opening '('
-        let mut depth = 1;
+        let Some(closer) = self.matching_paren(opener) else {
+            return false;
+        };
         let start = self.pos;
+        self.pos = closer + 1; // past ')'
+        self.skip_trivia();
         
-        while !self.is_at_end() && depth > 0 {
-            match self.current_kind() {
-                TokenKind::LeftParen => depth += 1,
-                TokenKind::RightParen => depth -= 1,
-                _ => {}
-            }
-            if depth > 0 {
-                self.advance();
+        // Check for => directly or as TYPE =>
+        let is_func = match self.current_kind() {
+            TokenKind::FatArrow => true,
+            TokenKind::As => {
+                // Skip past 'as' and the type, then check for =>
+                self.advance(); // consume 'as'
+                self.skip_trivia();
+                // Skip the type (could be identifier, list type, etc.)
+                self.skip_type_for_lookahead();
+                self.skip_trivia();
+                self.current_kind() == TokenKind::FatArrow
             }
-        }
-        
-        if depth == 0 {
-            self.advance(); // consume ')'
-            self.skip_trivia();
-            
-            // Check for => directly or as TYPE =>
-            let is_func = match self.current_kind() {
-                TokenKind::FatArrow => true,
-                TokenKind::As => {
-                    // Skip past 'as' and the type, then check for =>
-                    self.advance(); // consume 'as'
-                    self.skip_trivia();
-                    // Skip the type (could be identifier, list type, etc.)
-                    self.skip_type_for_lookahead();
-                    self.skip_trivia();
-                    self.current_kind() == TokenKind::FatArrow
-                }
-                _ => false,
-            };
-            
-            self.pos = start;
-            return is_func;
-        }
+            _ => false,
+        };
         
         self.pos = start;
-        false
+        is_func
+    }
+    
+    /// Index of the `)` closing the `(` at token `opener`, if there is one
+    fn matching_paren(&mut self, opener: usize) -> Option<usize> {
+        let tokens = &self.tokens;
+        self.matching_parens.get_or_insert_with(|| {
+            let mut matches = vec![None; tokens.len()];
+            let mut open = Vec::new();
+            for (i, token) in tokens.iter().enumerate() {
+                match token.kind {
+                    TokenKind::LeftParen => open.push(i),
+                    TokenKind::RightParen => {
+                        if let Some(opener) = open.pop() {
+                            matches[opener] = Some(i);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            matches
+        })[opener]
     }
     
     /// Skip a type expression during lookahead (for is_function_definition)
     fn skip_type_for_lookahead(&mut self) {
         match self.current_kind() {
+            // `nullable record`
+            TokenKind::Identifier(name) if name == "nullable" => {
+                self.advance();
+                self.skip_trivia();
+                self.skip_type_for_lookahead();
+            }
             TokenKind::Identifier(_) => {
                 self.advance();
             }
@@ -691,7 +1633,8 @@ impl Parser {
     }
     
     /// Parse function expression
-    fn parse_function_expression(&mut self, start_span: Span) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_function_expression(&mut self, start_span: Span) -> PResult<Expr> {
         let parameters = self.parse_parameter_list()?;
         
         self.skip_trivia();
@@ -709,9 +1652,8 @@ impl Parser {
         
         self.skip_trivia();
         self.expect(TokenKind::FatArrow)?;
-        self.skip_trivia();
         
-        let body = self.parse_expression()?;
+        let body = self.parse_with_comments(Self::parse_expression)?;
         let end_span = body.span;
         
         Ok(Expr::new(
@@ -725,18 +1667,15 @@ impl Parser {
     }
     
     /// Parse parameter list
-    fn parse_parameter_list(&mut self) -> Result<Vec<Parameter>, Vec<ParseError>> {
+    fn parse_parameter_list(&mut self) -> PResult<Vec<Parameter>> {
         let mut params = Vec::new();
         
-        while self.current_kind() != TokenKind::RightParen && !self.is_at_end() {
-            let param = self.parse_parameter()?;
+        while let Some(element) = self.parse_with_trivia(TokenKind::RightParen, Self::parse_function_parameter)? {
+            let mut param = element.value;
+            param.leading_trivia = element.leading_trivia;
+            param.trailing_trivia.extend(element.trailing_trivia);
             params.push(param);
-            
-            self.skip_trivia();
-            if self.current_kind() == TokenKind::Comma {
-                self.advance();
-                self.skip_trivia();
-            } else {
+            if !element.separated {
                 break;
             }
         }
@@ -744,8 +1683,24 @@ impl Parser {
         Ok(params)
     }
     
+    /// Parse a parameter of a function expression. Comments inside it (as
+    /// in `x /* c */ as number`) are kept as its trailing trivia.
+    fn parse_function_parameter(&mut self) -> PResult<Parameter> {
+        let start = self.pos;
+        let mut param = self.parse_parameter()?;
+        if self.options.attach_trivia {
+            let comments: Vec<usize> = (start..self.pos)
+                .take_while(|&i| self.tokens[i].kind != TokenKind::As)
+                .filter(|&i| matches!(self.tokens[i].kind, TokenKind::LineComment(_) | TokenKind::BlockComment(_)))
+                .collect();
+            param.trailing_trivia = comments.iter().map(|&i| self.trivia_at(i)).collect();
+            self.attached.extend(comments);
+        }
+        Ok(param)
+    }
+    
     /// Parse a parameter
-    fn parse_parameter(&mut self) -> Result<Parameter, Vec<ParseError>> {
+    fn parse_parameter(&mut self) -> PResult<Parameter> {
         let start_span = self.current_span();
         
         // Optional 'optional' keyword
@@ -760,12 +1715,15 @@ impl Parser {
         let name = self.parse_identifier()?;
         
         // Optional type annotation
+        let saved_pos = self.pos;
         self.skip_trivia();
         let type_annotation = if self.current_kind() == TokenKind::As {
             self.advance();
             self.skip_trivia();
             Some(self.parse_type_annotation()?)
         } else {
+            // Comments after the name are left for the caller
+            self.pos = saved_pos;
             None
         };
         
@@ -779,11 +1737,13 @@ impl Parser {
             type_annotation,
             optional,
             span: start_span.merge(end_span),
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
         })
     }
     
     /// Parse type annotation
-    fn parse_type_annotation(&mut self) -> Result<TypeAnnotation, Vec<ParseError>> {
+    fn parse_type_annotation(&mut self) -> PResult<TypeAnnotation> {
         let start_span = self.current_span();
         
         let kind = match self.current_kind() {
@@ -793,6 +1753,7 @@ impl Parser {
                 
                 match name_str.as_str() {
                     "any" => TypeKind::Any,
+                    "anynonnull" => TypeKind::AnyNonNull,
                     "none" => TypeKind::None,
                     "null" => TypeKind::Null,
                     "logical" => TypeKind::Logical,
@@ -805,6 +1766,7 @@ impl Parser {
                     "text" => TypeKind::Text,
                     "binary" => TypeKind::Binary,
                     "type" => TypeKind::Type,
+                    "action" => TypeKind::Action,
                     "list" => {
                         self.skip_trivia();
                         if self.current_kind() == TokenKind::LeftBrace {
@@ -822,28 +1784,30 @@ impl Parser {
                             TypeKind::List(None)
                         }
                     }
-                    "record" => {
-                        self.skip_trivia();
-                        if self.current_kind() == TokenKind::LeftBracket {
-                            let fields = self.parse_type_field_list()?;
-                            TypeKind::Record(fields)
-                        } else {
-                            TypeKind::Record(Vec::new())
-                        }
-                    }
-                    "table" => {
+                    // Without fields or parameters these are the primitive
+                    // types, so that `x is record` stays as written
+                    "record" | "table" | "function" => {
+                        let opener = match name_str.as_str() {
+                            "record" | "table" => TokenKind::LeftBracket,
+                            _ => TokenKind::LeftParen,
+                        };
+                        let pos = self.pos;
                         self.skip_trivia();
-                        if self.current_kind() == TokenKind::LeftBracket {
-                            let fields = self.parse_type_field_list()?;
-                            TypeKind::Table(fields)
+                        if self.current_kind() != opener {
+                            self.pos = pos;
+                            match name_str.as_str() {
+                                "record" => TypeKind::AnyRecord,
+                                "table" => TypeKind::AnyTable,
+                                _ => TypeKind::AnyFunction,
+                            }
+                        } else if name_str == "record" {
+                            TypeKind::Record(self.parse_type_field_list()?)
+                        } else if name_str == "table" {
+                            TypeKind::Table(self.parse_type_field_list()?)
                         } else {
-                            TypeKind::Table(Vec::new())
+                            self.parse_function_type()?
                         }
                     }
-                    "function" => TypeKind::Function(Vec::new(), Box::new(TypeAnnotation {
-                        kind: TypeKind::Any,
-                        span: start_span,
-                    })),
                     "nullable" => {
                         self.skip_trivia();
                         let inner = self.parse_type_annotation()?;
@@ -855,6 +1819,10 @@ impl Parser {
                     _ => TypeKind::Custom(name_str),
                 }
             }
+            TokenKind::Type => {
+                self.advance();
+                TypeKind::Type
+            }
             TokenKind::LeftBrace => {
                 self.advance();
                 self.skip_trivia();
@@ -875,10 +1843,33 @@ impl Parser {
                 let fields = self.parse_type_field_list()?;
                 TypeKind::Record(fields)
             }
+            TokenKind::LeftParen => {
+                self.advance();
+                self.skip_trivia();
+                // A parenthesized type, `(nullable number)`, if it is one
+                let (pos, error_count) = (self.pos, self.errors.len());
+                if let Ok(inner) = self.parse_type_annotation() {
+                    self.skip_trivia();
+                    if self.current_kind() == TokenKind::RightParen {
+                        self.advance();
+                        return Ok(TypeAnnotation {
+                            kind: TypeKind::Parenthesized(Box::new(inner)),
+                            span: start_span.merge(self.prev_span()),
+                        });
+                    }
+                }
+                self.pos = pos;
+                self.errors.truncate(error_count);
+                // Otherwise an expression: (type text meta [...])
+                let expr = self.parse_expression()?;
+                self.skip_trivia();
+                self.expect(TokenKind::RightParen)?;
+                TypeKind::Expr(Box::new(expr))
+            }
             _ => {
                 let msg = format!("Expected type, found {:?}", self.current_kind());
-                self.errors.push(ParseError::new(&msg, start_span));
-                return Err(self.errors.clone());
+                self.push_error(&msg, start_span);
+                return Err(Failed);
             }
         };
         
@@ -888,10 +1879,36 @@ impl Parser {
         })
     }
     
+    /// Parse function type: function (x as text, optional y as number) as table
+    fn parse_function_type(&mut self) -> PResult<TypeKind> {
+        self.expect(TokenKind::LeftParen)?;
+        self.skip_trivia();
+        
+        let mut parameters = Vec::new();
+        while self.current_kind() != TokenKind::RightParen {
+            parameters.push(self.parse_parameter()?);
+            self.skip_trivia();
+            if self.current_kind() == TokenKind::Comma {
+                self.advance();
+                self.skip_trivia();
+            } else {
+                break;
+            }
+        }
+        self.expect(TokenKind::RightParen)?;
+        self.skip_trivia();
+        
+        self.expect(TokenKind::As)?;
+        self.skip_trivia();
+        let return_type = self.parse_type_annotation()?;
+        
+        Ok(TypeKind::Function(parameters, Box::new(return_type)))
+    }
+    
     /// Parse type field list: [Field1 = type, Field2 = type, ...]
     /// Also supports fields without type: [Field1, Field2]
     /// Also supports space-separated identifiers: [Date accessed = datetimezone]
-    fn parse_type_field_list(&mut self) -> Result<Vec<FieldType>, Vec<ParseError>> {
+    fn parse_type_field_list(&mut self) -> PResult<Vec<FieldType>> {
         self.advance(); // consume '['
         self.skip_trivia();
         
@@ -955,7 +1972,7 @@ impl Parser {
     
     /// Parse type field name (may include spaces, e.g., "Date accessed")
     /// Collects all identifiers until we hit = or , or ]
-    fn parse_type_field_name(&mut self) -> Result<Identifier, Vec<ParseError>> {
+    fn parse_type_field_name(&mut self) -> PResult<Identifier> {
         let start_span = self.current_span();
         let mut name_parts = Vec::new();
         
@@ -973,8 +1990,8 @@ impl Parser {
             }
             _ => {
                 let msg = format!("Expected identifier, found {:?}", self.current_kind());
-                self.errors.push(ParseError::new(&msg, start_span));
-                return Err(self.errors.clone());
+                self.push_error(&msg, start_span);
+                return Err(Failed);
             }
         }
         
@@ -1018,12 +2035,15 @@ impl Parser {
     }
     
     /// Parse record expression or field projection
-    fn parse_record_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_record_expression(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume '['
-        self.skip_trivia();
+        self.skip_whitespace_only();
         
         // Empty record
+        let saved_pos = self.pos;
+        self.skip_trivia();
         if self.current_kind() == TokenKind::RightBracket {
             self.advance();
             return Ok(Expr::new(
@@ -1032,6 +2052,8 @@ impl Parser {
             ));
         }
         
+        self.pos = saved_pos;
+        
         // Check if this is a record literal or field projection
         // Look ahead: if we see "identifier =" it's a record, otherwise field projection
         let is_record = self.is_record_literal();
@@ -1046,15 +2068,12 @@ impl Parser {
     /// Check if current position starts a record literal (has "identifier =")
     fn is_record_literal(&mut self) -> bool {
         let saved_pos = self.pos;
+        self.skip_trivia();
         
         // Try to parse identifier
-        let result = match self.current_kind() {
-            TokenKind::Identifier(_) | TokenKind::QuotedIdentifier(_) => {
-                self.advance();
-                self.skip_trivia();
-                self.current_kind() == TokenKind::Equal
-            }
-            _ => false,
+        let result = self.skip_generalized_identifier() && {
+            self.skip_trivia();
+            self.current_kind() == TokenKind::Equal
         };
         
         self.pos = saved_pos;
@@ -1062,30 +2081,26 @@ impl Parser {
     }
     
     /// Parse record fields (when we know it's a record literal)
-    fn parse_record_fields(&mut self, start_span: Span) -> Result<Expr, Vec<ParseError>> {
+    fn parse_record_fields(&mut self, start_span: Span) -> PResult<Expr> {
         let mut fields = Vec::new();
         
-        while self.current_kind() != TokenKind::RightBracket && !self.is_at_end() {
-            // Collect leading trivia (comments before field)
-            let leading_trivia = self.collect_trivia();
-            
-            if self.current_kind() == TokenKind::RightBracket {
-                break;
-            }
-            
-            let mut field = self.parse_record_field()?;
-            field.leading_trivia = self.tokens_to_trivia(&leading_trivia);
-            
-            // Collect trailing trivia (comments after field value)
-            let trailing_trivia = self.collect_trivia();
-            field.trailing_trivia = self.tokens_to_trivia(&trailing_trivia);
-            
+        loop {
+            let context = Context {
+                element: "field",
+                container: "record",
+                index: fields.len() + 1,
+                line: start_span.line,
+            };
+            let element = self.in_context(context, |parser| {
+                parser.parse_with_trivia(TokenKind::RightBracket, Self::parse_record_field_recovering)
+            })?;
+            let Some(element) = element else { break };
+            let mut field = element.value;
+            field.leading_trivia = element.leading_trivia;
+            field.trailing_trivia = element.trailing_trivia;
             fields.push(field);
             
-            if self.current_kind() == TokenKind::Comma {
-                self.advance();
-                self.skip_whitespace_only();
-            } else {
+            if !element.separated && !self.starts_binding() {
                 break;
             }
         }
@@ -1101,15 +2116,17 @@ impl Parser {
     }
     
     /// Parse field projection: [field1, field2, ...]
-    fn parse_field_projection(&mut self, start_span: Span) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_field_projection(&mut self, start_span: Span) -> PResult<Expr> {
         // Field projection is syntactic sugar for record field access
         // [A, B] on a record selects fields A and B
         // For now, we treat single field [A] as implicit field access on _
         
         let mut fields = Vec::new();
         
+        self.skip_trivia();
         while self.current_kind() != TokenKind::RightBracket && !self.is_at_end() {
-            let ident = self.parse_identifier()?;
+            let ident = self.parse_generalized_identifier()?;
             fields.push(ident);
             
             self.skip_trivia();
@@ -1160,16 +2177,60 @@ impl Parser {
         }
     }
     
+    /// Parse a record field; one that cannot be parsed is skipped up to the
+    /// next `,` or `]` and kept as a verbatim field, so the rest of the
+    /// record is still parsed. The problem is an error (the document still
+    /// fails, with every broken field reported), or a warning in lenient mode.
+    fn parse_record_field_recovering(&mut self) -> PResult<RecordField> {
+        let start_pos = self.pos;
+        let error_count = self.errors.len();
+        let attached_count = self.attached.len();
+        let message = match self.parse_record_field() {
+            // The comma missing before a next field is already reported
+            Ok(field) if self.at_recovery_boundary() || self.starts_binding() => return Ok(field),
+            Ok(_) => {
+                self.skip_trivia();
+                let message = "Unexpected token after record field";
+                self.push_error(message, self.current_span());
+                message.to_string()
+            }
+            Err(Failed) => self
+                .errors
+                .get(error_count)
+                .map(|e| e.message.clone())
+                .unwrap_or_default(),
+        };
+        
+        self.pos = start_pos;
+        self.attached.truncate(attached_count);
+        let value = self.skip_to_recovery_boundary()?;
+        if self.options.lenient {
+            self.errors.truncate(error_count);
+            self.warnings.push(Diagnostic::warning(
+                "lenient-verbatim",
+                format!("{}; field kept as written", message),
+                value.span,
+            ));
+        }
+        Ok(RecordField {
+            name: Identifier::new(String::new(), false, value.span),
+            span: value.span,
+            value,
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
+            verbatim: true,
+        })
+    }
+    
     /// Parse record field
-    fn parse_record_field(&mut self) -> Result<RecordField, Vec<ParseError>> {
+    fn parse_record_field(&mut self) -> PResult<RecordField> {
         let start_span = self.current_span();
         let name = self.parse_generalized_identifier()?;
         
         self.skip_whitespace_only();  // Don't skip comments here
         self.expect(TokenKind::Equal)?;
-        self.skip_whitespace_only();  // Don't skip comments here
         
-        let value = self.parse_expression()?;
+        let value = self.parse_with_comments(Self::parse_value_or_verbatim)?;
         let end_span = value.span;
         
         Ok(RecordField {
@@ -1178,26 +2239,36 @@ impl Parser {
             span: start_span.merge(end_span),
             leading_trivia: Vec::new(),
             trailing_trivia: Vec::new(),
+            verbatim: false,
         })
     }
     
     /// Parse list expression
-    fn parse_list_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_list_expression(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume '{'
-        self.skip_trivia();
+        self.skip_whitespace_only();
         
         let mut items = Vec::new();
         
-        while self.current_kind() != TokenKind::RightBrace && !self.is_at_end() {
-            let item = self.parse_expression()?;
+        loop {
+            let context = Context {
+                element: "item",
+                container: "list",
+                index: items.len() + 1,
+                line: start_span.line,
+            };
+            let element = self.in_context(context, |parser| {
+                parser.parse_with_trivia(TokenKind::RightBrace, Self::parse_expression_or_verbatim)
+            })?;
+            let Some(element) = element else { break };
+            let mut item = element.value;
+            item.leading_trivia.splice(0..0, element.leading_trivia);
+            item.trailing_trivia.extend(element.trailing_trivia);
             items.push(item);
             
-            self.skip_trivia();
-            if self.current_kind() == TokenKind::Comma {
-                self.advance();
-                self.skip_trivia();
-            } else {
+            if !element.separated {
                 break;
             }
         }
@@ -1213,7 +2284,8 @@ impl Parser {
     }
     
     /// Parse type expression
-    fn parse_type_expression(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_type_expression(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume 'type'
         self.skip_trivia();
@@ -1222,13 +2294,14 @@ impl Parser {
         let end_span = type_annotation.span;
         
         Ok(Expr::new(
-            ExprKind::Type(Box::new(TypeExpr { type_annotation })),
+            ExprKind::Type(Box::new(TypeExpr { type_annotation, keyword: true })),
             start_span.merge(end_span),
         ))
     }
     
     /// Parse #table constructor
-    fn parse_hash_table(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_hash_table(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance(); // consume #table
         self.skip_trivia();
@@ -1255,7 +2328,8 @@ impl Parser {
     }
     
     /// Parse #date constructor
-    fn parse_hash_date(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_hash_date(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance();
         self.skip_trivia();
@@ -1285,8 +2359,31 @@ impl Parser {
         ))
     }
     
+    /// Parse #binary constructor
+    #[inline(never)]
+    fn parse_hash_binary(&mut self) -> PResult<Expr> {
+        let start_span = self.current_span();
+        self.advance();
+        self.skip_trivia();
+        
+        self.expect(TokenKind::LeftParen)?;
+        self.skip_trivia();
+        
+        let value = self.parse_expression()?;
+        self.skip_trivia();
+        
+        self.expect(TokenKind::RightParen)?;
+        let end_span = self.prev_span();
+        
+        Ok(Expr::new(
+            ExprKind::HashBinary(Box::new(HashBinaryExpr { value })),
+            start_span.merge(end_span),
+        ))
+    }
+    
     /// Parse #time constructor
-    fn parse_hash_time(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_hash_time(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance();
         self.skip_trivia();
@@ -1317,7 +2414,8 @@ impl Parser {
     }
     
     /// Parse #datetime constructor
-    fn parse_hash_datetime(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_hash_datetime(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance();
         self.skip_trivia();
@@ -1370,7 +2468,8 @@ impl Parser {
     }
     
     /// Parse #datetimezone constructor
-    fn parse_hash_datetimezone(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_hash_datetimezone(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance();
         self.skip_trivia();
@@ -1435,7 +2534,8 @@ impl Parser {
     }
     
     /// Parse #duration constructor
-    fn parse_hash_duration(&mut self) -> Result<Expr, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_hash_duration(&mut self) -> PResult<Expr> {
         let start_span = self.current_span();
         self.advance();
         self.skip_trivia();
@@ -1476,18 +2576,19 @@ impl Parser {
     }
     
     /// Parse argument list
-    fn parse_argument_list(&mut self) -> Result<Vec<Expr>, Vec<ParseError>> {
+    #[inline(never)]
+    fn parse_argument_list(&mut self) -> PResult<Vec<Expr>> {
         let mut args = Vec::new();
         
-        while self.current_kind() != TokenKind::RightParen && !self.is_at_end() {
-            let arg = self.parse_expression()?;
+        while let Some(element) =
+            self.parse_with_trivia(TokenKind::RightParen, Self::parse_expression_or_verbatim)?
+        {
+            let mut arg = element.value;
+            arg.leading_trivia.splice(0..0, element.leading_trivia);
+            arg.trailing_trivia.extend(element.trailing_trivia);
             args.push(arg);
             
-            self.skip_trivia();
-            if self.current_kind() == TokenKind::Comma {
-                self.advance();
-                self.skip_trivia();
-            } else {
+            if !element.separated {
                 break;
             }
         }
@@ -1496,7 +2597,7 @@ impl Parser {
     }
     
     /// Parse identifier
-    fn parse_identifier(&mut self) -> Result<Identifier, Vec<ParseError>> {
+    fn parse_identifier(&mut self) -> PResult<Identifier> {
         let span = self.current_span();
         
         match self.current_kind() {
@@ -1512,117 +2613,83 @@ impl Parser {
             }
             _ => {
                 let msg = format!("Expected identifier, found {:?}", self.current_kind());
-                self.errors.push(ParseError::new(&msg, span));
-                Err(self.errors.clone())
+                self.push_error(&msg, span);
+                Err(Failed)
             }
         }
     }
     
-    /// Parse generalized identifier (for record fields)
-    fn parse_generalized_identifier(&mut self) -> Result<Identifier, Vec<ParseError>> {
+    /// Parse generalized identifier (for record fields and field access)
+    fn parse_generalized_identifier(&mut self) -> PResult<Identifier> {
         let span = self.current_span();
         
-        match self.current_kind() {
-            TokenKind::Identifier(name) => {
-                let full_name = name.clone();
-                self.advance();
-                Ok(Identifier::new(full_name, false, span.merge(self.prev_span())))
-            }
+        let mut name = match self.current_kind() {
             TokenKind::QuotedIdentifier(name) => {
-                let name = name.clone();
-                self.advance();
-                Ok(Identifier::new(name, true, span))
-            }
-            // Allow keywords as field names in records
-            TokenKind::Type => {
-                self.advance();
-                Ok(Identifier::new("type".to_string(), false, span))
-            }
-            TokenKind::If => {
-                self.advance();
-                Ok(Identifier::new("if".to_string(), false, span))
-            }
-            TokenKind::Then => {
-                self.advance();
-                Ok(Identifier::new("then".to_string(), false, span))
-            }
-            TokenKind::Else => {
-                self.advance();
-                Ok(Identifier::new("else".to_string(), false, span))
-            }
-            TokenKind::Let => {
-                self.advance();
-                Ok(Identifier::new("let".to_string(), false, span))
-            }
-            TokenKind::In => {
-                self.advance();
-                Ok(Identifier::new("in".to_string(), false, span))
-            }
-            TokenKind::And => {
-                self.advance();
-                Ok(Identifier::new("and".to_string(), false, span))
-            }
-            TokenKind::Or => {
-                self.advance();
-                Ok(Identifier::new("or".to_string(), false, span))
-            }
-            TokenKind::Not => {
-                self.advance();
-                Ok(Identifier::new("not".to_string(), false, span))
-            }
-            TokenKind::Each => {
-                self.advance();
-                Ok(Identifier::new("each".to_string(), false, span))
-            }
-            TokenKind::Try => {
-                self.advance();
-                Ok(Identifier::new("try".to_string(), false, span))
-            }
-            TokenKind::Error => {
-                self.advance();
-                Ok(Identifier::new("error".to_string(), false, span))
-            }
-            TokenKind::As => {
-                self.advance();
-                Ok(Identifier::new("as".to_string(), false, span))
-            }
-            TokenKind::Is => {
-                self.advance();
-                Ok(Identifier::new("is".to_string(), false, span))
-            }
-            TokenKind::Otherwise => {
-                self.advance();
-                Ok(Identifier::new("otherwise".to_string(), false, span))
-            }
-            TokenKind::Meta => {
-                self.advance();
-                Ok(Identifier::new("meta".to_string(), false, span))
-            }
-            TokenKind::Section => {
-                self.advance();
-                Ok(Identifier::new("section".to_string(), false, span))
-            }
-            TokenKind::Shared => {
-                self.advance();
-                Ok(Identifier::new("shared".to_string(), false, span))
-            }
-            TokenKind::Null => {
                 self.advance();
-                Ok(Identifier::new("null".to_string(), false, span))
+                return Ok(Identifier::new(name, true, span));
             }
-            TokenKind::True => {
+            // `#table` and the other hash keywords are generalized
+            // identifiers on their own
+            kind if kind.is_hash_keyword() => {
                 self.advance();
-                Ok(Identifier::new("true".to_string(), false, span))
-            }
-            TokenKind::False => {
+                return Ok(Identifier::new(kind.to_string(), false, span));
+            }
+            kind => match Self::generalized_identifier_part(&kind) {
+                Some(part) => part,
+                None => {
+                    let msg = format!("Expected identifier, found {:?}", kind);
+                    self.push_error(&msg, span);
+                    return Err(Failed);
+                }
+            },
+        };
+        self.advance();
+        name.push_str(&self.parse_generalized_identifier_rest());
+        
+        Ok(Identifier::new(name, false, span.merge(self.prev_span())))
+    }
+    
+    /// Parse the parts of a generalized identifier after its first one,
+    /// separated from it by blanks as in `[Net Sales]`. Returns them with
+    /// the blanks as written.
+    fn parse_generalized_identifier_rest(&mut self) -> String {
+        let mut rest = String::new();
+        while let Some(TokenKind::Whitespace(blank)) = self.tokens.get(self.pos).map(|t| &t.kind) {
+            let part = self
+                .tokens
+                .get(self.pos + 1)
+                .and_then(|t| Self::generalized_identifier_part(&t.kind));
+            let Some(part) = part else { break };
+            rest.push_str(blank);
+            rest.push_str(&part);
+            self.pos += 2;
+        }
+        rest
+    }
+    
+    /// Skip a generalized identifier, if one starts here
+    fn skip_generalized_identifier(&mut self) -> bool {
+        match self.current_kind() {
+            TokenKind::QuotedIdentifier(_) => {}
+            kind if kind.is_hash_keyword() => {}
+            kind if Self::generalized_identifier_part(&kind).is_some() => {
                 self.advance();
-                Ok(Identifier::new("false".to_string(), false, span))
-            }
-            _ => {
-                let msg = format!("Expected identifier, found {:?}", self.current_kind());
-                self.errors.push(ParseError::new(&msg, span));
-                Err(self.errors.clone())
+                self.parse_generalized_identifier_rest();
+                return true;
             }
+            _ => return false,
+        }
+        self.advance();
+        true
+    }
+    
+    /// Text of a token that can be a part of a generalized identifier: an
+    /// identifier or any keyword
+    fn generalized_identifier_part(kind: &TokenKind) -> Option<String> {
+        match kind {
+            TokenKind::Identifier(name) => Some(name.clone()),
+            kind if kind.is_keyword() => Some(kind.to_string()),
+            _ => None,
         }
     }
     
@@ -1687,14 +2754,98 @@ impl Parser {
         while self.pos < self.tokens.len() && self.tokens[self.pos].kind.is_trivia() {
             let token = self.tokens[self.pos].clone();
             // Only collect comments, not whitespace/newlines
-            if matches!(token.kind, TokenKind::LineComment(_) | TokenKind::BlockComment(_)) {
+            if self.options.attach_trivia
+                && matches!(token.kind, TokenKind::LineComment(_) | TokenKind::BlockComment(_))
+            {
                 trivia.push(token);
+                self.attached.push(self.pos);
             }
             self.pos += 1;
         }
         trivia
     }
     
+    /// Skip trivia and return the comments as attachable trivia
+    fn take_comments(&mut self) -> Vec<Trivia> {
+        let tokens = self.collect_trivia();
+        self.tokens_to_trivia(&tokens)
+    }
+    
+    /// Parse an expression with `parse`, attaching the comments before it
+    /// as its leading trivia
+    fn parse_with_comments(
+        &mut self,
+        parse: fn(&mut Self) -> PResult<Expr>,
+    ) -> PResult<Expr> {
+        let leading_trivia = self.take_comments();
+        let mut expr = parse(self)?;
+        expr.leading_trivia.splice(0..0, leading_trivia);
+        Ok(expr)
+    }
+    
+    /// Parse one element of a comma-separated production (binding, field,
+    /// list item, argument) and the comma after it, together with its
+    /// comments. A comment on the same line as the element, before or after
+    /// the comma, is its trailing trivia; a comment on a line of its own
+    /// belongs to the element that follows. Returns `None` when `close`
+    /// follows instead of an element.
+    fn parse_with_trivia<T>(
+        &mut self,
+        close: TokenKind,
+        parse: fn(&mut Self) -> PResult<T>,
+    ) -> PResult<Option<Element<T>>> {
+        // Comments before `close` are left for `place_comments`
+        let saved_pos = self.pos;
+        self.skip_trivia();
+        if self.current_kind() == close || self.is_at_end() {
+            return Ok(None);
+        }
+        self.pos = saved_pos;
+        let leading_trivia = self.take_comments();
+        let value = parse(self)?;
+        let mut trailing_trivia = self.take_comments();
+        
+        let separated = self.current_kind() == TokenKind::Comma;
+        if separated {
+            self.advance();
+            trailing_trivia.extend(self.take_same_line_comments());
+            self.skip_whitespace_only();
+        }
+        
+        Ok(Some(Element {
+            leading_trivia,
+            value,
+            trailing_trivia,
+            separated,
+        }))
+    }
+    
+    /// Skip whitespace and comments up to the end of the current line and
+    /// return the comments
+    fn take_same_line_comments(&mut self) -> Vec<Trivia> {
+        let mut tokens = Vec::new();
+        while self.pos < self.tokens.len() {
+            let token = &self.tokens[self.pos];
+            match token.kind {
+                TokenKind::Whitespace(_) => {}
+                TokenKind::BlockComment(_) => tokens.push((self.pos, token.clone())),
+                TokenKind::LineComment(_) => {
+                    tokens.push((self.pos, token.clone()));
+                    self.pos += 1;
+                    break;
+                }
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        if !self.options.attach_trivia {
+            tokens.clear();
+        }
+        self.attached.extend(tokens.iter().map(|(index, _)| *index));
+        let tokens: Vec<Token> = tokens.into_iter().map(|(_, token)| token).collect();
+        self.tokens_to_trivia(&tokens)
+    }
+    
     /// Convert tokens to Trivia structs
     fn tokens_to_trivia(&self, tokens: &[Token]) -> Vec<Trivia> {
         tokens.iter().map(|t| {
@@ -1710,14 +2861,102 @@ impl Parser {
         self.current_kind() == TokenKind::Eof
     }
     
-    fn expect(&mut self, expected: TokenKind) -> Result<(), Vec<ParseError>> {
-        if std::mem::discriminant(&self.current_kind()) == std::mem::discriminant(&expected) {
+    /// Record an error, naming the (at most three innermost) record fields
+    /// and list items it is in. An error at the end of the input after
+    /// another one is dropped: it is a consequence of the first, such as an
+    /// unterminated text literal or bracket taking the rest of the input.
+    fn push_error(&mut self, message: impl Into<String>, span: Span) {
+        let at_end = self.tokens.last().is_some_and(|eof| span.start >= eof.span.start);
+        if at_end && !self.errors.is_empty() || self.gave_up() {
+            return;
+        }
+        if self.errors.len() == MAX_ERRORS {
+            self.errors.push(ParseError::new(
+                format!("Too many errors; stopped after {}", MAX_ERRORS),
+                span,
+            ));
+            return;
+        }
+        let mut message = message.into();
+        if !self.contexts.is_empty() {
+            let contexts: Vec<String> = self.contexts.iter().rev().take(3).map(|c| c.to_string()).collect();
+            message = format!("{} (in {})", message, contexts.join(", in "));
+        }
+        self.errors.push(ParseError::new(message, span));
+    }
+    
+    /// Whether so many errors were reported that the parser stopped
+    /// recovering from them, or parsing was interrupted
+    fn gave_up(&self) -> bool {
+        self.errors.len() > MAX_ERRORS || self.interrupted.get().is_some()
+    }
+    
+    /// Count a step of work and whether parsing should stop (see
+    /// [`Parser::set_cancellation`])
+    fn is_interrupted(&self) -> bool {
+        let Some(token) = self.cancel else {
+            return false;
+        };
+        if self.interrupted.get().is_some() {
+            return true;
+        }
+        self.work.set(self.work.get() + 1);
+        if token.load(Ordering::Relaxed) {
+            self.interrupted.set(Some(Interruption::Cancelled));
+        } else if self.work.get() > self.work_budget {
+            self.interrupted.set(Some(Interruption::OverBudget));
+        }
+        self.interrupted.get().is_some()
+    }
+    
+    /// Run `parse` with `context` on the context stack
+    fn in_context<T>(&mut self, context: Context, parse: impl FnOnce(&mut Self) -> T) -> T {
+        self.contexts.push(context);
+        let result = parse(self);
+        self.contexts.pop();
+        result
+    }
+    
+    fn expect(&mut self, expected: TokenKind) -> PResult<()> {
+        if core::mem::discriminant(&self.current_kind()) == core::mem::discriminant(&expected) {
             self.advance();
             Ok(())
         } else {
             let msg = format!("Expected {:?}, found {:?}", expected, self.current_kind());
-            self.errors.push(ParseError::new(&msg, self.current_span()));
-            Err(self.errors.clone())
+            let error_count = self.errors.len();
+            self.push_error(&msg, self.current_span());
+            if self.errors.len() == error_count {
+                return Err(Failed);
+            }
+            if let Some((bracket, span)) = self.unclosed(&expected) {
+                let label = format!("unclosed `{}` opened here", bracket);
+                let error = self.errors.pop().unwrap().with_label(label, span);
+                self.errors.push(error);
+            }
+            Err(Failed)
+        }
+    }
+    
+    /// The opening bracket that `close` would close at the current
+    /// position, and its span
+    fn unclosed(&self, close: &TokenKind) -> Option<(char, Span)> {
+        let (open, bracket) = match close {
+            TokenKind::RightParen => (TokenKind::LeftParen, '('),
+            TokenKind::RightBracket => (TokenKind::LeftBracket, '['),
+            TokenKind::RightBrace => (TokenKind::LeftBrace, '{'),
+            _ => return None,
+        };
+        let mut depth = 0;
+        for token in self.tokens[..self.pos.min(self.tokens.len())].iter().rev() {
+            if token.kind == *close {
+                depth += 1;
+            } else if token.kind == open {
+                if depth == 0 {
+                    return Some((bracket, token.span));
+                }
+                depth -= 1;
+            }
         }
+        None
     }
 }
@@ -0,0 +1,183 @@
+//! Passes over the formatted text
+//!
+//! Integrations often adjust the output of [`format`](crate::format) before
+//! writing it: a generated-by banner at the top, a line ending the target
+//! repository expects, no final newline for an editor that adds its own.
+//! A [`PostProcessor`] is such a pass; processors added to a [`Formatter`]
+//! with [`Formatter::add_post_processor`] run in order on its output.
+//! [`Banner`] and [`NewlinePolicy`] cover the common cases, and any
+//! `Fn(String) -> String` is a processor too.
+//!
+//! [`Formatter`]: crate::Formatter
+//! [`Formatter::add_post_processor`]: crate::Formatter::add_post_processor
+
+use crate::alloc_prelude::*;
+use crate::parser::NewlineKind;
+
+/// A pass over formatted text
+///
+/// Formatting the output again should give the same output, so a processor
+/// should leave text it has already processed unchanged (see [`Banner`]).
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::postprocess::PostProcessor;
+/// use pqm_formatter::{Config, Formatter, Lexer, Parser};
+///
+/// struct Uppercase;
+///
+/// impl PostProcessor for Uppercase {
+///     fn process(&self, text: String) -> String {
+///         text.to_uppercase()
+///     }
+/// }
+///
+/// let document = Parser::new(Lexer::new("let a = 1 in a").tokenize()).parse().unwrap();
+/// let mut formatter = Formatter::new(Config::compact());
+/// formatter.add_post_processor(Uppercase);
+/// formatter.add_post_processor(|text: String| text.replace(" IN ", " in "));
+/// assert_eq!(formatter.format(&document), "LET A = 1 in A\n");
+/// ```
+pub trait PostProcessor {
+    fn process(&self, text: String) -> String;
+}
+
+impl<F: Fn(String) -> String> PostProcessor for F {
+    fn process(&self, text: String) -> String {
+        self(text)
+    }
+}
+
+/// Line comments at the top of the text, such as a generated-by notice.
+/// Text that already starts with them is left unchanged.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::postprocess::{Banner, PostProcessor};
+///
+/// let banner = Banner::new("Generated by sync-queries\nDo not edit");
+/// let text = banner.process("1\n".to_string());
+/// assert_eq!(text, "// Generated by sync-queries\n// Do not edit\n1\n");
+/// assert_eq!(banner.process(text.clone()), text);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Banner {
+    lines: Vec<String>,
+}
+
+impl Banner {
+    /// A banner with one line comment per line of `text`
+    pub fn new(text: &str) -> Self {
+        Banner {
+            lines: text.lines().map(|line| line.trim_end().to_string()).collect(),
+        }
+    }
+}
+
+impl PostProcessor for Banner {
+    fn process(&self, text: String) -> String {
+        let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        let mut banner = String::new();
+        for line in &self.lines {
+            banner.push_str("//");
+            if !line.is_empty() {
+                banner.push(' ');
+                banner.push_str(line);
+            }
+            banner.push_str(newline);
+        }
+        if text.starts_with(&banner) {
+            text
+        } else {
+            banner + &text
+        }
+    }
+}
+
+/// Line endings and the number of line breaks at the end of the text
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::postprocess::{NewlinePolicy, PostProcessor};
+/// use pqm_formatter::NewlineKind;
+///
+/// let policy = NewlinePolicy {
+///     line_ending: NewlineKind::CrLf,
+///     final_newlines: 0,
+/// };
+/// assert_eq!(policy.process("let\n    a = 1\nin\n    a\n".to_string()), "let\r\n    a = 1\r\nin\r\n    a");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewlinePolicy {
+    pub line_ending: NewlineKind,
+    /// Line breaks after the last line (the formatter writes one)
+    pub final_newlines: usize,
+}
+
+impl Default for NewlinePolicy {
+    /// What the formatter writes: `\n`, with one after the last line
+    fn default() -> Self {
+        NewlinePolicy {
+            line_ending: NewlineKind::Lf,
+            final_newlines: 1,
+        }
+    }
+}
+
+impl PostProcessor for NewlinePolicy {
+    fn process(&self, text: String) -> String {
+        let newline = match self.line_ending {
+            NewlineKind::Lf => "\n",
+            NewlineKind::CrLf => "\r\n",
+        };
+        let body = text.trim_end_matches(['\r', '\n']);
+        let mut processed = String::with_capacity(text.len() + text.len() / 16);
+        for (i, line) in body.split('\n').enumerate() {
+            if i > 0 {
+                processed.push_str(newline);
+            }
+            processed.push_str(line.strip_suffix('\r').unwrap_or(line));
+        }
+        for _ in 0..self.final_newlines {
+            processed.push_str(newline);
+        }
+        processed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Formatter, Lexer, Parser};
+    
+    fn format_processed(code: &str) -> String {
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let mut formatter = Formatter::new(Config::default());
+        formatter.add_post_processor(Banner::new("Generated by sync-queries\n\nDo not edit"));
+        formatter.add_post_processor(NewlinePolicy {
+            line_ending: NewlineKind::CrLf,
+            final_newlines: 2,
+        });
+        formatter.format(&document)
+    }
+    
+    #[test]
+    fn test_chain_is_idempotent() {
+        let formatted = format_processed("let a = 1 in a");
+        assert_eq!(
+            formatted,
+            "// Generated by sync-queries\r\n//\r\n// Do not edit\r\nlet\r\n    a = 1\r\nin\r\n    a\r\n\r\n"
+        );
+        assert_eq!(format_processed(&formatted), formatted);
+    }
+    
+    #[test]
+    fn test_newline_policy() {
+        let policy = NewlinePolicy::default();
+        assert_eq!(policy.process("a\r\nb\r\n\r\n".to_string()), "a\nb\n");
+        assert_eq!(policy.process(String::new()), "\n");
+    }
+}
@@ -0,0 +1,19 @@
+//! The commonly used items, for a single glob import:
+//!
+//! ```rust
+//! use pqm_formatter::prelude::*;
+//!
+//! let formatted = format("let x=1 in x", Config::default()).unwrap();
+//! assert_eq!(formatted, "let\n    x = 1\nin\n    x\n");
+//! ```
+
+pub use crate::ast::{Document, Expr, ExprKind, Identifier, TypeKind};
+pub use crate::config::{
+    CallWrapStyle, CommentStyle, Config, Dialect, EachStyle, ListFillStyle, SectionAccessStyle, StyleEdition,
+};
+pub use crate::diagnostic::{Diagnostic, Severity};
+pub use crate::formatter::{FormatError, FormatStats, FormatWarning, Formatter};
+pub use crate::parser::{ParseError, ParseOptions};
+pub use crate::postprocess::PostProcessor;
+pub use crate::token::{Span, TokenKind};
+pub use crate::{format, format_with_diagnostics, format_with_stats, parse_document, validate};
@@ -0,0 +1,1196 @@
+//! Source-to-source refactorings
+
+use core::fmt;
+use core::str::FromStr;
+
+use crate::alloc_prelude::*;
+use crate::analysis::{connector_argument_role, is_connector_function, steps_let};
+use crate::ast::{
+    BinaryExpr, BinaryOp, Binding, Document, Expr, ExprKind, FunctionCallExpr, Identifier, LetExpr,
+    ListExpr, NodeRef, Parameter, RecordExpr, RecordField, Trivia, TypeAnnotation, TypeExpr, TypeKind,
+};
+use crate::config::Config;
+use crate::formatter::{FormatError, Formatter};
+use crate::lexer::{is_regular_identifier, Lexer};
+use crate::parser::ParseError;
+use crate::token::{Span, Token, TokenKind};
+
+/// A let binding created by [`extract_parameters`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractedParameter {
+    /// Name of the new binding, e.g. `ServerName`
+    pub name: String,
+    /// The literal text that was moved into it
+    pub value: String,
+}
+
+/// Result of [`extract_parameters`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extraction {
+    /// The rewritten, formatted query
+    pub code: String,
+    /// Bindings created, in the order they appear in `code`
+    pub parameters: Vec<ExtractedParameter>,
+}
+
+/// Move hard-coded connector arguments into let bindings at the top.
+///
+/// Text literals passed to connector functions (`Sql.Database`,
+/// `Web.Contents`, `File.Contents`, ...) become bindings named after their
+/// role (`ServerName`, `DatabaseName`, `Url`, `FilePath`, ...), and the
+/// call refers to the binding instead. A literal used more than once gets a
+/// single binding. The result is formatted with the default configuration.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::refactor::extract_parameters;
+///
+/// let result = extract_parameters(r#"let Source = Sql.Database("prod", "Sales") in Source"#).unwrap();
+/// assert_eq!(result.parameters[0].name, "ServerName");
+/// assert_eq!(result.parameters[0].value, "prod");
+/// assert!(result.code.contains("Sql.Database(ServerName, DatabaseName)"));
+/// ```
+pub fn extract_parameters(code: &str) -> Result<Extraction, Vec<ParseError>> {
+    let config = Config::default();
+    let (mut document, _) = crate::parse_document(code, &config)?;
+    
+    // The new bindings are in scope everywhere, so a name bound anywhere in
+    // the query (a function parameter, a nested let) would capture them
+    let mut taken = names_in(&document);
+    
+    let mut parameters = Vec::new();
+    extract_expr(&mut document.expression, &mut taken, &mut parameters);
+    
+    if !parameters.is_empty() {
+        let bindings: Vec<Binding> = parameters
+            .iter()
+            .map(|p| Binding {
+                name: Identifier::new(p.name.clone(), false, Span::default()),
+                inclusive: false,
+                value: Expr::new(ExprKind::Text(p.value.clone()), Span::default()),
+                span: Span::default(),
+                leading_trivia: Vec::new(),
+                trailing_trivia: Vec::new(),
+            })
+            .collect();
+        
+        match &mut document.expression.kind {
+            ExprKind::Let(l) => {
+                l.bindings.splice(0..0, bindings);
+            }
+            _ => {
+                let span = document.expression.span;
+                let body = core::mem::replace(
+                    &mut document.expression,
+                    Expr::new(ExprKind::Null, span),
+                );
+                document.expression = Expr::new(
+                    ExprKind::Let(LetExpr { bindings, body: Box::new(body) }),
+                    span,
+                );
+            }
+        }
+    }
+    
+    let code = Formatter::with_source(config, code).format(&document);
+    Ok(Extraction { code, parameters })
+}
+
+/// Every name the document binds or refers to, `_` included if it uses
+/// `each`
+fn names_in(document: &Document) -> Vec<String> {
+    let mut names = Vec::new();
+    for (node, _, _) in document.nodes() {
+        let name = match node {
+            NodeRef::Expr(expr) => match &expr.kind {
+                ExprKind::Identifier(name) | ExprKind::QuotedIdentifier(name) => name,
+                ExprKind::InclusiveIdentifier(ident) => &ident.name,
+                ExprKind::Each(_) | ExprKind::Underscore => "_",
+                _ => continue,
+            },
+            NodeRef::Binding(b) => &b.name.name,
+            NodeRef::SectionMember(m) => &m.name.name,
+            NodeRef::RecordField(f) => &f.name.name,
+            NodeRef::Parameter(p) => &p.name.name,
+        };
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+fn extract_expr(expr: &mut Expr, taken: &mut Vec<String>, parameters: &mut Vec<ExtractedParameter>) {
+    if let ExprKind::FunctionCall(call) = &mut expr.kind {
+        if let ExprKind::Identifier(function) = &call.function.kind {
+            if is_connector_function(function) {
+                let function = function.clone();
+                for (i, arg) in call.arguments.iter_mut().enumerate() {
+                    let ExprKind::Text(value) = &arg.kind else { continue };
+                    let name = match parameters.iter().find(|p| &p.value == value) {
+                        Some(existing) => existing.name.clone(),
+                        None => {
+                            let name = unique_name(base_name(&function, i), taken);
+                            taken.push(name.clone());
+                            parameters.push(ExtractedParameter {
+                                name: name.clone(),
+                                value: value.clone(),
+                            });
+                            name
+                        }
+                    };
+                    arg.kind = ExprKind::Identifier(name);
+                }
+            }
+        }
+    }
+    for child in expr.children_mut() {
+        extract_expr(child, taken, parameters);
+    }
+}
+
+/// Binding name for argument `index` of connector `function`
+fn base_name(function: &str, index: usize) -> &'static str {
+    match connector_argument_role(function, index) {
+        Some("server") => "ServerName",
+        Some("database") => "DatabaseName",
+        Some("warehouse") => "WarehouseName",
+        Some("url") => "Url",
+        Some("path") => "FilePath",
+        Some("connection") => "ConnectionString",
+        Some("query") => "Query",
+        _ => "Parameter",
+    }
+}
+
+/// `base`, or `base2`, `base3`, ... if already taken
+fn unique_name(base: &str, taken: &[String]) -> String {
+    if !taken.iter().any(|t| t == base) {
+        return base.to_string();
+    }
+    (2..)
+        .map(|n| format!("{}{}", base, n))
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap()
+}
+
+/// Naming style for [`normalize_step_names`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepNameStyle {
+    /// `#"Filtered Rows"` → `FilteredRows`
+    PascalCase,
+    /// `#"Filtered Rows"` → `filteredRows`
+    CamelCase,
+    /// `FilteredRows` → `#"Filtered Rows"`, as Power Query names steps
+    KeepSpaces,
+}
+
+impl FromStr for StepNameStyle {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PascalCase" | "pascal" => Ok(StepNameStyle::PascalCase),
+            "camelCase" | "camel" => Ok(StepNameStyle::CamelCase),
+            "keep-spaces" | "spaces" => Ok(StepNameStyle::KeepSpaces),
+            _ => Err(format!(
+                "Unknown step name style '{}' (expected PascalCase, camelCase, or keep-spaces)",
+                s
+            )),
+        }
+    }
+}
+
+/// Rename every step of a query to `style` and update all references.
+///
+/// The result is formatted with the default configuration.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::refactor::{normalize_step_names, StepNameStyle};
+///
+/// let code = r#"let #"Filtered Rows" = 1, Result = #"Filtered Rows" in Result"#;
+/// let renamed = normalize_step_names(code, StepNameStyle::CamelCase).unwrap();
+/// assert_eq!(renamed, "let\n    filteredRows = 1,\n    result = filteredRows\nin\n    result\n");
+/// ```
+pub fn normalize_step_names(code: &str, style: StepNameStyle) -> Result<String, Vec<ParseError>> {
+    let config = Config::default();
+    let (mut document, _) = crate::parse_document(code, &config)?;
+    rename_steps(&mut document, style);
+    Ok(Formatter::with_source(config, code).format(&document))
+}
+
+/// Rename the steps of a parsed query in place and update references to
+/// them. Steps are the bindings of the top-level `let` (or of the body of a
+/// query function). Names that would collide get a numeric suffix.
+///
+/// Returns the `(old, new)` pairs of the steps that changed.
+pub fn rename_steps(document: &mut Document, style: StepNameStyle) -> Vec<(String, String)> {
+    let renames = new_step_names(document, |old| Some(restyle(old, style)));
+    apply_step_renames(document, &renames);
+    renames
+}
+
+/// Shorter names for the steps whose names are too long to fit on a line
+/// under `config`, as `(old, new)` pairs to pass to [`apply_step_renames`].
+/// A suggestion keeps the leading words of the name, up to
+/// [`SUGGESTED_NAME_LENGTH`] characters, and is unique among the names the
+/// query uses.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::refactor::suggest_step_names;
+/// use pqm_formatter::{parse_document, Config};
+///
+/// let mut config = Config::default();
+/// config.max_line_length = 40;
+/// let code = r#"let #"Removed rows where the amount is missing or zero" = 1 in 1"#;
+/// let (document, _) = parse_document(code, &config).unwrap();
+/// let suggestions = suggest_step_names(&document, &config);
+/// assert_eq!(suggestions[0].1, "Removed rows where the amount");
+/// ```
+pub fn suggest_step_names(document: &Document, config: &Config) -> Vec<(String, String)> {
+    // Steps are written one level in; shortened names may need `#""`
+    let available = config
+        .max_line_length
+        .saturating_sub(config.indent_at(1).chars().count());
+    let written_width = |name: &str| {
+        if is_regular_identifier(name) {
+            name.chars().count()
+        } else {
+            name.chars().count() + name.matches('"').count() + 3
+        }
+    };
+    new_step_names(document, |old| {
+        (written_width(old) > available).then(|| shorten_name(old, SUGGESTED_NAME_LENGTH.min(available.saturating_sub(3))))
+    })
+}
+
+/// Longest name [`suggest_step_names`] suggests
+pub const SUGGESTED_NAME_LENGTH: usize = 30;
+
+/// `(old, new)` pairs for the steps `rename` gives a new name, made unique
+/// among each other and the other names the query uses
+pub(crate) fn new_step_names(document: &Document, mut rename: impl FnMut(&str) -> Option<String>) -> Vec<(String, String)> {
+    let mut referenced = Vec::new();
+    document.expression.walk(&mut |expr| {
+        match &expr.kind {
+            ExprKind::Identifier(name) | ExprKind::QuotedIdentifier(name) => referenced.push(name.clone()),
+            ExprKind::InclusiveIdentifier(ident) => referenced.push(ident.name.clone()),
+            _ => {}
+        }
+    });
+    
+    let Some(steps) = steps_let(&document.expression) else {
+        return Vec::new();
+    };
+    let old_names: Vec<String> = steps.bindings.iter().map(|b| b.name.name.clone()).collect();
+    
+    // Names the new ones must not capture: everything referenced that is
+    // not one of the steps
+    let mut taken: Vec<String> = referenced
+        .into_iter()
+        .filter(|name| !old_names.contains(name))
+        .collect();
+    // Steps that keep their names keep them even if an earlier step would
+    // take them
+    let proposed: Vec<Option<String>> = old_names.iter().map(|old| rename(old)).collect();
+    taken.extend(
+        old_names
+            .iter()
+            .zip(&proposed)
+            .filter(|(_, new)| new.is_none())
+            .map(|(old, _)| old.clone()),
+    );
+    let mut renames = Vec::new();
+    for (old, new) in old_names.iter().zip(proposed) {
+        let Some(new) = new else {
+            continue;
+        };
+        let new = unique_name(&new, &taken);
+        taken.push(new.clone());
+        if &new != old {
+            renames.push((old.clone(), new));
+        }
+    }
+    renames
+}
+
+/// Rename steps of a parsed query in place, as `(old, new)` pairs, and
+/// update references to them
+pub fn apply_step_renames(document: &mut Document, renames: &[(String, String)]) {
+    let Some(steps) = steps_let_mut(&mut document.expression) else {
+        return;
+    };
+    if renames.is_empty() {
+        return;
+    }
+    
+    for binding in &mut steps.bindings {
+        rename_references(&mut binding.value, renames, &mut Vec::new());
+    }
+    rename_references(&mut steps.body, renames, &mut Vec::new());
+    for binding in &mut steps.bindings {
+        if let Some((_, new)) = renames.iter().find(|(old, _)| *old == binding.name.name) {
+            binding.name.quoted = !is_regular_identifier(new);
+            binding.name.name = new.clone();
+        }
+    }
+}
+
+fn steps_let_mut(expr: &mut Expr) -> Option<&mut LetExpr> {
+    match &mut expr.kind {
+        ExprKind::Let(l) => Some(l),
+        ExprKind::Parenthesized(inner) => steps_let_mut(inner),
+        ExprKind::Function(f) => steps_let_mut(&mut f.body),
+        _ => None,
+    }
+}
+
+/// Rewrite references to renamed steps, skipping scopes that shadow them
+pub(crate) fn rename_references(expr: &mut Expr, renames: &[(String, String)], shadowed: &mut Vec<String>) {
+    let renamed = |name: &str| {
+        if shadowed.iter().any(|s| s == name) {
+            return None;
+        }
+        renames.iter().find(|(old, _)| old == name).map(|(_, new)| new.clone())
+    };
+    match &mut expr.kind {
+        ExprKind::Identifier(name) | ExprKind::QuotedIdentifier(name) => {
+            if let Some(new) = renamed(name) {
+                expr.kind = if is_regular_identifier(&new) {
+                    ExprKind::Identifier(new)
+                } else {
+                    ExprKind::QuotedIdentifier(new)
+                };
+            }
+            return;
+        }
+        ExprKind::InclusiveIdentifier(ident) => {
+            if let Some(new) = renamed(&ident.name) {
+                ident.quoted = !is_regular_identifier(&new);
+                ident.name = new;
+            }
+            return;
+        }
+        _ => {}
+    }
+    
+    let bound: Vec<String> = match &expr.kind {
+        ExprKind::Let(l) => l.bindings.iter().map(|b| b.name.name.clone()).collect(),
+        ExprKind::Record(r) => r.fields.iter().map(|f| f.name.name.clone()).collect(),
+        ExprKind::Function(f) => f.parameters.iter().map(|p| p.name.name.clone()).collect(),
+        _ => Vec::new(),
+    };
+    let depth = shadowed.len();
+    shadowed.extend(bound);
+    for child in expr.children_mut() {
+        rename_references(child, renames, shadowed);
+    }
+    shadowed.truncate(depth);
+}
+
+/// The leading words of `name` that fit in `length` characters, or its
+/// first `length` characters if the first word is longer
+fn shorten_name(name: &str, length: usize) -> String {
+    let mut short = String::new();
+    for word in name.split_whitespace() {
+        let separator = usize::from(!short.is_empty());
+        if short.chars().count() + separator + word.chars().count() > length {
+            break;
+        }
+        if separator == 1 {
+            short.push(' ');
+        }
+        short.push_str(word);
+    }
+    if short.is_empty() {
+        short = name.chars().take(length.max(1)).collect();
+    }
+    short
+}
+
+/// `name` rewritten in `style`, or unchanged if it has no words
+fn restyle(name: &str, style: StepNameStyle) -> String {
+    let words = split_words(name);
+    if words.is_empty() {
+        return name.to_string();
+    }
+    match style {
+        StepNameStyle::PascalCase => words.iter().map(|w| capitalize(w)).collect(),
+        StepNameStyle::CamelCase => {
+            let first = if words[0].chars().all(|c| !c.is_lowercase()) {
+                words[0].to_lowercase()
+            } else {
+                decapitalize(&words[0])
+            };
+            core::iter::once(first)
+                .chain(words[1..].iter().map(|w| capitalize(w)))
+                .collect()
+        }
+        StepNameStyle::KeepSpaces => words.iter().map(|w| capitalize(w)).collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// Words of an identifier: separated by non-alphanumerics and by case
+/// changes (`HTTPRequest` → `HTTP`, `Request`)
+fn split_words(name: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for part in name.split(|c: char| !c.is_alphanumeric()) {
+        let chars: Vec<char> = part.chars().collect();
+        let mut current = String::new();
+        for (i, &c) in chars.iter().enumerate() {
+            if i > 0 && c.is_uppercase() {
+                let prev = chars[i - 1];
+                let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+                if prev.is_lowercase() || (prev.is_uppercase() && next_lower) {
+                    words.push(core::mem::take(&mut current));
+                }
+            }
+            current.push(c);
+        }
+        if !current.is_empty() {
+            words.push(current);
+        }
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn decapitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Error returned by [`doc_stub`]
+#[derive(Debug, Clone)]
+pub enum DocStubError {
+    /// The input has syntax errors
+    Parse(Vec<ParseError>),
+    /// The document does not evaluate to a function expression
+    NotAFunction,
+}
+
+impl fmt::Display for DocStubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocStubError::Parse(errors) => write!(f, "{}", FormatError::Parse(errors.clone())),
+            DocStubError::NotAFunction => write!(f, "the document does not evaluate to a function"),
+        }
+    }
+}
+
+impl core::error::Error for DocStubError {}
+
+impl From<Vec<ParseError>> for DocStubError {
+    fn from(errors: Vec<ParseError>) -> Self {
+        DocStubError::Parse(errors)
+    }
+}
+
+/// Wrap a function query in `Value.ReplaceType` with a documentation
+/// skeleton, as custom connectors do to describe their functions.
+///
+/// The document must be a function, or a `let` whose result is a function
+/// or the name of a binding holding one. The generated `type function`
+/// repeats each parameter's name, type and optionality with empty
+/// `Documentation.*` fields to fill in; `name` is used for the function
+/// binding when the document has none. The result is formatted with the
+/// default configuration.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::refactor::doc_stub;
+///
+/// let stub = doc_stub("(sourceUrl as text) as table => Web.Contents(sourceUrl)", "GetData").unwrap();
+/// assert!(stub.contains("GetDataType = \n        type function ("));
+/// assert!(stub.contains("sourceUrl as (type text meta ["));
+/// assert!(stub.contains("Documentation.FieldCaption = \"Source Url\""));
+/// assert!(stub.ends_with("in\n    Value.ReplaceType(GetData, GetDataType)\n"));
+/// ```
+pub fn doc_stub(code: &str, name: &str) -> Result<String, DocStubError> {
+    let config = Config::default();
+    let (mut document, _) = crate::parse_document(code, &config)?;
+    
+    // Turn the document into `let <name> = <function>, ... in <name>`
+    match &document.expression.kind {
+        ExprKind::Function(_) => {
+            let span = document.expression.span;
+            let function = core::mem::replace(&mut document.expression, Expr::new(ExprKind::Null, span));
+            document.expression = Expr::new(
+                ExprKind::Let(LetExpr {
+                    bindings: vec![new_binding(name, function)],
+                    body: Box::new(reference(name)),
+                }),
+                span,
+            );
+        }
+        ExprKind::Let(l) if matches!(l.body.kind, ExprKind::Function(_)) => {
+            let ExprKind::Let(l) = &mut document.expression.kind else { unreachable!() };
+            let taken: Vec<String> = l.bindings.iter().map(|b| b.name.name.clone()).collect();
+            let name = unique_name(name, &taken);
+            let function = core::mem::replace(&mut *l.body, reference(&name));
+            l.bindings.push(new_binding(&name, function));
+        }
+        _ => {}
+    }
+    
+    let ExprKind::Let(l) = &mut document.expression.kind else {
+        return Err(DocStubError::NotAFunction);
+    };
+    let (ExprKind::Identifier(result) | ExprKind::QuotedIdentifier(result)) = &l.body.kind else {
+        return Err(DocStubError::NotAFunction);
+    };
+    let result = result.clone();
+    let Some(index) = l.bindings.iter().position(|b| b.name.name == result) else {
+        return Err(DocStubError::NotAFunction);
+    };
+    let ExprKind::Function(function) = &l.bindings[index].value.kind else {
+        return Err(DocStubError::NotAFunction);
+    };
+    
+    let parameters = function
+        .parameters
+        .iter()
+        .map(|p| {
+            let caption = restyle(&p.name.name, StepNameStyle::KeepSpaces);
+            let documented = documented_type(
+                p.type_annotation.clone().unwrap_or_else(any_type),
+                vec![
+                    ("Documentation.FieldCaption", text(&caption)),
+                    ("Documentation.FieldDescription", text("")),
+                    ("Documentation.SampleValues", list(Vec::new())),
+                ],
+            );
+            Parameter {
+                type_annotation: Some(TypeAnnotation {
+                    kind: TypeKind::Expr(Box::new(documented)),
+                    span: Span::default(),
+                }),
+                ..p.clone()
+            }
+        })
+        .collect();
+    let return_type = function.return_type.clone().unwrap_or_else(any_type);
+    let example = record(vec![
+        ("Description", text("")),
+        ("Code", text("")),
+        ("Result", text("")),
+    ]);
+    let function_type = documented_type(
+        TypeAnnotation {
+            kind: TypeKind::Function(parameters, Box::new(return_type)),
+            span: Span::default(),
+        },
+        vec![
+            ("Documentation.Name", text(&result)),
+            ("Documentation.LongDescription", text("")),
+            ("Documentation.Examples", list(vec![example])),
+        ],
+    );
+    
+    let taken: Vec<String> = l.bindings.iter().map(|b| b.name.name.clone()).collect();
+    let type_name = unique_name(&format!("{}Type", result), &taken);
+    l.bindings.insert(index + 1, new_binding(&type_name, function_type));
+    *l.body = Expr::new(
+        ExprKind::FunctionCall(Box::new(FunctionCallExpr {
+            function: reference("Value.ReplaceType"),
+            arguments: vec![reference(&result), reference(&type_name)],
+        })),
+        Span::default(),
+    );
+    
+    Ok(Formatter::with_source(config, code).format(&document))
+}
+
+/// A query that could not be added to a section document by [`bundle`]
+#[derive(Debug, Clone)]
+pub struct BundleError {
+    /// Name of the member the query was to become
+    pub member: String,
+    /// Syntax errors in the query
+    pub errors: Vec<ParseError>,
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.member, FormatError::Parse(self.errors.clone()))
+    }
+}
+
+impl core::error::Error for BundleError {}
+
+/// Combine queries into a section document with one `shared` member per
+/// query, in the given order.
+///
+/// Each query is formatted with `config`. Comments before a query are
+/// placed above its member, comments after it follow the `;`.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{refactor::bundle, Config};
+///
+/// let members = [("Sales", "// Raw sales\nlet Source = 1 in Source"), ("Total Cost", "2")];
+/// let section = bundle("Section1", &members, &Config::default()).unwrap();
+/// assert_eq!(
+///     section,
+///     "section Section1;\n\n// Raw sales\nshared Sales = let\n    Source = 1\nin\n    Source;\n\nshared #\"Total Cost\" = 2;\n"
+/// );
+/// ```
+pub fn bundle(section: &str, members: &[(&str, &str)], config: &Config) -> Result<String, BundleError> {
+    let mut output = format!("section {};\n", identifier_source(section));
+    for (name, code) in members {
+        let (mut document, _) = crate::parse_document(code, config).map_err(|errors| BundleError {
+            member: name.to_string(),
+            errors,
+        })?;
+        let leading = core::mem::take(&mut document.expression.leading_trivia);
+        let same_line = core::mem::take(&mut document.expression.trailing_trivia);
+        let trailing = core::mem::take(&mut document.trailing_trivia);
+        let body = Formatter::with_source(config.clone(), code).format(&document);
+        
+        output.push('\n');
+        for comment in leading.iter().filter_map(comment_source) {
+            output.push_str(&comment);
+            output.push('\n');
+        }
+        output.push_str(&format!("shared {} = {};", identifier_source(name), body.trim_end()));
+        for comment in same_line.iter().filter_map(comment_source) {
+            output.push(' ');
+            output.push_str(&comment);
+        }
+        output.push('\n');
+        for comment in trailing.iter().filter_map(comment_source) {
+            output.push_str(&comment);
+            output.push('\n');
+        }
+    }
+    Ok(output)
+}
+
+/// A member of a section document, as returned by [`split_section`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionMember {
+    /// Member name, without `#"..."` quoting
+    pub name: String,
+    /// The member's expression with its comments, formatted as a query
+    pub code: String,
+    /// Source text of the attribute record before the member
+    /// (`[DataSource.Kind = "..."]`), which a standalone query cannot keep
+    pub attributes: Option<String>,
+}
+
+/// Why [`split_section`] failed
+#[derive(Debug, Clone)]
+pub enum SplitError {
+    /// The text is not a `section Name; member = ...; ...` document
+    NotASection(ParseError),
+    /// A member's expression has syntax errors
+    Member(BundleError),
+}
+
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitError::NotASection(e) => write!(f, "{}", e),
+            SplitError::Member(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl core::error::Error for SplitError {}
+
+/// Split a section document into its members; the inverse of [`bundle`].
+///
+/// Each member's expression is formatted with `config` as a standalone
+/// query. Comments above a member and on the line of its `;` stay with it.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{refactor::split_section, Config};
+///
+/// let section = "section Section1;\n// Raw sales\nshared Sales = let Source = 1 in Source;\nshared #\"Total Cost\" = 2; // cost\n";
+/// let members = split_section(section, &Config::default()).unwrap();
+/// assert_eq!(members[0].name, "Sales");
+/// assert_eq!(members[0].code, "// Raw sales\nlet\n    Source = 1\nin\n    Source\n");
+/// assert_eq!(members[1].name, "Total Cost");
+/// assert_eq!(members[1].code, "2 // cost\n");
+/// ```
+pub fn split_section(code: &str, config: &Config) -> Result<Vec<SectionMember>, SplitError> {
+    let tokens = Lexer::new(code).tokenize();
+    let mut cursor = SectionCursor { tokens: &tokens, pos: 0 };
+    
+    cursor.skip_trivia();
+    cursor.expect(&TokenKind::Section, "Expected 'section'")?;
+    cursor.skip_trivia();
+    cursor.expect_name()?;
+    cursor.skip_trivia();
+    cursor.expect(&TokenKind::Semicolon, "Expected ';' after the section name")?;
+    
+    // (name, attributes, source of the expression with its comments)
+    let mut members: Vec<(String, Option<String>, String)> = Vec::new();
+    loop {
+        // Comments on the line of the previous `;` belong to that member
+        let scan_start = cursor.current_span().start;
+        let mut comments = Vec::new();
+        while let Some(token) = cursor.peek().filter(|t| t.kind.is_trivia()) {
+            match token.kind {
+                TokenKind::Newline => break,
+                TokenKind::LineComment(_) | TokenKind::BlockComment(_) => comments.push(token.span.slice(code)),
+                _ => {}
+            }
+            cursor.pos += 1;
+        }
+        if let Some((_, _, source)) = members.last_mut() {
+            for comment in comments {
+                source.push(' ');
+                source.push_str(comment);
+            }
+        }
+        
+        let leading_start = if members.is_empty() { scan_start } else { cursor.current_span().start };
+        cursor.skip_trivia();
+        if cursor.peek().is_none_or(|t| t.kind == TokenKind::Eof) {
+            break;
+        }
+        let leading = code[leading_start..cursor.current_span().start].trim();
+        
+        let attributes = if cursor.peek().is_some_and(|t| t.kind == TokenKind::LeftBracket) {
+            let start = cursor.current_span().start;
+            cursor.skip_group();
+            let end = cursor.tokens[cursor.pos - 1].span.end;
+            cursor.skip_trivia();
+            Some(code[start..end].to_string())
+        } else {
+            None
+        };
+        if cursor.peek().is_some_and(|t| t.kind == TokenKind::Shared) {
+            cursor.pos += 1;
+            cursor.skip_trivia();
+        }
+        let name = cursor.expect_name()?;
+        cursor.skip_trivia();
+        cursor.expect(&TokenKind::Equal, "Expected '=' after the member name")?;
+        
+        let start = cursor.current_span().start;
+        while cursor.peek().is_some_and(|t| !matches!(t.kind, TokenKind::Semicolon | TokenKind::Eof)) {
+            cursor.skip_group();
+        }
+        let end = cursor.current_span().start;
+        cursor.expect(&TokenKind::Semicolon, "Expected ';' after the member")?;
+        
+        let mut source = String::new();
+        if !leading.is_empty() {
+            source.push_str(leading);
+            source.push('\n');
+        }
+        source.push_str(code[start..end].trim());
+        members.push((name, attributes, source));
+    }
+    
+    members
+        .into_iter()
+        .map(|(name, attributes, source)| {
+            let code = crate::format(&source, config.clone()).map_err(|errors| {
+                SplitError::Member(BundleError {
+                    member: name.clone(),
+                    errors,
+                })
+            })?;
+            Ok(SectionMember { name, code, attributes })
+        })
+        .collect()
+}
+
+/// Token walker for the outline of a section document
+struct SectionCursor<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl SectionCursor<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    
+    fn current_span(&self) -> Span {
+        self.tokens.get(self.pos).or(self.tokens.last()).map_or(Span::default(), |t| t.span)
+    }
+    
+    fn skip_trivia(&mut self) {
+        while self.peek().is_some_and(|t| t.kind.is_trivia()) {
+            self.pos += 1;
+        }
+    }
+    
+    fn expect(&mut self, kind: &TokenKind, message: &str) -> Result<(), SplitError> {
+        if self.peek().is_some_and(|t| &t.kind == kind) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(SplitError::NotASection(ParseError::new(message, self.current_span())))
+        }
+    }
+    
+    fn expect_name(&mut self) -> Result<String, SplitError> {
+        match self.peek().map(|t| &t.kind) {
+            Some(TokenKind::Identifier(name) | TokenKind::QuotedIdentifier(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(SplitError::NotASection(ParseError::new("Expected a name", self.current_span()))),
+        }
+    }
+    
+    /// Skip one token, or a whole bracketed group starting at it
+    fn skip_group(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            match token.kind {
+                TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                    depth = depth.saturating_sub(1)
+                }
+                TokenKind::Eof => return,
+                _ => {}
+            }
+            self.pos += 1;
+            if depth == 0 {
+                return;
+            }
+        }
+    }
+}
+
+/// `name` as written in M, quoted when it is not a regular identifier
+fn identifier_source(name: &str) -> String {
+    if is_regular_identifier(name) {
+        name.to_string()
+    } else {
+        format!("#\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+/// A comment as the formatter prints it
+fn comment_source(trivia: &Trivia) -> Option<String> {
+    match trivia {
+        Trivia::LineComment(content) if content.is_empty() || content.starts_with(' ') => {
+            Some(format!("//{}", content))
+        }
+        Trivia::LineComment(content) => Some(format!("// {}", content)),
+        Trivia::BlockComment(content) => Some(format!("/*{}*/", content)),
+        _ => None,
+    }
+}
+
+fn new_binding(name: &str, value: Expr) -> Binding {
+    Binding {
+        name: Identifier::new(name.to_string(), !is_regular_identifier(name), Span::default()),
+        inclusive: false,
+        value,
+        span: Span::default(),
+        leading_trivia: Vec::new(),
+        trailing_trivia: Vec::new(),
+    }
+}
+
+fn reference(name: &str) -> Expr {
+    let kind = if is_regular_identifier(name) || name.contains('.') {
+        ExprKind::Identifier(name.to_string())
+    } else {
+        ExprKind::QuotedIdentifier(name.to_string())
+    };
+    Expr::new(kind, Span::default())
+}
+
+fn any_type() -> TypeAnnotation {
+    TypeAnnotation { kind: TypeKind::Any, span: Span::default() }
+}
+
+/// `type <annotation> meta [<fields>]`
+fn documented_type(annotation: TypeAnnotation, fields: Vec<(&str, Expr)>) -> Expr {
+    let type_expr = Expr::new(
+        ExprKind::Type(Box::new(TypeExpr { type_annotation: annotation, keyword: true })),
+        Span::default(),
+    );
+    Expr::new(
+        ExprKind::Binary(Box::new(BinaryExpr {
+            left: type_expr,
+            operator: BinaryOp::Meta,
+            right: record(fields),
+        })),
+        Span::default(),
+    )
+}
+
+fn record(fields: Vec<(&str, Expr)>) -> Expr {
+    let fields = fields
+        .into_iter()
+        .map(|(name, value)| RecordField {
+            name: Identifier::new(name.to_string(), false, Span::default()),
+            value,
+            span: Span::default(),
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
+            verbatim: false,
+        })
+        .collect();
+    Expr::new(ExprKind::Record(RecordExpr { fields }), Span::default())
+}
+
+fn list(items: Vec<Expr>) -> Expr {
+    Expr::new(ExprKind::List(ListExpr { items }), Span::default())
+}
+
+fn text(value: &str) -> Expr {
+    Expr::new(ExprKind::Text(value.to_string()), Span::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_extract_into_existing_let() {
+        let code = r#"let
+    Url = "unrelated",
+    Sales = Sql.Database("prod-sql01", "Sales"),
+    Stock = Sql.Database("prod-sql01", "Stock"),
+    Rates = Web.Contents("https://example.com/rates")
+in
+    Sales"#;
+        let result = extract_parameters(code).unwrap();
+        let names: Vec<&str> = result.parameters.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["ServerName", "DatabaseName", "DatabaseName2", "Url2"]);
+        assert_eq!(
+            result.code,
+            r#"let
+    ServerName = "prod-sql01",
+    DatabaseName = "Sales",
+    DatabaseName2 = "Stock",
+    Url2 = "https://example.com/rates",
+    Url = "unrelated",
+    Sales = 
+        Sql.Database(ServerName, DatabaseName),
+    Stock = 
+        Sql.Database(ServerName, DatabaseName2),
+    Rates = Web.Contents(Url2)
+in
+    Sales
+"#
+        );
+    }
+    
+    #[test]
+    fn test_extract_avoids_inner_names() {
+        // A function parameter named like the new binding would capture it
+        let result = extract_parameters(r#"let f = (ServerName) => Sql.Database("prod", ServerName) in f"#).unwrap();
+        assert_eq!(result.parameters[0].name, "ServerName2");
+        assert!(result.code.contains("Sql.Database(ServerName2, ServerName)"));
+        
+        // So would a nested let
+        let code = r#"let Page = let Url = 1 in Web.Contents("http://x") & Url in Page"#;
+        let result = extract_parameters(code).unwrap();
+        assert_eq!(result.parameters[0].name, "Url2");
+        assert!(result.code.contains("Web.Contents(Url2) & Url"));
+    }
+    
+    #[test]
+    fn test_extract_wraps_non_let() {
+        let result = extract_parameters(r#"Csv.Document(File.Contents("C:\data.csv"))"#).unwrap();
+        assert_eq!(
+            result.code,
+            "let\n    FilePath = \"C:\\data.csv\"\nin\n    Csv.Document(File.Contents(FilePath))\n"
+        );
+    }
+    
+    #[test]
+    fn test_restyle() {
+        assert_eq!(restyle("Filtered Rows", StepNameStyle::PascalCase), "FilteredRows");
+        assert_eq!(restyle("changed type1", StepNameStyle::PascalCase), "ChangedType1");
+        assert_eq!(restyle("HTTP Request", StepNameStyle::CamelCase), "httpRequest");
+        assert_eq!(restyle("Removed Columns", StepNameStyle::CamelCase), "removedColumns");
+        assert_eq!(restyle("HTTPRequest", StepNameStyle::KeepSpaces), "HTTP Request");
+        assert_eq!(restyle("filteredRows", StepNameStyle::KeepSpaces), "Filtered Rows");
+        assert_eq!(restyle("売上 集計", StepNameStyle::PascalCase), "売上集計");
+    }
+    
+    #[test]
+    fn test_normalize_step_names_updates_references() {
+        let code = r#"let
+    Source = Csv.Document(File.Contents(Path)),
+    #"Promoted Headers" = Table.PromoteHeaders(Source),
+    #"Changed Type" = Table.TransformColumnTypes(#"Promoted Headers", {{"Source", type text}}),
+    Custom = let Source = 1 in Source + @#"Changed Type"{0}[Value],
+    Fn = (#"Promoted Headers") => #"Promoted Headers"
+in
+    #"Changed Type""#;
+        let renamed = normalize_step_names(code, StepNameStyle::PascalCase).unwrap();
+        assert_eq!(
+            renamed,
+            r#"let
+    Source = Csv.Document(File.Contents(Path)),
+    PromotedHeaders = Table.PromoteHeaders(Source),
+    ChangedType = 
+        Table.TransformColumnTypes(
+            PromotedHeaders,
+            {{"Source", type text}}
+        ),
+    Custom = 
+        let
+            Source = 1
+        in
+            Source + @ChangedType{0}[Value],
+    Fn = (#"Promoted Headers") => #"Promoted Headers"
+in
+    ChangedType
+"#
+        );
+    }
+    
+    #[test]
+    fn test_rename_steps_avoids_collisions() {
+        let code = r#"let #"Filtered Rows" = 1, FilteredRows = 2, Result = #"Filtered Rows" + FilteredRows + #"Keep Me" in Result"#;
+        let renamed = normalize_step_names(code, StepNameStyle::KeepSpaces).unwrap();
+        assert_eq!(
+            renamed,
+            "let\n    #\"Filtered Rows\" = 1,\n    #\"Filtered Rows2\" = 2,\n    Result = #\"Filtered Rows\" + #\"Filtered Rows2\" + #\"Keep Me\"\nin\n    Result\n"
+        );
+    }
+    
+    #[test]
+    fn test_suggest_step_names() {
+        let config = Config {
+            max_line_length: 30,
+            ..Config::default()
+        };
+        let code = r#"let #"Sales per region and month" = 1, #"Sales per region and month, adjusted" = 2, Short = 3 in #"Sales per region and month" + Short"#;
+        let (mut document, _) = crate::parse_document(code, &config).unwrap();
+        let suggestions = suggest_step_names(&document, &config);
+        assert_eq!(
+            suggestions,
+            [
+                ("Sales per region and month".to_string(), "Sales per region and".to_string()),
+                ("Sales per region and month, adjusted".to_string(), "Sales per region and2".to_string()),
+            ]
+        );
+        
+        apply_step_renames(&mut document, &suggestions);
+        let formatted = Formatter::new(config).format(&document);
+        assert!(formatted.contains("in\n    #\"Sales per region and\" + Short\n"));
+    }
+    
+    #[test]
+    fn test_doc_stub() {
+        let stub = doc_stub("(sourceUrl as text, optional pageSize) as table => Web.Contents(sourceUrl)", "GetData").unwrap();
+        assert_eq!(
+            stub,
+            r#"let
+    GetData = (sourceUrl as text, optional pageSize) as table => Web.Contents(sourceUrl),
+    GetDataType = 
+        type function (
+            sourceUrl as (type text meta [
+                Documentation.FieldCaption = "Source Url",
+                Documentation.FieldDescription = "",
+                Documentation.SampleValues = {}
+            ]),
+            optional pageSize as (type any meta [
+                Documentation.FieldCaption = "Page Size",
+                Documentation.FieldDescription = "",
+                Documentation.SampleValues = {}
+            ])
+        ) as table meta [
+            Documentation.Name = "GetData",
+            Documentation.LongDescription = "",
+            Documentation.Examples = 
+                {
+                    [
+                        Description = "",
+                        Code = "",
+                        Result = ""
+                    ]
+                }
+        ]
+in
+    Value.ReplaceType(GetData, GetDataType)
+"#
+        );
+        assert_eq!(crate::format_default(&stub).unwrap(), stub);
+    }
+    
+    #[test]
+    fn test_doc_stub_uses_result_binding() {
+        let code = "let Helper = 2, Scale = (x as number) as number => x * Helper in Scale";
+        let stub = doc_stub(code, "Unused").unwrap();
+        assert!(stub.contains("Documentation.Name = \"Scale\""));
+        assert!(stub.contains("    Scale = (x as number) as number => x * Helper,\n    ScaleType = \n"));
+        assert!(stub.ends_with("Value.ReplaceType(Scale, ScaleType)\n"));
+        
+        assert!(matches!(doc_stub("let A = 1 in A", "F"), Err(DocStubError::NotAFunction)));
+        assert!(matches!(doc_stub("let A = in A", "F"), Err(DocStubError::Parse(_))));
+    }
+    
+    #[test]
+    fn test_bundle_keeps_comments() {
+        let members = [("A", "/* helper */ 1 //one\n// end of A\n"), ("type", "2")];
+        let section = bundle("Section1", &members, &Config::default()).unwrap();
+        assert_eq!(
+            section,
+            "section Section1;\n\n/* helper */\nshared A = 1; // one\n// end of A\n\nshared #\"type\" = 2;\n"
+        );
+        
+        let error = bundle("Section1", &[("Broken", "let A = in A")], &Config::default()).unwrap_err();
+        assert_eq!(error.member, "Broken");
+    }
+    
+    #[test]
+    fn test_split_section_round_trip() {
+        let members = [("A", "// first\nlet B = {\"a;b\", (1)} in B"), ("Total Cost", "[X = 1] // x")];
+        let section = bundle("Section1", &members, &Config::default()).unwrap();
+        let split = split_section(&section, &Config::default()).unwrap();
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0].name, "A");
+        assert_eq!(split[0].code, crate::format_default(members[0].1).unwrap());
+        assert_eq!(split[1].name, "Total Cost");
+        assert_eq!(split[1].code, crate::format_default(members[1].1).unwrap());
+    }
+    
+    #[test]
+    fn test_split_section_attributes_and_errors() {
+        let section = "section Connector;\n\n[DataSource.Kind = \"Connector\"]\nshared Connector.Contents = (url as text) => Web.Contents(url);\nHelper = 1;";
+        let split = split_section(section, &Config::default()).unwrap();
+        assert_eq!(split[0].name, "Connector.Contents");
+        assert_eq!(split[0].attributes.as_deref(), Some("[DataSource.Kind = \"Connector\"]"));
+        assert_eq!(split[1].name, "Helper");
+        assert_eq!(split[1].attributes, None);
+        
+        assert!(matches!(split_section("let A = 1 in A", &Config::default()), Err(SplitError::NotASection(_))));
+        assert!(matches!(
+            split_section("section S; A = let B = in B;", &Config::default()),
+            Err(SplitError::Member(e)) if e.member == "A"
+        ));
+    }
+    
+    #[test]
+    fn test_nothing_to_extract() {
+        let result = extract_parameters("let x = Text.Upper(\"a\") in x").unwrap();
+        assert!(result.parameters.is_empty());
+    }
+}
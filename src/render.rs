@@ -0,0 +1,309 @@
+//! Syntax-highlighted output for documentation and terminals
+//!
+//! [`to_html`] produces a self-contained, line-numbered `<pre>` block with
+//! inline styles, ready to embed in a documentation page without extra CSS;
+//! [`to_ansi`] produces the same highlighting with 24-bit terminal colors.
+//! [`to_numbered`] only numbers the lines, for a quick look in a terminal.
+//! Both highlight the code as given, so format it first to show the
+//! formatted form.
+
+use crate::alloc_prelude::*;
+use crate::lexer::Lexer;
+use crate::token::{Token, TokenKind};
+use core::fmt;
+use core::str::FromStr;
+
+/// Color scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Theme {
+    /// Dark text on a light background
+    #[default]
+    Light,
+    /// Light text on a dark background
+    Dark,
+}
+
+impl Theme {
+    fn background(self) -> &'static str {
+        match self {
+            Theme::Light => "#ffffff",
+            Theme::Dark => "#1e1e1e",
+        }
+    }
+    
+    fn foreground(self) -> &'static str {
+        match self {
+            Theme::Light => "#24292e",
+            Theme::Dark => "#d4d4d4",
+        }
+    }
+    
+    fn line_number(self) -> &'static str {
+        match self {
+            Theme::Light => "#959da5",
+            Theme::Dark => "#858585",
+        }
+    }
+    
+    fn color(self, class: Class) -> &'static str {
+        match (self, class) {
+            (Theme::Light, Class::Keyword) => "#d73a49",
+            (Theme::Light, Class::Function) => "#6f42c1",
+            (Theme::Light, Class::Text) => "#032f62",
+            (Theme::Light, Class::Number | Class::Constant) => "#005cc5",
+            (Theme::Light, Class::Comment) => "#6a737d",
+            (Theme::Light, Class::Invalid) => "#b31d28",
+            (Theme::Dark, Class::Keyword) => "#569cd6",
+            (Theme::Dark, Class::Function) => "#dcdcaa",
+            (Theme::Dark, Class::Text) => "#ce9178",
+            (Theme::Dark, Class::Number | Class::Constant) => "#b5cea8",
+            (Theme::Dark, Class::Comment) => "#6a9955",
+            (Theme::Dark, Class::Invalid) => "#f44747",
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            _ => Err(format!("Unknown theme '{}' (expected light or dark)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        })
+    }
+}
+
+/// What a token is highlighted as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Keyword,
+    /// Name of a called function: `Table.SelectRows(`
+    Function,
+    Text,
+    Number,
+    /// `true`, `false`, `null`, `#nan`, `#infinity`
+    Constant,
+    Comment,
+    Invalid,
+}
+
+/// Highlight `code` as an HTML `<pre>` block with line numbers.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::render::{to_html, Theme};
+///
+/// let html = to_html("let x = \"<a>\" in x\n", Theme::Light);
+/// assert!(html.starts_with("<pre class=\"pqm\""));
+/// assert!(html.contains("<span style=\"color:#032f62\">\"&lt;a&gt;\"</span>"));
+/// ```
+pub fn to_html(code: &str, theme: Theme) -> String {
+    let lines = highlight(code);
+    let width = lines.len().to_string().len();
+    let mut html = format!(
+        "<pre class=\"pqm\" style=\"background:{};color:{};padding:8px\"><code>",
+        theme.background(),
+        theme.foreground()
+    );
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            html.push('\n');
+        }
+        html.push_str(&format!(
+            "<span style=\"color:{};user-select:none\">{:>width$}  </span>",
+            theme.line_number(),
+            i + 1,
+            width = width
+        ));
+        for (text, class) in line {
+            let text = escape_html(text);
+            match class {
+                Some(class) => html.push_str(&format!("<span style=\"color:{}\">{}</span>", theme.color(*class), text)),
+                None => html.push_str(&text),
+            }
+        }
+    }
+    html.push_str("</code></pre>\n");
+    html
+}
+
+/// Highlight `code` with ANSI escape sequences (24-bit colors) and line
+/// numbers, for terminals.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::render::{to_ansi, Theme};
+///
+/// let ansi = to_ansi("1 // one\n", Theme::Dark);
+/// assert!(ansi.contains("\x1b[38;2;106;153;85m// one\x1b[0m"));
+/// ```
+pub fn to_ansi(code: &str, theme: Theme) -> String {
+    let lines = highlight(code);
+    let width = lines.len().to_string().len();
+    let mut out = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        out.push_str(&format!("{}{:>width$}{}  ", ansi_color(theme.line_number()), i + 1, RESET, width = width));
+        for (text, class) in line {
+            match class {
+                Some(class) => out.push_str(&format!("{}{}{}", ansi_color(theme.color(*class)), text, RESET)),
+                None => out.push_str(text),
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Number the lines of `code`, without highlighting. With `dim`, the
+/// numbers are dimmed with ANSI escape sequences.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::render::to_numbered;
+///
+/// assert_eq!(to_numbered("let\n    a = 1\nin\n    a\n", false), "1  let\n2      a = 1\n3  in\n4      a\n");
+/// assert!(to_numbered("1\n", true).starts_with("\x1b[2m1\x1b[0m  "));
+/// ```
+pub fn to_numbered(code: &str, dim: bool) -> String {
+    let lines: Vec<&str> = code.lines().collect();
+    let width = gutter_width(lines.len()) - 2;
+    let mut out = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        if dim {
+            out.push_str(&format!("{}{:>width$}{}  ", DIM, i + 1, RESET, width = width));
+        } else {
+            out.push_str(&format!("{:>width$}  ", i + 1, width = width));
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Columns the line numbers of [`to_numbered`] take before a text of
+/// `lines` lines, with the spaces after them
+pub fn gutter_width(lines: usize) -> usize {
+    lines.max(1).to_string().len() + 2
+}
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+
+/// Foreground escape sequence for a `#rrggbb` color
+fn ansi_color(hex: &str) -> String {
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0);
+    format!("\x1b[38;2;{};{};{}m", channel(1), channel(3), channel(5))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The lines of `code` (without line breaks), each as runs of text with
+/// their highlighting. Tokens spanning lines, such as block comments, are
+/// split at the line breaks.
+fn highlight(code: &str) -> Vec<Vec<(&str, Option<Class>)>> {
+    let tokens = Lexer::new(code).tokenize();
+    let mut lines = vec![Vec::new()];
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == TokenKind::Newline {
+            lines.push(Vec::new());
+            continue;
+        }
+        let class = classify(token, next_significant(&tokens[i + 1..]));
+        let text = token.span.slice(code);
+        for (j, part) in text.split('\n').enumerate() {
+            if j > 0 {
+                lines.push(Vec::new());
+            }
+            let part = part.strip_suffix('\r').unwrap_or(part);
+            if !part.is_empty() {
+                lines.last_mut().expect("at least one line").push((part, class));
+            }
+        }
+    }
+    // The line break ending the text does not start a line
+    if lines.len() > 1 && lines.last().is_some_and(Vec::is_empty) {
+        lines.pop();
+    }
+    lines
+}
+
+fn next_significant(tokens: &[Token]) -> Option<&TokenKind> {
+    tokens.iter().map(|t| &t.kind).find(|kind| !kind.is_trivia())
+}
+
+fn classify(token: &Token, next: Option<&TokenKind>) -> Option<Class> {
+    match &token.kind {
+        TokenKind::LineComment(_) | TokenKind::BlockComment(_) => Some(Class::Comment),
+        TokenKind::Text(_) => Some(Class::Text),
+        TokenKind::Number(_) => Some(Class::Number),
+        TokenKind::True | TokenKind::False | TokenKind::Null | TokenKind::HashNan | TokenKind::HashInfinity => {
+            Some(Class::Constant)
+        }
+        kind if kind.is_keyword() => Some(Class::Keyword),
+        TokenKind::HashBinary
+        | TokenKind::HashDate
+        | TokenKind::HashDatetime
+        | TokenKind::HashDatetimezone
+        | TokenKind::HashDuration
+        | TokenKind::HashSections
+        | TokenKind::HashShared
+        | TokenKind::HashTable
+        | TokenKind::HashTime
+        | TokenKind::HashUnknown(_) => Some(Class::Keyword),
+        TokenKind::Identifier(_) | TokenKind::QuotedIdentifier(_) if next == Some(&TokenKind::LeftParen) => {
+            Some(Class::Function)
+        }
+        TokenKind::Invalid(_) | TokenKind::UnexpectedChar(_) => Some(Class::Invalid),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_highlight() {
+        let lines = highlight("let\n    a = Text.Upper(\"x\") /* two\nlines */\nin\n    a\n");
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], [("let", Some(Class::Keyword))]);
+        assert!(lines[1].contains(&("Text.Upper", Some(Class::Function))));
+        assert!(lines[1].contains(&("\"x\"", Some(Class::Text))));
+        assert!(lines[1].contains(&("/* two", Some(Class::Comment))));
+        assert_eq!(lines[2], [("lines */", Some(Class::Comment))]);
+        assert_eq!(lines[4], [("    ", None), ("a", None)]);
+    }
+    
+    #[test]
+    fn test_line_numbers() {
+        let html = to_html(&"1,\n".repeat(10), Theme::Dark);
+        assert!(html.contains(">10  </span>"));
+        assert!(html.contains(">\u{20}1  </span>"));
+        assert_eq!(html.matches('\n').count(), 10);
+        
+        let ansi = to_ansi("1\r\n2\r\n", Theme::Light);
+        assert_eq!(ansi.lines().count(), 2);
+        assert!(!ansi.contains('\r'));
+        
+        let numbered = to_numbered(&"1,\r\n".repeat(10), true);
+        assert!(numbered.starts_with("\x1b[2m 1\x1b[0m  1,\n"));
+        assert!(numbered.ends_with("\x1b[2m10\x1b[0m  1,\n"));
+        assert_eq!(gutter_width(10), 4);
+    }
+}
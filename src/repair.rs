@@ -0,0 +1,90 @@
+//! Closing brackets missing at the end of truncated code
+//!
+//! Chat tools and size-limited text boxes cut long queries off, most often
+//! somewhere inside a call or a list near the end, so that only the
+//! closing `)`, `]` and `}` are missing. [`close_brackets`] adds them to
+//! code that is otherwise well nested, so that the formatter can parse it.
+
+use crate::alloc_prelude::*;
+use crate::lexer::Lexer;
+use crate::token::TokenKind;
+
+/// Code with the closing brackets it was missing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repaired {
+    pub code: String,
+    /// The brackets added at the end, innermost first
+    pub added: String,
+}
+
+/// `code` with the brackets left open at its end closed; `None` if
+/// nothing is open, or if the code is damaged in another way (a bracket
+/// closed by the wrong kind, an unterminated text literal or comment).
+/// After a line comment the brackets go on a line of their own.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::repair::close_brackets;
+///
+/// let repaired = close_brackets("Table.SelectRows(Source, each [Amount] > 1").unwrap();
+/// assert_eq!(repaired.code, "Table.SelectRows(Source, each [Amount] > 1)");
+/// assert_eq!(repaired.added, ")");
+/// assert_eq!(close_brackets("{[A = {1, 2 // two").unwrap().code, "{[A = {1, 2 // two\n}]}");
+/// assert_eq!(close_brackets("f(1, 2)"), None);
+/// assert_eq!(close_brackets("f(1, 2]"), None);
+/// ```
+pub fn close_brackets(code: &str) -> Option<Repaired> {
+    let mut open = Vec::new();
+    let mut after_line_comment = false;
+    for token in Lexer::new(code).tokenize() {
+        after_line_comment = match token.kind {
+            TokenKind::LineComment(_) => true,
+            TokenKind::Eof => after_line_comment,
+            _ => false,
+        };
+        match token.kind {
+            TokenKind::LeftParen => open.push(')'),
+            TokenKind::LeftBracket => open.push(']'),
+            TokenKind::LeftBrace => open.push('}'),
+            TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                let close = match token.kind {
+                    TokenKind::RightParen => ')',
+                    TokenKind::RightBracket => ']',
+                    _ => '}',
+                };
+                if open.pop() != Some(close) {
+                    return None;
+                }
+            }
+            TokenKind::Invalid(_) | TokenKind::UnexpectedChar(_) => return None,
+            _ => {}
+        }
+    }
+    if open.is_empty() {
+        return None;
+    }
+    let added: String = open.iter().rev().collect();
+    let separator = if after_line_comment { "\n" } else { "" };
+    Some(Repaired {
+        code: format!("{}{}{}", code, separator, added),
+        added,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_close_brackets() {
+        let repaired = close_brackets("Table.FromRecords({[A = 1], [A = 2\n").unwrap();
+        assert_eq!(repaired.added, "]})");
+        assert!(crate::validate(&repaired.code).is_ok());
+        
+        // Brackets in text literals and comments do not count
+        assert_eq!(close_brackets("f(\"(\", /* [ */ g(1").unwrap().added, "))");
+        assert_eq!(close_brackets("f(\"unterminated"), None);
+        assert_eq!(close_brackets("f(1))"), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Usage reports for `pqmfmt --report`
+//!
+//! A report accumulates statistics over many runs in a local JSON file:
+//! how many files were formatted and changed, how long the lines came out,
+//! and which rules laid constructs out over several lines. Teams can
+//! collect reports to see how the defaults behave on their queries before
+//! changing them. Nothing is sent anywhere.
+
+use crate::alloc_prelude::*;
+use crate::float;
+use crate::formatter::{FormatStats, FormatWarning};
+use crate::json::{self, JsonValue};
+use alloc::collections::BTreeMap;
+
+/// Statistics accumulated over formatting runs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageReport {
+    /// Invocations of the formatter that added to the report
+    pub runs: usize,
+    /// Inputs formatted successfully
+    pub files: usize,
+    /// Inputs whose formatted text differed from the original
+    pub files_changed: usize,
+    /// Lines of formatted output
+    pub lines: usize,
+    /// Sum of the formatted line lengths, in characters
+    pub total_line_length: usize,
+    pub longest_line: usize,
+    /// Lines longer than `max_line_length`
+    pub long_lines: usize,
+    /// Constructs laid out over several lines, by `construct/rule`
+    /// (see [`FormatStats::breaks`])
+    pub breaks: BTreeMap<String, usize>,
+}
+
+impl UsageReport {
+    /// Read a report written by [`UsageReport::to_json`]. Missing counts
+    /// are zero; `average_line_length` is derived and ignored.
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let value = json::parse(text)?;
+        if !matches!(value, JsonValue::Object(_)) {
+            return Err("report is not a JSON object".to_string());
+        }
+        let count = |key: &str| -> Result<usize, String> {
+            match value.get(key) {
+                None => Ok(0),
+                Some(JsonValue::Number(n)) if *n >= 0.0 && float::is_whole(*n) => Ok(*n as usize),
+                Some(_) => Err(format!("report field \"{}\" is not a count", key)),
+            }
+        };
+        let mut breaks = BTreeMap::new();
+        match value.get("breaks") {
+            None => {}
+            Some(object @ JsonValue::Object(_)) => {
+                for (key, value) in object.members() {
+                    match value {
+                        JsonValue::Number(n) if *n >= 0.0 && float::is_whole(*n) => {
+                            breaks.insert(key.clone(), *n as usize);
+                        }
+                        _ => return Err(format!("report break \"{}\" is not a count", key)),
+                    }
+                }
+            }
+            Some(_) => return Err("report field \"breaks\" is not an object".to_string()),
+        }
+        Ok(Self {
+            runs: count("runs")?,
+            files: count("files")?,
+            files_changed: count("files_changed")?,
+            lines: count("lines")?,
+            total_line_length: count("total_line_length")?,
+            longest_line: count("longest_line")?,
+            long_lines: count("long_lines")?,
+            breaks,
+        })
+    }
+    
+    /// Add one formatted input. `stats` should come from
+    /// [`format_with_stats`](crate::format_with_stats) so that the breaks
+    /// are counted.
+    pub fn add(&mut self, stats: &FormatStats, changed: bool) {
+        self.files += 1;
+        if changed {
+            self.files_changed += 1;
+        }
+        self.lines += stats.lines;
+        self.total_line_length += stats.total_length;
+        self.longest_line = self.longest_line.max(stats.longest_line);
+        self.long_lines += stats
+            .warnings
+            .iter()
+            .filter(|w| matches!(w, FormatWarning::LineTooLong { .. }))
+            .count();
+        for (key, count) in &stats.breaks {
+            *self.breaks.entry(key.clone()).or_insert(0) += count;
+        }
+    }
+    
+    /// Mean formatted line length in characters (0 before any lines)
+    pub fn average_line_length(&self) -> f64 {
+        if self.lines == 0 {
+            0.0
+        } else {
+            self.total_line_length as f64 / self.lines as f64
+        }
+    }
+    
+    /// The report as an indented JSON object, one member per line
+    pub fn to_json(&self) -> String {
+        let breaks: Vec<String> = self
+            .breaks
+            .iter()
+            .map(|(key, count)| format!("\n    {}: {}", json::quote(key), count))
+            .collect();
+        let breaks = if breaks.is_empty() {
+            "{}".to_string()
+        } else {
+            format!("{{{}\n  }}", breaks.join(","))
+        };
+        let members = [
+            ("runs", self.runs.to_string()),
+            ("files", self.files.to_string()),
+            ("files_changed", self.files_changed.to_string()),
+            ("lines", self.lines.to_string()),
+            ("total_line_length", self.total_line_length.to_string()),
+            ("average_line_length", format!("{:.1}", self.average_line_length())),
+            ("longest_line", self.longest_line.to_string()),
+            ("long_lines", self.long_lines.to_string()),
+            ("breaks", breaks),
+        ];
+        let body: Vec<String> = members
+            .iter()
+            .map(|(key, value)| format!("  {}: {}", json::quote(key), value))
+            .collect();
+        format!("{{\n{}\n}}\n", body.join(",\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format_with_stats, Config};
+    
+    #[test]
+    fn test_accumulate() {
+        let code = "let Source = Table.SelectRows(Data, each [Amount] > 100) in Source";
+        let (formatted, stats) = format_with_stats(code, Config::default()).unwrap();
+        assert_eq!(stats.breaks.get("let/always-expand"), Some(&1));
+        
+        let mut report = UsageReport { runs: 1, ..UsageReport::default() };
+        report.add(&stats, formatted != code);
+        report.add(&stats, false);
+        assert_eq!(report.files, 2);
+        assert_eq!(report.files_changed, 1);
+        assert_eq!(report.lines, stats.lines * 2);
+        assert_eq!(report.breaks.get("let/always-expand"), Some(&2));
+        assert_eq!(report.average_line_length(), stats.average_line_length());
+    }
+    
+    #[test]
+    fn test_json_round_trip() {
+        let mut report = UsageReport {
+            runs: 3,
+            files: 5,
+            files_changed: 2,
+            lines: 40,
+            total_line_length: 1000,
+            longest_line: 98,
+            long_lines: 1,
+            ..UsageReport::default()
+        };
+        report.breaks.insert("record/threshold".to_string(), 4);
+        report.breaks.insert("let/nested".to_string(), 2);
+        let text = report.to_json();
+        assert!(text.contains("\"average_line_length\": 25.0"));
+        assert_eq!(UsageReport::from_json(&text).unwrap(), report);
+        
+        assert_eq!(UsageReport::from_json("{}").unwrap(), UsageReport::default());
+        assert!(UsageReport::from_json("{\"runs\": -1}").is_err());
+        assert!(UsageReport::from_json("[]").is_err());
+    }
+}
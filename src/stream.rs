@@ -0,0 +1,277 @@
+//! Formatting very large documents a binding at a time
+//!
+//! Export tools sometimes generate a single `let` with hundreds of thousands
+//! of steps. Parsing such a document whole builds tokens and an AST many
+//! times the size of its text. [`split_let`] finds the bindings of a
+//! document that is one `let` with a single scan, and [`format_let`] parses
+//! and formats them one at a time, so the memory needed beyond the text and
+//! the output is that of the largest binding.
+//!
+//! The `let` is laid out over several lines, as any `let` longer than
+//! `max_line_length` is. Checks that need the whole document (lint,
+//! `strict`) are not run.
+
+use crate::alloc_prelude::*;
+use crate::ast::Trivia;
+use crate::config::Config;
+use crate::formatter::Formatter;
+use crate::lexer::Lexer;
+use crate::parser::{ParseError, Parser};
+use crate::text::LineIndex;
+use crate::token::TokenKind;
+use core::fmt;
+use core::ops::Range;
+
+/// Rough heap use of parsing and formatting text in one piece, in bytes per
+/// byte of text: tokens, AST, and output together (about 60 for generated
+/// step lists)
+pub const BYTES_PER_SOURCE_BYTE: usize = 64;
+
+/// Rough peak heap use of formatting `len` bytes of text in one piece
+pub fn estimated_memory(len: usize) -> usize {
+    len.saturating_mul(BYTES_PER_SOURCE_BYTE)
+}
+
+/// A document that is one `let`, by byte ranges of its text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LetParts {
+    /// Comments before `let`
+    pub start: Range<usize>,
+    /// Each binding with its comments, its comma, and the comments after the
+    /// comma on the same line
+    pub bindings: Vec<Range<usize>>,
+    /// The body after `in`, with the comments after it
+    pub body: Range<usize>,
+}
+
+impl LetParts {
+    /// Length of the largest binding (or body) in bytes
+    pub fn largest(&self) -> usize {
+        self.bindings
+            .iter()
+            .chain([&self.body])
+            .map(|range| range.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Why a document could not be formatted a binding at a time
+#[derive(Debug, Clone)]
+pub enum StreamError {
+    /// A binding or the body does not parse; spans are in the whole text
+    Parse(Vec<ParseError>),
+    /// The output could not be written
+    Write(fmt::Error),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Parse(errors) => {
+                let lines: Vec<String> = errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            StreamError::Write(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl core::error::Error for StreamError {}
+
+/// Find the bindings of a document that is one `let`, or `None` if it is
+/// something else. Nothing is parsed: a binding ends at a comma outside
+/// brackets and nested `let`s, and the body starts after the first `in`
+/// there.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::stream::split_let;
+///
+/// let code = "let a = {1, 2}, // two\n b = let c = 1, d = 2 in c in b";
+/// let parts = split_let(code).unwrap();
+/// let bindings: Vec<&str> = parts.bindings.iter().map(|r| &code[r.clone()]).collect();
+/// assert_eq!(bindings, [" a = {1, 2}, // two", "\n b = let c = 1, d = 2 in c "]);
+/// assert_eq!(&code[parts.body], " b");
+/// assert!(split_let("[a = 1]").is_none());
+/// ```
+pub fn split_let(code: &str) -> Option<LetParts> {
+    let mut lexer = Lexer::new(code);
+    let mut token = lexer.next_token();
+    while token.kind.is_trivia() {
+        token = lexer.next_token();
+    }
+    if token.kind != TokenKind::Let {
+        return None;
+    }
+    let start = 0..token.span.start;
+    
+    let mut bindings: Vec<Range<usize>> = Vec::new();
+    let mut binding_start = token.span.end;
+    let (mut depth, mut let_depth) = (0usize, 0usize);
+    // Comments after a comma, on its line, belong to the binding before it
+    let mut after_comma = false;
+    loop {
+        let token = lexer.next_token();
+        if after_comma {
+            match token.kind {
+                TokenKind::Whitespace(_) => continue,
+                TokenKind::BlockComment(_) | TokenKind::LineComment(_) => {
+                    if let Some(binding) = bindings.last_mut() {
+                        binding.end = token.span.end;
+                    }
+                    binding_start = token.span.end;
+                    // A line comment ends the line
+                    after_comma = matches!(token.kind, TokenKind::BlockComment(_));
+                    continue;
+                }
+                _ => after_comma = false,
+            }
+        }
+        match token.kind {
+            TokenKind::Eof => {
+                // No `in`: the last binding does not parse
+                bindings.push(binding_start..code.len());
+                return Some(LetParts {
+                    start,
+                    bindings,
+                    body: code.len()..code.len(),
+                });
+            }
+            TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => depth += 1,
+            TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                depth = depth.saturating_sub(1);
+            }
+            TokenKind::Let => let_depth += 1,
+            TokenKind::In if let_depth > 0 => let_depth -= 1,
+            TokenKind::In if depth == 0 => {
+                bindings.push(binding_start..token.span.start);
+                return Some(LetParts {
+                    start,
+                    bindings,
+                    body: token.span.end..code.len(),
+                });
+            }
+            TokenKind::Comma if depth == 0 && let_depth == 0 => {
+                bindings.push(binding_start..token.span.end);
+                binding_start = token.span.end;
+                after_comma = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Format a document that is one `let` one binding at a time, writing each
+/// to `out` as soon as it is formatted. `parts` comes from [`split_let`].
+/// The output is what [`format`](crate::format) gives for a `let` laid out
+/// over several lines.
+///
+/// A first-line `// pqmfmt:` directive is not applied; callers that honor it
+/// apply it to `config` first (see [`config::directive`](crate::config::directive)).
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::stream::{format_let, split_let};
+/// use pqm_formatter::{format, Config};
+///
+/// let code = "// steps\nlet a = 1, // one\n b = a+1 in b";
+/// let mut out = String::new();
+/// format_let(code, &split_let(code).unwrap(), &Config::default(), &mut out).unwrap();
+/// assert_eq!(out, format(code, Config::default()).unwrap());
+/// ```
+pub fn format_let<W: fmt::Write>(
+    code: &str,
+    parts: &LetParts,
+    config: &Config,
+    out: &mut W,
+) -> Result<(), StreamError> {
+    let options = crate::parse_options_for(config);
+    let mut formatter = Formatter::new(config.clone());
+    
+    let comments: Vec<Trivia> = Lexer::new(&code[parts.start.clone()])
+        .tokenize()
+        .into_iter()
+        .filter_map(|t| match t.kind {
+            TokenKind::LineComment(text) => Some(Trivia::LineComment(text)),
+            TokenKind::BlockComment(text) => Some(Trivia::BlockComment(text)),
+            _ => None,
+        })
+        .collect();
+    out.write_str(&formatter.format_let_start(&comments)).map_err(StreamError::Write)?;
+    
+    for (i, range) in parts.bindings.iter().enumerate() {
+        let text = &code[range.clone()];
+        let tokens = Lexer::new(text).tokenize();
+        let binding = Parser::with_options(tokens, options.clone())
+            .parse_let_binding()
+            .map_err(|errors| StreamError::Parse(relocate(errors, code, range.start)))?;
+        formatter.set_source(text);
+        let last = i == parts.bindings.len() - 1;
+        out.write_str(&formatter.format_let_binding(&binding, last)).map_err(StreamError::Write)?;
+    }
+    
+    let text = &code[parts.body.clone()];
+    let document = Parser::with_options(Lexer::new(text).tokenize(), options)
+        .parse()
+        .map_err(|errors| StreamError::Parse(relocate(errors, code, parts.body.start)))?;
+    formatter.set_source(text);
+    out.write_str(&formatter.format_let_end(&document)).map_err(StreamError::Write)?;
+    Ok(())
+}
+
+/// Move errors in the text starting at `offset` of `code` to their place in
+/// `code`
+fn relocate(mut errors: Vec<ParseError>, code: &str, offset: usize) -> Vec<ParseError> {
+    let index = LineIndex::new(code);
+    for error in &mut errors {
+        error.span = index.span(error.span.start + offset, error.span.end + offset);
+        for label in &mut error.labels {
+            label.span = index.span(label.span.start + offset, label.span.end + offset);
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format;
+    
+    fn format_streamed(code: &str, config: &Config) -> Result<String, StreamError> {
+        let mut out = String::new();
+        format_let(code, &split_let(code).unwrap(), config, &mut out)?;
+        Ok(out)
+    }
+    
+    #[test]
+    fn test_same_as_format() {
+        let code = "/* query */ let\n  // source\n  Source = Csv.Document(File.Contents(\"a.csv\"), [Delimiter = \",\"]), /* kept */\n  Typed = let t = Source in t, // nested\n  Rows = {1, 2}\nin\n  Rows // done\n";
+        for config in [Config::default(), Config::expanded()] {
+            assert_eq!(format_streamed(code, &config).unwrap(), format(code, config.clone()).unwrap());
+        }
+    }
+    
+    #[test]
+    fn test_errors_are_relocated() {
+        let code = "let\n    a = 1,\n    b = (2 +,\n    c = 3\nin\n    c";
+        let StreamError::Parse(errors) = format_streamed(code, &Config::default()).unwrap_err() else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(errors[0].span.line, 3);
+        assert_eq!(errors[0].span.column, 13);
+        assert_eq!(&code[errors[0].span.start..errors[0].span.start + 1], ",");
+        
+        // Columns count characters, not bytes
+        let code = "let Caf\u{e9} = 1, b = (2 +, c = 3 in c";
+        let StreamError::Parse(errors) = format_streamed(code, &Config::default()).unwrap_err() else {
+            panic!("expected a parse error");
+        };
+        assert_eq!((errors[0].span.line, errors[0].span.column), (1, 23));
+    }
+}
@@ -0,0 +1,135 @@
+//! Checks for constructs the parser accepts but the Power Query engine rejects
+
+use crate::alloc_prelude::*;
+use crate::ast::{Document, Expr, ExprKind, Identifier, TypeAnnotation, TypeKind};
+use crate::lexer::is_keyword;
+use crate::parser::ParseError;
+
+/// Report constructs in a parsed document that are not valid M.
+///
+/// The parser is forgiving so that real-world queries format even when they
+/// bend the grammar. This pass flags what the engine itself would refuse:
+///
+/// - reserved words used as unquoted field names (`[type]`, `[each = 1]`);
+///   they must be written `#"type"`
+/// - `@` references outside the initializer of a let binding or record
+///   field, where there is no inclusive scope to refer to
+/// - unknown `#keyword`s
+pub fn check(document: &Document) -> Vec<ParseError> {
+    let mut errors = Vec::new();
+    check_expr(&document.expression, false, &mut errors);
+    errors
+}
+
+fn check_expr(expr: &Expr, in_initializer: bool, errors: &mut Vec<ParseError>) {
+    match &expr.kind {
+        ExprKind::InclusiveIdentifier(ident) if !in_initializer => {
+            errors.push(ParseError::new(
+                format!("Inclusive reference @{} is only allowed inside a let or record initializer", ident.name),
+                expr.span,
+            ));
+        }
+        ExprKind::HashUnknown(name) => {
+            errors.push(ParseError::new(format!("Unknown keyword #{}", name), expr.span));
+        }
+        ExprKind::Let(l) => {
+            for binding in &l.bindings {
+                check_expr(&binding.value, true, errors);
+            }
+            check_expr(&l.body, in_initializer, errors);
+            return;
+        }
+        ExprKind::Record(r) => {
+            for field in &r.fields {
+                check_field_name(&field.name, errors);
+                check_expr(&field.value, true, errors);
+            }
+            return;
+        }
+        ExprKind::FieldAccess(access) => check_field_name(&access.field, errors),
+        ExprKind::FieldProjection(projection) => {
+            for field in &projection.fields {
+                check_field_name(field, errors);
+            }
+        }
+        ExprKind::Type(type_expr) => check_type(&type_expr.type_annotation, in_initializer, errors),
+        ExprKind::Function(function) => {
+            for parameter in &function.parameters {
+                if let Some(type_annotation) = &parameter.type_annotation {
+                    check_type(type_annotation, in_initializer, errors);
+                }
+            }
+        }
+        _ => {}
+    }
+    for child in expr.children() {
+        check_expr(child, in_initializer, errors);
+    }
+}
+
+fn check_type(type_annotation: &TypeAnnotation, in_initializer: bool, errors: &mut Vec<ParseError>) {
+    match &type_annotation.kind {
+        TypeKind::Record(fields) | TypeKind::Table(fields) => {
+            for field in fields {
+                check_field_name(&field.name, errors);
+                check_type(&field.type_annotation, in_initializer, errors);
+            }
+        }
+        TypeKind::List(Some(inner)) | TypeKind::Nullable(inner) | TypeKind::Parenthesized(inner) => check_type(inner, in_initializer, errors),
+        TypeKind::Function(parameters, return_type) => {
+            for parameter in parameters {
+                if let Some(type_annotation) = &parameter.type_annotation {
+                    check_type(type_annotation, in_initializer, errors);
+                }
+            }
+            check_type(return_type, in_initializer, errors);
+        }
+        TypeKind::Expr(expr) => check_expr(expr, in_initializer, errors),
+        _ => {}
+    }
+}
+
+fn check_field_name(name: &Identifier, errors: &mut Vec<ParseError>) {
+    if !name.quoted && is_keyword(&name.name) {
+        errors.push(ParseError::new(
+            format!("Keyword '{}' must be quoted as #\"{}\" when used as a field name", name.name, name.name),
+            name.span,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, Parser};
+    
+    fn strict_errors(code: &str) -> Vec<String> {
+        let document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        check(&document).into_iter().map(|e| e.message).collect()
+    }
+    
+    #[test]
+    fn test_keyword_field_names() {
+        assert_eq!(
+            strict_errors("[Name = 1, type = 2, #\"each\" = 3]"),
+            ["Keyword 'type' must be quoted as #\"type\" when used as a field name"]
+        );
+        assert_eq!(strict_errors("[A = 1, B = [C = 2, each = 3]]").len(), 1);
+        assert!(strict_errors("Source[#\"type\"]").is_empty());
+    }
+    
+    #[test]
+    fn test_inclusive_references() {
+        assert!(strict_errors("let f = (n) => if n = 0 then 1 else n * @f(n - 1) in f(5)").is_empty());
+        assert!(strict_errors("[A = 1, B = @A]").is_empty());
+        assert_eq!(
+            strict_errors("let a = 1 in @a"),
+            ["Inclusive reference @a is only allowed inside a let or record initializer"]
+        );
+    }
+    
+    #[test]
+    fn test_unknown_hash_keyword() {
+        assert_eq!(strict_errors("#customthing"), ["Unknown keyword #customthing"]);
+    }
+}
@@ -0,0 +1,242 @@
+//! Positions in source text
+//!
+//! Spans hold byte offsets, and the line and column where they start, both
+//! counted from 1 with the column in characters. Editors count differently:
+//! the Language Server Protocol counts lines from 0 and columns in UTF-16
+//! code units. [`LineIndex`] finds the line starts of a text once and
+//! converts between the three.
+//!
+//! [`decode_lossy`] reads bytes that should be UTF-8 but are not quite, as
+//! left by tools that cut multi-byte characters or mix encodings.
+
+use crate::alloc_prelude::*;
+use crate::diagnostic::Diagnostic;
+use crate::token::Span;
+
+/// Code of the diagnostics of [`decode_lossy`]
+pub const INVALID_UTF8_CODE: &str = "invalid-utf8";
+
+/// A position as the Language Server Protocol counts it: the line and the
+/// UTF-16 code units before the position on its line, both from 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Utf16Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+/// The line starts of a text, for converting between byte offsets, lines
+/// and columns, and UTF-16 positions.
+///
+/// Lines end at `\n`, as in the lexer, so a `\r` before it is the last
+/// character of its line; only [`from_utf16`](Self::from_utf16), which
+/// follows the Language Server Protocol, treats `\r\n` as the line break.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::text::{LineIndex, Utf16Position};
+///
+/// let code = "let\n    \u{1F600} = \"é\"\nin 1";
+/// let index = LineIndex::new(code);
+/// let offset = code.find('=').unwrap();
+/// assert_eq!(index.line_col(offset), (2, 7));
+/// assert_eq!(index.to_utf16(offset), Utf16Position { line: 1, character: 7 });
+/// assert_eq!(index.from_utf16(Utf16Position { line: 1, character: 7 }), Some(offset));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LineIndex<'a> {
+    text: &'a str,
+    /// Byte offset where each line starts; the first is 0
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(text: &'a str) -> Self {
+        let line_starts = core::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { text, line_starts }
+    }
+    
+    /// Number of lines; a text ending with `\n` has an empty last line
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+    
+    /// Line and character column of `offset`, both from 1, as in [`Span`].
+    /// Offsets past the end are taken as the end, and offsets inside a
+    /// character as the start of that character.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let (line, start, offset) = self.locate(offset);
+        (line + 1, self.text[start..offset].chars().count() + 1)
+    }
+    
+    /// Byte offset of the line and character column `line`, `column` (both
+    /// from 1), or `None` if there is no such line or the line is shorter.
+    /// The column after the last character of a line is its end.
+    pub fn offset(&self, line: usize, column: usize) -> Option<usize> {
+        let text = self.line_text(line.checked_sub(1)?)?;
+        let start = self.line_starts[line - 1];
+        match column.checked_sub(1)? {
+            0 => Some(start),
+            n => text.char_indices().nth(n - 1).map(|(i, c)| start + i + c.len_utf8()),
+        }
+    }
+    
+    /// The position of `offset` as the Language Server Protocol counts it
+    pub fn to_utf16(&self, offset: usize) -> Utf16Position {
+        let (line, start, offset) = self.locate(offset);
+        Utf16Position {
+            line,
+            character: self.text[start..offset].encode_utf16().count(),
+        }
+    }
+    
+    /// Byte offset of an LSP position, or `None` if there is no such line.
+    /// As the protocol asks, characters past the end of the line mean its
+    /// end; a position between the two halves of a surrogate pair is taken
+    /// as the start of the character.
+    pub fn from_utf16(&self, position: Utf16Position) -> Option<usize> {
+        let text = self.line_text(position.line)?;
+        let text = text.strip_suffix('\r').unwrap_or(text);
+        let start = self.line_starts[position.line];
+        let mut units = 0;
+        for (i, c) in text.char_indices() {
+            units += c.len_utf16();
+            if units > position.character {
+                return Some(start + i);
+            }
+        }
+        Some(start + text.len())
+    }
+    
+    /// A span from `start` to `end` with the line and column of `start`
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        let (line, column) = self.line_col(start);
+        Span::new(start, end, line, column)
+    }
+    
+    /// The line of `offset` (from 0), the offset where that line starts, and
+    /// `offset` moved into the text and onto a character boundary
+    fn locate(&self, offset: usize) -> (usize, usize, usize) {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        (line, self.line_starts[line], offset)
+    }
+    
+    /// Text of line `line` (from 0) without its line break
+    fn line_text(&self, line: usize) -> Option<&'a str> {
+        let start = *self.line_starts.get(line)?;
+        let end = self.line_starts.get(line + 1).map_or(self.text.len(), |next| next - 1);
+        Some(&self.text[start..end])
+    }
+}
+
+/// `bytes` as text, with each invalid UTF-8 sequence replaced by U+FFFD
+/// as in [`String::from_utf8_lossy`], and a warning with the code
+/// [`INVALID_UTF8_CODE`] for each run of replacements. The messages give
+/// the byte offsets in `bytes`; the spans are those of the replacements in
+/// the text.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::text::decode_lossy;
+///
+/// let (text, warnings) = decode_lossy(b"let x = 1 in\n\xE9x");
+/// assert_eq!(text, "let x = 1 in\n\u{FFFD}x");
+/// assert_eq!(warnings[0].message, "Invalid UTF-8 at bytes 13..14, replaced with U+FFFD");
+/// assert_eq!((warnings[0].span.line, warnings[0].span.column), (2, 1));
+/// ```
+pub fn decode_lossy(bytes: &[u8]) -> (String, Vec<Diagnostic>) {
+    let mut text = String::with_capacity(bytes.len());
+    // Runs of invalid bytes: their offsets in `bytes` and in `text`
+    let mut runs: Vec<((usize, usize), (usize, usize))> = Vec::new();
+    let mut offset = 0;
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        offset += chunk.valid().len();
+        let invalid = chunk.invalid().len();
+        if invalid == 0 {
+            continue;
+        }
+        let start = text.len();
+        text.push(char::REPLACEMENT_CHARACTER);
+        match runs.last_mut() {
+            Some((bytes, chars)) if bytes.1 == offset => {
+                bytes.1 += invalid;
+                chars.1 = text.len();
+            }
+            _ => runs.push(((offset, offset + invalid), (start, text.len()))),
+        }
+        offset += invalid;
+    }
+    
+    let index = LineIndex::new(&text);
+    let diagnostics = runs
+        .iter()
+        .map(|((start, end), (from, to))| {
+            let message = format!("Invalid UTF-8 at bytes {}..{}, replaced with U+FFFD", start, end);
+            Diagnostic::warning(INVALID_UTF8_CODE, message, index.span(*from, *to))
+        })
+        .collect();
+    (text, diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lexer;
+    
+    #[test]
+    fn test_line_col_matches_lexer() {
+        let code = "let\r\n    Caf\u{e9} = \"\u{1F600}\",\n    B = 1\nin\n    B";
+        let index = LineIndex::new(code);
+        for token in Lexer::new(code).tokenize() {
+            assert_eq!(index.line_col(token.span.start), (token.span.line, token.span.column));
+            assert_eq!(index.offset(token.span.line, token.span.column), Some(token.span.start));
+        }
+        assert_eq!(index.line_count(), 5);
+        assert_eq!(index.line_col(code.len() + 10), (5, 6));
+        assert_eq!(index.offset(1, 5), Some(4));
+        assert_eq!(index.offset(1, 6), None);
+        assert_eq!(index.offset(6, 1), None);
+    }
+    
+    #[test]
+    fn test_utf16() {
+        let code = "a\u{1F600}b\r\nc";
+        let index = LineIndex::new(code);
+        assert_eq!(index.to_utf16(5), Utf16Position { line: 0, character: 3 });
+        assert_eq!(index.to_utf16(3), Utf16Position { line: 0, character: 1 });
+        assert_eq!(index.to_utf16(8), Utf16Position { line: 1, character: 0 });
+        assert_eq!(index.from_utf16(Utf16Position { line: 0, character: 3 }), Some(5));
+        // Inside the surrogate pair, and past the end of the line
+        assert_eq!(index.from_utf16(Utf16Position { line: 0, character: 2 }), Some(1));
+        assert_eq!(index.from_utf16(Utf16Position { line: 0, character: 9 }), Some(6));
+        assert_eq!(index.from_utf16(Utf16Position { line: 2, character: 0 }), None);
+    }
+    
+    #[test]
+    fn test_decode_lossy() {
+        let (text, warnings) = decode_lossy("a\u{e9}b".as_bytes());
+        assert_eq!(text, "a\u{e9}b");
+        assert!(warnings.is_empty());
+        
+        // Adjacent invalid sequences are one run; a cut character at the end
+        let (text, warnings) = decode_lossy(b"\xFF\xFEab\nc\xE3\x81");
+        assert_eq!(text, "\u{FFFD}\u{FFFD}ab\nc\u{FFFD}");
+        let found: Vec<_> = warnings.iter().map(|w| (w.message.as_str(), w.span.line, w.span.column)).collect();
+        assert_eq!(
+            found,
+            [
+                ("Invalid UTF-8 at bytes 0..2, replaced with U+FFFD", 1, 1),
+                ("Invalid UTF-8 at bytes 6..8, replaced with U+FFFD", 2, 2),
+            ]
+        );
+        assert_eq!(warnings[0].span.end, 6);
+    }
+}
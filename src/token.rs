@@ -1,6 +1,7 @@
 //! Token definitions for Power Query M language
 
-use std::fmt;
+use crate::alloc_prelude::*;
+use core::fmt;
 
 /// Source location information
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -24,6 +25,12 @@ impl Span {
             column: self.column,
         }
     }
+    
+    /// The text this span covers in `source`, or `""` if it lies outside
+    /// (e.g. a span of a node created by a rewrite)
+    pub fn slice<'a>(&self, source: &'a str) -> &'a str {
+        source.get(self.start..self.end).unwrap_or("")
+    }
 }
 
 impl Default for Span {
@@ -45,14 +52,37 @@ impl Token {
     }
 }
 
+/// A number literal: its value, and its text as written (`0xFF`, `1.50`,
+/// `1E3`), which the formatter keeps
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumberLiteral {
+    pub value: f64,
+    /// Empty for numbers that were not written in code, which are
+    /// formatted from their value
+    pub text: String,
+}
+
+impl NumberLiteral {
+    pub fn new(value: f64, text: impl Into<String>) -> Self {
+        Self { value, text: text.into() }
+    }
+}
+
+impl From<f64> for NumberLiteral {
+    fn from(value: f64) -> Self {
+        Self::new(value, String::new())
+    }
+}
+
 /// Token kinds for Power Query M
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum TokenKind {
     // Literals
     Null,
     True,
     False,
-    Number(f64),
+    Number(NumberLiteral),
     Text(String),
     
     // Identifiers
@@ -91,6 +121,7 @@ pub enum TokenKind {
     HashShared,      // #shared
     HashTable,       // #table
     HashTime,        // #time
+    HashUnknown(String), // #name not (yet) known to the formatter
     
     // Operators
     Plus,            // +
@@ -134,7 +165,10 @@ pub enum TokenKind {
     // End of file
     Eof,
     
-    // Error token
+    // Error tokens: a character M does not use, or a description of what
+    // is wrong with the malformed construct the span covers from its start
+    // (for an unterminated one, the rest of the input)
+    UnexpectedChar(char),
     Invalid(String),
 }
 
@@ -167,6 +201,25 @@ impl TokenKind {
         )
     }
     
+    /// Check if this token is a hash keyword such as `#table`
+    pub fn is_hash_keyword(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::HashBinary
+                | TokenKind::HashDate
+                | TokenKind::HashDatetime
+                | TokenKind::HashDatetimezone
+                | TokenKind::HashDuration
+                | TokenKind::HashInfinity
+                | TokenKind::HashNan
+                | TokenKind::HashSections
+                | TokenKind::HashShared
+                | TokenKind::HashTable
+                | TokenKind::HashTime
+                | TokenKind::HashUnknown(_)
+        )
+    }
+    
     /// Check if this token is a binary operator
     pub fn is_binary_operator(&self) -> bool {
         matches!(
@@ -227,7 +280,8 @@ impl fmt::Display for TokenKind {
             TokenKind::Null => write!(f, "null"),
             TokenKind::True => write!(f, "true"),
             TokenKind::False => write!(f, "false"),
-            TokenKind::Number(n) => write!(f, "{}", n),
+            TokenKind::Number(n) if n.text.is_empty() => write!(f, "{}", n.value),
+            TokenKind::Number(n) => write!(f, "{}", n.text),
             TokenKind::Text(s) => write!(f, "\"{}\"", s),
             TokenKind::Identifier(s) => write!(f, "{}", s),
             TokenKind::QuotedIdentifier(s) => write!(f, "#\"{}\"", s),
@@ -260,6 +314,7 @@ impl fmt::Display for TokenKind {
             TokenKind::HashShared => write!(f, "#shared"),
             TokenKind::HashTable => write!(f, "#table"),
             TokenKind::HashTime => write!(f, "#time"),
+            TokenKind::HashUnknown(s) => write!(f, "#{}", s),
             TokenKind::Plus => write!(f, "+"),
             TokenKind::Minus => write!(f, "-"),
             TokenKind::Star => write!(f, "*"),
@@ -292,6 +347,7 @@ impl fmt::Display for TokenKind {
             TokenKind::Whitespace(s) => write!(f, "{}", s),
             TokenKind::Newline => writeln!(f),
             TokenKind::Eof => write!(f, ""),
+            TokenKind::UnexpectedChar(c) => write!(f, "{}", c),
             TokenKind::Invalid(s) => write!(f, "{}", s),
         }
     }
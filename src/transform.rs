@@ -0,0 +1,329 @@
+//! AST rewrites applied before formatting
+
+use crate::alloc_prelude::*;
+use crate::analysis::{connector_argument_role, is_connector_function};
+use crate::ast::{Document, Expr, ExprKind, FunctionExpr, Identifier, Parameter};
+use crate::config::EachStyle;
+use crate::token::Span;
+
+/// Parameter name of the lambdas written for `each` expressions
+const ROW_PARAMETER: &str = "row";
+
+/// Replace text literal arguments of connector functions with placeholders.
+///
+/// `Sql.Database("prod-sql01", "Sales")` becomes
+/// `Sql.Database("<server>", "<database>")`, so formatted queries can be
+/// shared without leaking connection details. Literals combined with `&`
+/// and those in records and lists passed to a connector, such as headers
+/// in the options of `Web.Contents`, are replaced too. Returns the number
+/// of literals replaced.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{transform, Config, Formatter, Lexer, Parser};
+///
+/// let code = r#"Web.Contents("https://intranet/api")"#;
+/// let mut document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+/// assert_eq!(transform::redact_sources(&mut document), 1);
+/// let formatted = Formatter::new(Config::default()).format(&document);
+/// assert_eq!(formatted, "Web.Contents(\"<url>\")\n");
+/// ```
+pub fn redact_sources(document: &mut Document) -> usize {
+    redact_expr(&mut document.expression)
+}
+
+fn redact_expr(expr: &mut Expr) -> usize {
+    let mut count = 0;
+    if let ExprKind::FunctionCall(call) = &mut expr.kind {
+        if let ExprKind::Identifier(name) = &call.function.kind {
+            if is_connector_function(name) {
+                let name = name.clone();
+                for (i, arg) in call.arguments.iter_mut().enumerate() {
+                    count += redact_argument(arg, connector_argument_role(&name, i).unwrap_or("redacted"));
+                }
+            }
+        }
+    }
+    for child in expr.children_mut() {
+        count += redact_expr(child);
+    }
+    count
+}
+
+/// Replace the text literals of a connector argument, through `&`,
+/// parentheses, records, and lists
+fn redact_argument(arg: &mut Expr, role: &str) -> usize {
+    match &mut arg.kind {
+        ExprKind::Text(text) => {
+            *text = format!("<{}>", role);
+            1
+        }
+        ExprKind::Parenthesized(inner) => redact_argument(inner, role),
+        ExprKind::Binary(binary) => redact_argument(&mut binary.left, role) + redact_argument(&mut binary.right, role),
+        ExprKind::Record(record) => record
+            .fields
+            .iter_mut()
+            .map(|field| redact_argument(&mut field.value, "redacted"))
+            .sum(),
+        ExprKind::List(list) => list.items.iter_mut().map(|item| redact_argument(item, "redacted")).sum(),
+        _ => 0,
+    }
+}
+
+/// Rewrite functions of one row to the form `style` prefers.
+///
+/// With [`EachStyle::Each`], `(row) => row[Amount] > 0` becomes
+/// `each _[Amount] > 0`; with [`EachStyle::Lambda`], the reverse, naming
+/// the parameter `row`. Only functions of one untyped parameter whose body
+/// reads fields of it (`x[A]`, `x[[A], [B]]`) and uses it no other way are
+/// rewritten, and only where the new name cannot refer to anything else: a
+/// lambda whose body uses an outer `_` keeps its form, and so does an
+/// `each` whose body mentions `row`. Returns the number of functions
+/// rewritten.
+///
+/// # Example
+///
+/// ```rust
+/// use pqm_formatter::{transform, Config, EachStyle, Formatter, Lexer, Parser};
+///
+/// let code = "Table.SelectRows(Source, (r) => r[Amount] > 0 and r[Region] = \"EU\")";
+/// let mut document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+/// assert_eq!(transform::prefer_each(&mut document, EachStyle::Each), 1);
+/// let formatted = Formatter::new(Config::compact()).format(&document);
+/// assert_eq!(formatted, "Table.SelectRows(Source, each _[Amount] > 0 and _[Region] = \"EU\")\n");
+/// ```
+pub fn prefer_each(document: &mut Document, style: EachStyle) -> usize {
+    rewrite_functions(&mut document.expression, style)
+}
+
+pub(crate) fn rewrite_functions(expr: &mut Expr, style: EachStyle) -> usize {
+    if expr.verbatim {
+        return 0;
+    }
+    let mut count = 0;
+    let rewritten = match (&expr.kind, style) {
+        (ExprKind::Function(function), EachStyle::Each) => each_body(function).map(|body| ExprKind::Each(Box::new(body))),
+        (ExprKind::Each(body), EachStyle::Lambda) => {
+            lambda(body, expr.span).map(|function| ExprKind::Function(Box::new(function)))
+        }
+        _ => None,
+    };
+    if let Some(kind) = rewritten {
+        expr.kind = kind;
+        count += 1;
+    }
+    for child in expr.children_mut() {
+        count += rewrite_functions(child, style);
+    }
+    count
+}
+
+/// Body of the `each` expression equivalent to `function`
+fn each_body(function: &FunctionExpr) -> Option<Expr> {
+    let [parameter] = function.parameters.as_slice() else {
+        return None;
+    };
+    if parameter.optional || parameter.type_annotation.is_some() || function.return_type.is_some() {
+        return None;
+    }
+    let name = parameter.name.name.as_str();
+    if parameter_accesses(&function.body, name, false)? == 0 {
+        return None;
+    }
+    let mut body = function.body.clone();
+    replace_row(&mut body, &|target| is_reference(target, name), &|span| {
+        Expr::new(ExprKind::Underscore, span)
+    });
+    Some(body)
+}
+
+/// Lambda equivalent to `each body`
+fn lambda(body: &Expr, span: Span) -> Option<FunctionExpr> {
+    if underscore_accesses(body)? == 0 || mentions(body, ROW_PARAMETER) {
+        return None;
+    }
+    let mut body = body.clone();
+    replace_row(&mut body, &|target| matches!(target.kind, ExprKind::Underscore), &|span| {
+        Expr::new(ExprKind::Identifier(ROW_PARAMETER.to_string()), span)
+    });
+    Some(FunctionExpr {
+        parameters: vec![Parameter {
+            name: Identifier::new(ROW_PARAMETER.to_string(), false, span),
+            type_annotation: None,
+            optional: false,
+            span,
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
+        }],
+        return_type: None,
+        body,
+    })
+}
+
+/// Number of field accesses on the parameter `name` in `expr`; `None` if
+/// it is used another way, bound again, or read inside a nested `each`
+/// (whose `_` would hide it), or if `expr` uses an outer `_`
+fn parameter_accesses(expr: &Expr, name: &str, in_each: bool) -> Option<usize> {
+    match &expr.kind {
+        ExprKind::FieldAccess(access) if is_reference(&access.expr, name) => return (!in_each).then_some(1),
+        ExprKind::FieldProjection(projection) if is_reference(&projection.expr, name) => {
+            return (!in_each).then_some(1);
+        }
+        ExprKind::Underscore if !in_each => return None,
+        _ if is_reference(expr, name) || binds(expr, name) => return None,
+        _ => {}
+    }
+    let in_each = in_each || matches!(expr.kind, ExprKind::Each(_)) || binds(expr, "_");
+    expr.children().into_iter().map(|child| parameter_accesses(child, name, in_each)).sum()
+}
+
+/// Number of field accesses on `_` in `expr`, outside nested functions
+/// with their own `_`; `None` if `_` is used another way
+fn underscore_accesses(expr: &Expr) -> Option<usize> {
+    match &expr.kind {
+        ExprKind::FieldAccess(access) if matches!(access.expr.kind, ExprKind::Underscore) => Some(1),
+        ExprKind::FieldProjection(projection) if matches!(projection.expr.kind, ExprKind::Underscore) => Some(1),
+        ExprKind::Underscore => None,
+        ExprKind::Each(_) => Some(0),
+        _ if binds(expr, "_") => Some(0),
+        _ => expr.children().into_iter().map(underscore_accesses).sum(),
+    }
+}
+
+/// Replace the targets of the field accesses on the row with `new`,
+/// outside nested functions with their own `_`
+fn replace_row(expr: &mut Expr, is_row: &dyn Fn(&Expr) -> bool, new: &dyn Fn(Span) -> Expr) {
+    if matches!(expr.kind, ExprKind::Each(_)) || binds(expr, "_") {
+        return;
+    }
+    let target = match &mut expr.kind {
+        ExprKind::FieldAccess(access) => Some(&mut access.expr),
+        ExprKind::FieldProjection(projection) => Some(&mut projection.expr),
+        _ => None,
+    };
+    if let Some(target) = target {
+        if is_row(target) {
+            let mut replacement = new(target.span);
+            replacement.leading_trivia = core::mem::take(&mut target.leading_trivia);
+            replacement.trailing_trivia = core::mem::take(&mut target.trailing_trivia);
+            *target = replacement;
+            return;
+        }
+    }
+    for child in expr.children_mut() {
+        replace_row(child, is_row, new);
+    }
+}
+
+fn is_reference(expr: &Expr, name: &str) -> bool {
+    match &expr.kind {
+        ExprKind::Identifier(n) | ExprKind::QuotedIdentifier(n) => n == name,
+        ExprKind::InclusiveIdentifier(ident) => ident.name == name,
+        _ => false,
+    }
+}
+
+/// Whether `expr` introduces `name` into the scope of its children
+fn binds(expr: &Expr, name: &str) -> bool {
+    match &expr.kind {
+        ExprKind::Let(l) => l.bindings.iter().any(|b| b.name.name == name),
+        ExprKind::Record(r) => r.fields.iter().any(|f| f.name.name == name),
+        ExprKind::Function(f) => f.parameters.iter().any(|p| p.name.name == name),
+        _ => false,
+    }
+}
+
+/// Whether `name` is referred to or bound anywhere in `expr`
+fn mentions(expr: &Expr, name: &str) -> bool {
+    is_reference(expr, name) || binds(expr, name) || expr.children().into_iter().any(|child| mentions(child, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Formatter, Lexer, Parser};
+    
+    fn redact(code: &str) -> (usize, String) {
+        let mut document = Parser::new(Lexer::new(code).tokenize()).parse().unwrap();
+        let count = redact_sources(&mut document);
+        (count, Formatter::new(Config::compact()).format(&document))
+    }
+    
+    #[test]
+    fn test_redact_connector_literals() {
+        let (count, formatted) = redact(
+            r#"let Source = Sql.Database("prod-sql01", "Sales", [Query = "select 1"]), Csv = Csv.Document(File.Contents("C:\data\a.csv"), [Delimiter = ","]) in Source"#,
+        );
+        assert_eq!(count, 5);
+        assert_eq!(
+            formatted,
+            "let Source = Sql.Database(\"<server>\", \"<database>\", [Query = \"<redacted>\"]), Csv = Csv.Document(File.Contents(\"<path>\"), [Delimiter = \"<redacted>\"]) in Source\n"
+        );
+    }
+    
+    #[test]
+    fn test_redact_options_and_concatenations() {
+        let (count, formatted) = redact(
+            r#"Web.Contents(u, [Headers = [Authorization = "Bearer abc"], RelativePath = "secret/x", Query = [ids = {"1", "2"}]])"#,
+        );
+        assert_eq!(count, 4);
+        assert_eq!(
+            formatted,
+            "Web.Contents(u, [Headers = [Authorization = \"<redacted>\"], RelativePath = \"<redacted>\", Query = [ids = {\"<redacted>\", \"<redacted>\"}]])\n"
+        );
+        
+        let (count, formatted) = redact(r#"File.Contents("C:\x" & ("y" & ".csv"))"#);
+        assert_eq!(count, 3);
+        assert_eq!(formatted, "File.Contents(\"<path>\" & (\"<path>\" & \"<path>\"))\n");
+    }
+    
+    #[test]
+    fn test_other_calls_untouched() {
+        let (count, formatted) = redact(r#"Text.Upper("secret")"#);
+        assert_eq!(count, 0);
+        assert_eq!(formatted, "Text.Upper(\"secret\")\n");
+    }    
+    fn prefer(code: &str, style: EachStyle) -> String {
+        let config = Config {
+            prefer_each: style,
+            ..Config::compact()
+        };
+        crate::format(code, config).unwrap()
+    }
+    
+    #[test]
+    fn test_prefer_each() {
+        assert_eq!(
+            prefer("List.Select(L, (x) => x[Keep] and List.Contains(x[Tags], \"a\"))", EachStyle::Each),
+            "List.Select(L, each _[Keep] and List.Contains(_[Tags], \"a\"))\n"
+        );
+        // The inner function becomes an `each` after the outer one
+        assert_eq!(
+            prefer("(x) => List.Select(x[Rows], (y) => y[A] > 0)", EachStyle::Each),
+            "each List.Select(_[Rows], each _[A] > 0)\n"
+        );
+        for kept in [
+            "(x) => x",
+            "(x as record) => x[A]",
+            "(x, y) => x[A] + y",
+            "(x) => [x = 1, y = x[A]]",
+            // `_` would refer to the inner `each`, or the outer one
+            "(x) => List.Select(x[Rows], each _ > x[Min])",
+            "each (x) => x[A] + _[B]",
+        ] {
+            assert_eq!(prefer(kept, EachStyle::Each), prefer(kept, EachStyle::Preserve), "{}", kept);
+        }
+    }
+    
+    #[test]
+    fn test_prefer_lambda() {
+        assert_eq!(
+            prefer("Table.SelectRows(S, each [A] > 1 and _[[B], [C]] <> null)", EachStyle::Lambda),
+            "Table.SelectRows(\n    S,\n    (row) => row[A] > 1 and row[[B], [C]] <> null\n)\n"
+        );
+        for kept in ["List.Transform(L, each _ * 2)", "each [A] + row", "each let row = 1 in [A] + row"] {
+            assert_eq!(prefer(kept, EachStyle::Lambda), prefer(kept, EachStyle::Preserve), "{}", kept);
+        }
+    }
+}
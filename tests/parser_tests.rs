@@ -1,6 +1,9 @@
 //! Parser integration tests for Power Query M
 
-use pqm_formatter::{format_default, validate};
+use pqm_formatter::{
+    format, format_default, format_with_diagnostics, validate, Config, Dialect, Lexer, NewlineKind, ParseError,
+    ParseOptions, Parser, StartRule, StyleEdition,
+};
 
 // ============================================
 // Basic Literals
@@ -133,6 +136,30 @@ fn test_try_complex() {
     assert!(validate("try Number.FromText(x) otherwise null").is_ok());
 }
 
+#[test]
+fn test_try_catch() {
+    assert!(validate("try Number.FromText(x) catch (e) => e[Message]").is_ok());
+    assert!(validate("try x catch () => null").is_ok());
+    assert!(validate("try x catch null").is_err());
+    assert_eq!(
+        format_default("try x catch(e)=>e[Message]").unwrap(),
+        "try x catch (e) => e[Message]\n"
+    );
+}
+
+#[test]
+fn test_dialect_warnings() {
+    let mut config = Config::default();
+    config.dialect = Dialect::Legacy;
+    let (_, warnings) = format_with_diagnostics("a ?? b", config.clone()).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].code, "unsupported-in-dialect");
+    
+    let mut strict = config;
+    strict.strict = true;
+    assert!(format_with_diagnostics("a ?? b", strict).is_err());
+}
+
 // ============================================
 // Error Expressions
 // ============================================
@@ -166,6 +193,72 @@ fn test_each_nested() {
     assert!(validate("each each _ + _").is_ok());
 }
 
+/// Format `code` with the default settings in edition 2025
+fn format_2025(code: &str) -> String {
+    let mut config = Config::default();
+    config.style_edition = StyleEdition::Edition2025;
+    format(code, config).unwrap()
+}
+
+#[test]
+fn test_each_long_predicate_one_clause_per_line() {
+    let code = r#"let Filtered = Table.SelectRows(Source, each [CustomerName] <> null and [OrderAmount] > 1000 and [Region] = "North America" and [Status] <> "Cancelled") in Filtered"#;
+    // Edition 2024 keeps the predicate on one line
+    assert_eq!(
+        format_default(code).unwrap(),
+        r#"let
+    Filtered = 
+        Table.SelectRows(
+            Source,
+            each _[CustomerName] <> null and _[OrderAmount] > 1000 and _[Region] = "North America" and _[Status] <> "Cancelled"
+        )
+in
+    Filtered
+"#
+    );
+    
+    let formatted = format_2025(code);
+    assert_eq!(
+        formatted,
+        r#"let
+    Filtered =
+        Table.SelectRows(
+            Source,
+            each _[CustomerName] <> null
+                and _[OrderAmount] > 1000
+                and _[Region] = "North America"
+                and _[Status] <> "Cancelled"
+        )
+in
+    Filtered
+"#
+    );
+    assert_eq!(format_2025(&formatted), formatted);
+}
+
+#[test]
+fn test_each_long_predicate_mixed_operators() {
+    let code = r#"Table.SelectRows(Orders, each ([Country] = "Japan" or [Country] = "Korea") and [ShippedDate] <> null and [Quantity] * [UnitPrice] >= 50000 or [Priority] = "High")"#;
+    let formatted = format_2025(code);
+    assert_eq!(
+        formatted,
+        r#"Table.SelectRows(
+    Orders,
+    each (_[Country] = "Japan" or _[Country] = "Korea")
+            and _[ShippedDate] <> null
+            and _[Quantity] * _[UnitPrice] >= 50000
+        or _[Priority] = "High"
+)
+"#
+    );
+}
+
+#[test]
+fn test_each_short_predicate_stays_on_one_line() {
+    let code = "Table.SelectRows(Source, each [A] <> null and [B] > 1)";
+    assert_eq!(format_2025(code), "Table.SelectRows(\n    Source,\n    each _[A] <> null and _[B] > 1\n)\n");
+}
+
 // ============================================
 // Function Expressions
 // ============================================
@@ -198,6 +291,7 @@ fn test_function_with_optional_param() {
 #[test]
 fn test_function_with_return_type() {
     assert!(validate("(x) as number => x * 2").is_ok());
+    assert!(validate("(x as nullable text) as nullable record => [Value = x]").is_ok());
 }
 
 // ============================================
@@ -229,6 +323,16 @@ fn test_record_with_quoted_field() {
     assert!(validate(r#"[#"Field Name" = 1]"#).is_ok());
 }
 
+#[test]
+fn test_keywords_as_field_names() {
+    assert!(validate("[type = 1, if = 2, each = 3, null = 4]").is_ok());
+    assert!(validate("[#table = 1, #date = 2, #custom = 3]").is_ok());
+    assert!(validate("r[type] + r[#table]?").is_ok());
+    assert!(validate("r[[in], [#shared]]").is_ok());
+    assert!(validate("each [let] + [#nan]").is_ok());
+    assert_eq!(format_default("[#table=1][#table]").unwrap(), "[#table = 1][#table]\n");
+}
+
 // ============================================
 // Lists
 // ============================================
@@ -267,6 +371,17 @@ fn test_field_access_quoted() {
     assert!(validate(r#"record[#"Field Name"]"#).is_ok());
 }
 
+#[test]
+fn test_field_names_with_blanks() {
+    // Parts of a generalized identifier keep the blanks between them
+    assert_eq!(format_default("[Net Sales = 1][Net Sales]").unwrap(), "[Net Sales = 1][Net Sales]\n");
+    assert_eq!(
+        format_default("each [Is  Weekend] and [Net and Gross]?").unwrap(),
+        "each _[Is  Weekend] and _[Net and Gross]?\n"
+    );
+    assert!(validate("Table.SelectRows(t, each [Unit Price] > 0)[[Unit Price], [Product Name]]").is_ok());
+}
+
 #[test]
 fn test_field_access_chained() {
     assert!(validate("record[A][B]").is_ok());
@@ -399,6 +514,67 @@ fn test_type_table_optional_column() {
     assert!(validate("type table [A = text, optional B = number]").is_ok());
 }
 
+#[test]
+fn test_type_function_with_parameters() {
+    assert!(validate("type function (a as text, optional b as nullable number) as table").is_ok());
+    assert!(validate(
+        r#"type function (a as (type text meta [Documentation.FieldCaption = "A"])) as any meta [Documentation.Name = "F"]"#
+    )
+    .is_ok());
+    assert_eq!(
+        format_default("type function (x as text, optional y) as table").unwrap(),
+        "type function (x as text, optional y) as table\n"
+    );
+}
+
+#[test]
+fn test_primitive_types() {
+    use pqm_formatter::ast::{ExprKind, TypeKind};
+    
+    let kinds = [
+        ("anynonnull", TypeKind::AnyNonNull),
+        ("action", TypeKind::Action),
+        ("record", TypeKind::AnyRecord),
+        ("table", TypeKind::AnyTable),
+        ("function", TypeKind::AnyFunction),
+    ];
+    for (name, kind) in kinds {
+        let code = format!("x is {}", name);
+        let document = parse_with(&code, ParseOptions::default()).unwrap();
+        let ExprKind::Binary(binary) = &document.expression.kind else {
+            panic!("expected a binary expression, got {:?}", document.expression.kind);
+        };
+        let ExprKind::Type(type_expr) = &binary.right.kind else {
+            panic!("expected a type, got {:?}", binary.right.kind);
+        };
+        assert_eq!(
+            core::mem::discriminant(&type_expr.type_annotation.kind),
+            core::mem::discriminant(&kind),
+            "{}",
+            name
+        );
+        assert_eq!(format_default(&code).unwrap(), format!("{}\n", code));
+    }
+    assert_eq!(
+        format_default("type function (f as function, t as table) as anynonnull").unwrap(),
+        "type function (f as function, t as table) as anynonnull\n"
+    );
+}
+
+#[test]
+fn test_parenthesized_types() {
+    for code in [
+        "type (nullable number)",
+        "type {(nullable text)}",
+        "type table [A = (nullable text)]",
+        "type function (a as (nullable text)) as (list)",
+        "x as (nullable number)",
+        "type (type text meta [A = 1])",
+    ] {
+        assert_eq!(format_default(code).unwrap(), format!("{}\n", code));
+    }
+}
+
 #[test]
 fn test_is_operator() {
     assert!(validate("x is number").is_ok());
@@ -409,6 +585,27 @@ fn test_as_operator() {
     assert!(validate("x as number").is_ok());
 }
 
+#[test]
+fn test_assertion_type_operands_round_trip() {
+    // Each is already formatted; formatting it again must not change it
+    for code in [
+        "x is record",
+        "x as function",
+        "x is table and y is list",
+        "x is type",
+        "x as type [A = number]",
+        "x is type table [A = text]",
+        "x as text as any",
+        "(x as table) is any",
+        "x is nullable number and y is text",
+        "type table []",
+    ] {
+        let formatted = format_default(code).unwrap();
+        assert_eq!(formatted, format!("{}\n", code));
+        assert_eq!(format_default(&formatted).unwrap(), formatted);
+    }
+}
+
 // ============================================
 // Hash Constructors
 // ============================================
@@ -438,6 +635,12 @@ fn test_hash_duration() {
     assert!(validate("#duration(1, 2, 30, 0)").is_ok());
 }
 
+#[test]
+fn test_hash_binary() {
+    assert!(validate(r#"#binary("AQID")"#).is_ok());
+    assert_eq!(format_default("#binary( {0x01,2,3} )").unwrap(), "#binary({0x01, 2, 3})\n");
+}
+
 #[test]
 fn test_hash_table() {
     assert!(validate(r#"#table({"A", "B"}, {{1, 2}, {3, 4}})"#).is_ok());
@@ -462,6 +665,154 @@ fn test_nested_block_comment() {
     assert!(validate("/* outer /* inner */ outer */ 1").is_ok());
 }
 
+#[test]
+fn test_comments_kept_in_nested_positions() {
+    let cases = [
+        ("call argument", "Foo(\n    // c\n    let a = 1 in a)"),
+        ("later call argument", "Foo(1,\n    // c\n    2)"),
+        ("first record field", "[\n    // c\n    A = 1,\n    B = 2\n]"),
+        ("record field value", "[A = // c\n    1]"),
+        ("list item", "{\n    // c\n    1,\n    2\n}"),
+        ("binding value", "let a = // c\n    1 in a"),
+        ("let body", "let a = 1 in // c\n    a"),
+        ("nested let in argument", "Foo(let\n    // c\n    a = 1 in a)"),
+        ("if branch", "if x then // c\n    1 else 2"),
+        ("each body", "each // c\n    _ + 1"),
+        ("function body", "(x) => // c\n    x + 1"),
+        ("parenthesized", "(\n    // c\n    1 + 2)"),
+        ("otherwise", "try x otherwise // c\n    0"),
+    ];
+    for (position, code) in cases {
+        let formatted = format_default(code).unwrap();
+        assert!(formatted.contains("// c\n"), "comment dropped in {}: {:?}", position, formatted);
+        assert_eq!(format_default(&formatted).unwrap(), formatted, "not idempotent in {}", position);
+    }
+}
+
+#[test]
+fn test_comments_inside_expressions_are_placed() {
+    // Comments no production attaches go to the nearest expression
+    let cases = [
+        ("around operator", "1 /* a */ + /* b */ 2"),
+        ("before operator on next line", "a // c\n    & b"),
+        ("operand of not", "not /* n */ a"),
+        ("field access", "a[/* f */ B]"),
+        ("item access", "a{/* i */ 0}"),
+        ("constructor argument", "#date(/* d */ 2020, 1, 1)"),
+        ("parameter type", "(a as /* t */ number) => a"),
+        ("parameter", "(x /* p */, y) => x + /* op */ y"),
+        ("parameter line comment", "(x, // first\n    y) => x"),
+        ("empty call", "f( /* none */ )"),
+        ("empty list", "{ /* none */ }"),
+        ("empty record", "[ /* none */ ]"),
+        ("record type", "type table [/* c */ A = number]"),
+    ];
+    let comments = |code: &str| -> Vec<String> {
+        let output = Parser::new(Lexer::new(code).tokenize()).parse_output().unwrap();
+        output.comments.into_iter().map(|(_, text)| text).collect()
+    };
+    for (position, code) in cases {
+        for config in [Config::default(), Config::compact(), Config::expanded()] {
+            let formatted = pqm_formatter::format(code, config.clone()).unwrap();
+            assert_eq!(comments(&formatted), comments(code), "comment dropped in {}: {:?}", position, formatted);
+            assert_eq!(pqm_formatter::format(&formatted, config).unwrap(), formatted, "not idempotent in {}", position);
+        }
+    }
+}
+
+#[test]
+fn test_parameter_comments_stay_in_parameter_list() {
+    assert_eq!(
+        format_default("(x /* p */, y) => x + /* op */ y").unwrap(),
+        "(x /* p */, y) => x + /* op */ y\n"
+    );
+    assert_eq!(format_default("(/* a */ x as number) => x").unwrap(), "(/* a */ x as number) => x\n");
+    assert_eq!(
+        format_default("(x, // first\ny // second\n) => x + y").unwrap(),
+        "(\n    x, // first\n    y // second\n) => x + y\n"
+    );
+}
+
+#[test]
+fn test_compact_breaks_around_line_comments() {
+    // A line comment ends its line, so the let cannot stay on one line
+    assert_eq!(
+        pqm_formatter::format("let x = a // c\n & b in x", Config::compact()).unwrap(),
+        "let\n    x = \n        a // c\n        & b\nin\n    x\n"
+    );
+    assert_eq!(
+        pqm_formatter::format("let x = a /* c */ & b in x", Config::compact()).unwrap(),
+        "let x = a /* c */ & b in x\n"
+    );
+}
+
+#[test]
+fn test_comments_in_arguments_and_list_items() {
+    assert_eq!(
+        format_default("Foo(\n    // first\n    1,\n    /* second */ 2)").unwrap(),
+        "Foo(\n    // first\n    1,\n    /* second */\n    2\n)\n"
+    );
+    assert_eq!(
+        format_default("let a = 1 in // result\n    a").unwrap(),
+        "let\n    a = 1\nin\n    // result\n    a\n"
+    );
+}
+
+#[test]
+fn test_same_line_comment_trails_previous_binding() {
+    let code = "let\n    a = 1, // about a\n    // about b\n    b = 2\nin\n    b";
+    let document = parse_with(code, ParseOptions::default()).unwrap();
+    let pqm_formatter::ast::ExprKind::Let(let_expr) = &document.expression.kind else {
+        panic!("expected let");
+    };
+    let comments = |trivia: &[pqm_formatter::ast::Trivia]| -> Vec<String> {
+        trivia.iter().map(|t| format!("{:?}", t)).collect()
+    };
+    assert_eq!(comments(&let_expr.bindings[0].trailing_trivia), ["LineComment(\" about a\")"]);
+    assert_eq!(comments(&let_expr.bindings[1].leading_trivia), ["LineComment(\" about b\")"]);
+    assert!(let_expr.bindings[1].trailing_trivia.is_empty());
+    assert_eq!(format_default(code).unwrap(), format!("{}\n", code));
+}
+
+#[test]
+fn test_same_line_comment_ownership_in_lists_records_and_calls() {
+    assert_eq!(
+        format_default("{1, // one\n    // before two\n    2}").unwrap(),
+        "{\n    1, // one\n    // before two\n    2\n}\n"
+    );
+    assert_eq!(
+        format_default("[A = 1, // a\n    B = 2]").unwrap(),
+        "[\n    A = 1, // a\n    B = 2\n]\n"
+    );
+    assert_eq!(
+        format_default("Foo(x, // first\n    y)").unwrap(),
+        "Foo(\n    x, // first\n    y\n)\n"
+    );
+    // A comment before the comma stays with its element and ends up after the comma
+    assert_eq!(
+        format_default("let a = 1 // a\n    , b = 2 in b").unwrap(),
+        "let\n    a = 1, // a\n    b = 2\nin\n    b\n"
+    );
+}
+
+#[test]
+fn test_parse_output_file_facts() {
+    let code = "\u{FEFF}let\r\n    // first\r\n    a = 1 /* inline */\r\nin\r\n    a // end";
+    let output = Parser::new(Lexer::new(code).tokenize()).parse_output().unwrap();
+    assert!(output.had_bom);
+    assert_eq!(output.newline_kind, NewlineKind::CrLf);
+    let comments: Vec<&str> = output.comments.iter().map(|(_, text)| text.as_str()).collect();
+    assert_eq!(comments, ["// first", "/* inline */", "// end"]);
+    for (span, text) in &output.comments {
+        assert_eq!(span.slice(code), text);
+    }
+    
+    let output = Parser::new(Lexer::new("1 + 2").tokenize()).parse_output().unwrap();
+    assert!(!output.had_bom);
+    assert_eq!(output.newline_kind, NewlineKind::Lf);
+    assert!(output.comments.is_empty());
+}
+
 // ============================================
 // Complex Real-World Examples
 // ============================================
@@ -680,15 +1031,19 @@ fn test_record_field_projection() {
 
 #[test]
 fn test_at_identifier() {
+    use pqm_formatter::ast::ExprKind;
+    
     let code = "let @x = 1 in @x";
-    // @ prefix for scoped identifier
-    let result = validate(code);
-    if let Err(ref e) = result {
-        for err in e {
-            eprintln!("Error: {}", err.message);
-        }
-    }
-    // This might fail if @ is not handled correctly, that's ok for now
+    let (document, _) = pqm_formatter::parse_document(code, &pqm_formatter::Config::default()).unwrap();
+    let ExprKind::Let(let_expr) = &document.expression.kind else { panic!() };
+    assert!(let_expr.bindings[0].inclusive);
+    assert_eq!(let_expr.bindings[0].name.name, "x");
+    let ExprKind::InclusiveIdentifier(ident) = &let_expr.body.kind else { panic!() };
+    assert_eq!(ident.name, "x");
+    assert_eq!(format_default(code).unwrap(), "let\n    @x = 1\nin\n    @x\n");
+    
+    // Quoted names keep their quotes
+    assert_eq!(format_default("@ #\"a b\"").unwrap(), "@#\"a b\"\n");
 }
 
 #[test]
@@ -750,9 +1105,16 @@ fn test_incomplete_function() {
     assert!(validate(code).is_err());
 }
 
-// ========== New tests for improved parser ==========
-
 #[test]
+fn test_lexer_errors_reported_where_they_start() {
+    let errors = validate("let\n    a = \"x\",\n    b = \"y #(bogus) z\"\nin\n    b").unwrap_err();
+    assert_eq!(errors[0].message, "Unknown escape sequence: bogus");
+    assert_eq!((errors[0].span.line, errors[0].span.column), (3, 9));
+    
+    let errors = validate("let a = 1 in\n    a & `b`").unwrap_err();
+    assert_eq!(errors[0].message, "Unexpected character '`'");
+    assert_eq!((errors[0].span.line, errors[0].span.column), (2, 9));
+}
 
 // ========== New tests for improved parser v0.5 ==========
 
@@ -828,3 +1190,432 @@ fn test_item_access_optional() {
     let formatted = result.unwrap();
     assert!(formatted.contains("{0}?"));
 }
+
+// ============================================
+// Parse Options
+// ============================================
+
+fn parse_with(code: &str, options: ParseOptions) -> Result<pqm_formatter::ast::Document, Vec<ParseError>> {
+    let tokens = Lexer::new(code).tokenize();
+    Parser::with_options(tokens, options).parse()
+}
+
+#[test]
+fn test_parse_options_default_matches_new() {
+    let code = "let x = 1 in x";
+    assert!(parse_with(code, ParseOptions::default()).is_ok());
+}
+
+#[test]
+fn test_parse_options_without_trivia() {
+    let code = "let\n    // comment\n    x = 1\nin\n    x";
+    let options = ParseOptions {
+        attach_trivia: false,
+        ..ParseOptions::default()
+    };
+    let doc = parse_with(code, options).unwrap();
+    if let pqm_formatter::ast::ExprKind::Let(let_expr) = &doc.expression.kind {
+        assert!(let_expr.bindings[0].leading_trivia.is_empty());
+    } else {
+        panic!("Expected let expression");
+    }
+}
+
+#[test]
+fn test_parse_options_max_depth() {
+    let code = format!("{}1{}", "(".repeat(20), ")".repeat(20));
+    let shallow = ParseOptions {
+        max_depth: 10,
+        ..ParseOptions::default()
+    };
+    let errors = parse_with(&code, shallow).unwrap_err();
+    assert!(errors[0].message.contains("nesting depth"));
+    assert!(parse_with(&code, ParseOptions::default()).is_ok());
+}
+
+#[test]
+fn test_deep_nesting_formats() {
+    // Debug builds need far more stack per level than a test thread has
+    let formats = std::thread::Builder::new()
+        .stack_size(256 << 20)
+        .spawn(|| {
+            let lists = format!("{}1{}", "{".repeat(999), "}".repeat(999));
+            assert!(format_default(&lists).is_ok());
+            
+            // Only nesting counts toward the depth limit, not chain length
+            let branches: String = (0..5000).map(|i| format!("if x = {} then {} else ", i, i)).collect();
+            let formatted = format_default(&format!("{}0", branches)).unwrap();
+            assert!(formatted.ends_with("else if x = 4999 then 4999 else 0\n"), "{}", formatted);
+            
+            let too_deep = format!("{}1{}", "(".repeat(1001), ")".repeat(1001));
+            let errors = validate(&too_deep).unwrap_err();
+            assert_eq!(errors[0].message, "Maximum nesting depth of 1000 exceeded");
+        })
+        .unwrap();
+    formats.join().unwrap();
+}
+
+#[test]
+fn test_parse_options_start_rule() {
+    let options = ParseOptions {
+        start: StartRule::Any,
+        ..ParseOptions::default()
+    };
+    assert!(parse_with("1 + 2", options.clone()).is_ok());
+    assert!(parse_with("[Version = \"1.0\"] section A; B = 1;", options).is_ok());
+    let expression = ParseOptions {
+        start: StartRule::Expression,
+        ..ParseOptions::default()
+    };
+    assert!(parse_with("section A; B = 1;", expression).is_err());
+    let section = ParseOptions {
+        start: StartRule::Section,
+        ..ParseOptions::default()
+    };
+    let document = parse_with("section A; B = 1;", section.clone()).unwrap();
+    assert!(matches!(document.expression.kind, pqm_formatter::ast::ExprKind::Section(_)));
+    assert!(parse_with("1 + 2", section).is_err());
+}
+
+// ============================================
+// Section Documents
+// ============================================
+
+#[test]
+fn test_section_document() {
+    let code = "// connector\nsection   Connector; // kind\n// Entry point\n[DataSource.Kind=\"Connector\"]\nshared Connector.Contents = (url as text) => Web.Contents(url);\nHelper=let a=1 in a /* helper */;\n// end\n";
+    let formatted = pqm_formatter::format(code, Config::default()).unwrap();
+    assert_eq!(
+        formatted,
+        "// connector\nsection Connector; // kind\n\n// Entry point\n[DataSource.Kind = \"Connector\"]\nshared Connector.Contents = (url as text) => Web.Contents(url);\n\nHelper = let\n    a = 1\nin\n    a; /* helper */\n// end\n"
+    );
+    assert_eq!(pqm_formatter::format(&formatted, Config::default()).unwrap(), formatted);
+    
+    let document = pqm_formatter::parse_document(code, &Config::default()).unwrap().0;
+    let pqm_formatter::ast::ExprKind::Section(section) = &document.expression.kind else {
+        panic!("expected a section document");
+    };
+    assert_eq!(section.name.name, "Connector");
+    assert!(section.members[0].shared && !section.members[1].shared);
+    assert!(section.members[0].attributes.is_some());
+}
+
+#[test]
+fn test_section_errors() {
+    assert!(pqm_formatter::format("section A; B = 1", Config::default()).is_err());
+    assert!(pqm_formatter::format("section A B = 1;", Config::default()).is_err());
+    assert!(pqm_formatter::format("section A; shared = 1;", Config::default()).is_err());
+}
+
+#[test]
+fn test_section_access() {
+    let document = parse_with("Section1!#\"My Query\"[Column]", ParseOptions::default()).unwrap();
+    let pqm_formatter::ast::ExprKind::FieldAccess(field) = &document.expression.kind else {
+        panic!("expected a field access");
+    };
+    let pqm_formatter::ast::ExprKind::SectionAccess(access) = &field.expr.kind else {
+        panic!("expected a section access");
+    };
+    assert!(matches!(&access.section.kind, pqm_formatter::ast::ExprKind::Identifier(name) if name == "Section1"));
+    assert_eq!(access.member.name, "My Query");
+    assert!(access.member.quoted);
+    
+    assert!(parse_with("#sections[Section1]!Query", ParseOptions::default()).is_ok());
+    assert!(parse_with("#shared[Query]", ParseOptions::default()).is_ok());
+    assert!(parse_with("Section1!", ParseOptions::default()).is_err());
+}
+
+#[test]
+fn test_section_access_style_setting() {
+    let mut config = Config::default();
+    config.set("section_access_style", "bracket").unwrap();
+    assert_eq!(pqm_formatter::format("Section1!Query", config).unwrap(), "#sections[Section1][Query]\n");
+    
+    let code = "// pqmfmt: section_access_style=bang\n#sections[Section1][Query]";
+    assert_eq!(format_default(code).unwrap(), "// pqmfmt: section_access_style=bang\nSection1!Query\n");
+    
+    // Left as written by default
+    assert_eq!(format_default("#sections[Section1][Query]").unwrap(), "#sections[Section1][Query]\n");
+}
+
+#[test]
+fn test_environment_records() {
+    for code in [
+        "#shared[Table.FromRows]",
+        "#shared[Text.Upper](\"a\")",
+        "Record.FieldNames(#shared){0}",
+        "#sections[Section1][#\"My Query\"]?",
+        "Record.FieldCount(#sections)",
+    ] {
+        assert_eq!(format_default(code).unwrap(), format!("{}\n", code));
+    }
+}
+
+#[test]
+fn test_document_nodes() {
+    use pqm_formatter::ast::NodeRef;
+    
+    let code = "section S;\nshared F = (x) => [a = x];\nG = let y = 1 in y;";
+    let document = parse_with(code, ParseOptions::default()).unwrap();
+    let nodes: Vec<(&str, &str, usize)> = document
+        .nodes()
+        .map(|(node, span, depth)| {
+            let kind = match node {
+                NodeRef::Expr(_) => "expr",
+                NodeRef::Binding(_) => "binding",
+                NodeRef::SectionMember(_) => "member",
+                NodeRef::RecordField(_) => "field",
+                NodeRef::Parameter(_) => "parameter",
+                _ => "other",
+            };
+            (kind, span.slice(code), depth)
+        })
+        .collect();
+    assert_eq!(
+        nodes[1..],
+        [
+            ("member", "shared F = (x) => [a = x];", 1),
+            ("expr", "(x) => [a = x]", 2),
+            ("parameter", "x", 3),
+            ("expr", "[a = x]", 3),
+            ("field", "a = x", 4),
+            ("expr", "x", 5),
+            ("member", "G = let y = 1 in y;", 1),
+            ("expr", "let y = 1 in y", 2),
+            ("binding", "y = 1", 3),
+            ("expr", "1", 4),
+            ("expr", "y", 3),
+        ]
+    );
+}
+
+#[test]
+#[cfg(feature = "transforms")]
+fn test_bundled_section_is_formatted() {
+    let members = [("Sales", "// Raw sales\nlet Source = 1 in Source // kept"), ("Total Cost", "2")];
+    let section = pqm_formatter::refactor::bundle("Section1", &members, &Config::default()).unwrap();
+    assert_eq!(pqm_formatter::format(&section, Config::default()).unwrap(), section);
+}
+
+// ============================================
+// Unknown Hash Keywords
+// ============================================
+
+#[test]
+fn test_unknown_hash_keyword_passthrough() {
+    let (formatted, warnings) =
+        format_with_diagnostics("#customthing(1,2)[Field]", Config::default()).unwrap();
+    assert_eq!(formatted.trim(), "#customthing(1, 2)[Field]");
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].code, "unknown-hash-keyword");
+}
+
+#[test]
+fn test_known_hash_keyword_has_no_warning() {
+    let (_, warnings) = format_with_diagnostics("#date(2024, 1, 1)", Config::default()).unwrap();
+    assert!(warnings.is_empty());
+}
+
+// ============================================
+// Lenient Mode
+// ============================================
+
+fn lenient() -> Config {
+    let mut config = Config::default();
+    config.lenient = true;
+    config
+}
+
+#[test]
+fn test_lenient_keeps_unparseable_field_verbatim() {
+    let code = "[A=1, B = ~~odd   stuff~~, C=3]";
+    assert!(validate(code).is_err());
+    let (formatted, warnings) = format_with_diagnostics(code, lenient()).unwrap();
+    assert!(formatted.contains("B = ~~odd   stuff~~"));
+    assert!(formatted.contains("C = 3"));
+    assert_eq!(warnings[0].code, "lenient-verbatim");
+}
+
+#[test]
+fn test_record_recovers_at_next_field() {
+    let code = "[A=1, B 2, C=3, 4 = D, E=5]";
+    let errors = validate(code).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    
+    let (formatted, warnings) = format_with_diagnostics(code, lenient()).unwrap();
+    assert_eq!(formatted, "[\n    A = 1,\n    B 2,\n    C = 3,\n    4 = D,\n    E = 5\n]\n");
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].message.ends_with("field kept as written"));
+}
+
+#[test]
+fn test_errors_name_field_and_item() {
+    let code = "[\n    A = 1, B = 2, C = 3,\n    D = 4, E = 5, F = 6,\n    G = 7 +, H = 8\n]";
+    let errors = validate(code).unwrap_err();
+    assert!(errors[0].message.ends_with(" (in field 7 of record starting at line 1)"));
+    
+    // The innermost element first
+    let code = "Table.TransformColumnTypes(\n    Source,\n    {\n        {\"A\", type text},\n        {\"B\" type number}\n    }\n)";
+    let errors = validate(code).unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "Unexpected token after expression (in item 1 of list starting at line 5, in item 2 of list starting at line 3)"
+    );
+    
+    // Not inside the elements
+    assert!(!validate("{1, 2").unwrap_err()[0].message.contains("(in "));
+}
+
+#[test]
+fn test_unclosed_bracket_label() {
+    let errors = validate("let\n    A = f(x,\n        [a = 1]\nin\n    A").unwrap_err();
+    assert_eq!(errors[0].labels.len(), 1);
+    assert_eq!(errors[0].labels[0].message, "unclosed `(` opened here");
+    assert_eq!((errors[0].labels[0].span.line, errors[0].labels[0].span.column), (2, 10));
+    assert_eq!(
+        errors[0].to_string(),
+        "Line 4: Expected RightParen, found In\n    Line 2: unclosed `(` opened here"
+    );
+    
+    // Other errors have none
+    assert!(validate("let A = 1 + in A").unwrap_err()[0].labels.is_empty());
+}
+
+#[test]
+fn test_errors_reported_in_one_pass() {
+    let code = "let\n    A = 1 +,\n    = 2,\n    C = {1, 2 3},\n    D = f(x, *),\n    E = [a = 1, b = ],\n    F = 5\nin\n    F";
+    let errors = validate(code).unwrap_err();
+    let lines: Vec<usize> = errors.iter().map(|e| e.span.line).collect();
+    assert_eq!(lines, [2, 3, 4, 5, 6]);
+    assert_eq!(errors[2].message, "Unexpected token after expression (in item 2 of list starting at line 4)");
+    
+    // A list item does not swallow the rest of the list
+    let errors = validate("{(1 +), 2, 3 4}").unwrap_err();
+    assert_eq!(errors.len(), 2);
+}
+
+#[test]
+fn test_no_follow_on_errors() {
+    let messages = |code| -> Vec<String> {
+        validate(code).unwrap_err().into_iter().map(|e| e.message).collect()
+    };
+    
+    // An unclosed `(` does not swallow the `in`
+    assert_eq!(
+        messages("let a = 1 +, b = , c = (3 in c"),
+        ["Unexpected token: Comma", "Unexpected token: Comma", "Expected RightParen, found In"]
+    );
+    
+    // Nothing more is reported at the end of the input
+    assert_eq!(messages("let x = \"abc in x"), ["Unterminated string"]);
+    
+    // A missing comma is reported once and the next binding is kept
+    assert_eq!(messages("let a = 1 b = 2 in a"), ["Expected Comma, found Identifier(\"b\")"]);
+    assert_eq!(
+        messages("[A = 1 B = 2 C = 3]"),
+        [
+            "Expected Comma, found Identifier(\"B\") (in field 1 of record starting at line 1)",
+            "Expected Comma, found Identifier(\"C\") (in field 2 of record starting at line 1)",
+        ]
+    );
+}
+
+#[test]
+fn test_recovery_on_large_unbalanced_input() {
+    let start = std::time::Instant::now();
+    
+    let bindings: Vec<String> = (0..20_000).map(|i| format!("a{} = (", i)).collect();
+    let errors = validate(&format!("let {} in a0", bindings.join(", "))).unwrap_err();
+    assert_eq!(errors.len(), pqm_formatter::parser::MAX_ERRORS + 1);
+    assert_eq!(errors[errors.len() - 1].message, "Too many errors; stopped after 100");
+    
+    // Each bracket nests, so this needs more stack than a test thread has
+    let nested = std::thread::Builder::new()
+        .stack_size(256 << 20)
+        .spawn(|| validate(&"[x=(".repeat(2000)).is_err() && validate(&"(x, ".repeat(5000)).is_err())
+        .unwrap();
+    assert!(nested.join().unwrap());
+    
+    // Recovery is linear in the input; this took minutes when each
+    // opening bracket was matched by scanning to the end
+    assert!(start.elapsed() < std::time::Duration::from_secs(5), "took {:?}", start.elapsed());
+}
+
+#[test]
+fn test_parse_cancellation() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    
+    // Set after lexing: the parser checks the token itself
+    let code = format!("let {} in a0", (0..20_000).map(|i| format!("a{} = {{{}, (", i, i)).collect::<Vec<_>>().join(", "));
+    let token = AtomicBool::new(false);
+    let mut parser = Parser::new(Lexer::new(&code).tokenize());
+    parser.set_cancellation(&token);
+    token.store(true, Ordering::Relaxed);
+    assert!(matches!(parser.try_parse(), Err(pqm_formatter::FormatError::Cancelled)));
+    
+    let mut parser = Parser::new(Lexer::new("let x = 1 in x").tokenize());
+    parser.set_cancellation(&token);
+    let errors = parser.parse().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "Parsing was cancelled");
+    
+    token.store(false, Ordering::Relaxed);
+    let mut parser = Parser::new(Lexer::new("let x = 1 in x").tokenize());
+    parser.set_cancellation(&token);
+    assert!(parser.try_parse().is_ok());
+}
+
+#[test]
+fn test_lenient_keeps_unexpected_trailing_tokens() {
+    let code = "let a = Foo(1,2), b = {1..10} in b";
+    let formatted = pqm_formatter::format(code, lenient()).unwrap();
+    assert!(formatted.contains("Foo(1, 2)"));
+    assert!(formatted.contains("{1..10}"));
+}
+
+#[test]
+fn test_lenient_valid_code_unchanged_behavior() {
+    let code = "let x = 1 in x";
+    let strict = format_default(code).unwrap();
+    let (formatted, warnings) = format_with_diagnostics(code, lenient()).unwrap();
+    assert_eq!(strict, formatted);
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn test_lenient_whole_document() {
+    let code = "1 2 3";
+    let formatted = pqm_formatter::format(code, lenient()).unwrap();
+    assert_eq!(formatted, "1 2 3\n");
+}
+
+// ============================================
+// Concrete Syntax Tree
+// ============================================
+
+#[test]
+fn test_syntax_tree_round_trips_corpus() {
+    use pqm_formatter::cst::{SyntaxElement, SyntaxNode, SyntaxTree};
+    
+    fn check(node: &SyntaxNode, source: &str) {
+        assert_eq!(node.text(), node.node.span().slice(source));
+        node.nodes().for_each(|child| check(child, source));
+    }
+    
+    let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/corpus");
+    for entry in std::fs::read_dir(corpus).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|e| e != "pq") {
+            continue;
+        }
+        let code = std::fs::read_to_string(&path).unwrap();
+        let (document, _) = pqm_formatter::parse_document(&code, &Config::default()).unwrap();
+        let tree = SyntaxTree::new(&document);
+        assert_eq!(tree.text(), code, "{}", path.display());
+        for child in &tree.children {
+            if let SyntaxElement::Node(node) = child {
+                check(node, &code);
+            }
+        }
+    }
+}
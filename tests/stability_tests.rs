@@ -0,0 +1,140 @@
+//! Golden stability tests for formatter output
+//!
+//! Formats every file in `tests/corpus` with each preset, and with the
+//! default settings in edition 2025, and compares the SHA-256 of the output
+//! against `tests/corpus/hashes.txt`. Any difference means the layout
+//! changed (deliberately or through nondeterminism). Every preset must also
+//! keep every comment of every file.
+//!
+//! Run with `cargo test --features stability-tests`. After a deliberate
+//! layout change, regenerate the hashes with `PQMFMT_BLESS=1` and the
+//! preset gallery with `cargo run --example gallery`.
+
+#![cfg(feature = "stability-tests")]
+
+use pqm_formatter::token::TokenKind;
+use pqm_formatter::{format, Config, Lexer, StyleEdition};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const HASHES_FILE: &str = "tests/corpus/hashes.txt";
+
+fn presets() -> Vec<(&'static str, Config)> {
+    let mut edition_2025 = Config::default();
+    edition_2025.style_edition = StyleEdition::Edition2025;
+    vec![
+        ("default", Config::default()),
+        ("compact", Config::compact()),
+        ("expanded", Config::expanded()),
+        ("edition2025", edition_2025),
+    ]
+}
+
+fn corpus_files() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir("tests/corpus")
+        .expect("corpus directory")
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|e| e == "pq").unwrap_or(false))
+        .collect();
+    // Directory iteration order is platform dependent
+    files.sort();
+    files
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+fn read_source(path: &Path) -> String {
+    // Checkouts may convert line endings; hash the LF form everywhere
+    fs::read_to_string(path).expect("corpus file").replace("\r\n", "\n")
+}
+
+fn sha256_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn compute_hashes() -> Vec<String> {
+    let mut lines = Vec::new();
+    for path in corpus_files() {
+        let source = read_source(&path);
+        for (name, config) in presets() {
+            let formatted = format(&source, config)
+                .unwrap_or_else(|e| panic!("{} failed to format: {:?}", path.display(), e));
+            lines.push(format!("{} {} {}", name, file_name(&path), sha256_hex(&formatted)));
+        }
+    }
+    lines
+}
+
+#[test]
+fn test_corpus_hashes_match() {
+    let actual = compute_hashes();
+    
+    if std::env::var("PQMFMT_BLESS").is_ok() {
+        fs::write(HASHES_FILE, actual.join("\n") + "\n").unwrap();
+        return;
+    }
+    
+    let expected: Vec<String> = fs::read_to_string(HASHES_FILE)
+        .expect("hashes file (run with PQMFMT_BLESS=1 to create it)")
+        .lines()
+        .map(|l| l.to_string())
+        .collect();
+    
+    let mismatches: Vec<String> = actual
+        .iter()
+        .filter(|line| !expected.contains(line))
+        .cloned()
+        .collect();
+    
+    assert!(
+        mismatches.is_empty() && actual.len() == expected.len(),
+        "formatted output changed for:\n{}\n(run with PQMFMT_BLESS=1 if the change is intended)",
+        mismatches.join("\n")
+    );
+}
+
+#[test]
+fn test_formatting_is_repeatable() {
+    for path in corpus_files() {
+        let source = read_source(&path);
+        for (name, config) in presets() {
+            let first = format(&source, config.clone()).unwrap();
+            let second = format(&source, config).unwrap();
+            assert_eq!(first, second, "{} ({}) is not deterministic", file_name(&path), name);
+        }
+    }
+}
+
+fn comment_count(code: &str) -> usize {
+    Lexer::new(code)
+        .tokenize()
+        .iter()
+        .filter(|t| matches!(t.kind, TokenKind::LineComment(_) | TokenKind::BlockComment(_)))
+        .count()
+}
+
+#[test]
+fn test_comments_are_kept() {
+    for path in corpus_files() {
+        let source = read_source(&path);
+        for (name, config) in presets() {
+            let formatted = format(&source, config.clone()).unwrap();
+            assert_eq!(
+                comment_count(&formatted),
+                comment_count(&source),
+                "{} ({}) lost comments:\n{}",
+                file_name(&path),
+                name,
+                formatted
+            );
+            let again = format(&formatted, config).unwrap();
+            assert_eq!(again, formatted, "{} ({}) is not idempotent", file_name(&path), name);
+        }
+    }
+}